@@ -0,0 +1,314 @@
+//! Static stack-depth analysis.
+//!
+//! This module abstractly interprets a program over the range of
+//! possible stack depths at each instruction, following every control
+//! flow edge whose target is known statically. Indirect jumps whose
+//! target cannot be determined are assumed to reach every location
+//! the program pushes, and depths that cannot be tracked (across tail
+//! calls, or calls not set up by SetFrame) are assumed to be
+//! anything, so the results err on the side of reporting problems.
+
+use alloc::{collections::BTreeSet, vec, vec::Vec};
+use core::fmt;
+#[cfg(feature = "std")]
+use std::error;
+use crate::isa::{*, Binop::*, Instr::*, Val::*};
+
+/// Upper bound standing for an unbounded depth.
+const UNBOUNDED: u32 = u32::MAX;
+
+/// What the analysis found.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StackReport {
+    /// Maximum possible stack depth, or None if it may grow without
+    /// bound.
+    pub max_depth: Option<u32>,
+    /// Locations of instructions that may pop more values than the
+    /// stack holds, in order.
+    pub underflows: Vec<u32>
+}
+
+/// Why a program could not be analyzed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AnalysisError {
+    /// The instruction at pc refers to a code location outside the
+    /// program.
+    BadTarget { pc: u32, target: u32 }
+}
+
+impl fmt::Display for AnalysisError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	match self {
+	    AnalysisError::BadTarget { pc, target } =>
+		write!(f, "{}: target {} outside program", pc, target)
+	}
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for AnalysisError {}
+
+/// Range of possible stack depths, lo to hi inclusive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Depth {
+    lo: u32,
+    hi: u32
+}
+
+impl Depth {
+    fn exactly(n: u32) -> Depth {
+	Depth { lo: n, hi: n }
+    }
+    fn any() -> Depth {
+	Depth { lo: 0, hi: UNBOUNDED }
+    }
+    /// The depth after popping pops values and pushing pushes.
+    fn apply(self, pops: u32, pushes: u32) -> Depth {
+	let shift = |n: u32| if n == UNBOUNDED {
+	    n
+	} else {
+	    n.saturating_sub(pops).saturating_add(pushes).min(UNBOUNDED - 1)
+	};
+	Depth { lo: shift(self.lo), hi: shift(self.hi) }
+    }
+    fn join(self, other: Depth) -> Depth {
+	Depth { lo: self.lo.min(other.lo), hi: self.hi.max(other.hi) }
+    }
+}
+
+/// Number of values instr pops and pushes, ignoring any transfer of
+/// control.
+fn effect(instr: &Instr) -> (u32, u32) {
+    match instr {
+	Push(_) | Var(_) | SetFrame(_) | Read | GGet(_) | PushPC => (0, 1),
+	// Peeking needs i+1 values, and leaves them in place.
+	Peek(i) => (i.saturating_add(1), i.saturating_add(2)),
+	Nop | Jmpi(_) | Globals(_) | Inc(_) | Dec(_) | Yield | Calli(_) => (0, 0),
+	Pop | Store(_) | Print | Free | Assert | GSet(_) | Jmp | Exit | Switch(_) => (1, 0),
+	Unary(_) | SLen | TypeTest(_) | AllocI(_) | GetF(_) | Len | Rand | Spawn | Join
+	    | Call => (1, 1),
+	Binary(AddC | SubC | MulC) => (2, 2),
+	Binary(_) | Alloc | Get | SConcat | Grow => (2, 1),
+	Swap | DivMod => (2, 2),
+	Dup => (1, 2),
+	Over => (2, 3),
+	Rot => (3, 3),
+	Set | Ret => (3, 0),
+	Select => (3, 1),
+	SetF(_) | Branch | BranchNot => (2, 0),
+	ArrCopy => (5, 0),
+	PopN(n) => (*n, 0),
+	AllocClos(n) => (n.saturating_add(1), 1),
+	Syscall(_, n) => (*n, 1),
+	// The target, then the arguments, return pc, and saved fp.
+	TailCall(n) => (n.saturating_add(3), 0),
+	// Halting needs a result on the stack, but leaves it there.
+	Halt => (1, 1),
+	CallClos => (1, 2)
+    }
+}
+
+/// Estimate the stack depth at every instruction reachable from the
+/// start of prog, reporting the maximum and any possible underflows.
+pub fn analyze_stack(prog: &[Instr]) -> Result<StackReport, AnalysisError> {
+    let len = prog.len() as u32;
+    let check = |pc: u32, target: u32| {
+	if target < len { Ok(target) } else { Err(AnalysisError::BadTarget { pc, target }) }
+    };
+    // Where an indirect jump might go: every location the program
+    // pushes.
+    let mut pushed = BTreeSet::new();
+    for (pc, instr) in prog.iter().enumerate() {
+	match instr {
+	    Push(Vloc(target)) => { pushed.insert(check(pc as u32, *target)?); }
+	    PushPC if pc + 1 < prog.len() => { pushed.insert(pc as u32 + 1); }
+	    _ => ()
+	}
+    }
+    let pushed: Vec<u32> = pushed.into_iter().collect();
+
+    // The target popped by the instruction at pc, if the previous
+    // instruction pushed a constant one.
+    let popped_target = |pc: u32| match pc.checked_sub(1).map(|p| &prog[p as usize]) {
+	Some(Push(Vloc(target))) => Some(*target),
+	_ => None
+    };
+    // Location of the SetFrame that set up the call at pc, if it is
+    // among the few instructions before it.
+    let frame_start = |pc: u32| {
+	(1..=3).filter_map(|k| pc.checked_sub(k))
+	    .find(|p| matches!(prog[*p as usize], SetFrame(_)))
+    };
+
+    let mut depths: Vec<Option<Depth>> = vec![None; prog.len()];
+    let mut visited = vec![false; prog.len()];
+    let mut worklist = BTreeSet::new();
+    let mut underflows = BTreeSet::new();
+    let mut max = 0;
+    if !prog.is_empty() {
+	depths[0] = Some(Depth::exactly(0));
+	worklist.insert(0);
+    }
+    // Process instructions in program order, so that code without
+    // back edges is visited once per instruction.
+    while let Some(pc) = worklist.pop_first() {
+	visited[pc as usize] = true;
+	let instr = &prog[pc as usize];
+	let before = depths[pc as usize].unwrap();
+	let (pops, pushes) = effect(instr);
+	if before.lo < pops {
+	    underflows.insert(pc);
+	}
+	let after = before.apply(pops, pushes);
+	max = max.max(before.hi);
+
+	// Successors and the depth on entry to each.
+	let mut succs: Vec<(u32, Depth)> = Vec::new();
+	let jump = |target: Option<u32>, depth: Depth, succs: &mut Vec<(u32, Depth)>| {
+	    match target {
+		Some(target) => succs.push((target, depth)),
+		None => succs.extend(pushed.iter().map(|t| (*t, depth)))
+	    }
+	};
+	// Depth after a call at pc returns: Ret truncates the stack to
+	// the frame pointer set by SetFrame and pushes the result.
+	let returned = match frame_start(pc).map(|p| (&prog[p as usize], depths[p as usize])) {
+	    Some((SetFrame(n), Some(depth))) => depth.apply(*n, 1),
+	    _ => Depth::any()
+	};
+	match instr {
+	    Halt | Exit | Ret => (),
+	    Jmpi(target) => succs.push((check(pc, *target)?, after)),
+	    Jmp => jump(popped_target(pc), after, &mut succs),
+	    Branch | BranchNot => {
+		jump(popped_target(pc), after, &mut succs);
+		succs.push((pc + 1, after))
+	    }
+	    Switch(targets) => {
+		for target in targets {
+		    succs.push((check(pc, *target)?, after))
+		}
+		succs.push((pc + 1, after))
+	    }
+	    Calli(target) => {
+		succs.push((check(pc, *target)?, before.apply(0, 1)));
+		succs.push((pc + 1, returned))
+	    }
+	    Call => {
+		jump(popped_target(pc), after, &mut succs);
+		succs.push((pc + 1, returned))
+	    }
+	    CallClos => {
+		jump(None, after, &mut succs);
+		succs.push((pc + 1, returned))
+	    }
+	    TailCall(_) => jump(popped_target(pc), Depth::any(), &mut succs),
+	    Spawn => {
+		jump(popped_target(pc), Depth::exactly(0), &mut succs);
+		succs.push((pc + 1, after))
+	    }
+	    _ => succs.push((pc + 1, after))
+	}
+
+	for (succ, depth) in succs {
+	    if succ >= len {
+		// Falling off the end is the verifier's business.
+		max = max.max(depth.hi);
+		continue
+	    }
+	    let slot = &mut depths[succ as usize];
+	    let mut new = slot.map_or(depth, |old| old.join(depth));
+	    if *slot == Some(new) {
+		continue
+	    }
+	    // Widen ranges that keep changing around a loop.
+	    if let (Some(old), true) = (*slot, visited[succ as usize]) {
+		if new.lo < old.lo {
+		    new.lo = 0
+		}
+		if new.hi > old.hi {
+		    new.hi = UNBOUNDED
+		}
+	    }
+	    *slot = Some(new);
+	    worklist.insert(succ);
+	}
+    }
+    Ok(StackReport {
+	max_depth: if max == UNBOUNDED { None } else { Some(max) },
+	underflows: underflows.into_iter().collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(max_depth: Option<u32>, underflows: &[u32]) -> Result<StackReport, AnalysisError> {
+	Ok(StackReport { max_depth, underflows: underflows.to_vec() })
+    }
+
+    #[test]
+    fn test_straight_line() {
+	let prog = [Push(Vi32(1)), Push(Vi32(2)), Dup, Binary(Add), Binary(Mul), Halt];
+	assert_eq!(analyze_stack(&prog), report(Some(3), &[]));
+	assert_eq!(analyze_stack(&[]), report(Some(0), &[]));
+    }
+
+    #[test]
+    fn test_diamond() {
+	// One arm pushes two values and the other one, so the join
+	// point may have one or two values.
+	let prog = [
+	    Push(Vbool(true)), Push(Vloc(6)), Branch,
+	    Push(Vi32(1)), Push(Vi32(2)), Jmpi(7),
+	    Push(Vi32(3)),
+	    Binary(Add), Halt
+	];
+	assert_eq!(analyze_stack(&prog), report(Some(2), &[7]));
+	// With a value pushed before the branch, both arms are safe.
+	let mut prog = prog.to_vec();
+	prog.insert(0, Push(Vi32(0)));
+	for instr in &mut prog {
+	    match instr {
+		Push(Vloc(t)) | Jmpi(t) => *t += 1,
+		_ => ()
+	    }
+	}
+	assert_eq!(analyze_stack(&prog), report(Some(3), &[]));
+    }
+
+    #[test]
+    fn test_underflow() {
+	assert_eq!(analyze_stack(&[Push(Vi32(1)), Binary(Add), Halt]), report(Some(1), &[1]));
+	assert_eq!(analyze_stack(&[Pop, Halt]), report(Some(0), &[0, 1]));
+	assert_eq!(analyze_stack(&[Push(Vi32(1)), Push(Vi32(2)), Peek(1), Halt]),
+		   report(Some(3), &[]));
+	assert_eq!(analyze_stack(&[Push(Vi32(1)), Peek(1), Halt]).unwrap().underflows, [1]);
+    }
+
+    #[test]
+    fn test_loops_and_calls() {
+	// A countdown loop keeps the depth constant.
+	let prog = [
+	    Push(Vi32(10)),
+	    Dec(0), Var(0), Push(Vi32(0)), Binary(Neq), Push(Vloc(1)), Branch,
+	    Halt
+	];
+	assert_eq!(analyze_stack(&prog), report(Some(3), &[]));
+	// A loop that pushes on every iteration is unbounded.
+	let prog = [Push(Vi32(1)), Push(Vbool(true)), Push(Vloc(0)), Branch, Halt];
+	assert_eq!(analyze_stack(&prog), report(None, &[]));
+	// Calls return to the caller's depth plus the result.
+	let prog = [
+	    Push(Vi32(20)), SetFrame(1), Calli(4), Halt,
+	    Var(0), Var(0), Binary(Add), Ret
+	];
+	assert_eq!(analyze_stack(&prog), report(Some(5), &[]));
+	// An unknown jump target may be any pushed location.
+	let prog = [Push(Vloc(4)), Push(Vloc(5)), Swap, Jmp, Halt, Pop, Halt];
+	assert_eq!(analyze_stack(&prog), report(Some(2), &[6]));
+	assert_eq!(analyze_stack(&[Jmpi(3)]),
+		   Err(AnalysisError::BadTarget { pc: 0, target: 3 }));
+    }
+}
@@ -4,11 +4,168 @@
 //! pseudo-instruction (assembly) programs into native
 //! programs by resolving label addresses.
 
-use std::collections::HashMap;
+use alloc::{collections::BTreeMap, format, string::String, vec, vec::Vec};
+#[cfg(feature = "std")]
+use core::str::FromStr;
+#[cfg(feature = "std")]
+use std::fs;
 use crate::isa::{*, Instr::*, PInstr::*, Val::*};
 
+/// Addresses of the labels of an assembled program.
+pub type Symbols = BTreeMap<Label, u32>;
+
+/// Native instructions that allocate string s on the heap, leaving
+/// its address on the stack.
+fn string_instrs(s: &str) -> Vec<Instr> {
+    let chars: Vec<char> = s.chars().collect();
+    let mut instrs = vec![
+	Push(Vi32(chars.len() as i32)),
+	Push(Vchar('\0')),
+	Alloc
+    ];
+    for (i, c) in chars.into_iter().enumerate() {
+	instrs.extend(vec![Dup, Push(Vi32(i as i32)), Push(Vchar(c)), Set]);
+    }
+    instrs
+}
+
 /// Translate an assembly program to an equivalent native program.
 pub fn assemble(pinstrs : Vec<PInstr>) -> Result<Vec<Instr>, String> {
-    // Fill in your solution.
-    unimplemented!()
+    assemble_at(pinstrs, 0)
+}
+
+/// Like assemble, for a program that will be loaded at address base.
+pub fn assemble_at(pinstrs : Vec<PInstr>, base: u32) -> Result<Vec<Instr>, String> {
+    assemble_with_symbols(pinstrs, base).map(|(instrs, _)| instrs)
+}
+
+/// Like assemble_at, also returning the address of every label.
+pub fn assemble_with_symbols(pinstrs: Vec<PInstr>, base: u32)
+			     -> Result<(Vec<Instr>, Symbols), String> {
+    // First pass: compute the address of every label.
+    let mut labels = Symbols::new();
+    let mut addr = base;
+    for pinstr in &pinstrs {
+	match pinstr {
+	    PLabel(lbl) => {
+		if labels.insert(lbl.clone(), addr).is_some() {
+		    return Err(format!("duplicate label: {}", lbl))
+		}
+	    }
+	    PPushStr(s) => addr += string_instrs(s).len() as u32,
+	    _ => addr += 1
+	}
+    }
+
+    // Second pass: replace label operands with their addresses.
+    let resolve = |lbl: &Label| {
+	labels.get(lbl).copied().ok_or(format!("unknown label: {}", lbl))
+    };
+    let mut instrs = Vec::with_capacity((addr - base) as usize);
+    for pinstr in pinstrs {
+	match pinstr {
+	    PLabel(_) => (),
+	    PPush(lbl) => instrs.push(Push(Vloc(resolve(&lbl)?))),
+	    PJmp(lbl) => instrs.push(Jmpi(resolve(&lbl)?)),
+	    PCall(lbl) => instrs.push(Calli(resolve(&lbl)?)),
+	    PSwitch(lbls) => {
+		let table = lbls.iter().map(resolve).collect::<Result<_, _>>()?;
+		instrs.push(Switch(table))
+	    }
+	    PPushStr(s) => instrs.extend(string_instrs(&s)),
+	    PI(instr) => instrs.push(instr)
+	}
+    }
+    Ok((instrs, labels))
+}
+
+/// Parse the assembly program in the file at path, skipping blank
+/// lines.
+#[cfg(feature = "std")]
+pub fn parse_file(path: &str) -> Result<Vec<PInstr>, String> {
+    let src = fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+    src.lines()
+	.enumerate()
+	.filter(|(_, l)| !l.trim().is_empty())
+	.map(|(i, l)| PInstr::from_str(l).map_err(|err| format!("{}:{}: {}", path, i + 1, err)))
+	.collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::str::FromStr;
+    use crate::vm::{run, Debug};
+
+    /// Parse assembly source, one pseudo-instruction per line.
+    fn parse(src: &str) -> Vec<PInstr> {
+	src.lines().map(|l| PInstr::from_str(l).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_labels() {
+	let prog = parse("push Lend\njmp Lend\nLend:\nhalt");
+	assert_eq!(assemble(prog), Ok(vec![Push(Vloc(2)), Jmpi(2), Halt]));
+	assert_eq!(assemble(parse("jmp Lnowhere")),
+		   Err("unknown label: Lnowhere".into()));
+	assert_eq!(assemble(parse("La:\nLa:\nhalt")),
+		   Err("duplicate label: La".into()));
+    }
+
+    #[test]
+    fn test_assemble_at() {
+	let prog = parse("push Lend\njmp Lend\nLend:\nhalt");
+	assert_eq!(assemble_at(prog, 10), Ok(vec![Push(Vloc(12)), Jmpi(12), Halt]));
+	let (_, symbols) = assemble_with_symbols(parse("La:\nnop\nLb:\nhalt"), 10).unwrap();
+	assert_eq!(symbols, Symbols::from([("La".into(), 10), ("Lb".into(), 11)]));
+    }
+
+    #[test]
+    fn test_branchnot() {
+	let src = |cond| format!(
+	    "push {}\npush Lelse\nbranchnot\npush 1\njmp Lend\nLelse:\npush 2\nLend:\nhalt",
+	    cond);
+	let prog = assemble(parse(&src("true"))).unwrap();
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi32(1)));
+	let prog = assemble(parse(&src("false"))).unwrap();
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi32(2)));
+    }
+
+    #[test]
+    fn test_calli() {
+	let src = "push 20\nsetframe 1\ncalli Ldouble\nhalt\n\
+		   Ldouble:\nvar 0\nvar 0\nbinary +\nret";
+	let prog = assemble(parse(src)).unwrap();
+	assert_eq!(prog[2], Calli(4));
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi32(40)));
+    }
+
+    #[test]
+    fn test_strings() {
+	let src = "pushstr \"ab\"\npushstr \"\"\nsconcat\npushstr \"cd\"\n\
+		   sconcat\npush Lend\njmp\nLend:\nhalt";
+	let prog = assemble(parse(src)).unwrap();
+	assert_eq!(prog[prog.len() - 2], Jmp);
+	assert_eq!(prog[prog.len() - 3], Push(Vloc(prog.len() as u32 - 1)));
+	let mut len = prog.clone();
+	len.insert(len.len() - 1, SLen);
+	assert_eq!(run(Debug::NODEBUG, &len), Ok(Vi32(4)));
+	let mut third = prog.clone();
+	third.splice(third.len() - 1.., vec![Push(Vi32(2)), Get, Halt]);
+	assert_eq!(run(Debug::NODEBUG, &third), Ok(Vchar('c')));
+    }
+
+    #[test]
+    fn test_switch() {
+	let src = |i| format!(
+	    "push {}\nswitch La Lb Lc\npush 0\nhalt\n\
+	     La:\npush 10\nhalt\nLb:\npush 20\nhalt\nLc:\npush 30\nhalt", i);
+	let prog = assemble(parse(&src(1))).unwrap();
+	assert_eq!(prog[1], Switch(vec![4, 6, 8]));
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi32(20)));
+	let prog = assemble(parse(&src(3))).unwrap();
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi32(0)));
+	assert_eq!(assemble(parse("switch La Lnowhere\nLa:\nhalt")),
+		   Err("unknown label: Lnowhere".into()));
+    }
 }
@@ -0,0 +1,39 @@
+//! Bytecode decoding and assembly benchmarks.
+
+mod programs;
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use grumpy::assemble::assemble;
+use grumpy::isa::{Instr, Val::*};
+use grumpy::vm::{run, Debug};
+use grumpy::FromBytes;
+
+/// Decode a blob of 100,000 instructions taken from the other
+/// benchmark programs.
+fn bench_decode(c: &mut Criterion) {
+    let prog: Vec<Instr> = [programs::fib(20), programs::array_fill(100, 100), programs::sum_loop(10)]
+        .concat()
+        .into_iter()
+        .cycle()
+        .take(100_000)
+        .collect();
+    let bytes = programs::encode(&prog);
+    assert_eq!(Vec::<Instr>::from_bytes(&mut bytes.iter().copied()).unwrap(), prog);
+    c.bench_function("decode 100k", |b| {
+        b.iter(|| Vec::<Instr>::from_bytes(&mut black_box(&bytes).iter().copied()))
+    });
+}
+
+/// Assemble a program of 10,000 functions, each defining two labels
+/// and referring to two.
+fn bench_assemble(c: &mut Criterion) {
+    let prog = assemble(programs::call_chain(100)).unwrap();
+    assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi32(100)));
+    let pinstrs = programs::call_chain(10_000);
+    c.bench_function("assemble 10k functions", |b| {
+        b.iter_batched(|| pinstrs.clone(), assemble, BatchSize::LargeInput)
+    });
+}
+
+criterion_group!(benches, bench_decode, bench_assemble);
+criterion_main!(benches);
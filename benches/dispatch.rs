@@ -0,0 +1,67 @@
+//! Instruction dispatch benchmarks.
+//!
+//! To compare with another revision, run
+//! `cargo bench --bench dispatch -- --save-baseline old` there, then
+//! `cargo bench --bench dispatch -- --baseline old` here.
+
+mod programs;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use grumpy::isa::{Instr::*, Val::*};
+use grumpy::vm::{run, run_with_config, Debug, SharedProgram, Vm, VmConfig};
+
+fn bench_dispatch(c: &mut Criterion) {
+    let prog = programs::sum_loop(10_000);
+    assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi32(49_995_000)));
+    c.bench_function("sum loop", |b| b.iter(|| run(Debug::NODEBUG, black_box(&prog))));
+}
+
+fn bench_fusion(c: &mut Criterion) {
+    let prog = programs::count_loop(10_000);
+    let fused = VmConfig { fuse: true, ..VmConfig::default() };
+    assert_eq!(run_with_config(Debug::NODEBUG, fused, &prog), Ok(Vi32(10_000)));
+    let mut group = c.benchmark_group("count loop");
+    group.bench_function("plain", |b| b.iter(|| run(Debug::NODEBUG, black_box(&prog))));
+    group.bench_function("fused", |b| {
+        b.iter(|| run_with_config(Debug::NODEBUG, fused, black_box(&prog)))
+    });
+    group.finish();
+}
+
+fn bench_calls(c: &mut Criterion) {
+    let prog = programs::fib(20);
+    assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi32(6765)));
+    c.bench_function("fib 20", |b| b.iter(|| run(Debug::NODEBUG, black_box(&prog))));
+}
+
+fn bench_heap(c: &mut Criterion) {
+    let prog = programs::array_fill(100, 100);
+    assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi32(99)));
+    c.bench_function("array fill", |b| b.iter(|| run(Debug::NODEBUG, black_box(&prog))));
+}
+
+/// Run a program of 10,000 instructions that jumps straight to its
+/// end 10,000 times, so that the cost of setting up each run
+/// dominates.
+fn bench_setup(c: &mut Criterion) {
+    let mut prog = vec![Push(Vi32(0)), Jmpi(9_999)];
+    prog.resize(9_999, Nop);
+    prog.push(Halt);
+    let shared = SharedProgram::new(prog.clone()).unwrap();
+    let mut group = c.benchmark_group("10k runs");
+    group.sample_size(10);
+    group.bench_function("copied", |b| b.iter(|| {
+        for _ in 0..10_000 {
+            run(Debug::NODEBUG, black_box(&prog)).unwrap();
+        }
+    }));
+    group.bench_function("shared", |b| b.iter(|| {
+        for _ in 0..10_000 {
+            Vm::with_shared_program(black_box(&shared)).run().unwrap();
+        }
+    }));
+    group.finish();
+}
+
+criterion_group!(benches, bench_dispatch, bench_fusion, bench_calls, bench_heap, bench_setup);
+criterion_main!(benches);
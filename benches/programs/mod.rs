@@ -0,0 +1,91 @@
+//! Program builders shared by the benchmarks.
+//!
+//! Each benchmark checks the result of its program before timing it,
+//! so `cargo test --benches` runs these programs end to end.
+
+// Each benchmark uses only some of the programs.
+#![allow(dead_code)]
+
+use grumpy::isa::{Binop::*, Instr, Instr::*, PInstr, PInstr::*, Val::*};
+use grumpy::ToBytes;
+
+/// A program summing the integers below n in a tight loop, with the
+/// sum in local 0 and the counter in local 1. Halts with the sum.
+pub fn sum_loop(n: i32) -> Vec<Instr> {
+    vec![
+        Push(Vi32(0)), Push(Vi32(0)),
+        Var(0), Var(1), Binary(Add), Store(0), Inc(1),
+        Var(1), Push(Vi32(n)), Binary(Lt), Push(Vloc(2)), Branch,
+        Var(0), Halt
+    ]
+}
+
+/// A loop counting to n the way a simple compiler would, made up of
+/// sequences that fusion combines. Halts with n.
+pub fn count_loop(n: i32) -> Vec<Instr> {
+    vec![
+        Push(Vi32(0)),
+        Var(0), Push(Vi32(1)), Binary(Add), Store(0),
+        Var(0), Push(Vi32(n)), Binary(Lt), Push(Vloc(1)), Branch,
+        Var(0), Halt
+    ]
+}
+
+/// A program computing the nth Fibonacci number with the doubly
+/// recursive function, called with SetFrame and Calli. Halts with
+/// the result.
+pub fn fib(n: i32) -> Vec<Instr> {
+    vec![
+        Push(Vi32(n)), SetFrame(1), Calli(4), Halt,
+        // fib(x) = x if x < 2, and fib(x - 1) + fib(x - 2) otherwise.
+        Var(0), Push(Vi32(2)), Binary(Lt), Push(Vloc(21)), Branch,
+        Var(0), Push(Vi32(1)), Binary(Sub), SetFrame(1), Calli(4),
+        Var(0), Push(Vi32(2)), Binary(Sub), SetFrame(1), Calli(4),
+        Binary(Add), Ret,
+        Var(0), Ret
+    ]
+}
+
+/// A program allocating count arrays of len elements one after
+/// another with Alloc, and setting each element to its index with
+/// Set. Only the last array stays live, so the collector reclaims the
+/// others. Halts with the last element of the last array, len - 1.
+pub fn array_fill(count: i32, len: i32) -> Vec<Instr> {
+    vec![
+        // Locals: the current array, arrays made so far, and the index.
+        Push(Vunit), Push(Vi32(0)), Push(Vi32(0)),
+        Push(Vi32(len)), Push(Vi32(0)), Alloc, Store(0),
+        Push(Vi32(0)), Store(2),
+        Var(0), Var(2), Var(2), Set, Inc(2),
+        Var(2), Push(Vi32(len)), Binary(Lt), Push(Vloc(9)), Branch,
+        Inc(1), Var(1), Push(Vi32(count)), Binary(Lt), Push(Vloc(3)), Branch,
+        Var(0), Push(Vi32(len - 1)), Get, Halt
+    ]
+}
+
+/// An assembly program made of n functions, each passing its argument
+/// plus one to the next and returning through a labelled jump, with
+/// the last returning its argument. Halts with n.
+pub fn call_chain(n: usize) -> Vec<PInstr> {
+    let mut pinstrs = vec![PI(Push(Vi32(0))), PI(SetFrame(1)), PCall("Lf0".into()), PI(Halt)];
+    for i in 0..n {
+        pinstrs.extend(vec![
+            PLabel(format!("Lf{}", i)),
+            PI(Var(0)), PI(Push(Vi32(1))), PI(Binary(Add)),
+            PI(SetFrame(1)), PCall(format!("Lf{}", i + 1)),
+            PPush(format!("Lret{}", i)), PI(Jmp),
+            PLabel(format!("Lret{}", i)), PI(Ret)
+        ]);
+    }
+    pinstrs.extend(vec![PLabel(format!("Lf{}", n)), PI(Var(0)), PI(Ret)]);
+    pinstrs
+}
+
+/// The bytecode encoding of prog: its length, then each instruction.
+pub fn encode(prog: &[Instr]) -> Vec<u8> {
+    let mut bytes = (prog.len() as u32).to_bytes();
+    for instr in prog {
+        bytes.extend(instr.to_bytes())
+    }
+    bytes
+}
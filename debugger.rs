@@ -0,0 +1,228 @@
+//! Interactive GrumpyVM debugger.
+//!
+//! This module runs a program under the control of commands read one
+//! line at a time: single-stepping forwards and back, running to
+//! breakpoints, and inspecting the stack, the heap and the active
+//! calls.
+
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+use crate::assemble::Symbols;
+use crate::isa::Val::*;
+use crate::vm::{StepOutcome, Vm};
+
+/// Snapshot interval and count for back, which can undo at least
+/// 255,000 instructions.
+const HISTORY_EVERY: u32 = 1000;
+const HISTORY_SNAPSHOTS: usize = 256;
+
+static HELP: &str = "\
+step              execute one instruction
+back              undo the last instruction
+continue          run to the next breakpoint or the end of the program
+break PC|LABEL    stop before executing the instruction at PC or LABEL
+print stack       show the stack
+print heap ADDR   show the array at address ADDR
+backtrace         show the current location and the active calls
+quit              leave the debugger";
+
+/// Debugger state: the machine being debugged and its breakpoints.
+pub struct Debugger {
+    vm: Vm,
+    /// Label addresses, for breakpoints set by label.
+    symbols: Symbols,
+    breakpoints: BTreeSet<u32>,
+    /// How the program stopped, once it has.
+    finished: Option<String>
+}
+
+impl Debugger {
+    /// A debugger for vm, which has not started running, resolving
+    /// labels with symbols.
+    pub fn new(mut vm: Vm, symbols: Symbols) -> Debugger {
+	vm.keep_history(HISTORY_EVERY, HISTORY_SNAPSHOTS);
+	Debugger { vm, symbols, breakpoints: BTreeSet::new(), finished: None }
+    }
+
+    /// Process one command, returning the text to show.
+    pub fn eval(&mut self, line: &str) -> String {
+	let toks: Vec<&str> = line.split_whitespace().collect();
+	match toks[..] {
+	    [] => String::new(),
+	    ["step"] => self.resume(false),
+	    ["back"] => self.back(),
+	    ["continue"] => self.resume(true),
+	    ["break", target] => self.set_breakpoint(target),
+	    ["print", "stack"] => format!("{:?}", self.vm.stack()),
+	    ["print", "heap", addr] => self.print_heap(addr),
+	    ["backtrace"] => self.backtrace(),
+	    ["help"] => HELP.into(),
+	    _ => format!("unknown command: {}; help for help", line.trim())
+	}
+    }
+
+    /// The next instruction to execute.
+    fn location(&self) -> String {
+	let pc = self.vm.pc();
+	match self.vm.instr(pc) {
+	    Some(instr) => format!("pc {}: {}", pc, instr),
+	    None => format!("pc {}: end of program", pc)
+	}
+    }
+
+    /// Execute one instruction, or if run is set, execute until the
+    /// next breakpoint.
+    fn resume(&mut self, run: bool) -> String {
+	if let Some(msg) = &self.finished {
+	    return format!("the program has finished: {}", msg)
+	}
+	loop {
+	    let end = match self.vm.step() {
+		Ok(StepOutcome::Continue) => None,
+		Ok(StepOutcome::Halted(v)) => Some(format!("halted with {:?}", v)),
+		Ok(StepOutcome::Exited(code)) => Some(format!("exited with status {}", code)),
+		Err(err) => Some(format!("error: {}", err))
+	    };
+	    if let Some(msg) = end {
+		self.finished = Some(msg.clone());
+		return msg
+	    }
+	    if !run || self.breakpoints.contains(&self.vm.pc()) {
+		return self.location()
+	    }
+	}
+    }
+
+    /// Undo the last instruction, even one that ended the program.
+    fn back(&mut self) -> String {
+	match self.vm.step_back() {
+	    Ok(()) => {
+		self.finished = None;
+		self.location()
+	    }
+	    Err(err) => format!("error: {}", err)
+	}
+    }
+
+    /// Set a breakpoint at target, a location or a label.
+    fn set_breakpoint(&mut self, target: &str) -> String {
+	let pc = match (target.parse(), self.symbols.get(target)) {
+	    (Ok(pc), _) | (_, Some(&pc)) => pc,
+	    _ => return format!("unknown label: {}", target)
+	};
+	if pc >= self.vm.program_len() {
+	    return format!("no instruction at pc {}", pc)
+	}
+	self.breakpoints.insert(pc);
+	format!("breakpoint at pc {}", pc)
+    }
+
+    /// Show the elements of the array at addr.
+    fn print_heap(&self, addr: &str) -> String {
+	let heap = self.vm.heap();
+	let addr: usize = match addr.parse() {
+	    Ok(addr) => addr,
+	    Err(_) => return format!("bad address: {}", addr)
+	};
+	match heap.get(addr) {
+	    Some(Vsize(size)) if addr + size < heap.len() =>
+		format!("{:?}", &heap[addr+1..addr+size+1]),
+	    _ => format!("no array at address {}", addr)
+	}
+    }
+
+    /// Show the current location and the return address of each
+    /// active call, innermost first.
+    fn backtrace(&self) -> String {
+	let mut lines = vec![self.location()];
+	lines.extend(self.vm.backtrace().iter().map(|ret| format!("  returns to pc {}", ret)));
+	lines.join("\n")
+    }
+}
+
+/// Run a debugging session, prompting on output for commands from
+/// input until end of input or quit.
+pub fn run_debugger<R: BufRead, W: Write>(mut debugger: Debugger, mut input: R, mut output: W)
+					  -> io::Result<()> {
+    writeln!(output, "{}", debugger.location())?;
+    loop {
+	write!(output, "(debug) ")?;
+	output.flush()?;
+	let mut line = String::new();
+	if input.read_line(&mut line)? == 0 || line.trim() == "quit" {
+	    return writeln!(output)
+	}
+	let reply = debugger.eval(&line);
+	if !reply.is_empty() {
+	    writeln!(output, "{}", reply)?
+	}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use crate::assemble::assemble_with_symbols;
+    use crate::isa::{Instr::*, PInstr};
+
+    /// A debugger for the assembly program src.
+    fn debugger(src: &str) -> Debugger {
+	let pinstrs = src.lines().map(|l| PInstr::from_str(l).unwrap()).collect();
+	let (prog, symbols) = assemble_with_symbols(pinstrs, 0).unwrap();
+	Debugger::new(Vm::new(&prog).unwrap(), symbols)
+    }
+
+    #[test]
+    fn test_session() {
+	let d = debugger("push 20\nsetframe 1\ncalli Ldouble\nhalt\n\
+			  Ldouble:\nvar 0\nvar 0\nbinary +\nret");
+	let script = "break Ldouble\ncontinue\nprint stack\nbacktrace\n\nstep\n\
+		      continue\nstep\nquit\nstep\n";
+	let mut out = Vec::new();
+	run_debugger(d, io::Cursor::new(script), &mut out).unwrap();
+	assert_eq!(String::from_utf8(out).unwrap(), format!("\
+pc 0: push 20
+(debug) breakpoint at pc 4
+(debug) pc 4: var 0
+(debug) {:?}
+(debug) pc 4: var 0
+  returns to pc 3
+(debug) (debug) pc 5: var 0
+(debug) halted with Vi32(40)
+(debug) the program has finished: halted with Vi32(40)
+(debug) \n", [Vi32(20), Vloc(0), Vloc(3)]));
+    }
+
+    #[test]
+    fn test_eval() {
+	let mut d = debugger("push 3\nalloci 2\npush 1\npush 0\nbinary /\nhalt");
+	assert_eq!(d.eval("break Lnowhere"), "unknown label: Lnowhere");
+	assert_eq!(d.eval("break 6"), "no instruction at pc 6");
+	assert_eq!(d.eval("break 4"), "breakpoint at pc 4");
+	assert_eq!(d.eval("continue"), "pc 4: binary /");
+	assert_eq!(d.eval("print heap 0"), format!("{:?}", [Vi32(3), Vi32(3)]));
+	assert_eq!(d.eval("print heap 1"), "no array at address 1");
+	assert_eq!(d.eval("print heap x"), "bad address: x");
+	assert_eq!(d.eval("frobnicate"), "unknown command: frobnicate; help for help");
+	assert_eq!(d.eval("continue"), "error: divide by zero at pc 4");
+	assert!(d.eval("help").contains("print heap ADDR"));
+	// The failed instruction did not execute, so back undoes the one
+	// before it.
+	assert_eq!(d.eval("back"), "pc 3: push 0");
+	assert_eq!(d.eval("print stack"), format!("{:?}", [Vaddr(0), Vi32(1)]));
+	assert_eq!(d.eval("step"), "pc 4: binary /");
+	// Breakpoints are checked before each instruction after the
+	// first.
+	let mut d = Debugger::new(Vm::new(&[Nop, Jmpi(0)]).unwrap(), Symbols::new());
+	d.eval("break 0");
+	assert_eq!(d.eval("continue"), "pc 0: nop");
+	assert_eq!(d.eval("continue"), "pc 0: nop");
+	assert_eq!(d.eval("back"), "pc 1: jmp 0");
+	let mut d = debugger("push 1\nhalt");
+	assert_eq!(d.eval("back"), "error: cannot step back before the first instruction");
+	d.eval("continue");
+	assert_eq!(d.eval("back"), "pc 1: halt");
+	assert_eq!(d.eval("step"), "halted with Vi32(1)");
+    }
+}
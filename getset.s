@@ -0,0 +1,10 @@
+push 3
+push 7
+alloc
+dup
+push 1
+push 9
+set
+push 1
+get
+halt
@@ -5,9 +5,12 @@
 
 use self::{Binop::*, Instr::*, PInstr::*, Unop::*, Val::*};
 use crate::{ParseError, FromBytes, ToBytes};
+use alloc::{format, string::String, vec, vec::Vec};
+#[cfg(feature = "arbitrary")]
+use arbitrary::{Arbitrary, Unstructured};
 use byteorder::{BigEndian, ByteOrder};
-use regex::Regex;
-use std::str::FromStr;
+use core::fmt::{self, Display};
+use core::str::FromStr;
 
 /// Heap addresses.
 pub type Address = usize;
@@ -26,6 +29,12 @@ pub enum Val {
     Vloc(u32),
     /// The undefined value.
     Vundef,
+    /// 32-bit floats.
+    Vf32(f32),
+    /// 64-bit signed integers.
+    Vi64(i64),
+    /// Unicode scalar values.
+    Vchar(char),
 
     // Value types that are used internally by the language
     // implementation, and may not appear in GrumpyVM programs:
@@ -58,6 +67,27 @@ impl Val {
 	    _ => None
 	}
     }
+    /// Try to extract an f32 from a Val.
+    pub fn to_f32(&self) -> Option<f32> {
+	match self {
+	    Vf32(f) => Some(*f),
+	    _ => None
+	}
+    }
+    /// Try to extract an i64 from a Val.
+    pub fn to_i64(&self) -> Option<i64> {
+	match self {
+	    Vi64(i) => Some(*i),
+	    _ => None
+	}
+    }
+    /// Try to extract a char from a Val.
+    pub fn to_char(&self) -> Option<char> {
+	match self {
+	    Vchar(c) => Some(*c),
+	    _ => None
+	}
+    }
     /// Try to extract an address (usize) from a Val.
     pub fn to_address(&self) -> Option<Address> {
 	match self {
@@ -74,12 +104,17 @@ pub enum Instr {
     Push(Val),
     /// Pop a value from the stack, discarding it.
     Pop,
-    /// Peek(i): Push onto the stack the ith value from the top.
+    /// Peek(i): Push onto the stack the ith value from the top, where
+    /// Peek(0) duplicates the top value. Earlier versions of the VM
+    /// indexed from the bottom of the stack instead, so bytecode
+    /// using Peek must be regenerated.
     Peek(u32),
     /// Unary(u): Apply u to the top value on the stack.
     Unary(Unop),
     /// Binary(b): Apply b to the top two values on the stack,
-    /// replacing them with the result.
+    /// replacing them with the result. The top value is the second
+    /// operand, so `push 10; push 3; binary -` leaves 7. Earlier
+    /// versions of the VM took the top value as the first operand.
     Binary(Binop),
     /// Swap the top two values.
     Swap,
@@ -103,6 +138,219 @@ pub enum Instr {
     Branch,
     /// Halt the machine.
     Halt,
+    /// Push a copy of the top value on the stack.
+    Dup,
+    /// Do nothing.
+    Nop,
+    /// Unconditional jump to a location popped from the stack.
+    Jmp,
+    /// Jmpi(loc): Unconditional jump to loc.
+    Jmpi(u32),
+    /// Conditional jump, taken when the condition is false.
+    BranchNot,
+    /// Calli(loc): Function call to loc.
+    Calli(u32),
+    /// Read an i32 from the input source.
+    Read,
+    /// Concatenate two heap-allocated strings into a new string.
+    SConcat,
+    /// Push the length of a heap-allocated string.
+    SLen,
+    /// TypeTest(t): Replace the top value with true if it has type t,
+    /// otherwise false.
+    TypeTest(TypeTag),
+    /// PopN(n): Pop n values from the stack, discarding them.
+    PopN(u32),
+    /// Rotate the top three values, moving the third value from the
+    /// top to the top (a b c -> b c a).
+    Rot,
+    /// Push a copy of the second value from the top.
+    Over,
+    /// Conditional move: pop a condition, then b, then a, and push a
+    /// if the condition is true, otherwise b.
+    Select,
+    /// AllocI(n): Allocate an array of n elements on the heap.
+    AllocI(u32),
+    /// GetF(i): Read element i of a heap-allocated array.
+    GetF(u32),
+    /// SetF(i): Write element i of a heap-allocated array.
+    SetF(u32),
+    /// Push the size of a heap-allocated array.
+    Len,
+    /// Copy elements between heap-allocated arrays. Pops len, src_off,
+    /// src_addr, dst_off and dst_addr (len is on top), then copies len
+    /// elements starting at src_off to dst_off.
+    ArrCopy,
+    /// Free a heap-allocated array.
+    Free,
+    /// Grow a heap-allocated array. Pops a new size and an array
+    /// address, copies the array into a new array of that size (the
+    /// extra elements are undef), frees the old array, and pushes the
+    /// new array's address.
+    Grow,
+    /// Pop a bound n and push a random i32 in [0, n).
+    Rand,
+    /// Pop a bool and fail with an assertion error if it is false.
+    Assert,
+    /// Pop an i32 and stop execution with it as the exit code.
+    Exit,
+    /// TailCall(n): Call a location popped from the stack, replacing
+    /// the current frame. The current function must first clear its
+    /// locals so that its return pc is just below the n new arguments
+    /// and the target:
+    ///
+    ///   ... args, saved fp, return pc, arg1, ..., argn, target
+    ///
+    /// The arguments are moved down to the base of the current frame
+    /// and the saved fp and return pc are pushed back on top, so the
+    /// callee sees the same layout as after SetFrame(n) and Call, and
+    /// its Ret returns directly to the original caller.
+    TailCall(u32),
+    /// Globals(n): Allocate n global slots, initialized to undef. n
+    /// may be at most the heap limit.
+    Globals(u32),
+    /// GGet(i): Push the value of global i.
+    GGet(u32),
+    /// GSet(i): Pop a value and store it in global i.
+    GSet(u32),
+    /// AllocClos(n): Pop n captured values and a code location, and
+    /// push the address of a new closure. A closure is a heap array
+    /// with the code location at offset 0 and the captures at offsets
+    /// 1 through n, in the order they were pushed.
+    AllocClos(u32),
+    /// Pop a closure address, push the return pc and then the closure
+    /// address, and jump to the closure's code. Like Call, this is
+    /// preceded by pushing the arguments and SetFrame; the callee
+    /// reads captures with GetF on the closure address (its first
+    /// local) and pops it before returning.
+    CallClos,
+    /// Switch(table): Pop an i32 index and jump to table[index],
+    /// falling through when the index is out of range.
+    Switch(Vec<u32>),
+    /// Pop a divisor and then a dividend, and push their quotient and
+    /// then their remainder (so the remainder is on top). Fails like
+    /// Binary(Div) on division by zero and on MIN / -1.
+    DivMod,
+    /// Push the location of the next instruction.
+    PushPC,
+    /// Inc(i): Add one to the i32 at stack position fp+i.
+    Inc(u32),
+    /// Dec(i): Subtract one from the i32 at stack position fp+i.
+    Dec(u32),
+    /// Pop a code location and start a green thread running there
+    /// with its own stack and the shared heap. Pushes the new thread's
+    /// id as an i32.
+    Spawn,
+    /// Switch to the next runnable thread.
+    Yield,
+    /// Pop a thread id, wait until that thread halts, and push the
+    /// value it halted with.
+    Join,
+    /// Syscall(f, n): Pop n arguments, call host function f on them
+    /// (in push order), and push its result.
+    Syscall(u32, u32),
+    /// Pop a value and write it, followed by a newline, to the output.
+    Print,
+}
+
+impl Instr {
+    /// The opcode byte that begins this instruction's encoding.
+    pub fn opcode(&self) -> u8 {
+	match self {
+	    Push(_) => 0x00,
+	    Pop => 0x01,
+	    Peek(_) => 0x02,
+	    Unary(_) => 0x03,
+	    Binary(_) => 0x04,
+	    Swap => 0x05,
+	    Alloc => 0x06,
+	    Set => 0x07,
+	    Get => 0x08,
+	    Var(_) => 0x09,
+	    Store(_) => 0x0A,
+	    SetFrame(_) => 0x0B,
+	    Call => 0x0C,
+	    Ret => 0x0D,
+	    Branch => 0x0E,
+	    Halt => 0x0F,
+	    Dup => 0x10,
+	    Nop => 0x11,
+	    Jmp => 0x12,
+	    Jmpi(_) => 0x13,
+	    BranchNot => 0x14,
+	    Calli(_) => 0x15,
+	    Read => 0x16,
+	    SConcat => 0x17,
+	    SLen => 0x18,
+	    TypeTest(_) => 0x19,
+	    PopN(_) => 0x1A,
+	    Rot => 0x1B,
+	    Over => 0x1C,
+	    Select => 0x1D,
+	    AllocI(_) => 0x1E,
+	    GetF(_) => 0x1F,
+	    SetF(_) => 0x20,
+	    Len => 0x21,
+	    ArrCopy => 0x22,
+	    Free => 0x23,
+	    Grow => 0x24,
+	    Rand => 0x25,
+	    Assert => 0x26,
+	    Exit => 0x27,
+	    TailCall(_) => 0x28,
+	    Globals(_) => 0x29,
+	    GGet(_) => 0x2A,
+	    GSet(_) => 0x2B,
+	    AllocClos(_) => 0x2C,
+	    CallClos => 0x2D,
+	    Switch(_) => 0x2E,
+	    DivMod => 0x2F,
+	    PushPC => 0x30,
+	    Inc(_) => 0x31,
+	    Dec(_) => 0x32,
+	    Spawn => 0x33,
+	    Yield => 0x34,
+	    Join => 0x35,
+	    Syscall(_, _) => 0x36,
+	    Print => 0x37,
+	}
+    }
+}
+
+/// Runtime type tags, tested by the TypeTest instruction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TypeTag {
+    /// Vi32 values.
+    I32,
+    /// Vbool values.
+    Bool,
+    /// Vunit values.
+    Unit,
+    /// Vaddr values.
+    Addr,
+    /// Vloc values.
+    Loc,
+    /// Vundef values.
+    Undef,
+    /// Vf32 values.
+    F32,
+    /// Vi64 values.
+    I64,
+    /// Vchar values.
+    Char,
+}
+
+/// TypeTag methods.
+impl TypeTag {
+    /// Test whether a Val has this type.
+    pub fn matches(&self, v: &Val) -> bool {
+	matches!((self, v),
+		 (TypeTag::I32, Vi32(_)) | (TypeTag::Bool, Vbool(_)) |
+		 (TypeTag::Unit, Vunit) | (TypeTag::Addr, Vaddr(_)) |
+		 (TypeTag::Loc, Vloc(_)) | (TypeTag::Undef, Vundef) |
+		 (TypeTag::F32, Vf32(_)) | (TypeTag::I64, Vi64(_)) |
+		 (TypeTag::Char, Vchar(_)))
+    }
 }
 
 /// Program labels.
@@ -117,6 +365,14 @@ pub enum PInstr {
     PLabel(Label),
     /// Push a label onto the stack.
     PPush(Label),
+    /// Jump to a label.
+    PJmp(Label),
+    /// Call the function at a label.
+    PCall(Label),
+    /// Allocate a string constant on the heap and push its address.
+    PPushStr(String),
+    /// Jump table with label targets.
+    PSwitch(Vec<Label>),
     /// Native machine instruction.
     PI(Instr),
 }
@@ -126,6 +382,19 @@ pub enum PInstr {
 pub enum Unop {
     /// Boolean negation.
     Neg,
+    /// i32 negation (raises an error on overflow).
+    NegI,
+    /// Convert a char to its i32 scalar value.
+    Ord,
+    /// Convert an i32 scalar value to a char (raises an error if the
+    /// value is not a Unicode scalar value).
+    Chr,
+    /// i32 absolute value (raises an error on overflow).
+    Abs,
+    /// Convert a bool to an i32 (true is 1, false is 0).
+    BoolToI32,
+    /// Convert an i32 to a bool (nonzero is true, 0 is false).
+    I32ToBool,
 }
 
 /// Binary operators.
@@ -143,6 +412,48 @@ pub enum Binop {
     Lt,
     /// Returns true if one i32 is equal another, otherwise false.
     Eq,
+    /// i32 remainder (raises an error on divide by zero).
+    Mod,
+    /// Boolean conjunction.
+    And,
+    /// Boolean disjunction.
+    Or,
+    /// i32 bitwise and.
+    BitAnd,
+    /// i32 bitwise or.
+    BitOr,
+    /// i32 bitwise exclusive or.
+    BitXor,
+    /// i32 left shift (the shift amount is masked to its low five bits).
+    Shl,
+    /// i32 arithmetic right shift (the shift amount is masked to its
+    /// low five bits).
+    Shr,
+    /// Returns true if one i32 is greater than another, otherwise false.
+    Gt,
+    /// Returns true if one i32 is less than or equal to another,
+    /// otherwise false.
+    Le,
+    /// Returns true if one i32 is greater than or equal to another,
+    /// otherwise false.
+    Ge,
+    /// Returns true if one i32 is not equal to another, otherwise false.
+    Neq,
+    /// The lesser of two i32s.
+    Min,
+    /// The greater of two i32s.
+    Max,
+    /// One i32 raised to the power of another.
+    Pow,
+    /// Wrapping i32 addition, followed by a bool overflow flag.
+    AddC,
+    /// Wrapping i32 subtraction, followed by a bool overflow flag.
+    SubC,
+    /// Wrapping i32 multiplication, followed by a bool overflow flag.
+    MulC,
+    /// Returns true if one i32 is less than another when both are
+    /// treated as unsigned, otherwise false.
+    Ltu,
 }
 
 ////////////////////////////////////////////////////////////////////////
@@ -155,6 +466,12 @@ impl FromStr for Unop {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s.trim() {
             "neg" => Ok(Neg),
+            "negi" => Ok(NegI),
+            "ord" => Ok(Ord),
+            "chr" => Ok(Chr),
+            "abs" => Ok(Abs),
+            "btoi" => Ok(BoolToI32),
+            "itob" => Ok(I32ToBool),
             _ => Err(ParseError(String::from("unknown unop"))),
         }
     }
@@ -171,11 +488,98 @@ impl FromStr for Binop {
             "/" => Ok(Div),
             "<" => Ok(Lt),
             "==" => Ok(Eq),
+            "%" => Ok(Mod),
+            "&&" => Ok(And),
+            "||" => Ok(Or),
+            "&" => Ok(BitAnd),
+            "|" => Ok(BitOr),
+            "^" => Ok(BitXor),
+            "<<" => Ok(Shl),
+            ">>" => Ok(Shr),
+            ">" => Ok(Gt),
+            "<=" => Ok(Le),
+            ">=" => Ok(Ge),
+            "!=" => Ok(Neq),
+            "min" => Ok(Min),
+            "max" => Ok(Max),
+            "**" => Ok(Pow),
+            "addc" => Ok(AddC),
+            "subc" => Ok(SubC),
+            "mulc" => Ok(MulC),
+            "<u" => Ok(Ltu),
             _ => Err(ParseError(String::from("unknown binop"))),
         }
     }
 }
 
+/// Translate the character following a backslash in a char or string
+/// literal.
+fn unescape(c: char) -> Option<char> {
+    match c {
+        'n' => Some('\n'),
+        't' => Some('\t'),
+        '0' => Some('\0'),
+        '\'' => Some('\''),
+        '"' => Some('"'),
+        '\\' => Some('\\'),
+        _ => None
+    }
+}
+
+/// Parse a quoted char literal such as 'a' or '\n'.
+fn parse_char(s: &str) -> Result<char, ParseError> {
+    let err = || ParseError(format!("bad char literal: {}", s));
+    if s.len() < 3 || !s.starts_with('\'') || !s.ends_with('\'') {
+        return Err(err())
+    }
+    let mut chars = s[1..s.len() - 1].chars();
+    let c = match chars.next().ok_or_else(err)? {
+        '\\' => chars.next().and_then(unescape).ok_or_else(err)?,
+        c => c
+    };
+    if chars.next().is_some() {
+        return Err(err())
+    }
+    Ok(c)
+}
+
+/// Parse a double-quoted string literal such as "hello\n".
+fn parse_string(s: &str) -> Result<String, ParseError> {
+    let err = || ParseError(format!("bad string literal: {}", s));
+    if s.len() < 2 || !s.starts_with('"') || !s.ends_with('"') {
+        return Err(err())
+    }
+    let mut chars = s[1..s.len() - 1].chars();
+    let mut string = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => string.push(chars.next().and_then(unescape).ok_or_else(err)?),
+            '"' => return Err(err()),
+            c => string.push(c)
+        }
+    }
+    Ok(string)
+}
+
+impl FromStr for TypeTag {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "i32" => Ok(TypeTag::I32),
+            "bool" => Ok(TypeTag::Bool),
+            "unit" => Ok(TypeTag::Unit),
+            "addr" => Ok(TypeTag::Addr),
+            "loc" => Ok(TypeTag::Loc),
+            "undef" => Ok(TypeTag::Undef),
+            "f32" => Ok(TypeTag::F32),
+            "i64" => Ok(TypeTag::I64),
+            "char" => Ok(TypeTag::Char),
+            _ => Err(ParseError(String::from("unknown type tag"))),
+        }
+    }
+}
+
 impl FromStr for Val {
     type Err = ParseError;
 
@@ -185,6 +589,13 @@ impl FromStr for Val {
             "true" => Ok(Vbool(true)),
             "false" => Ok(Vbool(false)),
             "undef" => Ok(Vundef),
+            tok if tok.starts_with("loc:") => Ok(Vloc(tok[4..].parse()?)),
+            tok if tok.starts_with("addr:") => Ok(Vaddr(tok[5..].parse()?)),
+            tok if tok.contains('.') => tok.parse()
+                .map(Vf32)
+                .map_err(|_| ParseError(format!("bad float literal: {}", tok))),
+            tok if tok.ends_with('L') => Ok(Vi64(tok[..tok.len() - 1].parse()?)),
+            tok if tok.starts_with('\'') => Ok(Vchar(parse_char(tok)?)),
             tok => Ok(Vi32(tok.parse()?))
         }
     }
@@ -198,39 +609,44 @@ impl FromStr for Instr {
         if let Some(tok) = toks.next() {
             match tok.trim() {
                 "push" => {
-                    let tok2 = toks.next().unwrap().trim();
-                    Ok(Push(Val::from_str(tok2)?))
+                    // The operand is the rest of the line, since char
+                    // literals may contain whitespace.
+                    let operand = &s.trim_start()[tok.len()..];
+                    if operand.trim().is_empty() {
+                        return Err(missing_operand(tok))
+                    }
+                    Ok(Push(Val::from_str(operand)?))
                 }
                 "pop" => Ok(Pop),
                 "peek" => {
-                    let tok2 = toks.next().unwrap().trim();
+                    let tok2 = next_operand(&mut toks, tok)?;
                     Ok(Peek(tok2.parse()?))
                 }
                 "unary" => {
-                    let tok2 = toks.next().unwrap().trim();
+                    let tok2 = next_operand(&mut toks, tok)?;
                     Ok(Unary(Unop::from_str(tok2)?))
                 }
                 "binary" => {
-                    let tok2 = toks.next().unwrap().trim();
+                    let tok2 = next_operand(&mut toks, tok)?;
                     let b = Binop::from_str(tok2)?;
                     Ok(Binary(b))
                 }
                 "swap" => Ok(Swap),
                 "alloc" => Ok(Alloc),
-                "get" => Ok(Set),
-                "set" => Ok(Get),
+                "get" => Ok(Get),
+                "set" => Ok(Set),
                 "var" => {
-                    let tok2 = toks.next().unwrap().trim();
+                    let tok2 = next_operand(&mut toks, tok)?;
                     let n = tok2.parse()?;
                     Ok(Var(n))
                 }
                 "store" => {
-                    let tok2 = toks.next().unwrap().trim();
+                    let tok2 = next_operand(&mut toks, tok)?;
                     let n = tok2.parse()?;
                     Ok(Store(n))
                 }
                 "setframe" => {
-                    let tok2 = toks.next().unwrap().trim();
+                    let tok2 = next_operand(&mut toks, tok)?;
                     let n = tok2.parse()?;
                     Ok(SetFrame(n))
                 }
@@ -238,6 +654,98 @@ impl FromStr for Instr {
                 "ret" => Ok(Ret),
                 "branch" => Ok(Branch),
                 "halt" => Ok(Halt),
+                "dup" => Ok(Dup),
+                "nop" => Ok(Nop),
+                "jmp" => {
+                    if let Some(tok2) = toks.next() {
+                        Ok(Jmpi(tok2.trim().parse()?))
+                    } else {
+                        Ok(Jmp)
+                    }
+                }
+                "branchnot" => Ok(BranchNot),
+                "calli" => {
+                    let tok2 = next_operand(&mut toks, tok)?;
+                    Ok(Calli(tok2.parse()?))
+                }
+                "read" => Ok(Read),
+                "sconcat" => Ok(SConcat),
+                "slen" => Ok(SLen),
+                "istype" => {
+                    let tok2 = next_operand(&mut toks, tok)?;
+                    Ok(TypeTest(TypeTag::from_str(tok2)?))
+                }
+                "popn" => {
+                    let tok2 = next_operand(&mut toks, tok)?;
+                    Ok(PopN(tok2.parse()?))
+                }
+                "rot" => Ok(Rot),
+                "over" => Ok(Over),
+                "select" => Ok(Select),
+                "alloci" => {
+                    let tok2 = next_operand(&mut toks, tok)?;
+                    Ok(AllocI(tok2.parse()?))
+                }
+                "getf" => {
+                    let tok2 = next_operand(&mut toks, tok)?;
+                    Ok(GetF(tok2.parse()?))
+                }
+                "setf" => {
+                    let tok2 = next_operand(&mut toks, tok)?;
+                    Ok(SetF(tok2.parse()?))
+                }
+                "len" => Ok(Len),
+                "arrcopy" => Ok(ArrCopy),
+                "free" => Ok(Free),
+                "grow" => Ok(Grow),
+                "rand" => Ok(Rand),
+                "assert" => Ok(Assert),
+                "exit" => Ok(Exit),
+                "tailcall" => {
+                    let tok2 = next_operand(&mut toks, tok)?;
+                    Ok(TailCall(tok2.parse()?))
+                }
+                "globals" => {
+                    let tok2 = next_operand(&mut toks, tok)?;
+                    Ok(Globals(tok2.parse()?))
+                }
+                "gget" => {
+                    let tok2 = next_operand(&mut toks, tok)?;
+                    Ok(GGet(tok2.parse()?))
+                }
+                "gset" => {
+                    let tok2 = next_operand(&mut toks, tok)?;
+                    Ok(GSet(tok2.parse()?))
+                }
+                "allocclos" => {
+                    let tok2 = next_operand(&mut toks, tok)?;
+                    Ok(AllocClos(tok2.parse()?))
+                }
+                "callclos" => Ok(CallClos),
+                "divmod" => Ok(DivMod),
+                "pushpc" => Ok(PushPC),
+                "spawn" => Ok(Spawn),
+                "yield" => Ok(Yield),
+                "join" => Ok(Join),
+                "print" => Ok(Print),
+                "syscall" => {
+                    let tok2 = next_operand(&mut toks, tok)?;
+                    let tok3 = next_operand(&mut toks, tok)?;
+                    Ok(Syscall(tok2.parse()?, tok3.parse()?))
+                }
+                "inc" => {
+                    let tok2 = next_operand(&mut toks, tok)?;
+                    Ok(Inc(tok2.parse()?))
+                }
+                "dec" => {
+                    let tok2 = next_operand(&mut toks, tok)?;
+                    Ok(Dec(tok2.parse()?))
+                }
+                "switch" => {
+                    let table = toks.map(|tok2| tok2.trim().parse())
+                        .collect::<Result<Vec<u32>, _>>()?;
+                    Ok(Switch(table))
+                }
                 _ => Err(ParseError(format!("unknown op: {}", tok))),
             }
         } else {
@@ -246,11 +754,21 @@ impl FromStr for Instr {
     }
 }
 
+/// Error for op written without its operand.
+fn missing_operand(op: &str) -> ParseError {
+    ParseError(format!("missing operand for {}", op))
+}
+
+/// Take the next operand of op from toks.
+fn next_operand<'a, T: Iterator<Item=&'a str>>(toks: &mut T, op: &str)
+                                              -> Result<&'a str, ParseError> {
+    toks.next().map(str::trim).ok_or_else(|| missing_operand(op))
+}
+
+/// Accept s as a label if it contains a label name, L followed by
+/// letters and digits (possibly after an underscore), anywhere.
 fn parse_label(s: &str) -> Result<Label, ParseError> {
-    if Regex::new("(L[a-zA-Z0-9]+)|(_L[a-zA-Z0-9]+)")
-        .unwrap()
-        .is_match(s)
-    {
+    if s.as_bytes().windows(2).any(|w| w[0] == b'L' && w[1].is_ascii_alphanumeric()) {
         Ok(String::from(s))
     } else {
         Err(ParseError(format!("bad label: {}", s)))
@@ -266,7 +784,7 @@ impl FromStr for PInstr {
             match tok.trim() {
                 "push" => {
                     // let suffix = s.strip_prefix("push")?.trim();
-                    let tok2 = toks.next().unwrap().trim();
+                    let tok2 = next_operand(&mut toks, tok)?;
                     if let Ok(lbl) = parse_label(tok2) {
                         Ok(PPush(lbl))
                     } else {
@@ -274,6 +792,32 @@ impl FromStr for PInstr {
                         Ok(PI(instr))
                     }
                 }
+                "jmp" => {
+                    match toks.next().map(|tok2| parse_label(tok2.trim())) {
+                        Some(Ok(lbl)) => Ok(PJmp(lbl)),
+                        _ => Ok(PI(Instr::from_str(s)?))
+                    }
+                }
+                "calli" => {
+                    let tok2 = next_operand(&mut toks, tok)?;
+                    if let Ok(lbl) = parse_label(tok2) {
+                        Ok(PCall(lbl))
+                    } else {
+                        Ok(PI(Instr::from_str(s)?))
+                    }
+                }
+                "pushstr" => {
+                    let operand = s.trim_start()[tok.len()..].trim();
+                    Ok(PPushStr(parse_string(operand)?))
+                }
+                "switch" => {
+                    let lbls: Vec<&str> = toks.collect();
+                    if !lbls.is_empty() && lbls.iter().all(|l| parse_label(l).is_ok()) {
+                        Ok(PSwitch(lbls.into_iter().map(String::from).collect()))
+                    } else {
+                        Ok(PI(Instr::from_str(s)?))
+                    }
+                }
                 _ => {
                     if tok.ends_with(":") {
                         let lbl = parse_label(&tok[0..tok.len() - 1])?;
@@ -290,6 +834,189 @@ impl FromStr for PInstr {
     }
 }
 
+////////////////////////////////////////////////////////////////////////
+// Display trait implementations
+////////////////////////////////////////////////////////////////////////
+
+/// Inverse of unescape, for characters that need escaping in char
+/// literals.
+fn escape(c: char) -> Option<char> {
+    match c {
+        '\n' => Some('n'),
+        '\t' => Some('t'),
+        '\0' => Some('0'),
+        '\'' => Some('\''),
+        '\\' => Some('\\'),
+        _ => None
+    }
+}
+
+/// Values are displayed in the syntax accepted by Val::from_str, so
+/// that they round-trip through text. The exceptions are sizes, which
+/// only appear in the heap, and non-finite floats.
+impl Display for Val {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Vunit => write!(f, "tt"),
+            Vi32(i) => write!(f, "{}", i),
+            Vbool(b) => write!(f, "{}", b),
+            Vloc(loc) => write!(f, "loc:{}", loc),
+            Vundef => write!(f, "undef"),
+            Vf32(x) => {
+                // Debug formatting always includes a '.' or exponent;
+                // make sure there is a '.' so the literal parses as f32.
+                let s = format!("{:?}", x);
+                match s.find('e') {
+                    Some(i) if !s.contains('.') => write!(f, "{}.0{}", &s[..i], &s[i..]),
+                    _ => write!(f, "{}", s)
+                }
+            }
+            Vi64(i) => write!(f, "{}L", i),
+            Vchar(c) => match escape(*c) {
+                Some(e) => write!(f, "'\\{}'", e),
+                None => write!(f, "'{}'", c)
+            },
+            Vsize(n) => write!(f, "size:{}", n),
+            Vaddr(addr) => write!(f, "addr:{}", addr)
+        }
+    }
+}
+
+impl Display for Unop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Neg => "neg",
+            NegI => "negi",
+            Ord => "ord",
+            Chr => "chr",
+            Abs => "abs",
+            BoolToI32 => "btoi",
+            I32ToBool => "itob"
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl Display for Binop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Add => "+",
+            Mul => "*",
+            Sub => "-",
+            Div => "/",
+            Lt => "<",
+            Eq => "==",
+            Mod => "%",
+            And => "&&",
+            Or => "||",
+            BitAnd => "&",
+            BitOr => "|",
+            BitXor => "^",
+            Shl => "<<",
+            Shr => ">>",
+            Gt => ">",
+            Le => "<=",
+            Ge => ">=",
+            Neq => "!=",
+            Min => "min",
+            Max => "max",
+            Pow => "**",
+            AddC => "addc",
+            SubC => "subc",
+            MulC => "mulc",
+            Ltu => "<u"
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl Display for TypeTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            TypeTag::I32 => "i32",
+            TypeTag::Bool => "bool",
+            TypeTag::Unit => "unit",
+            TypeTag::Addr => "addr",
+            TypeTag::Loc => "loc",
+            TypeTag::Undef => "undef",
+            TypeTag::F32 => "f32",
+            TypeTag::I64 => "i64",
+            TypeTag::Char => "char"
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Instructions are displayed as assembly mnemonics.
+impl Display for Instr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Push(v) => write!(f, "push {}", v),
+            Pop => write!(f, "pop"),
+            Peek(i) => write!(f, "peek {}", i),
+            Unary(u) => write!(f, "unary {}", u),
+            Binary(b) => write!(f, "binary {}", b),
+            Swap => write!(f, "swap"),
+            Alloc => write!(f, "alloc"),
+            Set => write!(f, "set"),
+            Get => write!(f, "get"),
+            Var(i) => write!(f, "var {}", i),
+            Store(i) => write!(f, "store {}", i),
+            SetFrame(i) => write!(f, "setframe {}", i),
+            Call => write!(f, "call"),
+            Ret => write!(f, "ret"),
+            Branch => write!(f, "branch"),
+            Halt => write!(f, "halt"),
+            Dup => write!(f, "dup"),
+            Nop => write!(f, "nop"),
+            Jmp => write!(f, "jmp"),
+            Jmpi(target) => write!(f, "jmp {}", target),
+            BranchNot => write!(f, "branchnot"),
+            Calli(target) => write!(f, "calli {}", target),
+            Read => write!(f, "read"),
+            SConcat => write!(f, "sconcat"),
+            SLen => write!(f, "slen"),
+            TypeTest(tag) => write!(f, "istype {}", tag),
+            PopN(n) => write!(f, "popn {}", n),
+            Rot => write!(f, "rot"),
+            Over => write!(f, "over"),
+            Select => write!(f, "select"),
+            AllocI(n) => write!(f, "alloci {}", n),
+            GetF(i) => write!(f, "getf {}", i),
+            SetF(i) => write!(f, "setf {}", i),
+            Len => write!(f, "len"),
+            ArrCopy => write!(f, "arrcopy"),
+            Free => write!(f, "free"),
+            Grow => write!(f, "grow"),
+            Rand => write!(f, "rand"),
+            Assert => write!(f, "assert"),
+            Exit => write!(f, "exit"),
+            TailCall(n) => write!(f, "tailcall {}", n),
+            Globals(n) => write!(f, "globals {}", n),
+            GGet(i) => write!(f, "gget {}", i),
+            GSet(i) => write!(f, "gset {}", i),
+            AllocClos(n) => write!(f, "allocclos {}", n),
+            CallClos => write!(f, "callclos"),
+            Switch(table) => {
+                write!(f, "switch")?;
+                for target in table {
+                    write!(f, " {}", target)?
+                }
+                Ok(())
+            }
+            DivMod => write!(f, "divmod"),
+            PushPC => write!(f, "pushpc"),
+            Inc(i) => write!(f, "inc {}", i),
+            Dec(i) => write!(f, "dec {}", i),
+            Spawn => write!(f, "spawn"),
+            Yield => write!(f, "yield"),
+            Join => write!(f, "join"),
+            Syscall(index, n) => write!(f, "syscall {} {}", index, n),
+            Print => write!(f, "print")
+        }
+    }
+}
+
 ////////////////////////////////////////////////////////////////////////
 // ToBytes trait implementations
 ////////////////////////////////////////////////////////////////////////
@@ -310,10 +1037,38 @@ impl ToBytes for i32 {
     }
 }
 
+impl ToBytes for i64 {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut v = vec![0x00; 8];
+        BigEndian::write_i64(&mut v, *self);
+        v
+    }
+}
+
+impl ToBytes for char {
+    fn to_bytes(&self) -> Vec<u8> {
+        (*self as u32).to_bytes()
+    }
+}
+
+impl ToBytes for f32 {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut v = vec![0x00; 4];
+        BigEndian::write_f32(&mut v, *self);
+        v
+    }
+}
+
 impl ToBytes for Unop {
     fn to_bytes(&self) -> Vec<u8> {
         match self {
             Neg => vec![0x00],
+            NegI => vec![0x01],
+            Ord => vec![0x02],
+            Chr => vec![0x03],
+            Abs => vec![0x04],
+            BoolToI32 => vec![0x05],
+            I32ToBool => vec![0x06],
         }
     }
 }
@@ -327,6 +1082,41 @@ impl ToBytes for Binop {
             Div => vec![0x03],
             Lt => vec![0x04],
             Eq => vec![0x05],
+            Mod => vec![0x06],
+            And => vec![0x07],
+            Or => vec![0x08],
+            BitAnd => vec![0x09],
+            BitOr => vec![0x0A],
+            BitXor => vec![0x0B],
+            Shl => vec![0x0C],
+            Shr => vec![0x0D],
+            Gt => vec![0x0E],
+            Le => vec![0x0F],
+            Ge => vec![0x10],
+            Neq => vec![0x11],
+            Min => vec![0x12],
+            Max => vec![0x13],
+            Pow => vec![0x14],
+            AddC => vec![0x15],
+            SubC => vec![0x16],
+            MulC => vec![0x17],
+            Ltu => vec![0x18],
+        }
+    }
+}
+
+impl ToBytes for TypeTag {
+    fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            TypeTag::I32 => vec![0x00],
+            TypeTag::Bool => vec![0x01],
+            TypeTag::Unit => vec![0x02],
+            TypeTag::Addr => vec![0x03],
+            TypeTag::Loc => vec![0x04],
+            TypeTag::Undef => vec![0x05],
+            TypeTag::F32 => vec![0x06],
+            TypeTag::I64 => vec![0x07],
+            TypeTag::Char => vec![0x08],
         }
     }
 }
@@ -348,6 +1138,21 @@ impl ToBytes for Val {
                 bs
             }
             Vundef => vec![0x05],
+            Vf32(f) => {
+                let mut bs = vec![0x06];
+                bs.append(&mut f.to_bytes());
+                bs
+            }
+            Vi64(i) => {
+                let mut bs = vec![0x07];
+                bs.append(&mut i.to_bytes());
+                bs
+            }
+            Vchar(c) => {
+                let mut bs = vec![0x08];
+                bs.append(&mut c.to_bytes());
+                bs
+            }
             _ => panic!("Val::ToBytes: unsupported constructor"),
         }
     }
@@ -355,52 +1160,31 @@ impl ToBytes for Val {
 
 impl ToBytes for Instr {
     fn to_bytes(&self) -> Vec<u8> {
+        let mut bs = vec![self.opcode()];
         match self {
-            Push(v) => {
-                let mut bs = vec![0x00];
-                bs.append(&mut v.to_bytes());
-                bs
-            }
-            Pop => vec![0x01],
-            Peek(i) => {
-                let mut bs = vec![0x02];
-                bs.append(&mut i.to_bytes());
-                bs
-            }
-            Unary(u) => {
-                let mut bs = vec![0x03];
-                bs.append(&mut u.to_bytes());
-                bs
-            }
-            Binary(b) => {
-                let mut bs = vec![0x04];
-                bs.append(&mut b.to_bytes());
-                bs
-            }
-            Swap => vec![0x05],
-            Alloc => vec![0x06],
-            Get => vec![0x07],
-            Set => vec![0x08],
-            Var(i) => {
-                let mut bs = vec![0x09];
-                bs.append(&mut i.to_bytes());
-                bs
-            }
-            Store(i) => {
-                let mut bs = vec![0x0A];
-                bs.append(&mut i.to_bytes());
-                bs
+            Push(v) => bs.append(&mut v.to_bytes()),
+            Unary(u) => bs.append(&mut u.to_bytes()),
+            Binary(b) => bs.append(&mut b.to_bytes()),
+            TypeTest(t) => bs.append(&mut t.to_bytes()),
+            Peek(n) | Var(n) | Store(n) | SetFrame(n) | Jmpi(n) | Calli(n) | PopN(n)
+                | AllocI(n) | GetF(n) | SetF(n) | TailCall(n) | Globals(n) | GGet(n)
+                | GSet(n) | AllocClos(n) | Inc(n) | Dec(n) => bs.append(&mut n.to_bytes()),
+            Switch(table) => {
+                bs.append(&mut (table.len() as u32).to_bytes());
+                for loc in table {
+                    bs.append(&mut loc.to_bytes());
+                }
             }
-            SetFrame(i) => {
-                let mut bs = vec![0x0B];
-                bs.append(&mut i.to_bytes());
-                bs
+            Syscall(f, n) => {
+                bs.append(&mut f.to_bytes());
+                bs.append(&mut n.to_bytes());
             }
-            Call => vec![0x0C],
-            Ret => vec![0x0D],
-            Branch => vec![0x0E],
-            Halt => vec![0x0F],
+            Pop | Swap | Alloc | Set | Get | Call | Ret | Branch | Halt | Dup | Nop | Jmp
+                | BranchNot | Read | SConcat | SLen | Rot | Over | Select | Len | ArrCopy
+                | Free | Grow | Rand | Assert | Exit | CallClos | DivMod | PushPC | Spawn
+                | Yield | Join | Print => ()
         }
+        bs
     }
 }
 
@@ -432,11 +1216,50 @@ impl FromBytes for i32 {
     }
 }
 
+impl FromBytes for i64 {
+    type Err = ParseError;
+    fn from_bytes<T: Iterator<Item=u8>>(bytes: &mut T) -> Result<i64, ParseError> {
+	let v: Vec<u8> = bytes.take(8).collect();
+	if v.len() == 8 {
+	    Ok(BigEndian::read_i64(&v))
+	} else {
+	    Err(ParseError("not enough bytes".into()))
+	}
+    }
+}
+
+impl FromBytes for char {
+    type Err = ParseError;
+    fn from_bytes<T: Iterator<Item=u8>>(bytes: &mut T) -> Result<char, ParseError> {
+	let u = u32::from_bytes(bytes)?;
+	core::char::from_u32(u)
+	    .ok_or_else(|| ParseError(format!("invalid char scalar value: {:#x}", u)))
+    }
+}
+
+impl FromBytes for f32 {
+    type Err = ParseError;
+    fn from_bytes<T: Iterator<Item=u8>>(bytes: &mut T) -> Result<f32, ParseError> {
+	let v: Vec<u8> = bytes.take(4).collect();
+	if v.len() == 4 {
+	    Ok(BigEndian::read_f32(&v))
+	} else {
+	    Err(ParseError("not enough bytes".into()))
+	}
+    }
+}
+
 impl FromBytes for Unop {
     type Err = ParseError;
     fn from_bytes<T: Iterator<Item=u8>>(bytes: &mut T) -> Result<Unop, ParseError> {
 	match bytes.next().ok_or(ParseError("not enough bytes".into()))? {
             0x00 => Ok(Neg),
+            0x01 => Ok(NegI),
+            0x02 => Ok(Ord),
+            0x03 => Ok(Chr),
+            0x04 => Ok(Abs),
+            0x05 => Ok(BoolToI32),
+            0x06 => Ok(I32ToBool),
             b => Err(ParseError(format!("unknown unop code: {}", b))),
 	}
     }
@@ -452,11 +1275,48 @@ impl FromBytes for Binop {
             0x03 => Ok(Div),
             0x04 => Ok(Lt),
             0x05 => Ok(Eq),
+            0x06 => Ok(Mod),
+            0x07 => Ok(And),
+            0x08 => Ok(Or),
+            0x09 => Ok(BitAnd),
+            0x0A => Ok(BitOr),
+            0x0B => Ok(BitXor),
+            0x0C => Ok(Shl),
+            0x0D => Ok(Shr),
+            0x0E => Ok(Gt),
+            0x0F => Ok(Le),
+            0x10 => Ok(Ge),
+            0x11 => Ok(Neq),
+            0x12 => Ok(Min),
+            0x13 => Ok(Max),
+            0x14 => Ok(Pow),
+            0x15 => Ok(AddC),
+            0x16 => Ok(SubC),
+            0x17 => Ok(MulC),
+            0x18 => Ok(Ltu),
             b => Err(ParseError(format!("unknown binop code: {}", b))),
 	}
     }
 }
 
+impl FromBytes for TypeTag {
+    type Err = ParseError;
+    fn from_bytes<T: Iterator<Item=u8>>(bytes: &mut T) -> Result<TypeTag, ParseError> {
+	match bytes.next().ok_or(ParseError("not enough bytes".into()))? {
+            0x00 => Ok(TypeTag::I32),
+            0x01 => Ok(TypeTag::Bool),
+            0x02 => Ok(TypeTag::Unit),
+            0x03 => Ok(TypeTag::Addr),
+            0x04 => Ok(TypeTag::Loc),
+            0x05 => Ok(TypeTag::Undef),
+            0x06 => Ok(TypeTag::F32),
+            0x07 => Ok(TypeTag::I64),
+            0x08 => Ok(TypeTag::Char),
+            b => Err(ParseError(format!("unknown type tag code: {}", b))),
+	}
+    }
+}
+
 impl FromBytes for Val {
     type Err = ParseError;
     fn from_bytes<T: Iterator<Item=u8>>(bytes: &mut T) -> Result<Val, ParseError> {
@@ -467,6 +1327,9 @@ impl FromBytes for Val {
             0x03 => Ok(Vbool(false)),
             0x04 => Ok(Vloc(u32::from_bytes(bytes)?)),
             0x05 => Ok(Vundef),
+            0x06 => Ok(Vf32(f32::from_bytes(bytes)?)),
+            0x07 => Ok(Vi64(i64::from_bytes(bytes)?)),
+            0x08 => Ok(Vchar(char::from_bytes(bytes)?)),
 	    b => Err(ParseError(format!("unknown val code: {}", b))),
 	}
     }
@@ -492,11 +1355,61 @@ impl FromBytes for Instr {
             0x0D => Ok(Ret),
             0x0E => Ok(Branch),
             0x0F => Ok(Halt),
+            0x10 => Ok(Dup),
+            0x11 => Ok(Nop),
+            0x12 => Ok(Jmp),
+            0x13 => Ok(Jmpi(u32::from_bytes(bytes)?)),
+            0x14 => Ok(BranchNot),
+            0x15 => Ok(Calli(u32::from_bytes(bytes)?)),
+            0x16 => Ok(Read),
+            0x17 => Ok(SConcat),
+            0x18 => Ok(SLen),
+            0x19 => Ok(TypeTest(TypeTag::from_bytes(bytes)?)),
+            0x1A => Ok(PopN(u32::from_bytes(bytes)?)),
+            0x1B => Ok(Rot),
+            0x1C => Ok(Over),
+            0x1D => Ok(Select),
+            0x1E => Ok(AllocI(u32::from_bytes(bytes)?)),
+            0x1F => Ok(GetF(u32::from_bytes(bytes)?)),
+            0x20 => Ok(SetF(u32::from_bytes(bytes)?)),
+            0x21 => Ok(Len),
+            0x22 => Ok(ArrCopy),
+            0x23 => Ok(Free),
+            0x24 => Ok(Grow),
+            0x25 => Ok(Rand),
+            0x26 => Ok(Assert),
+            0x27 => Ok(Exit),
+            0x28 => Ok(TailCall(u32::from_bytes(bytes)?)),
+            0x29 => Ok(Globals(u32::from_bytes(bytes)?)),
+            0x2A => Ok(GGet(u32::from_bytes(bytes)?)),
+            0x2B => Ok(GSet(u32::from_bytes(bytes)?)),
+            0x2C => Ok(AllocClos(u32::from_bytes(bytes)?)),
+            0x2D => Ok(CallClos),
+            0x2E => {
+                let n = u32::from_bytes(bytes)?;
+                let table = (0..n).map(|_| u32::from_bytes(bytes))
+                    .collect::<Result<Vec<u32>, _>>()?;
+                Ok(Switch(table))
+            }
+            0x2F => Ok(DivMod),
+            0x30 => Ok(PushPC),
+            0x31 => Ok(Inc(u32::from_bytes(bytes)?)),
+            0x32 => Ok(Dec(u32::from_bytes(bytes)?)),
+            0x33 => Ok(Spawn),
+            0x34 => Ok(Yield),
+            0x35 => Ok(Join),
+            0x36 => {
+                let f = u32::from_bytes(bytes)?;
+                Ok(Syscall(f, u32::from_bytes(bytes)?))
+            }
+            0x37 => Ok(Print),
             b => Err(ParseError(format!("unknown instr code: {}", b))),
 	}
     }
 }
 
+/// Decoding never panics: any byte input, however malformed or
+/// truncated, yields either a program or an Err.
 impl FromBytes for Vec<Instr> {
     type Err = ParseError;
     fn from_bytes<T: Iterator<Item=u8>>(bytes: &mut T) -> Result<Vec<Instr>, ParseError> {
@@ -511,10 +1424,119 @@ impl FromBytes for Vec<Instr> {
     }
 }
 
+// Arbitrary values are restricted to those a program may contain,
+// which are exactly those the bytecode can encode.
+
+#[cfg(feature = "arbitrary")]
+impl<'a> Arbitrary<'a> for Val {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Val> {
+	Ok(match u.int_in_range(0..=7)? {
+	    0 => Vunit,
+	    1 => Vi32(u.arbitrary()?),
+	    2 => Vbool(u.arbitrary()?),
+	    3 => Vloc(u.arbitrary()?),
+	    4 => Vundef,
+	    5 => Vf32(u.arbitrary()?),
+	    6 => Vi64(u.arbitrary()?),
+	    _ => Vchar(u.arbitrary()?)
+	})
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a> Arbitrary<'a> for Unop {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Unop> {
+	Ok(*u.choose(&[Neg, NegI, Ord, Chr, Abs, BoolToI32, I32ToBool])?)
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a> Arbitrary<'a> for Binop {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Binop> {
+	Ok(*u.choose(&[
+	    Add, Mul, Sub, Div, Lt, Eq, Mod, And, Or, BitAnd, BitOr, BitXor, Shl, Shr,
+	    Gt, Le, Ge, Neq, Min, Max, Pow, AddC, SubC, MulC, Ltu
+	])?)
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a> Arbitrary<'a> for TypeTag {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<TypeTag> {
+	use TypeTag::*;
+	Ok(*u.choose(&[I32, Bool, Unit, Addr, Loc, Undef, F32, I64, Char])?)
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a> Arbitrary<'a> for Instr {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Instr> {
+	Ok(match u.int_in_range(0..=0x37)? {
+	    0x00 => Push(u.arbitrary()?),
+	    0x01 => Pop,
+	    0x02 => Peek(u.arbitrary()?),
+	    0x03 => Unary(u.arbitrary()?),
+	    0x04 => Binary(u.arbitrary()?),
+	    0x05 => Swap,
+	    0x06 => Alloc,
+	    0x07 => Set,
+	    0x08 => Get,
+	    0x09 => Var(u.arbitrary()?),
+	    0x0A => Store(u.arbitrary()?),
+	    0x0B => SetFrame(u.arbitrary()?),
+	    0x0C => Call,
+	    0x0D => Ret,
+	    0x0E => Branch,
+	    0x0F => Halt,
+	    0x10 => Dup,
+	    0x11 => Nop,
+	    0x12 => Jmp,
+	    0x13 => Jmpi(u.arbitrary()?),
+	    0x14 => BranchNot,
+	    0x15 => Calli(u.arbitrary()?),
+	    0x16 => Read,
+	    0x17 => SConcat,
+	    0x18 => SLen,
+	    0x19 => TypeTest(u.arbitrary()?),
+	    0x1A => PopN(u.arbitrary()?),
+	    0x1B => Rot,
+	    0x1C => Over,
+	    0x1D => Select,
+	    0x1E => AllocI(u.arbitrary()?),
+	    0x1F => GetF(u.arbitrary()?),
+	    0x20 => SetF(u.arbitrary()?),
+	    0x21 => Len,
+	    0x22 => ArrCopy,
+	    0x23 => Free,
+	    0x24 => Grow,
+	    0x25 => Rand,
+	    0x26 => Assert,
+	    0x27 => Exit,
+	    0x28 => TailCall(u.arbitrary()?),
+	    0x29 => Globals(u.arbitrary()?),
+	    0x2A => GGet(u.arbitrary()?),
+	    0x2B => GSet(u.arbitrary()?),
+	    0x2C => AllocClos(u.arbitrary()?),
+	    0x2D => CallClos,
+	    0x2E => Switch(u.arbitrary()?),
+	    0x2F => DivMod,
+	    0x30 => PushPC,
+	    0x31 => Inc(u.arbitrary()?),
+	    0x32 => Dec(u.arbitrary()?),
+	    0x33 => Spawn,
+	    0x34 => Yield,
+	    0x35 => Join,
+	    0x36 => Syscall(u.arbitrary()?, u.arbitrary()?),
+	    _ => Print
+	})
+    }
+}
+
 // Put all your test cases in this module.
 #[cfg(test)]
 mod tests {
     use super::*;
+    use alloc::string::ToString;
 
     // Example test case.
     #[test]
@@ -524,4 +1546,524 @@ mod tests {
 		   PLabel(String::from("Labc123"))
         );
     }
+
+    #[test]
+    fn test_labels() {
+        assert_eq!(PInstr::from_str("_Lend:").unwrap(), PLabel(String::from("_Lend")));
+        assert_eq!(PInstr::from_str("jmp Lloop").unwrap(), PJmp(String::from("Lloop")));
+        assert!(PInstr::from_str("L:").is_err());
+        assert!(PInstr::from_str("jmp loop").is_err());
+    }
+
+    #[cfg(feature = "arbitrary")]
+    #[test]
+    fn test_arbitrary() {
+        // Arbitrary programs survive a trip through the bytecode.
+        let data: Vec<u8> = (0..8192u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 11) as u8).collect();
+        let mut u = Unstructured::new(&data);
+        let encode = |prog: &[Instr]| {
+            let mut bytes = (prog.len() as u32).to_bytes();
+            for instr in prog {
+                bytes.extend(instr.to_bytes())
+            }
+            bytes
+        };
+        while !u.is_empty() {
+            let prog: Vec<Instr> = u.arbitrary().unwrap();
+            let decoded = Vec::<Instr>::from_bytes(&mut encode(&prog).into_iter()).unwrap();
+            // Compare encodings, since NaN is not equal to itself.
+            assert_eq!(encode(&decoded), encode(&prog));
+        }
+    }
+
+    #[test]
+    fn test_get_set() {
+        // Text, instruction and bytecode all agree on which is which.
+        for (text, i, op) in [("get", Get, 0x08), ("set", Set, 0x07)] {
+            let parsed = Instr::from_str(text).unwrap();
+            assert_eq!(parsed, i);
+            assert_eq!(parsed.to_bytes(), vec![op]);
+            assert_eq!(Instr::from_bytes(&mut parsed.to_bytes().into_iter()).unwrap(), i);
+            assert_eq!(i.to_string(), text);
+        }
+    }
+
+    #[test]
+    fn test_dup() {
+        assert_eq!(Instr::from_str("dup").unwrap(), Dup);
+        assert_eq!(Dup.to_bytes(), vec![0x10]);
+        assert_eq!(Instr::from_bytes(&mut Dup.to_bytes().into_iter()).unwrap(), Dup);
+    }
+
+    #[test]
+    fn test_nop() {
+        assert_eq!(Instr::from_str("nop").unwrap(), Nop);
+        assert_eq!(Nop.to_bytes(), vec![0x11]);
+        assert_eq!(Instr::from_bytes(&mut Nop.to_bytes().into_iter()).unwrap(), Nop);
+    }
+
+    #[test]
+    fn test_negi() {
+        assert_eq!(Instr::from_str("unary negi").unwrap(), Unary(NegI));
+        assert_eq!(Unary(NegI).to_bytes(), vec![0x03, 0x01]);
+        assert_eq!(Instr::from_bytes(&mut Unary(NegI).to_bytes().into_iter()).unwrap(),
+                   Unary(NegI));
+    }
+
+    #[test]
+    fn test_jmp() {
+        assert_eq!(Instr::from_str("jmp").unwrap(), Jmp);
+        assert_eq!(Instr::from_str("jmp 12").unwrap(), Jmpi(12));
+        assert_eq!(PInstr::from_str("jmp Lloop").unwrap(), PJmp("Lloop".into()));
+        assert_eq!(PInstr::from_str("jmp 12").unwrap(), PI(Jmpi(12)));
+        assert_eq!(PInstr::from_str("jmp").unwrap(), PI(Jmp));
+        assert_eq!(Jmpi(258).to_bytes(), vec![0x13, 0, 0, 1, 2]);
+        for i in [Jmp, Jmpi(0), Jmpi(u32::MAX)] {
+            assert_eq!(Instr::from_bytes(&mut i.to_bytes().into_iter()).unwrap(), i);
+        }
+    }
+
+    #[test]
+    fn test_branchnot() {
+        assert_eq!(Instr::from_str("branchnot").unwrap(), BranchNot);
+        assert_eq!(BranchNot.to_bytes(), vec![0x14]);
+        assert_eq!(Instr::from_bytes(&mut BranchNot.to_bytes().into_iter()).unwrap(),
+                   BranchNot);
+    }
+
+    #[test]
+    fn test_calli() {
+        assert_eq!(Instr::from_str("calli 7").unwrap(), Calli(7));
+        assert_eq!(PInstr::from_str("calli Lfunc").unwrap(), PCall("Lfunc".into()));
+        assert_eq!(PInstr::from_str("calli 7").unwrap(), PI(Calli(7)));
+        assert_eq!(Calli(7).to_bytes(), vec![0x15, 0, 0, 0, 7]);
+        assert_eq!(Instr::from_bytes(&mut Calli(7).to_bytes().into_iter()).unwrap(),
+                   Calli(7));
+    }
+
+    #[test]
+    fn test_read() {
+        assert_eq!(Instr::from_str("read").unwrap(), Read);
+        assert_eq!(Instr::from_bytes(&mut Read.to_bytes().into_iter()).unwrap(), Read);
+    }
+
+    #[test]
+    fn test_abs() {
+        assert_eq!(Instr::from_str("unary abs").unwrap(), Unary(Abs));
+        assert_eq!(Unary(Abs).to_bytes(), vec![0x03, 0x04]);
+        assert_eq!(Instr::from_bytes(&mut Unary(Abs).to_bytes().into_iter()).unwrap(),
+                   Unary(Abs));
+    }
+
+    #[test]
+    fn test_bool_conversions() {
+        assert_eq!(Instr::from_str("unary btoi").unwrap(), Unary(BoolToI32));
+        assert_eq!(Instr::from_str("unary itob").unwrap(), Unary(I32ToBool));
+        for &u in &[BoolToI32, I32ToBool] {
+            let i = Unary(u);
+            assert_eq!(Instr::from_bytes(&mut i.to_bytes().into_iter()).unwrap(), i);
+        }
+    }
+
+    #[test]
+    fn test_f32() {
+        assert_eq!(Instr::from_str("push 3.25").unwrap(), Push(Vf32(3.25)));
+        assert_eq!(Instr::from_str("push -0.5").unwrap(), Push(Vf32(-0.5)));
+        assert_eq!(Instr::from_str("push 3").unwrap(), Push(Vi32(3)));
+        assert!(Instr::from_str("push 1.2.3").is_err());
+        assert_eq!(Vf32(1.0).to_bytes(), vec![0x06, 0x3F, 0x80, 0x00, 0x00]);
+        assert_eq!(Vf32(-0.0).to_bytes(), vec![0x06, 0x80, 0x00, 0x00, 0x00]);
+        // Compare bit patterns so that NaN and -0.0 are checked exactly.
+        for &bits in &[0x0000_0000, 0x8000_0000, 0x3FC0_0000, 0x0000_0001,
+                       0x7F80_0000, 0xFF80_0000, 0x7FC0_0000, 0x7FC1_2345] {
+            let v = Vf32(f32::from_bits(bits));
+            let f = Val::from_bytes(&mut v.to_bytes().into_iter()).unwrap().to_f32();
+            assert_eq!(f.map(f32::to_bits), Some(bits));
+        }
+    }
+
+    #[test]
+    fn test_i64() {
+        assert_eq!(Instr::from_str("push 123L").unwrap(), Push(Vi64(123)));
+        assert_eq!(Instr::from_str("push -9223372036854775808L").unwrap(),
+                   Push(Vi64(i64::MIN)));
+        assert!(Instr::from_str("push 9223372036854775808L").is_err());
+        assert_eq!(Vi64(1).to_bytes(), vec![0x07, 0, 0, 0, 0, 0, 0, 0, 1]);
+        for &i in &[i64::MIN, -1, 0, 1, i64::MAX] {
+            let v = Vi64(i);
+            assert_eq!(Val::from_bytes(&mut v.to_bytes().into_iter()).unwrap(), v);
+        }
+        assert!(Val::from_bytes(&mut vec![0x07, 0, 0, 0].into_iter()).is_err());
+    }
+
+    #[test]
+    fn test_char() {
+        assert_eq!(Instr::from_str("push 'a'").unwrap(), Push(Vchar('a')));
+        assert_eq!(Instr::from_str("push ' '").unwrap(), Push(Vchar(' ')));
+        assert_eq!(Instr::from_str("push '\\n'").unwrap(), Push(Vchar('\n')));
+        assert_eq!(Instr::from_str("push '\\''").unwrap(), Push(Vchar('\'')));
+        assert_eq!(Instr::from_str("push '\\\\'").unwrap(), Push(Vchar('\\')));
+        assert_eq!(Instr::from_str("push 'λ'").unwrap(), Push(Vchar('λ')));
+        assert_eq!(PInstr::from_str("push 'L'").unwrap(), PI(Push(Vchar('L'))));
+        for bad in &["push ''", "push 'ab'", "push 'a", "push '\\q'"] {
+            assert!(Instr::from_str(bad).is_err(), "{}", bad);
+        }
+        assert_eq!(Instr::from_str("unary ord").unwrap(), Unary(Ord));
+        assert_eq!(Instr::from_str("unary chr").unwrap(), Unary(Chr));
+        assert_eq!(Vchar('A').to_bytes(), vec![0x08, 0, 0, 0, 0x41]);
+        for &c in &['\0', 'a', 'λ', '\u{10FFFF}'] {
+            let v = Vchar(c);
+            assert_eq!(Val::from_bytes(&mut v.to_bytes().into_iter()).unwrap(), v);
+        }
+        // Surrogates and values past U+10FFFF are not scalar values.
+        for bytes in &[[0x08, 0, 0, 0xD8, 0], [0x08, 0, 0x11, 0, 0]] {
+            assert!(Val::from_bytes(&mut bytes.iter().copied()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_val_display() {
+        assert_eq!(Instr::from_str("push loc:42").unwrap(), Push(Vloc(42)));
+        assert_eq!(Instr::from_str("push addr:7").unwrap(), Push(Vaddr(7)));
+        assert_eq!(PInstr::from_str("push loc:42").unwrap(), PI(Push(Vloc(42))));
+        assert!(Val::from_str("loc:-1").is_err());
+        assert!(Val::from_str("loc:").is_err());
+        assert_eq!(Vloc(42).to_string(), "loc:42");
+        assert_eq!(Vf32(1.0).to_string(), "1.0");
+        assert_eq!(Vchar('\n').to_string(), "'\\n'");
+        assert_eq!(Vsize(3).to_string(), "size:3");
+        let vals = [Vunit, Vi32(-7), Vbool(true), Vbool(false), Vloc(0), Vloc(u32::MAX),
+                    Vundef, Vf32(3.25), Vf32(-0.0), Vf32(1e-10), Vf32(f32::MAX),
+                    Vi64(i64::MIN), Vchar('a'), Vchar(' '), Vchar('λ'), Vchar('\''),
+                    Vchar('\\'), Vchar('\t'), Vchar('\0'), Vchar('"'), Vaddr(7)];
+        for &v in &vals {
+            assert_eq!(Val::from_str(&v.to_string()).unwrap(), v, "{}", v);
+            assert_eq!(Instr::from_str(&format!("push {}", v)).unwrap(), Push(v));
+        }
+    }
+
+    #[test]
+    fn test_instr_display() {
+        // One of every variant, so a mnemonic that parses back to the
+        // wrong instruction can't hide.
+        let instrs = [
+            Push(Vchar(' ')), Pop, Peek(2), Unary(BoolToI32), Binary(Ltu), Swap, Alloc,
+            Set, Get, Var(1), Store(2), SetFrame(3), Call, Ret, Branch, Halt, Dup, Nop,
+            Jmp, Jmpi(4), BranchNot, Calli(5), Read, SConcat, SLen, TypeTest(TypeTag::Loc),
+            PopN(6), Rot, Over, Select, AllocI(7), GetF(8), SetF(9), Len, ArrCopy, Free,
+            Grow, Rand, Assert, Exit, TailCall(10), Globals(11), GGet(12), GSet(13),
+            AllocClos(3), CallClos, Switch(vec![1, 2, 3]), DivMod, PushPC, Inc(14),
+            Dec(15), Spawn, Yield, Join, Syscall(1, 2), Print
+        ];
+        let mut ops: Vec<u8> = instrs.iter().map(Instr::opcode).collect();
+        ops.dedup();
+        assert_eq!(ops, (0..=Print.opcode()).collect::<Vec<_>>());
+        for instr in instrs.iter().chain(&[Binary(Pow), Switch(vec![])]) {
+            assert_eq!(&Instr::from_str(&instr.to_string()).unwrap(), instr);
+        }
+        assert_eq!(Binary(Add).to_string(), "binary +");
+        assert_eq!(Push(Vloc(3)).to_string(), "push loc:3");
+        assert_eq!(Get.to_string(), "get");
+    }
+
+    #[test]
+    fn test_strings() {
+        assert_eq!(Instr::from_str("sconcat").unwrap(), SConcat);
+        assert_eq!(Instr::from_str("slen").unwrap(), SLen);
+        for i in [SConcat, SLen] {
+            assert_eq!(Instr::from_bytes(&mut i.to_bytes().into_iter()).unwrap(), i);
+        }
+        assert_eq!(PInstr::from_str("pushstr \"a b\\n\\\"\"").unwrap(),
+                   PPushStr("a b\n\"".into()));
+        assert_eq!(PInstr::from_str("pushstr \"\"").unwrap(), PPushStr("".into()));
+        for bad in &["pushstr abc", "pushstr \"abc", "pushstr \"a\"b\"", "pushstr \"\\q\""] {
+            assert!(PInstr::from_str(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_min_max() {
+        assert_eq!(Instr::from_str("binary min").unwrap(), Binary(Min));
+        assert_eq!(Instr::from_str("binary max").unwrap(), Binary(Max));
+        assert!(Instr::from_str("binary mid").is_err());
+        for &b in &[Min, Max] {
+            let i = Binary(b);
+            assert_eq!(Instr::from_bytes(&mut i.to_bytes().into_iter()).unwrap(), i);
+        }
+    }
+
+    #[test]
+    fn test_pow() {
+        assert_eq!(Instr::from_str("binary **").unwrap(), Binary(Pow));
+        assert_eq!(Binary(Pow).to_bytes(), vec![0x04, 0x14]);
+        assert_eq!(Instr::from_bytes(&mut Binary(Pow).to_bytes().into_iter()).unwrap(),
+                   Binary(Pow));
+    }
+
+    #[test]
+    fn test_carry() {
+        assert_eq!(Instr::from_str("binary addc").unwrap(), Binary(AddC));
+        assert_eq!(Instr::from_str("binary subc").unwrap(), Binary(SubC));
+        assert_eq!(Instr::from_str("binary mulc").unwrap(), Binary(MulC));
+        for &b in &[AddC, SubC, MulC] {
+            let i = Binary(b);
+            assert_eq!(Instr::from_bytes(&mut i.to_bytes().into_iter()).unwrap(), i);
+        }
+    }
+
+    #[test]
+    fn test_ltu() {
+        assert_eq!(Instr::from_str("binary <u").unwrap(), Binary(Ltu));
+        assert_eq!(Binary(Ltu).to_bytes(), vec![0x04, 0x18]);
+        assert_eq!(Instr::from_bytes(&mut Binary(Ltu).to_bytes().into_iter()).unwrap(),
+                   Binary(Ltu));
+    }
+
+    #[test]
+    fn test_typetest() {
+        let tags = [("i32", TypeTag::I32), ("bool", TypeTag::Bool),
+                    ("unit", TypeTag::Unit), ("addr", TypeTag::Addr),
+                    ("loc", TypeTag::Loc), ("undef", TypeTag::Undef),
+                    ("f32", TypeTag::F32), ("i64", TypeTag::I64),
+                    ("char", TypeTag::Char)];
+        for &(name, t) in &tags {
+            let i = TypeTest(t);
+            assert_eq!(Instr::from_str(&format!("istype {}", name)).unwrap(), i);
+            assert_eq!(Instr::from_bytes(&mut i.to_bytes().into_iter()).unwrap(), i);
+        }
+        assert!(Instr::from_str("istype string").is_err());
+        assert!(Instr::from_bytes(&mut vec![0x19, 0x09].into_iter()).is_err());
+    }
+
+    #[test]
+    fn test_popn() {
+        assert_eq!(Instr::from_str("popn 5").unwrap(), PopN(5));
+        assert_eq!(PopN(5).to_bytes(), vec![0x1A, 0, 0, 0, 5]);
+        assert_eq!(Instr::from_bytes(&mut PopN(5).to_bytes().into_iter()).unwrap(),
+                   PopN(5));
+    }
+
+    #[test]
+    fn test_rot() {
+        assert_eq!(Instr::from_str("rot").unwrap(), Rot);
+        assert_eq!(Instr::from_bytes(&mut Rot.to_bytes().into_iter()).unwrap(), Rot);
+    }
+
+    #[test]
+    fn test_over() {
+        assert_eq!(Instr::from_str("over").unwrap(), Over);
+        assert_eq!(Instr::from_bytes(&mut Over.to_bytes().into_iter()).unwrap(), Over);
+    }
+
+    #[test]
+    fn test_select() {
+        assert_eq!(Instr::from_str("select").unwrap(), Select);
+        assert_eq!(Instr::from_bytes(&mut Select.to_bytes().into_iter()).unwrap(), Select);
+    }
+
+    #[test]
+    fn test_alloci() {
+        assert_eq!(Instr::from_str("alloci 16").unwrap(), AllocI(16));
+        assert_eq!(AllocI(16).to_bytes(), vec![0x1E, 0, 0, 0, 16]);
+        assert_eq!(Instr::from_bytes(&mut AllocI(16).to_bytes().into_iter()).unwrap(),
+                   AllocI(16));
+    }
+
+    #[test]
+    fn test_getf_setf() {
+        assert_eq!(Instr::from_str("getf 2").unwrap(), GetF(2));
+        assert_eq!(Instr::from_str("setf 2").unwrap(), SetF(2));
+        for i in [GetF(2), SetF(u32::MAX)] {
+            assert_eq!(Instr::from_bytes(&mut i.to_bytes().into_iter()).unwrap(), i);
+        }
+    }
+
+    #[test]
+    fn test_len() {
+        assert_eq!(Instr::from_str("len").unwrap(), Len);
+        assert_eq!(Instr::from_bytes(&mut Len.to_bytes().into_iter()).unwrap(), Len);
+    }
+
+    #[test]
+    fn test_arrcopy() {
+        assert_eq!(Instr::from_str("arrcopy").unwrap(), ArrCopy);
+        assert_eq!(Instr::from_bytes(&mut ArrCopy.to_bytes().into_iter()).unwrap(),
+                   ArrCopy);
+    }
+
+    #[test]
+    fn test_free() {
+        assert_eq!(Instr::from_str("free").unwrap(), Free);
+        assert_eq!(Instr::from_bytes(&mut Free.to_bytes().into_iter()).unwrap(), Free);
+    }
+
+    #[test]
+    fn test_grow() {
+        assert_eq!(Instr::from_str("grow").unwrap(), Grow);
+        assert_eq!(Instr::from_bytes(&mut Grow.to_bytes().into_iter()).unwrap(), Grow);
+    }
+
+    #[test]
+    fn test_tailcall() {
+        assert_eq!(Instr::from_str("tailcall 2").unwrap(), TailCall(2));
+        assert_eq!(TailCall(2).to_bytes(), vec![0x28, 0, 0, 0, 2]);
+        assert_eq!(Instr::from_bytes(&mut TailCall(2).to_bytes().into_iter()).unwrap(),
+                   TailCall(2));
+    }
+
+    #[test]
+    fn test_globals() {
+        assert_eq!(Instr::from_str("globals 3").unwrap(), Globals(3));
+        assert_eq!(Instr::from_str("gget 1").unwrap(), GGet(1));
+        assert_eq!(Instr::from_str("gset 2").unwrap(), GSet(2));
+        assert_eq!(GGet(1).to_bytes(), vec![0x2A, 0, 0, 0, 1]);
+        for i in [Globals(3), GGet(1), GSet(2)] {
+            assert_eq!(Instr::from_bytes(&mut i.to_bytes().into_iter()).unwrap(), i);
+        }
+    }
+
+    #[test]
+    fn test_closures() {
+        assert_eq!(Instr::from_str("allocclos 2").unwrap(), AllocClos(2));
+        assert_eq!(Instr::from_str("callclos").unwrap(), CallClos);
+        assert_eq!(AllocClos(2).to_bytes(), vec![0x2C, 0, 0, 0, 2]);
+        for i in [AllocClos(2), CallClos] {
+            assert_eq!(Instr::from_bytes(&mut i.to_bytes().into_iter()).unwrap(), i);
+        }
+    }
+
+    #[test]
+    fn test_switch() {
+        assert_eq!(Instr::from_str("switch 3 5 7").unwrap(), Switch(vec![3, 5, 7]));
+        assert_eq!(Instr::from_str("switch").unwrap(), Switch(vec![]));
+        assert_eq!(PInstr::from_str("switch La Lb").unwrap(),
+                   PSwitch(vec!["La".into(), "Lb".into()]));
+        assert_eq!(PInstr::from_str("switch 3 5").unwrap(), PI(Switch(vec![3, 5])));
+        assert!(PInstr::from_str("switch La 5").is_err());
+        assert_eq!(Switch(vec![3, 5]).to_bytes(),
+                   vec![0x2E, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 5]);
+        let big = Switch((0..1000).map(|i| i * 7).collect());
+        let bytes = big.to_bytes();
+        assert_eq!(bytes.len(), 1 + 4 + 4000);
+        assert_eq!(Instr::from_bytes(&mut bytes.into_iter()).unwrap(), big);
+        assert!(Instr::from_bytes(&mut vec![0x2E, 0, 0, 0, 2, 0, 0, 0, 3].into_iter()).is_err());
+    }
+
+    #[test]
+    fn test_divmod() {
+        assert_eq!(Instr::from_str("divmod").unwrap(), DivMod);
+        assert_eq!(Instr::from_bytes(&mut DivMod.to_bytes().into_iter()).unwrap(), DivMod);
+    }
+
+    #[test]
+    fn test_pushpc() {
+        assert_eq!(Instr::from_str("pushpc").unwrap(), PushPC);
+        assert_eq!(Instr::from_bytes(&mut PushPC.to_bytes().into_iter()).unwrap(), PushPC);
+    }
+
+    #[test]
+    fn test_inc_dec() {
+        assert_eq!(Instr::from_str("inc 0").unwrap(), Inc(0));
+        assert_eq!(Instr::from_str("dec 3").unwrap(), Dec(3));
+        assert_eq!(Dec(3).to_bytes(), vec![0x32, 0, 0, 0, 3]);
+        for i in [Inc(0), Dec(3)] {
+            assert_eq!(Instr::from_bytes(&mut i.to_bytes().into_iter()).unwrap(), i);
+        }
+    }
+
+    #[test]
+    fn test_threads() {
+        assert_eq!(Instr::from_str("spawn").unwrap(), Spawn);
+        assert_eq!(Instr::from_str("yield").unwrap(), Yield);
+        assert_eq!(Instr::from_str("join").unwrap(), Join);
+        for i in [Spawn, Yield, Join] {
+            assert_eq!(Instr::from_bytes(&mut i.to_bytes().into_iter()).unwrap(), i);
+        }
+    }
+
+    #[test]
+    fn test_syscall() {
+        assert_eq!(Instr::from_str("syscall 3 2").unwrap(), Syscall(3, 2));
+        assert_eq!(Syscall(3, 2).to_bytes(), vec![0x36, 0, 0, 0, 3, 0, 0, 0, 2]);
+        let i = Syscall(7, 0);
+        assert_eq!(Instr::from_bytes(&mut i.to_bytes().into_iter()).unwrap(), i);
+    }
+
+    #[test]
+    fn test_opcode() {
+        let instrs = [Push(Vi32(1)), Binary(Add), Get, Set, Halt, Switch(vec![1, 2]),
+                      TailCall(2), Join, Syscall(1, 2), Print];
+        for i in &instrs {
+            assert_eq!(i.opcode(), i.to_bytes()[0]);
+            assert_eq!(Instr::from_bytes(&mut i.to_bytes().into_iter()).unwrap(), *i);
+        }
+    }
+
+    #[test]
+    fn test_exit() {
+        assert_eq!(Instr::from_str("exit").unwrap(), Exit);
+        assert_eq!(Instr::from_bytes(&mut Exit.to_bytes().into_iter()).unwrap(), Exit);
+    }
+
+    #[test]
+    fn test_assert() {
+        assert_eq!(Instr::from_str("assert").unwrap(), Assert);
+        assert_eq!(Instr::from_bytes(&mut Assert.to_bytes().into_iter()).unwrap(), Assert);
+    }
+
+    #[test]
+    fn test_rand() {
+        assert_eq!(Instr::from_str("rand").unwrap(), Rand);
+        assert_eq!(Instr::from_bytes(&mut Rand.to_bytes().into_iter()).unwrap(), Rand);
+    }
+
+    #[test]
+    fn test_mod() {
+        assert_eq!(Instr::from_str("binary %").unwrap(), Binary(Mod));
+        assert_eq!(Binary(Mod).to_bytes(), vec![0x04, 0x06]);
+        assert_eq!(Instr::from_bytes(&mut Binary(Mod).to_bytes().into_iter()).unwrap(),
+                   Binary(Mod));
+    }
+
+    #[test]
+    fn test_and_or() {
+        assert_eq!(Instr::from_str("binary &&").unwrap(), Binary(And));
+        assert_eq!(Instr::from_str("binary ||").unwrap(), Binary(Or));
+        for &b in &[And, Or] {
+            assert_eq!(Binop::from_bytes(&mut b.to_bytes().into_iter()).unwrap(), b);
+        }
+    }
+
+    #[test]
+    fn test_bitwise() {
+        assert_eq!(Instr::from_str("binary &").unwrap(), Binary(BitAnd));
+        assert_eq!(Instr::from_str("binary |").unwrap(), Binary(BitOr));
+        assert_eq!(Instr::from_str("binary ^").unwrap(), Binary(BitXor));
+        for &b in &[BitAnd, BitOr, BitXor] {
+            let i = Binary(b);
+            assert_eq!(Instr::from_bytes(&mut i.to_bytes().into_iter()).unwrap(), i);
+        }
+    }
+
+    #[test]
+    fn test_shifts() {
+        assert_eq!(Instr::from_str("binary <<").unwrap(), Binary(Shl));
+        assert_eq!(Instr::from_str("binary >>").unwrap(), Binary(Shr));
+        for &b in &[Shl, Shr] {
+            let i = Binary(b);
+            assert_eq!(Instr::from_bytes(&mut i.to_bytes().into_iter()).unwrap(), i);
+        }
+    }
+
+    #[test]
+    fn test_comparisons() {
+        assert_eq!(Instr::from_str("binary >").unwrap(), Binary(Gt));
+        assert_eq!(Instr::from_str("binary <=").unwrap(), Binary(Le));
+        assert_eq!(Instr::from_str("binary >=").unwrap(), Binary(Ge));
+        assert_eq!(Instr::from_str("binary !=").unwrap(), Binary(Neq));
+        for &b in &[Gt, Le, Ge, Neq] {
+            let i = Binary(b);
+            assert_eq!(Instr::from_bytes(&mut i.to_bytes().into_iter()).unwrap(), i);
+        }
+    }
 }
@@ -1,11 +1,24 @@
 //! The Grumpy compiler.
 
 #![warn(clippy::all)]
-use std::{error, fmt, io, num};
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+use alloc::{format, string::String, vec::Vec};
+use core::{fmt, num};
+#[cfg(feature = "std")]
+use std::{error, io};
 
 // Declare modules in the grumpy crate.
+pub mod analyze;
 pub mod assemble;
+#[cfg(feature = "std")]
+pub mod debugger;
 pub mod isa;
+#[cfg(feature = "std")]
+pub mod repl;
+pub mod verify;
 pub mod vm;
 
 /// Trait for types that can be serialized to a binary representation.
@@ -29,6 +42,7 @@ impl fmt::Display for ParseError {
     }
 }
 
+#[cfg(feature = "std")]
 impl error::Error for ParseError {}
 
 impl From<num::ParseIntError> for ParseError {
@@ -37,6 +51,7 @@ impl From<num::ParseIntError> for ParseError {
     }
 }
 
+#[cfg(feature = "std")]
 impl From<ParseError> for io::Error {
     fn from(err: ParseError) -> Self {
         io::Error::new(io::ErrorKind::Other, format!("{:?}", err))
@@ -9,20 +9,98 @@ use std::process::exit;
 use grumpy::{*, isa::*, vm::*};
 
 fn main() -> io::Result<()> {
-    // Read input file (command line argument at index 1).
-    let path_str = env::args().nth(1).expect("missing file argument");
+    // Parse command line: optional --gc-stress, --implicit-halt,
+    // --check-frames, --repl, --debugger, --analyze, --profile, and
+    // --profile-time flags and the input file.
+    let mut cfg = VmConfig { verify: true, ..VmConfig::default() };
+    let mut path_str = None;
+    let mut repl = false;
+    let mut debugger = false;
+    let mut analyze = false;
+    let mut profile = None;
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--gc-stress" => cfg.gc_stress = true,
+            "--implicit-halt" => cfg.implicit_halt = true,
+            "--check-frames" => cfg.check_frames = true,
+            "--repl" => repl = true,
+            "--debugger" => debugger = true,
+            "--analyze" => analyze = true,
+            "--profile" => profile = Some(false),
+            "--profile-time" => profile = Some(true),
+            _ => path_str = Some(arg)
+        }
+    }
+    if repl {
+        return repl::run_repl(io::stdin().lock(), io::stdout());
+    }
+    let path_str = path_str.expect("missing file argument");
     let path = Path::new(&path_str);
-    let mut file = OpenOptions::new().read(true).open(path)?;
 
-    // Deserialize program from bytecode.
-    let mut bytes = Vec::new();
-    file.read_to_end(&mut bytes)?;
-    let instrs = Vec::<Instr>::from_bytes(&mut bytes.into_iter())?;
+    // Assemble the program, keeping its labels, if given assembly, and
+    // otherwise deserialize it from bytecode.
+    let (instrs, symbols) = if path_str.ends_with(".s") {
+        assemble::parse_file(&path_str)
+            .and_then(|pinstrs| assemble::assemble_with_symbols(pinstrs, 0))
+            .map_err(io::Error::other)?
+    } else {
+        let mut file = OpenOptions::new().read(true).open(path)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        (Vec::<Instr>::from_bytes(&mut bytes.into_iter())?, assemble::Symbols::new())
+    };
+
+    if debugger {
+        let vm = match Vm::with_config(&instrs, cfg) {
+            Ok(vm) => vm,
+            Err(msg) => {
+                print!("{}", msg);
+                exit(1)
+            }
+        };
+        let debugger = debugger::Debugger::new(vm, symbols);
+        return debugger::run_debugger(debugger, io::stdin().lock(), io::stdout());
+    }
+
+    // Report the static stack analysis instead of running.
+    if analyze {
+        match analyze::analyze_stack(&instrs) {
+            Ok(report) => {
+                match report.max_depth {
+                    Some(n) => println!("max stack depth: {}", n),
+                    None => println!("max stack depth: unbounded")
+                }
+                for pc in &report.underflows {
+                    println!("possible underflow at {}", pc)
+                }
+                exit(if report.underflows.is_empty() { 0 } else { 1 })
+            }
+            Err(err) => {
+                println!("{}", err);
+                exit(1)
+            }
+        }
+    }
+
+    // Run the program, then report its hottest instructions.
+    if let Some(timed) = profile {
+        let (result, profile) = run_profiled_with_config(cfg, &instrs, timed);
+        match &result {
+            Ok(v) => println!("{:?}", v),
+            Err(msg) => println!("{}", msg)
+        }
+        eprintln!("{}", profile.report(&instrs, &symbols, 20));
+        exit(if result.is_ok() { 0 } else { 1 })
+    }
 
     // Run program in VM.
     // match run(Debug::DEBUG, &instrs) {
-    match run(Debug::DEBUG, &instrs) {
-        Ok(v) => print!("{:?}", v),
+    match run_outcome_with_config(Debug::DEBUG, cfg, &instrs) {
+        Ok(Outcome::Halted(v)) => print!("{:?}", v),
+        Ok(Outcome::Exited(code)) => {
+            print!("{:?}", Val::Vi32(code));
+            exit(code)
+        }
         Err(msg) => {
             print!("{}", msg);
             exit(1)
@@ -0,0 +1,226 @@
+//! Interactive GrumpyVM REPL.
+//!
+//! This module reads assembly one line at a time and executes each
+//! native instruction as soon as it is entered, on a machine that
+//! persists between lines. Pseudo-instructions that define or refer
+//! to labels are buffered until `:run`, so that forward references
+//! can be resolved.
+
+use std::io::{self, BufRead, Write};
+use std::str::FromStr;
+use crate::assemble::{assemble_at, parse_file};
+use crate::isa::{*, PInstr::*};
+use crate::vm::{StepOutcome, Vm};
+
+static HELP: &str = "\
+:stack      show the stack
+:heap       show the heap
+:run        execute buffered instructions
+:reset      start over with an empty machine
+:load FILE  execute the assembly program in FILE
+:quit       leave the REPL";
+
+/// REPL state: the machine and any buffered pseudo-instructions.
+pub struct Repl {
+    vm: Vm,
+    /// Instructions waiting for :run, because they involve labels.
+    pending: Vec<PInstr>
+}
+
+impl Default for Repl {
+    fn default() -> Repl {
+	Repl::new()
+    }
+}
+
+impl Repl {
+    /// A REPL with an empty machine.
+    pub fn new() -> Repl {
+	// An empty program has nothing to reject.
+	Repl { vm: Vm::new(&[]).unwrap(), pending: Vec::new() }
+    }
+
+    /// Process one line of input, returning the text to show.
+    pub fn eval(&mut self, line: &str) -> String {
+	let line = line.trim();
+	if line.is_empty() {
+	    return String::new()
+	}
+	if let Some(cmd) = line.strip_prefix(':') {
+	    let mut toks = cmd.splitn(2, char::is_whitespace);
+	    let name = toks.next().unwrap_or("");
+	    return self.command(name, toks.next().map(str::trim))
+	}
+	let pinstr = match PInstr::from_str(line) {
+	    Ok(pinstr) => pinstr,
+	    Err(err) => return format!("error: {}", err)
+	};
+	let labelled = matches!(pinstr, PLabel(_) | PPush(_) | PJmp(_) | PCall(_) | PSwitch(_));
+	if labelled || !self.pending.is_empty() {
+	    self.pending.push(pinstr);
+	    return format!("buffered {} instructions; :run to execute", self.pending.len())
+	}
+	self.execute(vec![pinstr])
+    }
+
+    /// Run a meta-command.
+    fn command(&mut self, name: &str, arg: Option<&str>) -> String {
+	match (name, arg) {
+	    ("stack", None) => format!("{:?}", self.vm.stack()),
+	    ("heap", None) => format!("{:?}", self.vm.heap()),
+	    ("run", None) if self.pending.is_empty() => "nothing to run".into(),
+	    ("run", None) => {
+		let pinstrs = std::mem::take(&mut self.pending);
+		self.execute(pinstrs)
+	    }
+	    ("reset", None) => {
+		*self = Repl::new();
+		"reset".into()
+	    }
+	    ("load", Some(path)) => match parse_file(path) {
+		Ok(pinstrs) => {
+		    self.pending.extend(pinstrs);
+		    let pinstrs = std::mem::take(&mut self.pending);
+		    self.execute(pinstrs)
+		}
+		Err(err) => format!("error: {}", err)
+	    },
+	    ("help", None) => HELP.into(),
+	    _ => format!("unknown command :{}; :help for help", name)
+	}
+    }
+
+    /// Append pinstrs to the program and execute until the machine
+    /// reaches the end of the program, halts, exits, or fails.
+    fn execute(&mut self, pinstrs: Vec<PInstr>) -> String {
+	let instrs = match assemble_at(pinstrs, self.vm.program_len()) {
+	    Ok(instrs) => instrs,
+	    Err(err) => return format!("error: {}", err)
+	};
+	if let Err(err) = self.vm.extend_program(&instrs) {
+	    return format!("error: {}", err)
+	}
+	while self.vm.pc() < self.vm.program_len() {
+	    match self.vm.step() {
+		Ok(StepOutcome::Continue) => (),
+		Ok(StepOutcome::Halted(v)) => return format!("halted with {:?}", v),
+		Ok(StepOutcome::Exited(code)) => return format!("exited with status {}", code),
+		Err(err) => return format!("error: {}", err)
+	    }
+	}
+	format!("{:?}", self.vm.stack())
+    }
+}
+
+/// Run a REPL session, prompting on output for lines from input until
+/// end of input or :quit.
+pub fn run_repl<R: BufRead, W: Write>(mut input: R, mut output: W) -> io::Result<()> {
+    let mut repl = Repl::new();
+    loop {
+	write!(output, "> ")?;
+	output.flush()?;
+	let mut line = String::new();
+	if input.read_line(&mut line)? == 0 || line.trim() == ":quit" {
+	    return writeln!(output)
+	}
+	let reply = repl.eval(&line);
+	if !reply.is_empty() {
+	    writeln!(output, "{}", reply)?
+	}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::isa::Val::*;
+
+    #[test]
+    fn test_eval() {
+	let mut repl = Repl::new();
+	assert_eq!(repl.eval("push 1"), format!("{:?}", [Vi32(1)]));
+	assert_eq!(repl.eval("push 2"), format!("{:?}", [Vi32(1), Vi32(2)]));
+	assert_eq!(repl.eval("binary +"), format!("{:?}", [Vi32(3)]));
+	assert_eq!(repl.eval(""), "");
+	assert_eq!(repl.eval(":stack"), format!("{:?}", [Vi32(3)]));
+	assert_eq!(repl.eval("binary +"), "error: attempt to pop empty stack");
+	assert!(repl.eval("frobnicate").starts_with("error: "));
+	repl.eval("push 3");
+	assert_eq!(repl.eval("halt"), "halted with Vi32(3)");
+	assert_eq!(repl.eval("pushstr \"hi\""), format!("{:?}", [Vi32(3), Vaddr(0)]));
+	assert_eq!(repl.eval(":heap"), format!("{:?}", [Vsize(2), Vchar('h'), Vchar('i')]));
+	assert_eq!(repl.eval(":reset"), "reset");
+	assert_eq!(repl.eval(":stack"), "[]");
+	assert!(repl.eval(":bogus").starts_with("unknown command :bogus"));
+	assert_eq!(repl.eval(":run"), "nothing to run");
+    }
+
+    #[test]
+    fn test_get_set() {
+	let mut repl = Repl::new();
+	repl.eval("push 3");
+	repl.eval("push 7");
+	assert_eq!(repl.eval("alloc"), format!("{:?}", [Vaddr(0)]));
+	repl.eval("dup");
+	repl.eval("push 1");
+	repl.eval("push 9");
+	assert_eq!(repl.eval("set"), format!("{:?}", [Vaddr(0)]));
+	assert_eq!(repl.eval(":heap"), format!("{:?}", [Vsize(3), Vi32(7), Vi32(9), Vi32(7)]));
+	repl.eval("push 1");
+	assert_eq!(repl.eval("get"), format!("{:?}", [Vi32(9)]));
+	// Addresses can't be pushed as constants.
+	assert_eq!(repl.eval("push addr:0"),
+		   "error: verify: 9: illegal constant Vaddr(0)");
+	assert_eq!(repl.eval(":stack"), format!("{:?}", [Vi32(9)]));
+    }
+
+    #[test]
+    fn test_missing_operand() {
+	let mut repl = Repl::new();
+	repl.eval("push 1");
+	for line in ["push", "popn", "calli", "alloci", "syscall 3", "binary"] {
+	    let op = line.split_whitespace().next().unwrap();
+	    assert_eq!(repl.eval(line), format!("error: missing operand for {}", op));
+	}
+	assert_eq!(repl.eval(":stack"), format!("{:?}", [Vi32(1)]));
+    }
+
+    #[test]
+    fn test_labels() {
+	let mut repl = Repl::new();
+	repl.eval("push 5");
+	assert_eq!(repl.eval("jmp Lend"), "buffered 1 instructions; :run to execute");
+	assert_eq!(repl.eval("push 6"), "buffered 2 instructions; :run to execute");
+	repl.eval("Lend:");
+	repl.eval("push 7");
+	// Labels are resolved relative to where the code is loaded.
+	assert_eq!(repl.eval(":run"), format!("{:?}", [Vi32(5), Vi32(7)]));
+	// A failed :run discards the buffer.
+	repl.eval("jmp Lnowhere");
+	assert_eq!(repl.eval(":run"), "error: unknown label: Lnowhere");
+	assert_eq!(repl.eval("push 8"), format!("{:?}", [Vi32(5), Vi32(7), Vi32(8)]));
+    }
+
+    #[test]
+    fn test_load() {
+	let path = std::env::temp_dir().join(format!("grumpy-repl-{}.s", std::process::id()));
+	fs::write(&path, "push 20\nsetframe 1\ncalli Ldouble\nhalt\n\n\
+			  Ldouble:\nvar 0\nvar 0\nbinary +\nret\n").unwrap();
+	let mut repl = Repl::new();
+	assert_eq!(repl.eval(&format!(":load {}", path.display())), "halted with Vi32(40)");
+	fs::write(&path, "push 1\nbogus\n").unwrap();
+	let err = repl.eval(&format!(":load {}", path.display()));
+	assert!(err.starts_with("error: ") && err.contains(":2: "));
+	fs::remove_file(&path).unwrap();
+	assert!(repl.eval(&format!(":load {}", path.display())).starts_with("error: "));
+    }
+
+    #[test]
+    fn test_run_repl() {
+	let mut out = Vec::new();
+	run_repl(io::Cursor::new("push 1\n\npush 2\n:quit\npush 3\n"), &mut out).unwrap();
+	assert_eq!(String::from_utf8(out).unwrap(),
+		   format!("> {:?}\n> > {:?}\n> \n", [Vi32(1)], [Vi32(1), Vi32(2)]));
+    }
+}
@@ -1,7 +1,7 @@
 #!/bin/bash
 
 ERR=0
-INPUTS=`ls *.o`
+INPUTS=`ls *.o *.s`
 
 # Build project
 echo "Building PA2:"
@@ -14,8 +14,8 @@ fi
 
 for f in $INPUTS;
 do
-    ../target/release/vm $f > "${f%.o}.student"
-    if ! diff -q "${f%.o}.student" "${f%.o}.expected" &>/dev/null; then
+    ../target/release/vm $f > "${f%.*}.student"
+    if ! diff -q "${f%.*}.student" "${f%.*}.expected" &>/dev/null; then
 	printf "%-10s %10s\n" $f "ERROR, outputs differ"
 	ERR=1
     else
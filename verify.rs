@@ -0,0 +1,109 @@
+//! GrumpyVM bytecode verifier.
+//!
+//! This module checks the properties of a program that can be
+//! decided without running it, so that malformed bytecode is rejected
+//! up front instead of failing partway through execution.
+
+use core::fmt;
+#[cfg(feature = "std")]
+use std::error;
+use crate::isa::{*, Instr::*, Val::*};
+
+/// Why a program failed verification.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VerifyError {
+    /// The program has no instructions.
+    Empty,
+    /// The instruction at pc refers to a code location outside the
+    /// program.
+    BadTarget { pc: u32, target: u32 },
+    /// The instruction at pc pushes a value that only the machine
+    /// itself may create.
+    IllegalConstant { pc: u32, val: Val },
+    /// Execution can run past the last instruction, at pc.
+    FallsOffEnd(u32)
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	match self {
+	    VerifyError::Empty => write!(f, "empty program"),
+	    VerifyError::BadTarget { pc, target } =>
+		write!(f, "{}: target {} outside program", pc, target),
+	    VerifyError::IllegalConstant { pc, val } =>
+		write!(f, "{}: illegal constant {:?}", pc, val),
+	    VerifyError::FallsOffEnd(pc) =>
+		write!(f, "{}: execution falls off the end of the program", pc)
+	}
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for VerifyError {}
+
+/// Whether execution never continues from instr to the next
+/// instruction.
+fn is_terminator(instr: &Instr) -> bool {
+    matches!(instr, Halt | Exit | Jmp | Jmpi(_) | Ret | TailCall(_))
+}
+
+/// Check that prog is non-empty, that every static code location in
+/// it is within the program, that it pushes no sizes or heap
+/// addresses, and that it ends with an instruction execution cannot
+/// fall through.
+pub fn verify(prog: &[Instr]) -> Result<(), VerifyError> {
+    let len = prog.len() as u32;
+    let check = |pc: u32, target: u32| {
+	if target < len { Ok(()) } else { Err(VerifyError::BadTarget { pc, target }) }
+    };
+    for (pc, instr) in prog.iter().enumerate() {
+	let pc = pc as u32;
+	match instr {
+	    Push(Vloc(target)) | Jmpi(target) | Calli(target) => check(pc, *target)?,
+	    Push(val @ (Vsize(_) | Vaddr(_))) =>
+		return Err(VerifyError::IllegalConstant { pc, val: *val }),
+	    Switch(targets) => {
+		for target in targets {
+		    check(pc, *target)?
+		}
+	    }
+	    _ => ()
+	}
+    }
+    match prog.last() {
+	None => Err(VerifyError::Empty),
+	Some(instr) if is_terminator(instr) => Ok(()),
+	Some(_) => Err(VerifyError::FallsOffEnd(len - 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{string::ToString, vec};
+
+    #[test]
+    fn test_verify() {
+	assert_eq!(verify(&[Push(Vi32(1)), Push(Vloc(3)), Jmp, Halt]), Ok(()));
+	assert_eq!(verify(&[Push(Vloc(0)), Calli(0), Jmpi(1)]), Ok(()));
+	assert_eq!(verify(&[]), Err(VerifyError::Empty));
+	assert_eq!(verify(&[Push(Vloc(2)), Jmp]),
+		   Err(VerifyError::BadTarget { pc: 0, target: 2 }));
+	assert_eq!(verify(&[Nop, Jmpi(7)]),
+		   Err(VerifyError::BadTarget { pc: 1, target: 7 }));
+	assert_eq!(verify(&[Calli(9), Halt]),
+		   Err(VerifyError::BadTarget { pc: 0, target: 9 }));
+	assert_eq!(verify(&[Push(Vi32(0)), Switch(vec![3, 4]), Halt, Halt]),
+		   Err(VerifyError::BadTarget { pc: 1, target: 4 }));
+	assert_eq!(verify(&[Push(Vaddr(0)), Halt]),
+		   Err(VerifyError::IllegalConstant { pc: 0, val: Vaddr(0) }));
+	assert_eq!(verify(&[Nop, Push(Vsize(3)), Halt]),
+		   Err(VerifyError::IllegalConstant { pc: 1, val: Vsize(3) }));
+	assert_eq!(verify(&[Push(Vi32(1)), Push(Vi32(2)), Binary(Binop::Add)]),
+		   Err(VerifyError::FallsOffEnd(2)));
+	assert_eq!(verify(&[Push(Vi32(0)), Switch(vec![0])]),
+		   Err(VerifyError::FallsOffEnd(1)));
+	assert_eq!(VerifyError::FallsOffEnd(2).to_string(),
+		   "2: execution falls off the end of the program");
+    }
+}
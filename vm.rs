@@ -1,33 +1,1085 @@
-use std::fmt::{self, Display};
+use alloc::{boxed::Box, collections::{BTreeMap, VecDeque}, format, string::{String, ToString}, vec, vec::Vec};
+use alloc::sync::Arc;
+use core::fmt::{self, Display};
+use core::mem;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+#[cfg(feature = "std")]
+use std::io::{self, BufRead, Write};
+#[cfg(feature = "std")]
+use std::sync::{Mutex, atomic::AtomicUsize};
+#[cfg(feature = "std")]
+use std::thread;
+#[cfg(feature = "std")]
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use super::assemble::Symbols;
 use super::isa::{*, Binop::*, Instr::*, Val::*, Unop::*};
+use super::{FromBytes, ParseError, ToBytes};
+use super::verify::{verify, VerifyError};
 
 static STK_SIZE: usize = 1024;
-static HEAP_SIZE: usize = 1024;
+static MAX_HEAP: usize = 1024;
+static INIT_HEAP: usize = 64;
+static TRACE_DEPTH: usize = 8;
+
+/// Resource limits for a VM run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VmConfig {
+    /// Maximum number of values on the stack.
+    pub stack_size: usize,
+    /// Maximum number of heap slots. The heap starts small and grows
+    /// on demand up to this size.
+    pub max_heap: usize,
+    /// Garbage collection strategy.
+    pub gc: GcKind,
+    /// Collect before every allocation, to expose missing roots.
+    pub gc_stress: bool,
+    /// Seed for the random number generator. Derived from the system
+    /// clock if not given.
+    pub seed: Option<u64>,
+    /// Reject instructions whose behavior would depend on anything
+    /// but the program, its input, and this configuration, such as
+    /// Rand without a seed. Heap addresses never depend on anything
+    /// else, so deterministic runs are reproducible exactly.
+    pub deterministic: bool,
+    /// Verify the program before running it.
+    pub verify: bool,
+    /// Gas charged per instruction by metered runs.
+    pub gas_costs: GasCosts,
+    /// Maximum number of bytes taken up by the heap and stack, if
+    /// limited.
+    pub max_memory_bytes: Option<usize>,
+    /// What Add, Sub, Mul, Inc and Dec do on integer overflow.
+    pub arithmetic: Arithmetic,
+    /// Halt on running off the end of the program, as if it ended
+    /// with Halt.
+    pub implicit_halt: bool,
+    /// Number of stack values, from the top, and of heap slots shown
+    /// at each step of the debug trace.
+    pub trace_depth: usize,
+    /// Execute common instruction sequences as single steps, when no
+    /// fuel, gas, statistics or profile is being tracked. The trace
+    /// then skips the states inside each fused sequence.
+    pub fuse: bool,
+    /// Check that every call and return follows the calling
+    /// convention, failing with VmError::FrameCheck at the first
+    /// instruction that does not.
+    pub check_frames: bool,
+    /// Maximum number of calls a thread can be inside at once, if
+    /// limited.
+    pub max_call_depth: Option<usize>
+}
+
+impl Default for VmConfig {
+    fn default() -> VmConfig {
+	VmConfig { stack_size: STK_SIZE, max_heap: MAX_HEAP, gc: GcKind::MarkSweep,
+		   gc_stress: false, seed: None, deterministic: false, verify: false,
+		   gas_costs: GasCosts::default(), max_memory_bytes: None,
+		   arithmetic: Arithmetic::Wrapping, implicit_halt: false,
+		   trace_depth: TRACE_DEPTH, fuse: false, check_frames: false,
+		   max_call_depth: None }
+    }
+}
+
+/// Gas cost model. An instruction costs its base cost, plus per_slot
+/// for each heap slot it allocates or copies.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GasCosts {
+    /// Base cost of each instruction, indexed by opcode.
+    pub base: [u64; 256],
+    /// Cost of each heap slot allocated or copied.
+    pub per_slot: u64
+}
+
+impl Default for GasCosts {
+    fn default() -> GasCosts {
+	GasCosts { base: [1; 256], per_slot: 1 }
+    }
+}
+
+/// Garbage collection strategies.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GcKind {
+    /// Sweep unreachable arrays onto the free list, leaving live
+    /// arrays in place.
+    MarkSweep,
+    /// Copy live arrays into a fresh to-space, rewriting every address
+    /// to its new location and compacting the heap.
+    Copying
+}
+
+/// Integer overflow behavior of Add, Sub, Mul, Inc and Dec. The
+/// result does not depend on how the VM itself was built.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Arithmetic {
+    /// Wrap around in two's complement.
+    Wrapping,
+    /// Fail with VmError::Overflow.
+    Checked
+}
+
+/// A runtime failure.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VmError {
+    /// Push past the stack limit.
+    StackOverflow,
+    /// Pop from an empty stack.
+    StackUnderflow,
+    /// A value of the wrong type.
+    TypeError { expected: &'static str, found: Val },
+    /// Binary operator applied to operands of the wrong types.
+    OperandTypeError { op: Binop, expected: &'static str },
+    /// No room left on the heap, even after collecting garbage.
+    OutOfHeap,
+    /// Alloc of a negative size, or of an array too big to fit on
+    /// the heap.
+    BadAllocSize(i32),
+    /// Grow of an array of size len to the smaller size.
+    CannotShrink { len: usize, size: usize },
+    /// Growing the heap or stack by requested bytes would take the
+    /// used bytes past the configured limit.
+    MemoryLimit { used: usize, requested: usize, limit: usize },
+    /// Array index past the end of the array.
+    IndexOutOfBounds { index: usize, len: usize },
+    /// Address past the end of the heap.
+    HeapOutOfBounds(Address),
+    /// Address that does not point at an array header.
+    NotAnArray(Address),
+    /// Len of an array whose size does not fit in an i32.
+    ArrayTooLong(usize),
+    /// Window [off, off+len) past the end of an array of size size.
+    WindowOutOfBounds { off: usize, len: usize, size: usize },
+    /// Var, Store, Inc or Dec of local variable offset from frame
+    /// pointer fp, whose slot is past the end of the stack or past
+    /// the largest stack index.
+    BadVarIndex { fp: u32, offset: u32 },
+    /// Peek past the bottom of the stack.
+    PeekOutOfBounds(u32),
+    /// SetFrame of more arguments than the stack holds.
+    BadFrame { depth: usize, requested: u32 },
+    /// Ret with only the given number of values on the stack, short
+    /// of a result, return pc and saved fp.
+    ShortReturn(usize),
+    /// Ret where the frame holds found instead of the location
+    /// expected describes.
+    BadReturn { expected: &'static str, found: Val },
+    /// Ret with the frame pointer fp above the saved fp, which is at
+    /// stack index saved_at.
+    BadFramePointer { fp: u32, saved_at: usize },
+    /// Ret to a saved fp above the current frame pointer fp.
+    BadSavedFp { saved: u32, fp: u32 },
+    /// TailCall of args arguments with only depth values on the stack,
+    /// short of the arguments, return pc and saved fp.
+    ShortTailCall { args: usize, depth: usize },
+    /// AllocClos of captures values with only depth values on the
+    /// stack, short of them and the code location.
+    ShortClosure { captures: usize, depth: usize },
+    /// The instruction at pc, in a replayed run, took a value from
+    /// outside the machine where the recording has something else,
+    /// as msg describes.
+    ReplayDiverged { pc: u32, msg: String },
+    /// With frame checking on, the instruction at pc broke the
+    /// calling convention in the way msg describes.
+    FrameCheck { pc: u32, msg: String },
+    /// The call at pc would have been depth calls deep, past
+    /// VmConfig::max_call_depth.
+    CallDepthExceeded { depth: usize, pc: u32 },
+    /// Global index past the last global.
+    GlobalOutOfBounds { index: u32, len: usize },
+    /// Division or remainder by zero at the given pc.
+    DivByZero(u32),
+    /// Checked integer arithmetic overflowed.
+    Overflow,
+    /// Pow of an integer to the given negative exponent.
+    NegativeExponent(i32),
+    /// Rand with the given bound, which is not positive.
+    BadRandBound(i32),
+    /// Chr of an integer that is not a Unicode scalar value.
+    InvalidChar(i32),
+    /// Jump to a location outside the program, from the instruction
+    /// at pc.
+    BadJumpTarget { target: u32, pc: u32 },
+    /// Execution reached the end of the program, at the given pc,
+    /// without halting.
+    MissingHalt(u32),
+    /// The pc is past the end of the program.
+    PcOutOfBounds(u32),
+    /// Fuel ran out before the instruction at the given pc.
+    OutOfFuel(u32),
+    /// A run with a time limit was still going after elapsed, having
+    /// executed the given number of instructions.
+    Timeout { elapsed: Duration, instructions: u64 },
+    /// The instruction at pc would exceed the gas budget, of which
+    /// used had been consumed.
+    OutOfGas { pc: u32, used: u64 },
+    /// The host asked an interruptible run to stop. The machine can
+    /// be resumed.
+    Interrupted,
+    /// Step back before any instruction has executed.
+    AtStart,
+    /// Step back with no history kept, or further back than the
+    /// oldest snapshot kept.
+    NoHistory,
+    /// Free of an address that is already free.
+    DoubleFree(Address),
+    /// Assert of false at the given pc.
+    AssertionFailed(u32),
+    /// Every thread is waiting on another.
+    Deadlock,
+    /// Syscall of a host function index with nothing registered.
+    UnknownHostFn(u32),
+    /// A host function returned an error.
+    HostFn { index: u32, msg: String },
+    /// Join of a thread id with no thread.
+    UnknownThread(i32),
+    /// Join by a thread of its own id.
+    SelfJoin(i32),
+    /// An instruction with no injected source of nondeterminism, in
+    /// deterministic mode.
+    Nondeterministic(&'static str),
+    /// Read instruction failed.
+    Read(String),
+    /// Print instruction failed.
+    Write(String),
+    /// The program failed verification.
+    Verify(VerifyError),
+    /// Writing the execution trace failed.
+    Trace(String),
+    /// An error from within the named operation.
+    Context(&'static str, Box<VmError>),
+    /// An error inside a function call, with the return addresses of
+    /// the active frames, innermost first.
+    Backtrace { error: Box<VmError>, frames: Vec<u32> },
+    /// Any other failure, described by its message.
+    Other(String)
+}
+
+impl VmError {
+    /// Wrap self as an error from within the named operation.
+    fn context(self, op: &'static str) -> VmError {
+	VmError::Context(op, Box::new(self))
+    }
+
+    /// Record pc as the location of self, for errors raised by code
+    /// that does not know it.
+    fn at(self, pc: u32) -> VmError {
+	match self {
+	    VmError::DivByZero(_) => VmError::DivByZero(pc),
+	    err => err
+	}
+    }
+}
+
+impl Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	use VmError::*;
+	match self {
+	    StackOverflow => write!(f, "out of stack space"),
+	    StackUnderflow => write!(f, "attempt to pop empty stack"),
+	    TypeError { expected, .. } => write!(f, "expected {}", expected),
+	    OperandTypeError { op, expected } =>
+		write!(f, "{:?}: expected {} operands", op, expected),
+	    OutOfHeap => write!(f, "out of heap space"),
+	    BadAllocSize(size) => write!(f, "bad allocation size: {}", size),
+	    CannotShrink { len, size } =>
+		write!(f, "cannot shrink array of size {} to {}", len, size),
+	    MemoryLimit { used, requested, limit } =>
+		write!(f, "memory limit of {} bytes exceeded: {} used, {} requested",
+		       limit, used, requested),
+	    IndexOutOfBounds { .. } => write!(f, "index past end of array"),
+	    HeapOutOfBounds(_) => write!(f, "indexing past end of heap"),
+	    NotAnArray(_) => write!(f, "expected size at array location"),
+	    ArrayTooLong(len) => write!(f, "array size {} exceeds i32::MAX", len),
+	    WindowOutOfBounds { off, len, size } =>
+		write!(f, "window [{}, {}+{}) past end of array of size {}",
+		       off, off, len, size),
+	    BadVarIndex { fp, offset } =>
+		write!(f, "variable {} past end of stack (frame pointer {})", offset, fp),
+	    PeekOutOfBounds(_) => write!(f, "peek past bottom of stack"),
+	    BadFrame { depth, requested } =>
+		write!(f, "frame of {} arguments on a stack of {} values", requested, depth),
+	    ShortReturn(n) =>
+		write!(f, "return requires a result, pc and fp on the stack, found {} values", n),
+	    BadReturn { expected, found } =>
+		write!(f, "expected {} in return, found {:?}", expected, found),
+	    BadFramePointer { fp, saved_at } =>
+		write!(f, "frame pointer {} is above the saved fp at {}", fp, saved_at),
+	    BadSavedFp { saved, fp } =>
+		write!(f, "saved fp {} is above the frame pointer {}", saved, fp),
+	    ShortTailCall { args, depth } =>
+		write!(f, "tail call requires {} arguments above the return pc, found {} values",
+		       args, depth),
+	    ShortClosure { captures, depth } =>
+		write!(f, "allocclos requires a code location and {} captures, found {} values",
+		       captures, depth),
+	    FrameCheck { pc, msg } => write!(f, "{} at pc {}", msg, pc),
+	    ReplayDiverged { pc, msg } => write!(f, "replay diverged at pc {}: {}", pc, msg),
+	    CallDepthExceeded { depth, pc } =>
+		write!(f, "call depth limit exceeded: {} calls deep at pc {}", depth, pc),
+	    GlobalOutOfBounds { index, len } =>
+		write!(f, "global index out of range: {} ({} globals)", index, len),
+	    DivByZero(pc) => write!(f, "divide by zero at pc {}", pc),
+	    Overflow => write!(f, "integer overflow"),
+	    NegativeExponent(i) => write!(f, "negative exponent: {}", i),
+	    BadRandBound(n) => write!(f, "rand: bound must be positive, got {}", n),
+	    InvalidChar(i) => write!(f, "invalid char scalar value: {}", i),
+	    BadJumpTarget { target, pc } =>
+		write!(f, "jump target out of bounds: {} at pc {}", target, pc),
+	    MissingHalt(_) => write!(f, "program ended without halt"),
+	    PcOutOfBounds(pc) => write!(f, "pc out of bounds: {}", pc),
+	    OutOfFuel(pc) => write!(f, "out of fuel at pc {}", pc),
+	    OutOfGas { pc, used } => write!(f, "out of gas at pc {} after using {}", pc, used),
+	    Interrupted => write!(f, "interrupted"),
+	    AtStart => write!(f, "cannot step back before the first instruction"),
+	    NoHistory => write!(f, "no saved state to step back to"),
+	    Timeout { elapsed, instructions } =>
+		write!(f, "timed out after {:?} and {} instructions", elapsed, instructions),
+	    DoubleFree(base) => write!(f, "double free of address {}", base),
+	    AssertionFailed(pc) => write!(f, "assertion failed at pc {}", pc),
+	    Deadlock => write!(f, "deadlock: all threads are blocked"),
+	    UnknownHostFn(i) => write!(f, "unknown host function: {}", i),
+	    HostFn { index, msg } => write!(f, "host function {}: {}", index, msg),
+	    UnknownThread(t) => write!(f, "unknown thread: {}", t),
+	    SelfJoin(t) => write!(f, "thread {} cannot join itself", t),
+	    Nondeterministic(op) => write!(f, "{}: nondeterministic without a seed", op),
+	    Read(msg) => write!(f, "read: {}", msg),
+	    Write(msg) => write!(f, "print: {}", msg),
+	    Verify(err) => write!(f, "verify: {}", err),
+	    Trace(msg) => write!(f, "trace write failed: {}", msg),
+	    Context(op, err) => write!(f, "{}: {}", op, err),
+	    Backtrace { error, frames } => {
+		let pcs: Vec<String> = frames.iter().map(u32::to_string).collect();
+		write!(f, "{} (backtrace: {})", error, pcs.join(", "))
+	    }
+	    Other(msg) => write!(f, "{}", msg)
+	}
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for VmError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+	match self {
+	    VmError::Context(_, err) | VmError::Backtrace { error: err, .. } =>
+		Some(err.as_ref()),
+	    VmError::Verify(err) => Some(err),
+	    _ => None
+	}
+    }
+}
+
+impl From<String> for VmError {
+    fn from(msg: String) -> VmError {
+	VmError::Other(msg)
+    }
+}
+
+/// Convert v with conv, or fail with a type error naming the expected
+/// type.
+fn expect<T>(v: Val, conv: fn(&Val) -> Option<T>, expected: &'static str)
+	     -> Result<T, VmError> {
+    conv(&v).ok_or(VmError::TypeError { expected, found: v })
+}
+
+/// If v is the address of an array in from, copy the array to the
+/// end of to (unless it has already been copied) and rewrite v to
+/// point at the copy.
+fn forward(v: &mut Val, from: &[Val], to: &mut Vec<Val>, fwd: &mut [Option<Address>]) {
+    if let Vaddr(base) = *v {
+	if let Some(&Vsize(size)) = from.get(base) {
+	    if base + size < from.len() {
+		let loc = *fwd[base].get_or_insert_with(|| {
+		    to.extend_from_slice(&from[base..base+size+1]);
+		    to.len() - size - 1
+		});
+		*v = Vaddr(loc)
+	    }
+	}
+    }
+}
+
+/// An instruction decoded for execution. Operands are widened to the
+/// types the machine uses them at, and switch tables are stored out of
+/// line, so that executing an instruction borrows nothing from the
+/// program.
+#[derive(Debug, Clone, Copy)]
+#[repr(u8)]
+enum Op {
+    Push(Val), Pop, Peek(usize), Unary(Unop), Binary(Binop), Swap, Alloc, Set, Get,
+    Var(usize), Store(usize), SetFrame(u32), Call, Ret, Branch, Halt, Dup, Nop, Jmp,
+    Jmpi(u32), BranchNot, Calli(u32), Read, Print, SConcat, SLen, TypeTest(TypeTag),
+    PopN(usize), Rot, Over, Select, AllocI(usize), GetF(usize), SetF(usize), Len,
+    ArrCopy, Free, Grow, TailCall(usize), Globals(usize), GGet(u32), GSet(u32),
+    AllocClos(usize), CallClos,
+    /// Index of the jump table in DecodedProg::tables.
+    Switch(usize),
+    DivMod, PushPC, Inc(usize), Dec(usize), Spawn, Yield, Join, Exit, Assert,
+    Syscall(u32, usize), Rand,
+    // Superinstructions, each standing for the sequence starting at
+    // its own location.
+    /// Push(v), Binary(b).
+    PushBinary(Val, Binop),
+    /// Var(i), Push(Vi32(1)), Binary(Add), Store(i).
+    IncLocal(usize),
+    /// Push(Vloc(target)), then Branch if the flag is set, and
+    /// BranchNot otherwise.
+    BranchTo(u32, bool)
+}
+
+impl Op {
+    /// Number of instructions a superinstruction stands for, and the
+    /// number of stack slots they need beyond the current depth.
+    fn fused_len(self) -> (u32, usize) {
+	match self {
+	    Op::PushBinary(..) | Op::BranchTo(..) => (2, 1),
+	    Op::IncLocal(_) => (4, 2),
+	    _ => (1, 0)
+	}
+    }
+}
+
+/// A program with each instruction decoded once, up front. It
+/// dereferences to the instructions as given, for display and
+/// serialization. Programs may not push addresses or sizes, which
+/// only snapshots can hold, so decoding rejects them.
+#[derive(Clone)]
+struct DecodedProg {
+    instrs: Arc<[Instr]>,
+    /// The decoded form of each instruction in instrs.
+    ops: Vec<Op>,
+    /// Like ops, but with the first instruction of each fusable
+    /// sequence replaced by a superinstruction.
+    fused: Vec<Op>,
+    /// Switch jump tables.
+    tables: Vec<Vec<u32>>
+}
+
+impl DecodedProg {
+    fn new(instrs: Arc<[Instr]>) -> Result<DecodedProg, VmError> {
+	let mut prog = DecodedProg { instrs, ops: Vec::new(), fused: Vec::new(),
+				     tables: Vec::new() };
+	prog.decode_from(0)?;
+	Ok(prog)
+    }
+    /// Append and decode instrs. If they fail to decode, self is left
+    /// as it was.
+    fn extend(&mut self, instrs: &[Instr]) -> Result<(), VmError> {
+	let start = self.instrs.len();
+	let mut prog = DecodedProg {
+	    instrs: self.instrs.iter().chain(instrs).cloned().collect(),
+	    ..self.clone()
+	};
+	prog.decode_from(start)?;
+	*self = prog;
+	Ok(())
+    }
+    /// Decode the instructions from start on, then fuse them.
+    fn decode_from(&mut self, start: usize) -> Result<(), VmError> {
+	let instrs = Arc::clone(&self.instrs);
+	for (pc, instr) in instrs.iter().enumerate().skip(start) {
+	    let op = self.decode(pc as u32, instr)?;
+	    self.ops.push(op)
+	}
+	self.fuse();
+	Ok(())
+    }
+    /// Rebuild fused from ops. The instructions inside a fused
+    /// sequence keep their own ops, so jumps into the middle of one
+    /// still work.
+    fn fuse(&mut self) {
+	self.fused = self.ops.clone();
+	for pc in 0..self.ops.len() {
+	    self.fused[pc] = match self.ops[pc..] {
+		[Op::Var(i), Op::Push(Vi32(1)), Op::Binary(Add), Op::Store(j), ..] if i == j =>
+		    Op::IncLocal(i),
+		[Op::Push(Vloc(target)), Op::Branch, ..] => Op::BranchTo(target, true),
+		[Op::Push(Vloc(target)), Op::BranchNot, ..] => Op::BranchTo(target, false),
+		[Op::Push(v), Op::Binary(b), ..] => Op::PushBinary(v, b),
+		_ => continue
+	    }
+	}
+    }
+    /// Decode instr, found at pc.
+    fn decode(&mut self, pc: u32, instr: &Instr) -> Result<Op, VmError> {
+	Ok(match *instr {
+	    Push(val @ (Vsize(_) | Vaddr(_))) =>
+		return Err(VmError::Verify(VerifyError::IllegalConstant { pc, val })),
+	    Push(v) => Op::Push(v),
+	    Pop => Op::Pop,
+	    Peek(i) => Op::Peek(i as usize),
+	    Unary(u) => Op::Unary(u),
+	    Binary(b) => Op::Binary(b),
+	    Swap => Op::Swap,
+	    Alloc => Op::Alloc,
+	    Set => Op::Set,
+	    Get => Op::Get,
+	    Var(i) => Op::Var(i as usize),
+	    Store(i) => Op::Store(i as usize),
+	    SetFrame(i) => Op::SetFrame(i),
+	    Call => Op::Call,
+	    Ret => Op::Ret,
+	    Branch => Op::Branch,
+	    Halt => Op::Halt,
+	    Dup => Op::Dup,
+	    Nop => Op::Nop,
+	    Jmp => Op::Jmp,
+	    Jmpi(target) => Op::Jmpi(target),
+	    BranchNot => Op::BranchNot,
+	    Calli(target) => Op::Calli(target),
+	    Read => Op::Read,
+	    Print => Op::Print,
+	    SConcat => Op::SConcat,
+	    SLen => Op::SLen,
+	    TypeTest(t) => Op::TypeTest(t),
+	    PopN(n) => Op::PopN(n as usize),
+	    Rot => Op::Rot,
+	    Over => Op::Over,
+	    Select => Op::Select,
+	    AllocI(size) => Op::AllocI(size as usize),
+	    GetF(ix) => Op::GetF(ix as usize),
+	    SetF(ix) => Op::SetF(ix as usize),
+	    Len => Op::Len,
+	    ArrCopy => Op::ArrCopy,
+	    Free => Op::Free,
+	    Grow => Op::Grow,
+	    TailCall(n) => Op::TailCall(n as usize),
+	    Globals(n) => Op::Globals(n as usize),
+	    GGet(i) => Op::GGet(i),
+	    GSet(i) => Op::GSet(i),
+	    AllocClos(n) => Op::AllocClos(n as usize),
+	    CallClos => Op::CallClos,
+	    Switch(ref table) => {
+		self.tables.push(table.clone());
+		Op::Switch(self.tables.len() - 1)
+	    }
+	    DivMod => Op::DivMod,
+	    PushPC => Op::PushPC,
+	    Inc(i) => Op::Inc(i as usize),
+	    Dec(i) => Op::Dec(i as usize),
+	    Spawn => Op::Spawn,
+	    Yield => Op::Yield,
+	    Join => Op::Join,
+	    Exit => Op::Exit,
+	    Assert => Op::Assert,
+	    Syscall(f, n) => Op::Syscall(f, n as usize),
+	    Rand => Op::Rand
+	})
+    }
+}
+
+impl core::ops::Deref for DecodedProg {
+    type Target = [Instr];
+    fn deref(&self) -> &[Instr] {
+	&self.instrs
+    }
+}
+
+/// A program decoded once, for any number of machines to run without
+/// copying or decoding it again. Cloning the handle is cheap.
+#[derive(Clone)]
+pub struct SharedProgram(Arc<DecodedProg>);
+
+impl SharedProgram {
+    /// Decode prog for sharing. Fails if prog pushes an address or a
+    /// size.
+    pub fn new<P: Into<Arc<[Instr]>>>(prog: P) -> Result<SharedProgram, VmError> {
+	Ok(SharedProgram(Arc::new(DecodedProg::new(prog.into())?)))
+    }
+}
+
+impl core::ops::Deref for SharedProgram {
+    type Target = [Instr];
+    fn deref(&self) -> &[Instr] {
+	&self.0
+    }
+}
 
 /// GrumpyVM state.
-#[derive(Debug)]
 struct State {
     /// Program counter.
     pc: u32,
     /// Frame pointer.
     fp: u32,
-    /// The stack, with maximum size STK_SIZE.
+    /// The stack, with maximum size cfg.stack_size.
     stk: Vec<Val>,
-    /// The heap, with maximum size HEAP_SIZE.
+    /// The heap, with maximum size cfg.max_heap.
     heap: Vec<Val>,
-    /// The program being executed, a vector of instructions.
-    prog: Vec<Instr>
+    /// Free heap blocks, as (address, number of slots) pairs.
+    free: Vec<(Address, usize)>,
+    /// The program being executed, which may be shared with other
+    /// machines.
+    prog: Arc<DecodedProg>,
+    /// Where Read instructions take input from and where Print
+    /// instructions and the debug trace write to.
+    io: Box<dyn VmIo>,
+    /// Whether to write the state to io before each instruction.
+    trace: bool,
+    /// Format of the trace written to the writer passed to exec.
+    trace_format: TraceFormat,
+    /// Xorshift state for Rand instructions.
+    rng: u64,
+    /// Global variable slots.
+    globals: Vec<Val>,
+    /// Saved contexts of all green threads, indexed by thread id. The
+    /// running thread's pc, fp, and stack live in the fields above,
+    /// and its entry here holds an empty placeholder stack.
+    threads: Vec<Thread>,
+    /// Stack indices of the saved fp slots of the running thread's
+    /// active frames, innermost last. The return pc is in the slot
+    /// above each.
+    frames: Vec<usize>,
+    /// With frame checking on, a shadow of frames: where each of the
+    /// running thread's active frames should have its saved fp, and
+    /// whether it has been called yet. Kept apart from frames so that
+    /// checking never changes what the machine does.
+    shadow: Vec<(usize, bool)>,
+    /// Number of calls the running thread is inside.
+    call_depth: usize,
+    /// Id of the running thread. The main thread has id 0.
+    tid: usize,
+    /// Stack and heap limits.
+    cfg: VmConfig,
+    /// Number of instructions left to execute, if limited.
+    fuel: Option<u64>,
+    /// Time limit, if any.
+    #[cfg(feature = "std")]
+    deadline: Option<Deadline>,
+    /// Gas left, if metered.
+    gas: Option<u64>,
+    /// Gas consumed so far.
+    gas_used: u64,
+    /// Execution statistics, if being collected.
+    stats: Option<ExecStats>,
+    /// Per-instruction profile, if being collected.
+    profile: Option<Profile>,
+    /// Watched heap locations.
+    watches: Vec<Address>,
+    /// Writes to watched locations made by the last instruction.
+    watch_hits: Vec<WatchHit>,
+    /// Heap location most recently allocated, read by Get or GetF,
+    /// or written. The trace shows the heap around it.
+    touched: Option<Address>,
+    /// Host functions callable by Syscall, by index.
+    host_fns: BTreeMap<u32, HostFn>,
+    /// Recording or recording being replayed, if any.
+    tape: Tape
+}
+
+/// A host function. It receives the Syscall arguments in push order
+/// and returns the value to push.
+type HostFn = Box<dyn FnMut(&mut [Val]) -> Result<Val, String> + Send>;
+
+/// Dynamic execution statistics.
+#[derive(Clone)]
+pub struct ExecStats {
+    /// Number of times each instruction was executed, indexed by opcode.
+    pub counts: [u64; 256],
+    /// Total number of instructions executed.
+    pub total: u64,
+    /// Maximum number of values on the stack.
+    pub max_stack: usize,
+    /// Maximum number of heap slots in use.
+    pub max_heap: usize,
+    /// Number of function calls, including tail and closure calls.
+    pub calls: u64
+}
+
+impl ExecStats {
+    fn new() -> ExecStats {
+	ExecStats { counts: [0; 256], total: 0, max_stack: 0, max_heap: 0, calls: 0 }
+    }
+    /// Number of times instructions with the same opcode as instr
+    /// were executed.
+    pub fn count(&self, instr: &Instr) -> u64 {
+	self.counts[instr.opcode() as usize]
+    }
+}
+
+/// Per-instruction execution profile.
+#[derive(Debug, Clone)]
+pub struct Profile {
+    /// Number of times the instruction at each pc was executed.
+    pub counts: Vec<u64>,
+    /// Time spent executing the instruction at each pc, if timed.
+    pub times: Option<Vec<Duration>>,
+    /// The instruction being timed and when it started.
+    #[cfg(feature = "std")]
+    last: Option<(usize, Instant)>
+}
+
+impl Profile {
+    fn new(len: usize, timed: bool) -> Profile {
+	Profile {
+	    counts: vec![0; len],
+	    times: timed.then(|| vec![Duration::ZERO; len]),
+	    #[cfg(feature = "std")]
+	    last: None
+	}
+    }
+    /// Record the start of the instruction at pc.
+    fn record(&mut self, pc: u32) {
+	self.stop();
+	let pc = pc as usize;
+	if let Some(n) = self.counts.get_mut(pc) {
+	    *n += 1
+	}
+	#[cfg(feature = "std")]
+	if self.times.is_some() {
+	    self.last = Some((pc, Instant::now()))
+	}
+    }
+    /// Charge the time since the last instruction started to it.
+    fn stop(&mut self) {
+	#[cfg(feature = "std")]
+	if let (Some(times), Some((pc, start))) = (self.times.as_mut(), self.last.take()) {
+	    if let Some(t) = times.get_mut(pc) {
+		*t += start.elapsed()
+	    }
+	}
+    }
+    /// The n most executed pcs, most executed first.
+    pub fn hottest(&self, n: usize) -> Vec<u32> {
+	let mut pcs: Vec<u32> = (0..self.counts.len() as u32)
+	    .filter(|pc| self.counts[*pc as usize] > 0)
+	    .collect();
+	pcs.sort_by_key(|pc| core::cmp::Reverse(self.counts[*pc as usize]));
+	pcs.truncate(n);
+	pcs
+    }
+    /// A table of the n most executed instructions of prog, with the
+    /// label each is under in symbols, if any.
+    pub fn report(&self, prog: &[Instr], symbols: &Symbols, n: usize) -> String {
+	let mut out = String::from("    pc      count");
+	if self.times.is_some() {
+	    out.push_str("      time ns")
+	}
+	out.push_str("  instruction");
+	for pc in self.hottest(n) {
+	    out.push_str(&format!("\n{:>6} {:>10}", pc, self.counts[pc as usize]));
+	    if let Some(times) = &self.times {
+		out.push_str(&format!(" {:>12}", times[pc as usize].as_nanos()))
+	    }
+	    let instr = prog.get(pc as usize).map_or(String::new(), |i| i.to_string());
+	    out.push_str(&format!("  {:<16}", instr));
+	    // The enclosing label is the last one at or before pc.
+	    let label = symbols.iter()
+		.filter(|(_, addr)| **addr <= pc)
+		.max_by(|(l1, a1), (l2, a2)| a1.cmp(a2).then(l2.cmp(l1)));
+	    match label {
+		Some((label, addr)) if *addr == pc => out.push_str(label),
+		Some((label, addr)) => out.push_str(&format!("{}+{}", label, pc - addr)),
+		None => ()
+	    }
+	    out.truncate(out.trim_end().len())
+	}
+	out
+    }
+}
+
+/// Instructions executed between checks of the clock against a time
+/// limit. Reading the clock costs as much as several instructions, so
+/// checking this rarely keeps the overhead well under 1%. A power
+/// of two, so that the check is a mask.
+#[cfg(feature = "std")]
+const DEADLINE_INTERVAL: u64 = 16_384;
+
+/// A time limit on a run.
+#[cfg(feature = "std")]
+struct Deadline {
+    start: Instant,
+    limit: Duration,
+    /// Number of instructions executed so far.
+    instructions: u64
+}
+
+#[cfg(feature = "std")]
+impl Deadline {
+    fn new(limit: Duration) -> Deadline {
+	Deadline { start: Instant::now(), limit, instructions: 0 }
+    }
+    /// Count an executed instruction, failing if the time is up.
+    fn tick(&mut self) -> Result<(), VmError> {
+	self.instructions += 1;
+	if self.instructions & (DEADLINE_INTERVAL - 1) == 0 {
+	    let elapsed = self.start.elapsed();
+	    if elapsed >= self.limit {
+		return Err(VmError::Timeout { elapsed, instructions: self.instructions })
+	    }
+	}
+	Ok(())
+    }
+}
+
+/// Scheduling status of a green thread.
+#[derive(Clone, Copy, PartialEq)]
+enum Status {
+    Runnable,
+    /// Waiting for the thread with the given id to halt.
+    Joining(usize),
+    /// Halted with the given result.
+    Done(Val)
+}
+
+/// A green thread's execution context.
+struct Thread {
+    pc: u32,
+    fp: u32,
+    stk: Vec<Val>,
+    frames: Vec<usize>,
+    shadow: Vec<(usize, bool)>,
+    call_depth: usize,
+    status: Status
+}
+
+impl Thread {
+    fn new(pc: u32) -> Thread {
+	Thread {
+	    pc,
+	    fp: 0,
+	    stk: Vec::new(),
+	    frames: Vec::new(),
+	    shadow: Vec::new(),
+	    call_depth: 0,
+	    status: Status::Runnable
+	}
+    }
+}
+
+/// Write the values of vals in [start, end), numbered from the
+/// bottom if numbered, with the counts of those elided on each side.
+fn fmt_window(f: &mut fmt::Formatter<'_>, vals: &[Val], start: usize, end: usize,
+	      numbered: bool) -> fmt::Result {
+    let mut items = Vec::new();
+    if start > 0 {
+	items.push(format!("... {} more", start))
+    }
+    for (i, v) in vals[start..end].iter().enumerate() {
+	items.push(if numbered { format!("{}: {}", start + i, v) } else { v.to_string() })
+    }
+    if end < vals.len() {
+	items.push(format!("... {} more", vals.len() - end))
+    }
+    write!(f, "[{}]", items.join(", "))
 }
 
 /// Display implementation for State (modify as you wish).
+/// The trace shows the top cfg.trace_depth values of the stack, and as
+/// many heap slots around the location last touched.
 impl Display for State {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-	write!(f, "pc: {}\ninstr: {:?}\nfp: {}\nstk: {:?}\nheap: {:?}",
-	       self.pc, self.prog[self.pc as usize], self.fp, self.stk, self.heap)?;
-	write!(f, "\nheap size: {}", self.heap.len())
+	let n = self.cfg.trace_depth;
+	match self.prog.get(self.pc as usize) {
+	    Some(instr) => write!(f, "pc: {}\ninstr: {}", self.pc, instr)?,
+	    None => write!(f, "pc: {}\ninstr: none", self.pc)?
+	}
+	write!(f, "\nfp: {}\ncall depth: {}\nstk: ", self.fp, self.call_depth)?;
+	fmt_window(f, &self.stk, self.stk.len().saturating_sub(n), self.stk.len(), false)?;
+	let len = self.heap.len();
+	let end = (self.touched.unwrap_or(0) + n / 2 + 1).max(n).min(len);
+	write!(f, "\nheap: ")?;
+	fmt_window(f, &self.heap, end.saturating_sub(n), end, true)?;
+	write!(f, "\nheap size: {}", len)
+    }
+}
+
+/// Quote s as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+	match c {
+	    '"' => out.push_str("\\\""),
+	    '\\' => out.push_str("\\\\"),
+	    '\n' => out.push_str("\\n"),
+	    c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+	    c => out.push(c)
+	}
+    }
+    out.push('"');
+    out
+}
+
+impl State {
+    /// The state as a one-line JSON object, with the top
+    /// cfg.trace_depth stack values, bottom first, in assembly syntax.
+    fn to_json(&self) -> String {
+	let instr = self.prog.get(self.pc as usize).map_or("none".into(), Instr::to_string);
+	let top = &self.stk[self.stk.len().saturating_sub(self.cfg.trace_depth)..];
+	let top: Vec<String> = top.iter().map(|v| json_string(&v.to_string())).collect();
+	format!("{{\"pc\":{},\"instr\":{},\"fp\":{},\"call_depth\":{},\"stack_depth\":{},\
+		 \"stack_top\":[{}]}}",
+		self.pc, json_string(&instr), self.fp, self.call_depth, self.stk.len(), top.join(","))
+    }
+}
+
+/// Formats of the execution trace.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    /// The state before each instruction, as shown by the debug trace,
+    /// followed by a blank line.
+    Text,
+    /// One JSON object per instruction and line, with fields pc,
+    /// instr, fp, call_depth, stack_depth, and stack_top.
+    Json
+}
+
+/// How a program stopped: by halting with a value on top of the stack,
+/// or by exiting with a status code.
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Halted(Val),
+    Exited(i32)
+}
+
+impl Outcome {
+    /// The program result, with exit codes reported as i32 values.
+    pub fn value(self) -> Val {
+	match self {
+	    Outcome::Halted(v) => v,
+	    Outcome::Exited(code) => Vi32(code)
+	}
+    }
+}
+
+/// Heap occupancy, counting garbage not yet collected as in use.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct HeapStats {
+    /// Heap slots not on the free list, headers included.
+    pub used_slots: usize,
+    /// Number of arrays.
+    pub object_count: usize,
+    /// Number of elements of the largest array.
+    pub largest_object: usize,
+    /// Heap slots on the free list.
+    pub free_slots: usize
+}
+
+/// The machine state when a program stopped.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FinalState {
+    /// The main thread's stack, bottom first.
+    pub stack: Vec<Val>,
+    /// The heap, including garbage not yet collected.
+    pub heap: Vec<Val>,
+    /// Occupancy of the heap.
+    pub heap_stats: HeapStats,
+    /// Location after the last instruction executed.
+    pub pc: u32,
+    /// The frame pointer.
+    pub fp: u32,
+    /// The status code, if the program stopped via Exit.
+    pub exit_code: Option<i32>
+}
+
+impl FinalState {
+    /// The program result as run reports it: the exit code as an i32
+    /// if the program exited, and otherwise the value on top of the
+    /// stack.
+    pub fn result(&self) -> Result<Val, VmError> {
+	match self.exit_code {
+	    Some(code) => Ok(Vi32(code)),
+	    None => self.stack.last().copied().ok_or(VmError::StackUnderflow)
+	}
+    }
+    /// A copy of the elements of the array at addr.
+    pub fn read_array(&self, addr: Address) -> Result<Vec<Val>, VmError> {
+	array_at(&self.heap, addr).map(<[Val]>::to_vec)
+    }
+}
+
+/// The result of executing a single instruction.
+#[derive(Debug, PartialEq)]
+pub enum StepOutcome {
+    /// The machine is ready to execute the next instruction.
+    Continue,
+    /// The machine halted with the given value on top of the stack.
+    Halted(Val),
+    /// The program exited with the given status code.
+    Exited(i32)
+}
+
+/// A write to a watched heap location.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WatchHit {
+    /// The heap location written.
+    pub addr: Address,
+    /// Its value before the write.
+    pub old: Val,
+    /// Its value after the write.
+    pub new: Val,
+    /// Location of the writing instruction.
+    pub pc: u32
+}
+
+/// Why run_until_break stopped.
+#[derive(Debug, PartialEq)]
+pub enum BreakOutcome {
+    Halted(Val),
+    Exited(i32),
+    /// A watched heap location was written. Execution resumes after
+    /// the writing instruction.
+    WatchHit(WatchHit)
+}
+
+/// A value a run took from outside the machine.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// The line a Read got from the input source, empty at the end of
+    /// input.
+    Read(Result<String, String>),
+    /// What host function index returned to a Syscall.
+    Syscall { index: u32, result: Result<Val, String> },
+    /// The number a Rand drew.
+    Rand(i32)
+}
+
+/// Where an event comes from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Source {
+    Read,
+    Syscall(u32),
+    Rand
+}
+
+impl Event {
+    fn source(&self) -> Source {
+	match self {
+	    Event::Read(_) => Source::Read,
+	    Event::Syscall { index, .. } => Source::Syscall(*index),
+	    Event::Rand(_) => Source::Rand
+	}
+    }
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	match self {
+	    Source::Read => write!(f, "read"),
+	    Source::Syscall(index) => write!(f, "syscall {}", index),
+	    Source::Rand => write!(f, "rand")
+	}
     }
 }
 
+/// The values a run took from outside the machine, in order, from
+/// which Vm::run_replay can repeat the run exactly.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Recording {
+    /// Each read, random number and syscall result, oldest first.
+    pub events: Vec<Event>
+}
+
+/// Whether a run is being recorded or replayed.
+enum Tape {
+    Off,
+    Record(Vec<Event>),
+    /// The recorded events and the index of the next one to replay.
+    Replay(Vec<Event>, usize),
+    /// The events since the oldest snapshot kept for stepping back,
+    /// and the index of the next one. After a step back, the events
+    /// from there on are replayed before new ones are recorded.
+    History(Vec<Event>, usize)
+}
+
 /// Debug enum (whether to print debug information during execution or not).
 #[derive(Clone, Copy)]
 pub enum Debug {
@@ -35,201 +1087,5046 @@ pub enum Debug {
     NODEBUG
 }
 
+/// An I/O backend for the VM.
+pub trait VmIo: Send {
+    /// Write s to the output.
+    fn write_str(&mut self, s: &str) -> Result<(), String>;
+    /// Read a line of input, including its terminator. Returns an
+    /// empty string at end of input.
+    fn read_line(&mut self) -> Result<String, String>;
+}
+
+/// Backend reading from stdin and writing to stdout. Stdin is locked
+/// per read rather than for the whole run, so that several VMs can
+/// coexist.
+#[cfg(feature = "std")]
+pub struct StdIo;
+
+#[cfg(feature = "std")]
+impl VmIo for StdIo {
+    fn write_str(&mut self, s: &str) -> Result<(), String> {
+	io::stdout().write_all(s.as_bytes()).map_err(|e| e.to_string())
+    }
+    fn read_line(&mut self) -> Result<String, String> {
+	let mut line = String::new();
+	io::stdin().read_line(&mut line).map_err(|e| e.to_string())?;
+	Ok(line)
+    }
+}
+
+/// In-memory backend reading from a fixed string and capturing
+/// output. Clones share their buffers, so a clone kept outside the VM
+/// sees everything the VM writes.
+#[cfg(feature = "std")]
+#[derive(Clone, Default)]
+pub struct MemIo {
+    input: Arc<Mutex<io::Cursor<String>>>,
+    output: Arc<Mutex<String>>
+}
+
+#[cfg(feature = "std")]
+impl MemIo {
+    /// A backend whose input is the given text.
+    pub fn new(input: &str) -> MemIo {
+	MemIo {
+	    input: Arc::new(Mutex::new(io::Cursor::new(input.into()))),
+	    output: Arc::default()
+	}
+    }
+    /// Everything written so far.
+    pub fn output(&self) -> String {
+	self.output.lock().unwrap().clone()
+    }
+}
+
+#[cfg(feature = "std")]
+impl VmIo for MemIo {
+    fn write_str(&mut self, s: &str) -> Result<(), String> {
+	self.output.lock().unwrap().push_str(s);
+	Ok(())
+    }
+    fn read_line(&mut self) -> Result<String, String> {
+	let mut line = String::new();
+	self.input.lock().unwrap().read_line(&mut line).map_err(|e| e.to_string())?;
+	Ok(line)
+    }
+}
+
+/// Backend reading from stdin and collecting output, so that it can
+/// be written to stdout in one piece once the program stops.
+#[cfg(feature = "std")]
+struct HeldStdIo(Arc<Mutex<String>>);
+
+#[cfg(feature = "std")]
+impl VmIo for HeldStdIo {
+    fn write_str(&mut self, s: &str) -> Result<(), String> {
+	self.0.lock().unwrap().push_str(s);
+	Ok(())
+    }
+    fn read_line(&mut self) -> Result<String, String> {
+	StdIo.read_line()
+    }
+}
+
+/// Backend for targets without standard I/O. There is no input, and
+/// output fails, so programs that print need a backend of their own,
+/// passed to run_with_io or Vm::set_io.
+#[cfg(not(feature = "std"))]
+struct NoIo;
+
+#[cfg(not(feature = "std"))]
+impl VmIo for NoIo {
+    fn write_str(&mut self, _: &str) -> Result<(), String> {
+	Err("no output device".into())
+    }
+    fn read_line(&mut self) -> Result<String, String> {
+	Ok(String::new())
+    }
+}
+
+/// Backend that ignores output and has no input, for re-executing
+/// instructions whose output has already been written.
+struct Discard;
+
+impl VmIo for Discard {
+    fn write_str(&mut self, _: &str) -> Result<(), String> {
+	Ok(())
+    }
+    fn read_line(&mut self) -> Result<String, String> {
+	Ok(String::new())
+    }
+}
+
 /// State methods.
 impl State {
-    /// Create initial state for given program.
-    fn init(prog: Vec<Instr>) -> State {
+    /// Create initial state for given program, I/O backend, and
+    /// configuration. Fails if the program does not decode.
+    fn init<P: Into<Arc<[Instr]>>>(prog: P, io: Box<dyn VmIo>, cfg: VmConfig)
+				   -> Result<State, VmError> {
+	Ok(State::with_program(SharedProgram::new(prog)?, io, cfg))
+    }
+    /// Like init, running a program that has already been decoded.
+    fn with_program(prog: SharedProgram, io: Box<dyn VmIo>, cfg: VmConfig) -> State {
+	let seed = match cfg.seed {
+	    Some(seed) => seed,
+	    // Rand fails without a seed, so the state need not vary.
+	    None if cfg.deterministic => 0,
+	    None => clock_seed()
+	};
 	State {
 	    pc: 0, 
 	    fp: 0,
-	    stk: Vec::with_capacity(STK_SIZE),
-	    heap: Vec::with_capacity(HEAP_SIZE),
-	    prog: prog
+	    stk: Vec::with_capacity(cfg.stack_size),
+	    heap: Vec::with_capacity(INIT_HEAP.min(cfg.max_heap)),
+	    free: Vec::new(),
+	    prog: prog.0,
+	    io,
+	    trace: false,
+	    trace_format: TraceFormat::Text,
+	    // Xorshift gets stuck at zero.
+	    rng: if seed == 0 { 0x9E37_79B9_7F4A_7C15 } else { seed },
+	    globals: Vec::new(),
+	    threads: vec![Thread::new(0)],
+	    frames: Vec::new(),
+	    shadow: Vec::new(),
+	    call_depth: 0,
+	    tid: 0,
+	    cfg,
+	    fuel: None,
+	    #[cfg(feature = "std")]
+	    deadline: None,
+	    gas: None,
+	    gas_used: 0,
+	    stats: None,
+	    profile: None,
+	    watches: Vec::new(),
+	    watch_hits: Vec::new(),
+	    touched: None,
+	    host_fns: BTreeMap::new(),
+	    tape: Tape::Off
 	}
     }
     /// Push a Val to the stack, checking for overflow.
-    fn push(&mut self, v: Val) -> Result<(), String> {
-	if self.stk.len() < STK_SIZE {
+    fn push(&mut self, v: Val) -> Result<(), VmError> {
+	if self.stk.len() < self.cfg.stack_size {
+	    self.check_memory(1)?;
     	    Ok(self.stk.push(v))
 	} else {
-	    Err("out of stack space".into())
+	    Err(VmError::StackOverflow)
 	}
     }
     /// Pop a Val from the stack, checking for underflow.
-    fn pop(&mut self) -> Result<Val, String> {
-    	self.stk.pop().ok_or("attempt to pop empty stack".into())
+    fn pop(&mut self) -> Result<Val, VmError> {
+    	self.stk.pop().ok_or(VmError::StackUnderflow)
     }
-}
-
-/// Evaluate a unary operation on a value.
-fn unop(u: Unop, v: Val) -> Result<Val, String> {
-    match u {
-	Neg => {
-	    let b = v.to_bool().ok_or("expected bool")?;
-	    Ok(Vbool(!b))
-	}
+    /// Get the value n slots below the top of the stack, leaving it
+    /// in place.
+    fn peek(&self, n: usize) -> Result<Val, VmError> {
+	self.stk.iter().rev().nth(n).copied()
+	    .ok_or(VmError::StackUnderflow)
     }
-}
-
-/// Evaluate a binary operation on a value.
-fn binop(b: Binop, v1: Val, v2: Val) -> Result<Val, String> {
-    let i1 = v1.to_i32().ok_or("expected i32")?;
-    let i2 = v2.to_i32().ok_or("expected i32")?;
-    Ok(match b {
-	Add => Vi32(i1 + i2),
-	Mul => Vi32(i1 * i2),
-	Sub => Vi32(i1 - i2),
-	Div => Vi32(i1 / i2),
-	Lt => Vbool(i1 <= i2),
-	Eq => Vbool(i1 == i2)
-    })
-}
-
-/// Execute from initial state s.
-fn exec(d: Debug, s: &mut State) -> Result<(), String> {
-    loop {
-	if let Debug::DEBUG = d {
-	    println!("{}\n", s)
+    /// Jump to a location, checking that it is within the program.
+    /// Must be called after pc has moved past the jump instruction.
+    fn jump(&mut self, target: u32) -> Result<(), VmError> {
+	if (target as usize) < self.prog.len() {
+	    self.pc = target;
+	    Ok(())
+	} else {
+	    Err(VmError::BadJumpTarget { target, pc: self.pc - 1 })
 	}
-	if s.pc as usize >= s.prog.len() {
-	    return Err("pc out of bounds".into())
+    }
+    /// Allocate an array of size elements on the heap, initialized to
+    /// Vundef, and return its address. The first free block large
+    /// enough is reused if there is one, otherwise the heap grows. If
+    /// neither succeeds, the garbage collector runs and the allocation
+    /// is retried. In stress mode the collector runs first every time.
+    /// Since collection may happen here, callers must keep any
+    /// addresses they still need on the stack until alloc returns.
+    fn alloc(&mut self, size: usize) -> Result<Address, VmError> {
+	// The header takes a slot too.
+	let slots = size.checked_add(1).ok_or(VmError::OutOfHeap)?;
+	if self.cfg.gc_stress {
+	    self.collect()
 	}
-	let instr = &s.prog[s.pc as usize];
-	s.pc += 1;
-	match instr {
-	    Push(v) => {
-		let v = *v; // Satisfy borrow checker
-		s.push(v)?
-	    }
-	    Pop => { s.pop()?; }
-	    Peek(i) => {
-		let i = *i as usize; // Satisfy borrow checker
-		s.push(s.stk[i])?
-	    }
-	    Unary(u) => {
-		let u = *u; // Satisfy borrow checker
-		let v = s.pop()?;
-		let i = unop(u, v)?;
-		s.stk.push(i)
-	    }
-	    Binary(b) => {
-		let b = *b; // Satisfy borrow checker
-	    	let (v1, v2) = (s.pop()?, s.pop()?);
-	    	let i = binop(b, v1, v2)?;
-	    	s.stk.push(i)
-	    }
-	    Swap => {
-                let v2 = s.pop()?;
-                let v1 = s.pop()?;
-		s.stk.push(v2);
-		s.stk.push(v1);
-	    }
-	    Alloc => {
-                let vinit = s.pop()?;
-                let vsize = s.pop()?;
-		let size = vsize.to_i32().ok_or("expected i32")? as usize;
-		if s.heap.len() + size + 1 < HEAP_SIZE {
-		    let loc = s.heap.len();
-		    s.heap.push(Vsize(size));
-		    s.heap.append(&mut vec![vinit; size]);
-		    s.stk.push(Vaddr(loc))
-		} else {
-		    return Err("out of heap space".into())
-		}
-	    }
-	    Set => {
-		let (v, vix, vbase) = (s.pop()?, s.pop()?, s.pop()?);
-		let ix = vix.to_i32().ok_or("expected i32")? as usize;
-		let base = vbase.to_address().ok_or("expected address")?;
-		if base + ix < HEAP_SIZE {
-		    if let Vsize(size) = s.heap[base] {
-			if ix < size {
-			    s.heap[base+ix+1] = v
-			} else {
-			    return Err("index past end of array".into())
-			}
-		    } else {
-			return Err("expected size at array location".into())
+	let loc = match self.reserve(slots) {
+	    Some(loc) => loc,
+	    None => {
+		self.collect();
+		match self.reserve(slots) {
+		    Some(loc) => loc,
+		    None => {
+			// Report the memory limit if that is what got in
+			// the way.
+			self.check_memory(slots)?;
+			return Err(VmError::OutOfHeap)
 		    }
-		} else {
-		    return Err("indexing past end of heap".into())
-		}
-	    }
-	    Get => {
-                let vix = s.pop()?;
-                let vbase = s.pop()?;
-		let ix = vix.to_i32().ok_or("expected i32")? as usize;
-		let base = vbase.to_address().ok_or("expected address")?;
-		if base + ix < HEAP_SIZE {
-		    if let Vsize(size) = s.heap[base] {
-			if ix < size {
-			    s.push(s.heap[base+ix+1])?;
-			} else {
-			    return Err("index past end of array".into())
-			}
-		    } else {
-			return Err("expected size at array location".into())
-		    }
-		} else {
-		    return Err("indexing past end of heap".into())
 		}
 	    }
-	    Var(i) => {
-		let ix = (s.fp + *i) as usize;
-		if ix < s.stk.len() {
-		    s.push(s.stk[ix])?;
-		} else {
-		    return Err("variable access past end of stack".into())
-		}
+	};
+	self.heap[loc] = Vsize(size);
+	self.touched = Some(loc);
+	Ok(loc)
+    }
+    /// Find room for slots heap slots, header included, without
+    /// collecting.
+    fn reserve(&mut self, slots: usize) -> Option<Address> {
+	if let Some(i) = self.free.iter().position(|&(_, n)| n >= slots) {
+	    let (loc, n) = self.free[i];
+	    if n > slots {
+		self.free[i] = (loc + slots, n - slots)
+	    } else {
+		self.free.remove(i);
 	    }
-	    Store(i) => {
-		let ix = (s.fp + *i) as usize;
-		let v = s.pop()?;
-		if ix < s.stk.len() {
-		    s.stk[ix] = v;
-		} else {
-		    return Err("store past end of stack".into())
-		}
+	    Some(loc)
+	} else if self.heap.len().checked_add(slots).is_some_and(|n| n <= self.cfg.max_heap)
+	    && self.check_memory(slots).is_ok() {
+	    let loc = self.heap.len();
+	    let needed = loc + slots;
+	    if needed > self.heap.capacity() {
+		// Grow geometrically, but never past the maximum.
+		let cap = needed.max(2 * self.heap.capacity()).min(self.cfg.max_heap);
+		self.heap.reserve_exact(cap - loc)
 	    }
-	    SetFrame(i) => {
-		let i = *i; // Satisfy borrow checker
-		s.push(Vloc(s.fp))?;
-		s.fp = s.stk.len() as u32 - i - 1
+	    self.heap.resize(needed, Vundef);
+	    Some(loc)
+	} else {
+	    None
+	}
+    }
+    /// Number of bytes taken up by the heap and the running thread's
+    /// stack.
+    fn memory_usage(&self) -> usize {
+	(self.heap.len() + self.stk.len()) * mem::size_of::<Val>()
+    }
+    /// Check that slots more heap or stack slots fit in the memory
+    /// limit.
+    fn check_memory(&self, slots: usize) -> Result<(), VmError> {
+	if let Some(limit) = self.cfg.max_memory_bytes {
+	    let used = self.memory_usage();
+	    let requested = slots.saturating_mul(mem::size_of::<Val>());
+	    if used.saturating_add(requested) > limit {
+		return Err(VmError::MemoryLimit { used, requested, limit })
 	    }
-	    Call => {
-		if let Vloc(target) = s.pop()? {
-		    s.stk.push(Vloc(s.pc));
-		    s.pc = target
-		} else {
-		    return Err("expected loc for call target".into())
+	}
+	Ok(())
+    }
+    /// Run the configured garbage collector.
+    fn collect(&mut self) {
+	match self.cfg.gc {
+	    GcKind::MarkSweep => self.mark_sweep(),
+	    GcKind::Copying => self.copy_live()
+	}
+    }
+    /// Mark-and-sweep garbage collection. Roots are the addresses on
+    /// the stacks of all threads and in the globals; marking follows
+    /// addresses stored in heap arrays. Unreachable arrays are cleared
+    /// and, together with the existing free blocks, coalesced into a
+    /// new free list. Free space at the end of the heap is given back.
+    fn mark_sweep(&mut self) {
+	let mut marked = vec![false; self.heap.len()];
+	let mut work: Vec<Address> = self.stk.iter()
+	    .chain(self.threads.iter().flat_map(|t| t.stk.iter()))
+	    .chain(self.globals.iter())
+	    .filter_map(Val::to_address)
+	    .collect();
+	while let Some(base) = work.pop() {
+	    if let Some(Vsize(size)) = self.heap.get(base) {
+		if !marked[base] && base + size < self.heap.len() {
+		    marked[base] = true;
+		    work.extend(self.heap[base+1..base+size+1].iter()
+				.filter_map(Val::to_address))
 		}
 	    }
-	    Ret => {
-		if let (vret, Vloc(pc), Vloc(fp)) = (s.pop()?, s.pop()?, s.pop()?) {
-		    s.stk.truncate(s.fp as usize);
-		    s.pc = pc;
-		    s.fp = fp;
-		    s.stk.push(vret)
-		} else {
-		    return Err("expected location for pc and fp in return".into())
+	}
+	let mut old_free = mem::take(&mut self.free);
+	old_free.sort();
+	let mut old_free = old_free.into_iter().peekable();
+	let mut free: Vec<(Address, usize)> = Vec::new();
+	let mut p = 0;
+	while p < self.heap.len() {
+	    let n = match old_free.peek() {
+		Some(&(loc, n)) if loc == p => {
+		    old_free.next();
+		    n
 		}
-	    }
-	    Branch => {
-                let vtarget = s.pop()?;
-                let vb = s.pop()?;
-		let target = vtarget.to_loc().ok_or("expected location")?;
-		if vb.to_bool().ok_or("expected bool")? {
-		    s.pc = target
+		_ => match self.heap[p] {
+		    Vsize(size) if marked[p] => {
+			p += size + 1;
+			continue
+		    }
+		    Vsize(size) => size + 1,
+		    _ => 1
 		}
+	    };
+	    for slot in &mut self.heap[p..p+n] {
+		*slot = Vundef
+	    }
+	    match free.last_mut() {
+		Some((loc, m)) if *loc + *m == p => *m += n,
+		_ => free.push((p, n))
+	    }
+	    p += n
+	}
+	if let Some(&(loc, n)) = free.last() {
+	    if loc + n == self.heap.len() {
+		free.pop();
+		self.heap.truncate(loc)
 	    }
-	    Halt => return Ok(())
 	}
+	self.free = free
+    }
+    /// Copying garbage collection. Arrays reachable from the roots
+    /// are copied, breadth first, into a to-space that replaces the
+    /// heap. Every address on the stacks, in the globals and inside
+    /// the copied arrays is rewritten to the forwarded location, and
+    /// the free list is emptied so allocation bumps from the end.
+    fn copy_live(&mut self) {
+	let from = mem::take(&mut self.heap);
+	let mut to = Vec::new();
+	let mut fwd = vec![None; from.len()];
+	for v in self.stk.iter_mut()
+	    .chain(self.threads.iter_mut().flat_map(|t| t.stk.iter_mut()))
+	    .chain(self.globals.iter_mut()) {
+	    forward(v, &from, &mut to, &mut fwd)
+	}
+	let mut scan = 0;
+	while scan < to.len() {
+	    let mut v = to[scan];
+	    forward(&mut v, &from, &mut to, &mut fwd);
+	    to[scan] = v;
+	    scan += 1
+	}
+	self.heap = to;
+	self.free.clear()
+    }
+    /// Return the array at base to the free list.
+    fn free(&mut self, base: Address) -> Result<(), VmError> {
+	if self.free.iter().any(|&(loc, n)| loc <= base && base < loc + n) {
+	    return Err(VmError::DoubleFree(base))
+	}
+	let size = self.array(base)?.len();
+	for slot in &mut self.heap[base..base+size+1] {
+	    *slot = Vundef
+	}
+	self.free.push((base, size + 1));
+	Ok(())
+    }
+    /// Store v at heap location loc, recording the write if loc is
+    /// watched.
+    fn heap_write(&mut self, loc: usize, v: Val) {
+	if !self.watches.is_empty() && self.watches.contains(&loc) {
+	    let old = self.heap[loc];
+	    self.watch_hits.push(WatchHit { addr: loc, old, new: v, pc: self.pc - 1 })
+	}
+	self.touched = Some(loc);
+	self.heap[loc] = v
+    }
+    /// Get the stack index of local variable i, checking that it is
+    /// on the stack. The addition is checked, since fp + i can overflow
+    /// where usize is 32 bits.
+    fn local(&self, i: usize) -> Result<usize, VmError> {
+	match (self.fp as usize).checked_add(i) {
+	    Some(ix) if ix < self.stk.len() => Ok(ix),
+	    _ => Err(VmError::BadVarIndex { fp: self.fp, offset: i as u32 })
+	}
+    }
+    /// Get the heap location of element ix of the array at base,
+    /// checking that base holds an array header and that ix is in
+    /// bounds.
+    fn elem_loc(&self, base: Address, ix: usize) -> Result<usize, VmError> {
+	let size = self.array(base)?.len();
+	if ix < size {
+	    Ok(base+ix+1)
+	} else {
+	    Err(VmError::IndexOutOfBounds { index: ix, len: size })
+	}
+    }
+    /// Get the heap location of the len-element window starting at
+    /// element off of the array at base, checking that it is in bounds.
+    fn window_loc(&self, base: Address, off: usize, len: usize) -> Result<usize, VmError> {
+	let size = self.array(base)?.len();
+	match off.checked_add(len) {
+	    Some(end) if end <= size => Ok(base+off+1),
+	    _ => Err(VmError::WindowOutOfBounds { off, len, size })
+	}
+    }
+    /// Get the elements of the heap-allocated array at base.
+    fn array(&self, base: Address) -> Result<&[Val], VmError> {
+	array_at(&self.heap, base)
+    }
+    /// Walk the heap, array header by array header, skipping free
+    /// blocks.
+    fn heap_stats(&self) -> HeapStats {
+	let mut free = self.free.clone();
+	free.sort();
+	let mut free = free.into_iter().peekable();
+	let mut stats = HeapStats::default();
+	let mut p = 0;
+	while p < self.heap.len() {
+	    let n = match (free.peek(), self.heap[p]) {
+		(Some(&(loc, n)), _) if loc == p => {
+		    free.next();
+		    stats.free_slots += n;
+		    p += n;
+		    continue
+		}
+		(_, Vsize(size)) => {
+		    stats.object_count += 1;
+		    stats.largest_object = stats.largest_object.max(size);
+		    size + 1
+		}
+		_ => 1
+	    };
+	    stats.used_slots += n;
+	    p += n
+	}
+	stats
+    }
+    /// Save the running thread's context and resume thread t.
+    fn switch_to(&mut self, t: usize) {
+	let cur = &mut self.threads[self.tid];
+	cur.pc = self.pc;
+	cur.fp = self.fp;
+	mem::swap(&mut cur.stk, &mut self.stk);
+	mem::swap(&mut cur.frames, &mut self.frames);
+	mem::swap(&mut cur.shadow, &mut self.shadow);
+	cur.call_depth = self.call_depth;
+	let next = &mut self.threads[t];
+	self.pc = next.pc;
+	self.fp = next.fp;
+	mem::swap(&mut next.stk, &mut self.stk);
+	mem::swap(&mut next.frames, &mut self.frames);
+	mem::swap(&mut next.shadow, &mut self.shadow);
+	self.call_depth = next.call_depth;
+	self.tid = t
+    }
+    /// Switch to the next runnable thread in round-robin order after
+    /// the running one, which is resumed only if no other thread can
+    /// run. A thread whose join target has halted becomes runnable
+    /// again with the target's result pushed on its stack.
+    fn schedule(&mut self) -> Result<(), VmError> {
+	let n = self.threads.len();
+	for t in (1..=n).map(|k| (self.tid + k) % n) {
+	    match self.threads[t].status {
+		Status::Runnable => {
+		    self.switch_to(t);
+		    return Ok(())
+		}
+		Status::Joining(j) => {
+		    if let Status::Done(v) = self.threads[j].status {
+			self.threads[t].status = Status::Runnable;
+			self.switch_to(t);
+			return self.push(v)
+		    }
+		}
+		Status::Done(_) => ()
+	    }
+	}
+	Err(VmError::Deadlock)
+    }
+    /// Get a mutable reference to global i, checking bounds.
+    fn global(&mut self, i: u32) -> Result<&mut Val, VmError> {
+	let len = self.globals.len();
+	self.globals.get_mut(i as usize)
+	    .ok_or(VmError::GlobalOutOfBounds { index: i, len })
+    }
+    /// Return addresses of the active frames, innermost first.
+    fn backtrace(&self) -> Vec<u32> {
+	self.frames.iter().rev()
+	    .filter_map(|&i| self.stk.get(i + 1)?.to_loc())
+	    .collect()
+    }
+    /// Check, for frame checking, that the instruction just executed,
+    /// op, finds the innermost frame called if called is set and
+    /// not yet called otherwise, with exactly above values from its
+    /// saved fp up.
+    fn check_frame(&self, op: &str, called: bool, above: usize) -> Result<(), VmError> {
+	let err = |msg| Err(VmError::FrameCheck { pc: self.pc - 1, msg });
+	let slot = match self.shadow.last() {
+	    Some(&(slot, c)) if c == called => slot,
+	    Some(_) if called => return err(format!("{} executed before calling its frame", op)),
+	    _ if called => return err(format!("{} executed outside any call", op)),
+	    _ => return err(format!("{} executed without a setframe", op))
+	};
+	let (len, want) = (self.stk.len(), slot + above);
+	let values = |n, adj| format!("{} {}value{}", n, adj, if n == 1 { "" } else { "s" });
+	if len > want {
+	    err(format!("{} executed with {} on the frame", op, values(len - want, "extra ")))
+	} else if len < want {
+	    err(format!("{} executed with {} missing from the frame", op, values(want - len, "")))
+	} else {
+	    Ok(())
+	}
+    }
+    /// Check, for frame checking, that a call instruction op finds
+    /// the frame set up by the last SetFrame with nothing above the
+    /// saved fp, and record that the frame has been called.
+    fn check_call(&mut self, op: &str) -> Result<(), VmError> {
+	self.check_frame(op, false, 1)?;
+	self.shadow.last_mut().unwrap().1 = true;
+	Ok(())
+    }
+    /// Count a call made by the instruction just executed, failing if
+    /// it would exceed the configured maximum call depth.
+    fn enter_call(&mut self) -> Result<(), VmError> {
+	let depth = self.call_depth + 1;
+	if matches!(self.cfg.max_call_depth, Some(max) if depth > max) {
+	    return Err(VmError::CallDepthExceeded { depth, pc: self.pc - 1 })
+	}
+	self.call_depth = depth;
+	Ok(())
+    }
+    /// Attach the return addresses of the active frames to err, if
+    /// it happened inside a function call.
+    fn with_backtrace(&self, err: VmError) -> VmError {
+	let frames = self.backtrace();
+	if frames.is_empty() {
+	    err
+	} else {
+	    VmError::Backtrace { error: Box::new(err), frames }
+	}
+    }
+    /// Draw a uniformly distributed i32 in [0, n).
+    fn rand(&mut self, n: i32) -> Result<i32, VmError> {
+	if n <= 0 {
+	    return Err(VmError::BadRandBound(n))
+	}
+	self.rng ^= self.rng << 13;
+	self.rng ^= self.rng >> 7;
+	self.rng ^= self.rng << 17;
+	Ok((self.rng % n as u64) as i32)
+    }
+    /// When replaying, get the next recorded event, failing unless
+    /// it came from src. None when not replaying. Recording the event
+    /// moves past it.
+    fn replay(&mut self, src: Source) -> Result<Option<Event>, VmError> {
+	let (events, next) = match &self.tape {
+	    Tape::Replay(events, next) | Tape::History(events, next) => (events, *next),
+	    _ => return Ok(None)
+	};
+	match events.get(next) {
+	    Some(e) if e.source() == src => Ok(Some(e.clone())),
+	    None if matches!(self.tape, Tape::History(..)) => Ok(None),
+	    e => {
+		let recorded = e.map_or("nothing more".into(), |e| e.source().to_string());
+		Err(VmError::ReplayDiverged {
+		    pc: self.pc - 1, msg: format!("{} where the recording has {}", src, recorded)
+		})
+	    }
+	}
+    }
+    /// Record e, or when replaying, move past it.
+    fn record(&mut self, e: Event) {
+	match &mut self.tape {
+	    Tape::Off => (),
+	    Tape::Record(events) => events.push(e),
+	    Tape::Replay(_, next) => *next += 1,
+	    Tape::History(events, next) => {
+		if *next == events.len() {
+		    events.push(e)
+		}
+		*next += 1
+	    }
+	}
+    }
+    /// Go back to the state saved in the snapshot bytes, taken when
+    /// the next event in the history was next, and re-execute the n
+    /// instructions executed after it. The re-execution writes no
+    /// output, is not limited, counted or profiled, and replays the
+    /// values the instructions took from outside the machine.
+    fn redo(&mut self, bytes: &[u8], next: usize, n: u64) -> Result<(), VmError> {
+	let saved = State::from_bytes(&mut bytes.iter().copied())
+	    .map_err(|err| VmError::Other(err.to_string()))?;
+	self.pc = saved.pc;
+	self.fp = saved.fp;
+	self.rng = saved.rng;
+	self.stk = saved.stk;
+	self.frames = saved.frames;
+	self.shadow = saved.shadow;
+	self.call_depth = saved.call_depth;
+	self.heap = saved.heap;
+	self.free = saved.free;
+	self.globals = saved.globals;
+	self.tid = saved.tid;
+	self.threads = saved.threads;
+	if let Tape::History(_, i) = &mut self.tape {
+	    *i = next
+	}
+	let io = mem::replace(&mut self.io, Box::new(Discard));
+	let (fuel, gas, stats, profile) =
+	    (self.fuel.take(), self.gas.take(), self.stats.take(), self.profile.take());
+	#[cfg(feature = "std")]
+	let deadline = self.deadline.take();
+	let result = (0..n).try_for_each(|_| step(self).map(drop));
+	self.io = io;
+	self.fuel = fuel;
+	self.gas = gas;
+	self.stats = stats;
+	self.profile = profile;
+	#[cfg(feature = "std")]
+	{
+	    self.deadline = deadline;
+	}
+	self.touched = None;
+	self.watch_hits.clear();
+	result
+    }
+    /// Read a line containing a single i32 from the input source, or
+    /// when replaying, from the recording.
+    fn read_i32(&mut self) -> Result<i32, VmError> {
+	let line = match self.replay(Source::Read)? {
+	    Some(Event::Read(line)) => line,
+	    _ => self.io.read_line()
+	};
+	self.record(Event::Read(line.clone()));
+	match line {
+	    Ok(line) if line.is_empty() => Err(VmError::Read("end of input".into())),
+	    Ok(line) => line.trim().parse()
+		.map_err(|_| VmError::Read(format!("malformed input: {}", line.trim()))),
+	    Err(err) => Err(VmError::Read(err))
+	}
+    }
+}
+
+/// Get the elements of the array at base in heap, checking that base
+/// holds an array header and that the array fits in the heap.
+fn array_at(heap: &[Val], base: Address) -> Result<&[Val], VmError> {
+    match heap.get(base) {
+	Some(Vsize(size)) if base.checked_add(*size).is_some_and(|end| end < heap.len()) =>
+	    Ok(&heap[base+1..base+size+1]),
+	Some(Vsize(_)) | None => Err(VmError::HeapOutOfBounds(base)),
+	Some(_) => Err(VmError::NotAnArray(base))
+    }
+}
+
+/// Magic number at the start of every snapshot.
+const SNAPSHOT_MAGIC: &[u8; 4] = b"GSNP";
+
+/// Largest stack or heap size a snapshot may configure, so that
+/// restoring corrupt bytes cannot reserve unbounded memory.
+const SNAPSHOT_MAX_SLOTS: usize = 1 << 24;
+
+/// Snapshot encoding of a value. Unlike program bytecode, snapshots
+/// may contain the internal Vsize and Vaddr constructors.
+fn val_bytes(v: &Val) -> Vec<u8> {
+    let (code, n) = match v {
+	Vsize(n) => (0x09, n),
+	Vaddr(a) => (0x0A, a),
+	v => return v.to_bytes()
+    };
+    let mut bs = vec![code];
+    bs.append(&mut (*n as u32).to_bytes());
+    bs
+}
+
+/// Decode a value written by val_bytes.
+fn val_from_bytes<T: Iterator<Item=u8>>(bytes: &mut T) -> Result<Val, ParseError> {
+    match bytes.next().ok_or(ParseError("not enough bytes".into()))? {
+	0x09 => Ok(Vsize(u32::from_bytes(bytes)? as usize)),
+	0x0A => Ok(Vaddr(u32::from_bytes(bytes)? as usize)),
+	b => Val::from_bytes(&mut core::iter::once(b).chain(bytes))
+    }
+}
+
+fn vals_bytes(vs: &[Val]) -> Vec<u8> {
+    let mut bs = (vs.len() as u32).to_bytes();
+    for v in vs {
+	bs.append(&mut val_bytes(v))
+    }
+    bs
+}
+
+fn vals_from_bytes<T: Iterator<Item=u8>>(bytes: &mut T) -> Result<Vec<Val>, ParseError> {
+    let n = u32::from_bytes(bytes)?;
+    (0..n).map(|_| val_from_bytes(bytes)).collect()
+}
+
+fn usizes_bytes(ns: &[usize]) -> Vec<u8> {
+    let mut bs = (ns.len() as u32).to_bytes();
+    for n in ns {
+	bs.append(&mut (*n as u32).to_bytes())
+    }
+    bs
+}
+
+fn usizes_from_bytes<T: Iterator<Item=u8>>(bytes: &mut T) -> Result<Vec<usize>, ParseError> {
+    let n = u32::from_bytes(bytes)?;
+    (0..n).map(|_| Ok(u32::from_bytes(bytes)? as usize)).collect()
+}
+
+/// Check that a restored stack fits in stack_size and that its frame
+/// pointer and frames lie within it. A program that pops its own
+/// frame can leave them above the top of the stack, but never above
+/// the stack size.
+fn check_stack(stk: &[Val], fp: u32, frames: &[usize], shadow: &[(usize, bool)],
+	       stack_size: usize) -> Result<(), ParseError> {
+    if stk.len() > stack_size {
+	return Err(ParseError(format!("stack larger than its maximum: {}", stk.len())))
+    }
+    if fp as usize > stack_size {
+	return Err(ParseError(format!("frame pointer out of range: {}", fp)))
+    }
+    match frames.iter().chain(shadow.iter().map(|(slot, _)| slot)).find(|&&i| i > stack_size) {
+	Some(i) => Err(ParseError(format!("frame out of range: {}", i))),
+	None => Ok(())
+    }
+}
+
+fn flag_from_bytes<T: Iterator<Item=u8>>(bytes: &mut T) -> Result<bool, ParseError> {
+    match bytes.next().ok_or(ParseError("not enough bytes".into()))? {
+	0 => Ok(false),
+	1 => Ok(true),
+	b => Err(ParseError(format!("invalid flag: {}", b)))
+    }
+}
+
+fn shadow_bytes(shadow: &[(usize, bool)]) -> Vec<u8> {
+    let mut bs = (shadow.len() as u32).to_bytes();
+    for &(slot, called) in shadow {
+	bs.append(&mut (slot as u32).to_bytes());
+	bs.push(called as u8)
+    }
+    bs
+}
+
+fn shadow_from_bytes<T: Iterator<Item=u8>>(bytes: &mut T)
+					   -> Result<Vec<(usize, bool)>, ParseError> {
+    let n = u32::from_bytes(bytes)?;
+    (0..n).map(|_| Ok((u32::from_bytes(bytes)? as usize, flag_from_bytes(bytes)?))).collect()
+}
+
+/// Snapshot encoding of the machine state: configuration, registers,
+/// stack, heap, globals, threads, and program. The input source and
+/// any fuel, gas, or statistics are not saved, and the gas costs are
+/// reset to the defaults.
+impl ToBytes for State {
+    fn to_bytes(&self) -> Vec<u8> {
+	let mut bs = SNAPSHOT_MAGIC.to_vec();
+	bs.append(&mut (self.cfg.stack_size as u32).to_bytes());
+	bs.append(&mut (self.cfg.max_heap as u32).to_bytes());
+	bs.push(match self.cfg.gc { GcKind::MarkSweep => 0, GcKind::Copying => 1 });
+	bs.push(self.cfg.gc_stress as u8);
+	match self.cfg.seed {
+	    Some(seed) => {
+		bs.push(1);
+		bs.append(&mut (seed as i64).to_bytes())
+	    }
+	    None => bs.push(0)
+	}
+	bs.push(self.cfg.deterministic as u8);
+	bs.push(self.cfg.verify as u8);
+	match self.cfg.max_memory_bytes {
+	    Some(limit) => {
+		bs.push(1);
+		bs.append(&mut (limit as i64).to_bytes())
+	    }
+	    None => bs.push(0)
+	}
+	bs.push(self.cfg.arithmetic as u8);
+	bs.push(self.cfg.implicit_halt as u8);
+	bs.append(&mut (self.cfg.trace_depth as u32).to_bytes());
+	bs.push(self.cfg.fuse as u8);
+	bs.push(self.cfg.check_frames as u8);
+	match self.cfg.max_call_depth {
+	    Some(limit) => {
+		bs.push(1);
+		bs.append(&mut (limit as i64).to_bytes())
+	    }
+	    None => bs.push(0)
+	}
+	bs.append(&mut self.pc.to_bytes());
+	bs.append(&mut self.fp.to_bytes());
+	bs.append(&mut (self.rng as i64).to_bytes());
+	bs.append(&mut vals_bytes(&self.stk));
+	bs.append(&mut usizes_bytes(&self.frames));
+	bs.append(&mut shadow_bytes(&self.shadow));
+	bs.append(&mut (self.call_depth as u32).to_bytes());
+	bs.append(&mut vals_bytes(&self.heap));
+	bs.append(&mut (self.free.len() as u32).to_bytes());
+	for &(loc, n) in &self.free {
+	    bs.append(&mut (loc as u32).to_bytes());
+	    bs.append(&mut (n as u32).to_bytes())
+	}
+	bs.append(&mut vals_bytes(&self.globals));
+	bs.append(&mut (self.tid as u32).to_bytes());
+	bs.append(&mut (self.threads.len() as u32).to_bytes());
+	for t in &self.threads {
+	    bs.append(&mut t.pc.to_bytes());
+	    bs.append(&mut t.fp.to_bytes());
+	    bs.append(&mut vals_bytes(&t.stk));
+	    bs.append(&mut usizes_bytes(&t.frames));
+	    bs.append(&mut shadow_bytes(&t.shadow));
+	    bs.append(&mut (t.call_depth as u32).to_bytes());
+	    match t.status {
+		Status::Runnable => bs.push(0),
+		Status::Joining(j) => {
+		    bs.push(1);
+		    bs.append(&mut (j as u32).to_bytes())
+		}
+		Status::Done(v) => {
+		    bs.push(2);
+		    bs.append(&mut val_bytes(&v))
+		}
+	    }
+	}
+	bs.append(&mut (self.prog.len() as u32).to_bytes());
+	for instr in self.prog.iter() {
+	    bs.append(&mut instr.to_bytes())
+	}
+	bs
+    }
+}
+
+impl FromBytes for State {
+    type Err = ParseError;
+    fn from_bytes<T: Iterator<Item=u8>>(bytes: &mut T) -> Result<State, ParseError> {
+	let magic: Vec<u8> = bytes.take(SNAPSHOT_MAGIC.len()).collect();
+	if magic != SNAPSHOT_MAGIC {
+	    return Err(ParseError("not a snapshot".into()))
+	}
+	let stack_size = u32::from_bytes(bytes)? as usize;
+	let max_heap = u32::from_bytes(bytes)? as usize;
+	if stack_size > SNAPSHOT_MAX_SLOTS {
+	    return Err(ParseError(format!("stack size too large: {}", stack_size)))
+	}
+	if max_heap > SNAPSHOT_MAX_SLOTS {
+	    return Err(ParseError(format!("heap size too large: {}", max_heap)))
+	}
+	let gc = match bytes.next().ok_or(ParseError("not enough bytes".into()))? {
+	    0 => GcKind::MarkSweep,
+	    1 => GcKind::Copying,
+	    b => return Err(ParseError(format!("unknown gc kind: {}", b)))
+	};
+	let gc_stress = flag_from_bytes(bytes)?;
+	let seed = if flag_from_bytes(bytes)? {
+	    Some(i64::from_bytes(bytes)? as u64)
+	} else {
+	    None
+	};
+	let deterministic = flag_from_bytes(bytes)?;
+	let verify = flag_from_bytes(bytes)?;
+	let max_memory_bytes = if flag_from_bytes(bytes)? {
+	    Some(i64::from_bytes(bytes)? as usize)
+	} else {
+	    None
+	};
+	let arithmetic = match bytes.next().ok_or(ParseError("not enough bytes".into()))? {
+	    0 => Arithmetic::Wrapping,
+	    1 => Arithmetic::Checked,
+	    b => return Err(ParseError(format!("unknown arithmetic: {}", b)))
+	};
+	let implicit_halt = flag_from_bytes(bytes)?;
+	let trace_depth = u32::from_bytes(bytes)? as usize;
+	let fuse = flag_from_bytes(bytes)?;
+	let check_frames = flag_from_bytes(bytes)?;
+	let max_call_depth = if flag_from_bytes(bytes)? {
+	    Some(i64::from_bytes(bytes)? as usize)
+	} else {
+	    None
+	};
+	let cfg = VmConfig { stack_size, max_heap, gc, gc_stress, seed, deterministic, verify,
+			     gas_costs: GasCosts::default(), max_memory_bytes, arithmetic,
+			     implicit_halt, trace_depth, fuse, check_frames, max_call_depth };
+	let mut s = State::init(Vec::new(), std_io(), cfg)
+	    .map_err(|err| ParseError(err.to_string()))?;
+	s.pc = u32::from_bytes(bytes)?;
+	s.fp = u32::from_bytes(bytes)?;
+	s.rng = i64::from_bytes(bytes)? as u64;
+	s.stk = vals_from_bytes(bytes)?;
+	s.frames = usizes_from_bytes(bytes)?;
+	s.shadow = shadow_from_bytes(bytes)?;
+	s.call_depth = u32::from_bytes(bytes)? as usize;
+	check_stack(&s.stk, s.fp, &s.frames, &s.shadow, stack_size)?;
+	s.heap = vals_from_bytes(bytes)?;
+	if s.heap.len() > max_heap {
+	    return Err(ParseError(format!("heap larger than its maximum: {}", s.heap.len())))
+	}
+	let n = u32::from_bytes(bytes)?;
+	for _ in 0..n {
+	    let loc = u32::from_bytes(bytes)? as usize;
+	    let len = u32::from_bytes(bytes)? as usize;
+	    match loc.checked_add(len) {
+		Some(end) if len > 0 && end <= s.heap.len() => s.free.push((loc, len)),
+		_ => return Err(ParseError(format!("free block out of range: {}", loc)))
+	    }
+	}
+	s.globals = vals_from_bytes(bytes)?;
+	s.tid = u32::from_bytes(bytes)? as usize;
+	let n = u32::from_bytes(bytes)?;
+	s.threads = Vec::new();
+	for _ in 0..n {
+	    let mut t = Thread::new(u32::from_bytes(bytes)?);
+	    t.fp = u32::from_bytes(bytes)?;
+	    t.stk = vals_from_bytes(bytes)?;
+	    t.frames = usizes_from_bytes(bytes)?;
+	    t.shadow = shadow_from_bytes(bytes)?;
+	    t.call_depth = u32::from_bytes(bytes)? as usize;
+	    t.status = match bytes.next().ok_or(ParseError("not enough bytes".into()))? {
+		0 => Status::Runnable,
+		1 => Status::Joining(u32::from_bytes(bytes)? as usize),
+		2 => Status::Done(val_from_bytes(bytes)?),
+		b => return Err(ParseError(format!("unknown thread status: {}", b)))
+	    };
+	    s.threads.push(t)
+	}
+	if s.tid >= s.threads.len() {
+	    return Err(ParseError(format!("unknown thread: {}", s.tid)))
+	}
+	for (i, t) in s.threads.iter().enumerate() {
+	    if let Status::Joining(j) = t.status {
+		if j >= s.threads.len() {
+		    return Err(ParseError(format!("unknown thread: {}", j)))
+		}
+	    }
+	    // The running thread's context is in the state itself.
+	    if i != s.tid {
+		check_stack(&t.stk, t.fp, &t.frames, &t.shadow, stack_size)?
+	    }
+	}
+	s.prog = Arc::new(DecodedProg::new(Vec::<Instr>::from_bytes(bytes)?.into())
+			  .map_err(|err| ParseError(err.to_string()))?);
+	Ok(s)
+    }
+}
+
+/// Magic number at the start of every recording.
+const RECORDING_MAGIC: &[u8; 4] = b"GREC";
+
+fn str_bytes(s: &str) -> Vec<u8> {
+    let mut bs = (s.len() as u32).to_bytes();
+    bs.extend_from_slice(s.as_bytes());
+    bs
+}
+
+fn str_from_bytes<T: Iterator<Item=u8>>(bytes: &mut T) -> Result<String, ParseError> {
+    let n = u32::from_bytes(bytes)? as usize;
+    let bs: Vec<u8> = bytes.take(n).collect();
+    if bs.len() < n {
+	return Err(ParseError("not enough bytes".into()))
+    }
+    String::from_utf8(bs).map_err(|_| ParseError("invalid UTF-8 in string".into()))
+}
+
+/// Encoding of the outcome of a read or host function call: a flag
+/// that is set for errors, then the value or error message.
+fn result_bytes<V>(r: &Result<V, String>, ok_bytes: fn(&V) -> Vec<u8>) -> Vec<u8> {
+    match r {
+	Ok(v) => [vec![0], ok_bytes(v)].concat(),
+	Err(msg) => [vec![1], str_bytes(msg)].concat()
+    }
+}
+
+fn result_from_bytes<T: Iterator<Item=u8>, V>(
+    bytes: &mut T, ok_from_bytes: fn(&mut T) -> Result<V, ParseError>)
+    -> Result<Result<V, String>, ParseError> {
+    Ok(if flag_from_bytes(bytes)? {
+	Err(str_from_bytes(bytes)?)
+    } else {
+	Ok(ok_from_bytes(bytes)?)
+    })
+}
+
+/// Recording encoding: the number of events, then each event as a
+/// tag byte followed by its contents.
+impl ToBytes for Recording {
+    fn to_bytes(&self) -> Vec<u8> {
+	let mut bs = RECORDING_MAGIC.to_vec();
+	bs.append(&mut (self.events.len() as u32).to_bytes());
+	for e in &self.events {
+	    match e {
+		Event::Read(line) => {
+		    bs.push(0);
+		    bs.append(&mut result_bytes(line, |l| str_bytes(l)))
+		}
+		Event::Syscall { index, result } => {
+		    bs.push(1);
+		    bs.append(&mut index.to_bytes());
+		    bs.append(&mut result_bytes(result, val_bytes))
+		}
+		Event::Rand(i) => {
+		    bs.push(2);
+		    bs.append(&mut i.to_bytes())
+		}
+	    }
+	}
+	bs
+    }
+}
+
+impl FromBytes for Recording {
+    type Err = ParseError;
+    fn from_bytes<T: Iterator<Item=u8>>(bytes: &mut T) -> Result<Recording, ParseError> {
+	let magic: Vec<u8> = bytes.take(RECORDING_MAGIC.len()).collect();
+	if magic != RECORDING_MAGIC {
+	    return Err(ParseError("not a recording".into()))
+	}
+	let n = u32::from_bytes(bytes)?;
+	let mut events = Vec::new();
+	for _ in 0..n {
+	    events.push(match bytes.next().ok_or(ParseError("not enough bytes".into()))? {
+		0 => Event::Read(result_from_bytes(bytes, str_from_bytes)?),
+		1 => {
+		    let index = u32::from_bytes(bytes)?;
+		    Event::Syscall { index, result: result_from_bytes(bytes, val_from_bytes)? }
+		}
+		2 => Event::Rand(i32::from_bytes(bytes)?),
+		b => return Err(ParseError(format!("unknown event: {}", b)))
+	    })
+	}
+	Ok(Recording { events })
+    }
+}
+
+/// Evaluate a unary operation on a value.
+fn unop(u: Unop, v: Val) -> Result<Val, VmError> {
+    match u {
+	Neg => {
+	    let b = expect(v, Val::to_bool, "bool")?;
+	    Ok(Vbool(!b))
+	}
+	NegI => {
+	    let i = expect(v, Val::to_i32, "i32")?;
+	    Ok(Vi32(i.checked_neg().ok_or(VmError::Overflow)?))
+	}
+	Ord => {
+	    let c = expect(v, Val::to_char, "char")?;
+	    Ok(Vi32(c as i32))
+	}
+	Chr => {
+	    let i = expect(v, Val::to_i32, "i32")?;
+	    let c = core::char::from_u32(i as u32)
+		.ok_or(VmError::InvalidChar(i))?;
+	    Ok(Vchar(c))
+	}
+	Abs => {
+	    let i = expect(v, Val::to_i32, "i32")?;
+	    Ok(Vi32(i.checked_abs().ok_or(VmError::Overflow)?))
+	}
+	BoolToI32 => {
+	    let b = expect(v, Val::to_bool, "bool")?;
+	    Ok(Vi32(b as i32))
+	}
+	I32ToBool => {
+	    let i = expect(v, Val::to_i32, "i32")?;
+	    Ok(Vbool(i != 0))
+	}
+    }
+}
+
+/// Type error for binary operator b applied to operands of the wrong type.
+fn binop_type_error(b: Binop) -> VmError {
+    let expected = match b {
+	And | Or => "bool",
+	Eq | Neq => "same-typed",
+	Add | Mul | Sub | Div | Lt | Gt | Le | Ge => "numeric",
+	_ => "i32"
+    };
+    VmError::OperandTypeError { op: b, expected }
+}
+
+/// Divide i1 by i2 with div, failing on division by zero or
+/// overflow (MIN / -1). The error's pc is left for the caller to fill
+/// in.
+fn checked_div<T: Default + PartialEq>(i1: T, i2: T, div: fn(T, T) -> Option<T>)
+				       -> Result<T, VmError> {
+    if i2 == T::default() {
+	return Err(VmError::DivByZero(0))
+    }
+    div(i1, i2).ok_or(VmError::Overflow)
+}
+
+/// Evaluate a binary operation on i32 operands.
+fn i32_binop(b: Binop, i1: i32, i2: i32) -> Result<Val, VmError> {
+    Ok(match b {
+	Add => Vi32(i1.wrapping_add(i2)),
+	Mul => Vi32(i1.wrapping_mul(i2)),
+	Sub => Vi32(i1.wrapping_sub(i2)),
+	Div => Vi32(checked_div(i1, i2, i32::checked_div)?),
+	Mod => {
+	    if i2 == 0 {
+		return Err(VmError::DivByZero(0))
+	    }
+	    Vi32(i1.wrapping_rem(i2))
+	}
+	Lt => Vbool(i1 < i2),
+	Eq => Vbool(i1 == i2),
+	BitAnd => Vi32(i1 & i2),
+	BitOr => Vi32(i1 | i2),
+	BitXor => Vi32(i1 ^ i2),
+	Shl => Vi32(i1.wrapping_shl(i2 as u32)),
+	Shr => Vi32(i1.wrapping_shr(i2 as u32)),
+	Gt => Vbool(i1 > i2),
+	Le => Vbool(i1 <= i2),
+	Ge => Vbool(i1 >= i2),
+	Neq => Vbool(i1 != i2),
+	Min => Vi32(i1.min(i2)),
+	Max => Vi32(i1.max(i2)),
+	Ltu => Vbool((i1 as u32) < (i2 as u32)),
+	AddC => Vi32(i1.wrapping_add(i2)),
+	SubC => Vi32(i1.wrapping_sub(i2)),
+	MulC => Vi32(i1.wrapping_mul(i2)),
+	Pow => {
+	    if i2 < 0 {
+		return Err(VmError::NegativeExponent(i2))
+	    }
+	    Vi32(i1.checked_pow(i2 as u32).ok_or(VmError::Overflow)?)
+	}
+	And | Or => return Err(binop_type_error(b))
+    })
+}
+
+/// Evaluate a binary operation on i64 operands.
+fn i64_binop(b: Binop, i1: i64, i2: i64) -> Result<Val, VmError> {
+    Ok(match b {
+	Add => Vi64(i1.wrapping_add(i2)),
+	Mul => Vi64(i1.wrapping_mul(i2)),
+	Sub => Vi64(i1.wrapping_sub(i2)),
+	Div => Vi64(checked_div(i1, i2, i64::checked_div)?),
+	Mod => {
+	    if i2 == 0 {
+		return Err(VmError::DivByZero(0))
+	    }
+	    Vi64(i1.wrapping_rem(i2))
+	}
+	Lt => Vbool(i1 < i2),
+	Eq => Vbool(i1 == i2),
+	Gt => Vbool(i1 > i2),
+	Le => Vbool(i1 <= i2),
+	Ge => Vbool(i1 >= i2),
+	Neq => Vbool(i1 != i2),
+	_ => return Err(binop_type_error(b))
+    })
+}
+
+/// Evaluate a binary operation on f32 operands.
+fn f32_binop(b: Binop, f1: f32, f2: f32) -> Result<Val, VmError> {
+    Ok(match b {
+	Add => Vf32(f1 + f2),
+	Mul => Vf32(f1 * f2),
+	Sub => Vf32(f1 - f2),
+	Div => Vf32(f1 / f2),
+	Lt => Vbool(f1 < f2),
+	Eq => Vbool(f1 == f2),
+	Gt => Vbool(f1 > f2),
+	Le => Vbool(f1 <= f2),
+	Ge => Vbool(f1 >= f2),
+	Neq => Vbool(f1 != f2),
+	_ => return Err(binop_type_error(b))
+    })
+}
+
+/// Evaluate a binary operation on char operands.
+fn char_binop(b: Binop, c1: char, c2: char) -> Result<Val, VmError> {
+    Ok(match b {
+	Lt => Vbool(c1 < c2),
+	Eq => Vbool(c1 == c2),
+	Gt => Vbool(c1 > c2),
+	Le => Vbool(c1 <= c2),
+	Ge => Vbool(c1 >= c2),
+	Neq => Vbool(c1 != c2),
+	_ => return Err(binop_type_error(b))
+    })
+}
+
+/// Evaluate a binary operation on bool operands.
+fn bool_binop(b: Binop, b1: bool, b2: bool) -> Result<Val, VmError> {
+    match b {
+	And => Ok(Vbool(b1 && b2)),
+	Or => Ok(Vbool(b1 || b2)),
+	_ => Err(binop_type_error(b))
+    }
+}
+
+/// Evaluate a binary operation on a value, dispatching on the
+/// operand types. Values of any type can be compared for equality
+/// with another of the same type; addresses are equal when they point
+/// to the same array.
+fn binop(b: Binop, v1: Val, v2: Val) -> Result<Val, VmError> {
+    if let Eq | Neq = b {
+	if mem::discriminant(&v1) != mem::discriminant(&v2) {
+	    return Err(binop_type_error(b))
+	}
+	return Ok(Vbool((v1 == v2) == (b == Eq)))
+    }
+    match (v1, v2) {
+	(Vi32(i1), Vi32(i2)) => i32_binop(b, i1, i2),
+	(Vi64(i1), Vi64(i2)) => i64_binop(b, i1, i2),
+	(Vf32(f1), Vf32(f2)) => f32_binop(b, f1, f2),
+	(Vchar(c1), Vchar(c2)) => char_binop(b, c1, c2),
+	(Vbool(b1), Vbool(b2)) => bool_binop(b, b1, b2),
+	_ => Err(binop_type_error(b))
+    }
+}
+
+/// The overflow flag pushed after the result of a checked arithmetic
+/// operation, or None if b does not produce one.
+fn overflow_flag(b: Binop, v1: Val, v2: Val) -> Option<bool> {
+    let (i1, i2) = (v1.to_i32()?, v2.to_i32()?);
+    match b {
+	AddC => Some(i1.overflowing_add(i2).1),
+	SubC => Some(i1.overflowing_sub(i2).1),
+	MulC => Some(i1.overflowing_mul(i2).1),
+	_ => None
+    }
+}
+
+/// Whether Add, Sub or Mul (b) of v1 and v2 overflows, for integer
+/// operands.
+fn overflows(b: Binop, v1: Val, v2: Val) -> bool {
+    match (b, v1, v2) {
+	(Add, Vi32(i1), Vi32(i2)) => i1.checked_add(i2).is_none(),
+	(Sub, Vi32(i1), Vi32(i2)) => i1.checked_sub(i2).is_none(),
+	(Mul, Vi32(i1), Vi32(i2)) => i1.checked_mul(i2).is_none(),
+	(Add, Vi64(i1), Vi64(i2)) => i1.checked_add(i2).is_none(),
+	(Sub, Vi64(i1), Vi64(i2)) => i1.checked_sub(i2).is_none(),
+	(Mul, Vi64(i1), Vi64(i2)) => i1.checked_mul(i2).is_none(),
+	_ => false
+    }
+}
+
+/// Gas charged for executing instr in state s. Allocations and heap
+/// copies are charged by size; operands of the wrong type cost
+/// nothing extra, since the instruction will fail.
+fn cost(instr: &Instr, s: &State) -> u64 {
+    let costs = &s.cfg.gas_costs;
+    let i32_at = |n: usize| s.peek(n).ok()
+	.and_then(|v| v.to_i32())
+	.map_or(0, |i| i.max(0) as u64);
+    let len_at = |n: usize| s.peek(n).ok()
+	.and_then(|v| v.to_address())
+	.and_then(|addr| s.array(addr).ok())
+	.map_or(0, |a| a.len() as u64);
+    let slots = match instr {
+	Alloc => i32_at(1),
+	AllocI(n) => *n as u64,
+	AllocClos(n) => *n as u64 + 1,
+	SConcat => len_at(0) + len_at(1),
+	ArrCopy => i32_at(0),
+	Grow => i32_at(0),
+	_ => 0
+    };
+    costs.base[instr.opcode() as usize].saturating_add(costs.per_slot.saturating_mul(slots))
+}
+
+/// Verify the program, if cfg.verify asks for it.
+fn check_verified(s: &State) -> Result<(), VmError> {
+    if s.cfg.verify {
+	match verify(&s.prog) {
+	    Err(VerifyError::FallsOffEnd(_)) if s.cfg.implicit_halt => (),
+	    r => r.map_err(VmError::Verify)?
+	}
+    }
+    Ok(())
+}
+
+/// Execute a single instruction.
+fn step(s: &mut State) -> Result<StepOutcome, VmError> {
+    if s.pc as usize == s.prog.len() && !s.cfg.implicit_halt {
+	return Err(VmError::MissingHalt(s.pc))
+    }
+    if s.pc as usize > s.prog.len() {
+	return Err(VmError::PcOutOfBounds(s.pc))
+    }
+    if let Some(fuel) = s.fuel.as_mut() {
+	if *fuel == 0 {
+	    return Err(VmError::OutOfFuel(s.pc))
+	}
+	*fuel -= 1
+    }
+    // The end of the program is an implicit Halt, if enabled.
+    let instr = s.prog.get(s.pc as usize).unwrap_or(&Halt);
+    if let Some(gas) = s.gas {
+	let c = cost(instr, s);
+	if c > gas {
+	    return Err(VmError::OutOfGas { pc: s.pc, used: s.gas_used })
+	}
+	s.gas = Some(gas - c);
+	s.gas_used += c
+    }
+    if let Some(profile) = s.profile.as_mut() {
+	profile.record(s.pc)
+    }
+    if let Some(stats) = s.stats.as_mut() {
+	stats.counts[instr.opcode() as usize] += 1;
+	stats.total += 1;
+	stats.max_stack = stats.max_stack.max(s.stk.len());
+	stats.max_heap = stats.max_heap.max(s.heap.len());
+	if let Call | Calli(_) | CallClos | TailCall(_) = instr {
+	    stats.calls += 1
+	}
+    }
+    let fuse = s.cfg.fuse && s.fuel.is_none() && s.gas.is_none() && s.stats.is_none()
+	&& s.profile.is_none();
+    let ops = if fuse { &s.prog.fused } else { &s.prog.ops };
+    let op = ops.get(s.pc as usize).copied().unwrap_or(Op::Halt);
+    s.pc += 1;
+    if !s.watches.is_empty() {
+	s.watch_hits.clear()
+    }
+    dispatch(s, op)
+}
+
+/// Push the result of v1 b v2, with the pc just past the Binary
+/// instruction.
+fn binary(s: &mut State, b: Binop, v1: Val, v2: Val) -> Result<(), VmError> {
+    let i = binop(b, v1, v2).map_err(|err| err.at(s.pc - 1))?;
+    if s.cfg.arithmetic == Arithmetic::Checked && overflows(b, v1, v2) {
+	return Err(VmError::Overflow)
+    }
+    s.stk.push(i);
+    if let Some(o) = overflow_flag(b, v1, v2) {
+	s.push(Vbool(o))?
+    }
+    Ok(())
+}
+
+/// Execute the decoded instruction op, with the pc already advanced
+/// past it.
+#[inline(always)]
+fn dispatch(s: &mut State, op: Op) -> Result<StepOutcome, VmError> {
+    use Op::*;
+    match op {
+	Push(v) => s.push(v)?,
+	Pop => { s.pop()?; }
+	Peek(i) => {
+	    let v = s.peek(i).map_err(|_| VmError::PeekOutOfBounds(i as u32))?;
+	    s.push(v)?
+	}
+	Unary(u) => {
+	    let v = s.pop()?;
+	    let i = unop(u, v)?;
+	    s.stk.push(i)
+	}
+	Binary(b) => {
+	    // The top value is the second operand.
+	    let (v2, v1) = (s.pop()?, s.pop()?);
+	    binary(s, b, v1, v2)?
+	}
+	Swap => {
+	    let v2 = s.pop()?;
+	    let v1 = s.pop()?;
+	    s.stk.push(v2);
+	    s.stk.push(v1);
+	    if let (true, Some((slot, false))) = (s.cfg.check_frames, s.shadow.last_mut()) {
+		// Compiled calls swap the call target above the saved fp
+		// of a frame that counts the target as an argument; follow
+		// the saved fp so that the call finds it.
+		let len = s.stk.len();
+		if *slot + 1 == len {
+		    *slot -= 1
+		} else if *slot + 2 == len {
+		    *slot += 1
+		}
+	    }
+	}
+	Alloc => {
+	    let vsize = s.peek(1)?;
+	    let size = expect(vsize, Val::to_i32, "i32")?;
+	    // The header takes a slot too.
+	    if size < 0 || size as usize >= s.cfg.max_heap {
+		return Err(VmError::BadAllocSize(size))
+	    }
+	    let size = size as usize;
+	    let loc = s.alloc(size)?;
+	    let vinit = s.pop()?;
+	    s.pop()?;
+	    for slot in &mut s.heap[loc+1..loc+size+1] {
+		*slot = vinit
+	    }
+	    s.stk.push(Vaddr(loc))
+	}
+	Set => {
+	    let (v, vix, vbase) = (s.pop()?, s.pop()?, s.pop()?);
+	    let ix = expect(vix, Val::to_i32, "i32")? as usize;
+	    let base = expect(vbase, Val::to_address, "address")?;
+	    let loc = s.elem_loc(base, ix)?;
+	    s.heap_write(loc, v)
+	}
+	Get => {
+	    let vix = s.pop()?;
+	    let vbase = s.pop()?;
+	    let ix = expect(vix, Val::to_i32, "i32")? as usize;
+	    let base = expect(vbase, Val::to_address, "address")?;
+	    let loc = s.elem_loc(base, ix)?;
+	    s.touched = Some(loc);
+	    s.push(s.heap[loc])?
+	}
+	Var(i) => {
+	    let ix = s.local(i)?;
+	    s.push(s.stk[ix])?
+	}
+	Store(i) => {
+	    let v = s.pop()?;
+	    let ix = s.local(i)?;
+	    s.stk[ix] = v
+	}
+	SetFrame(i) => {
+	    let depth = s.stk.len();
+	    if depth < i as usize {
+		return Err(VmError::BadFrame { depth, requested: i })
+	    }
+	    s.push(Vloc(s.fp))?;
+	    s.frames.push(s.stk.len() - 1);
+	    if s.cfg.check_frames {
+		s.shadow.push((s.stk.len() - 1, false))
+	    }
+	    s.fp = s.stk.len() as u32 - i - 1
+	}
+	Call => {
+	    let target = expect(s.pop()?, Val::to_loc, "loc for call target")?;
+	    if s.cfg.check_frames {
+		s.check_call("call")?
+	    }
+	    s.enter_call()?;
+	    let ret = s.pc;
+	    s.jump(target)?;
+	    s.stk.push(Vloc(ret))
+	}
+	Ret => {
+	    if s.cfg.check_frames {
+		// The saved fp, the return pc, and the result.
+		s.check_frame("ret", true, 3)?
+	    }
+	    // Check the whole frame before touching it, so that a bad
+	    // return leaves the machine as it was.
+	    let len = s.stk.len();
+	    if len < 3 {
+		return Err(VmError::ShortReturn(len))
+	    }
+	    let (vret, pc, fp) = match (s.stk[len-1], s.stk[len-2], s.stk[len-3]) {
+		(vret, Vloc(pc), Vloc(fp)) => (vret, pc, fp),
+		(_, Vloc(_), found) =>
+		    return Err(VmError::BadReturn { expected: "location for saved fp", found }),
+		(_, found, _) =>
+		    return Err(VmError::BadReturn { expected: "location for return pc", found })
+	    };
+	    if s.fp as usize > len - 3 {
+		return Err(VmError::BadFramePointer { fp: s.fp, saved_at: len - 3 })
+	    }
+	    if fp > s.fp {
+		return Err(VmError::BadSavedFp { saved: fp, fp: s.fp })
+	    }
+	    s.stk.truncate(s.fp as usize);
+	    s.frames.pop();
+	    if s.cfg.check_frames {
+		s.shadow.pop();
+	    }
+	    s.call_depth = s.call_depth.saturating_sub(1);
+	    s.pc = pc;
+	    s.fp = fp;
+	    s.stk.push(vret)
+	}
+	Branch => {
+	    let vtarget = s.pop()?;
+	    let vb = s.pop()?;
+	    let target = expect(vtarget, Val::to_loc, "location")?;
+	    if expect(vb, Val::to_bool, "bool")? {
+		s.jump(target)?
+	    }
+	}
+	Halt => {
+	    if s.tid == 0 {
+		let v = *s.stk.last().ok_or(VmError::StackUnderflow)?;
+		return Ok(StepOutcome::Halted(v))
+	    }
+	    let v = s.pop()?;
+	    s.threads[s.tid].status = Status::Done(v);
+	    s.schedule()?
+	}
+	Dup => {
+	    let v = s.peek(0)?;
+	    s.push(v)?
+	}
+	Nop => (),
+	Jmp => {
+	    let target = expect(s.pop()?, Val::to_loc, "location")?;
+	    s.jump(target)?
+	}
+	Jmpi(target) => s.jump(target)?,
+	BranchNot => {
+	    let vtarget = s.pop()?;
+	    let vb = s.pop()?;
+	    let target = expect(vtarget, Val::to_loc, "location")?;
+	    if !expect(vb, Val::to_bool, "bool")? {
+		s.jump(target)?
+	    }
+	}
+	Calli(target) => {
+	    if s.cfg.check_frames {
+		s.check_call("calli")?
+	    }
+	    s.enter_call()?;
+	    s.push(Vloc(s.pc))?;
+	    s.jump(target)?
+	}
+	Read => {
+	    let i = s.read_i32()?;
+	    s.push(Vi32(i))?
+	}
+	Print => {
+	    let line = format!("{}\n", s.pop()?);
+	    s.io.write_str(&line).map_err(VmError::Write)?
+	}
+	SConcat => {
+	    let addr1 = expect(s.peek(1)?, Val::to_address, "address")?;
+	    let addr2 = expect(s.peek(0)?, Val::to_address, "address")?;
+	    let len1 = s.array(addr1)?.len();
+	    let len2 = s.array(addr2)?.len();
+	    let loc = s.alloc(len1 + len2)?;
+	    let addr2 = expect(s.pop()?, Val::to_address, "address")?;
+	    let addr1 = expect(s.pop()?, Val::to_address, "address")?;
+	    s.heap.copy_within(addr1+1..addr1+len1+1, loc+1);
+	    s.heap.copy_within(addr2+1..addr2+len2+1, loc+len1+1);
+	    s.stk.push(Vaddr(loc))
+	}
+	SLen => {
+	    let addr = expect(s.pop()?, Val::to_address, "address")?;
+	    let len = s.array(addr)?.len();
+	    s.stk.push(Vi32(len as i32))
+	}
+	TypeTest(t) => {
+	    let v = s.pop()?;
+	    s.stk.push(Vbool(t.matches(&v)))
+	}
+	PopN(n) => {
+	    if n > s.stk.len() {
+		return Err(VmError::StackUnderflow)
+	    }
+	    let len = s.stk.len() - n;
+	    s.stk.truncate(len)
+	}
+	Rot => {
+	    let len = s.stk.len();
+	    if len < 3 {
+		return Err(VmError::StackUnderflow)
+	    }
+	    s.stk[len-3..].rotate_left(1)
+	}
+	Over => {
+	    let len = s.stk.len();
+	    if len < 2 {
+		return Err(VmError::StackUnderflow)
+	    }
+	    s.push(s.stk[len-2])?
+	}
+	Select => {
+	    let vcond = s.pop()?;
+	    let vb = s.pop()?;
+	    let va = s.pop()?;
+	    let cond = expect(vcond, Val::to_bool, "bool")?;
+	    s.stk.push(if cond { va } else { vb })
+	}
+	AllocI(size) => {
+	    s.peek(0)?;
+	    let loc = s.alloc(size)?;
+	    let vinit = s.pop()?;
+	    for slot in &mut s.heap[loc+1..loc+size+1] {
+		*slot = vinit
+	    }
+	    s.stk.push(Vaddr(loc))
+	}
+	GetF(ix) => {
+	    let base = expect(s.pop()?, Val::to_address, "address")?;
+	    let loc = s.elem_loc(base, ix)?;
+	    s.touched = Some(loc);
+	    s.push(s.heap[loc])?
+	}
+	SetF(ix) => {
+	    let (v, vbase) = (s.pop()?, s.pop()?);
+	    let base = expect(vbase, Val::to_address, "address")?;
+	    let loc = s.elem_loc(base, ix)?;
+	    s.heap_write(loc, v)
+	}
+	Len => {
+	    let base = expect(s.pop()?, Val::to_address, "address")?;
+	    let len = s.array(base)?.len();
+	    if len > i32::MAX as usize {
+		return Err(VmError::ArrayTooLong(len))
+	    }
+	    s.stk.push(Vi32(len as i32))
+	}
+	ArrCopy => {
+	    let to_usize = |v: Val| v.to_i32()
+		.filter(|i| *i >= 0)
+		.map(|i| i as usize)
+		.ok_or(VmError::TypeError { expected: "non-negative i32", found: v });
+	    let len = to_usize(s.pop()?)?;
+	    let src_off = to_usize(s.pop()?)?;
+	    let src = expect(s.pop()?, Val::to_address, "address")?;
+	    let dst_off = to_usize(s.pop()?)?;
+	    let dst = expect(s.pop()?, Val::to_address, "address")?;
+	    let src_loc = s.window_loc(src, src_off, len)
+		.map_err(|err| err.context("arrcopy source"))?;
+	    let dst_loc = s.window_loc(dst, dst_off, len)
+		.map_err(|err| err.context("arrcopy destination"))?;
+	    if s.watches.is_empty() {
+		s.heap.copy_within(src_loc..src_loc+len, dst_loc)
+	    } else {
+		let src: Vec<Val> = s.heap[src_loc..src_loc+len].to_vec();
+		for (i, v) in src.into_iter().enumerate() {
+		    s.heap_write(dst_loc + i, v)
+		}
+	    }
+	}
+	Free => {
+	    let base = expect(s.pop()?, Val::to_address, "address")?;
+	    s.free(base)?
+	}
+	Grow => {
+	    let size = expect(s.peek(0)?, Val::to_i32, "i32")?;
+	    // As with Alloc, the header takes a slot too.
+	    if size < 0 || size as usize >= s.cfg.max_heap {
+		return Err(VmError::BadAllocSize(size))
+	    }
+	    let size = size as usize;
+	    let base = expect(s.peek(1)?, Val::to_address, "address")?;
+	    let len = s.array(base)?.len();
+	    if size < len {
+		return Err(VmError::CannotShrink { len, size })
+	    }
+	    let loc = s.alloc(size)?;
+	    s.pop()?;
+	    let base = expect(s.pop()?, Val::to_address, "address")?;
+	    s.heap.copy_within(base+1..base+len+1, loc+1);
+	    s.free(base)?;
+	    s.stk.push(Vaddr(loc))
+	}
+	TailCall(n) => {
+	    let target = expect(s.pop()?, Val::to_loc, "loc for call target")?;
+	    if s.cfg.check_frames {
+		// The saved fp, the return pc, and the arguments.
+		s.check_frame("tailcall", true, n + 2)?
+	    }
+	    if s.stk.len() < n + 2 {
+		return Err(VmError::ShortTailCall { args: n, depth: s.stk.len() })
+	    }
+	    let args = s.stk.split_off(s.stk.len() - n);
+	    let (vpc, vfp) = (s.pop()?, s.pop()?);
+	    let pc = expect(vpc, Val::to_loc, "location for return pc")?;
+	    let fp = expect(vfp, Val::to_loc, "location for saved fp")?;
+	    s.stk.truncate(s.fp as usize);
+	    s.stk.extend(args);
+	    if let Some(slot) = s.frames.last_mut() {
+		*slot = s.stk.len()
+	    }
+	    if let Some((slot, _)) = s.shadow.last_mut() {
+		*slot = s.stk.len()
+	    }
+	    s.stk.push(Vloc(fp));
+	    s.stk.push(Vloc(pc));
+	    s.jump(target)?
+	}
+	// Globals share the heap's limit, so that a bad count cannot
+	// exhaust the host's memory.
+	Globals(n) if n > s.cfg.max_heap => return Err(VmError::OutOfHeap),
+	Globals(n) => s.globals = vec![Vundef; n],
+	GGet(i) => {
+	    let v = *s.global(i)?;
+	    s.push(v)?
+	}
+	GSet(i) => {
+	    let v = s.pop()?;
+	    *s.global(i)? = v
+	}
+	AllocClos(n) => {
+	    if s.stk.len() < n + 1 {
+		return Err(VmError::ShortClosure { captures: n, depth: s.stk.len() })
+	    }
+	    expect(s.peek(n)?, Val::to_loc, "code location")?;
+	    let loc = s.alloc(n + 1)?;
+	    let caps = s.stk.split_off(s.stk.len() - n - 1);
+	    for (slot, v) in s.heap[loc+1..loc+n+2].iter_mut().zip(caps) {
+		*slot = v
+	    }
+	    s.stk.push(Vaddr(loc))
+	}
+	CallClos => {
+	    let base = expect(s.pop()?, Val::to_address, "closure address")?;
+	    let code = expect(s.heap[s.elem_loc(base, 0)?], Val::to_loc,
+			      "code location in closure")?;
+	    if s.cfg.check_frames {
+		s.check_call("callclos")?
+	    }
+	    s.enter_call()?;
+	    s.push(Vloc(s.pc))?;
+	    s.push(Vaddr(base))?;
+	    s.jump(code)?
+	}
+	Switch(table) => {
+	    let i = expect(s.pop()?, Val::to_i32, "i32")?;
+	    let target = if i >= 0 { s.prog.tables[table].get(i as usize).copied() } else { None };
+	    if let Some(target) = target {
+		s.jump(target)?
+	    }
+	}
+	DivMod => {
+	    let (v2, v1) = (s.pop()?, s.pop()?);
+	    let i1 = expect(v1, Val::to_i32, "i32")?;
+	    let i2 = expect(v2, Val::to_i32, "i32")?;
+	    // Divide as Binary(Div) does, failing on MIN / -1.
+	    let q = checked_div(i1, i2, i32::checked_div).map_err(|err| err.at(s.pc - 1))?;
+	    s.stk.push(Vi32(q));
+	    s.stk.push(Vi32(i1.wrapping_rem(i2)))
+	}
+	PushPC => s.push(Vloc(s.pc))?,
+	Inc(i) | Dec(i) => {
+	    let delta = if let Inc(_) = op { 1 } else { -1 };
+	    let ix = s.local(i)?;
+	    let n = expect(s.stk[ix], Val::to_i32, "i32")?;
+	    s.stk[ix] = Vi32(match s.cfg.arithmetic {
+		Arithmetic::Wrapping => n.wrapping_add(delta),
+		Arithmetic::Checked => n.checked_add(delta).ok_or(VmError::Overflow)?
+	    })
+	}
+	Spawn => {
+	    let target = expect(s.pop()?, Val::to_loc, "location")?;
+	    if target as usize >= s.prog.len() {
+		return Err(VmError::BadJumpTarget { target, pc: s.pc - 1 })
+	    }
+	    s.threads.push(Thread::new(target));
+	    s.push(Vi32(s.threads.len() as i32 - 1))?
+	}
+	Yield => s.schedule()?,
+	Join => {
+	    let t = expect(s.pop()?, Val::to_i32, "i32")?;
+	    if t as usize == s.tid {
+		return Err(VmError::SelfJoin(t))
+	    }
+	    let status = s.threads.get(t as usize).map(|th| th.status)
+		.ok_or(VmError::UnknownThread(t))?;
+	    if let Status::Done(v) = status {
+		s.push(v)?
+	    } else {
+		s.threads[s.tid].status = Status::Joining(t as usize);
+		s.schedule()?
+	    }
+	}
+	Exit => {
+	    let code = expect(s.pop()?, Val::to_i32, "i32")?;
+	    return Ok(StepOutcome::Exited(code))
+	}
+	Assert => {
+	    let b = expect(s.pop()?, Val::to_bool, "bool").map_err(|err| err.context("assert"))?;
+	    if !b {
+		return Err(VmError::AssertionFailed(s.pc - 1))
+	    }
+	}
+	Syscall(f, n) => {
+	    let replaying = matches!(s.tape, Tape::Replay(..));
+	    if !replaying && !s.host_fns.contains_key(&f) {
+		return Err(VmError::UnknownHostFn(f))
+	    }
+	    if s.stk.len() < n {
+		return Err(VmError::StackUnderflow)
+	    }
+	    let mut args = s.stk.split_off(s.stk.len() - n);
+	    let result = match s.replay(Source::Syscall(f))? {
+		Some(Event::Syscall { result, .. }) => result,
+		_ => s.host_fns.get_mut(&f).unwrap()(&mut args)
+	    };
+	    s.record(Event::Syscall { index: f, result: result.clone() });
+	    let v = result.map_err(|msg| VmError::HostFn { index: f, msg })?;
+	    s.push(v)?
+	}
+	Rand => {
+	    let n = expect(s.pop()?, Val::to_i32, "i32")?;
+	    let i = match s.replay(Source::Rand)? {
+		Some(Event::Rand(i)) => {
+		    // Draw anyway, to leave the generator where the
+		    // recorded run did.
+		    s.rand(n)?;
+		    i
+		}
+		_ => {
+		    if s.cfg.deterministic && s.cfg.seed.is_none() {
+			return Err(VmError::Nondeterministic("rand"))
+		    }
+		    s.rand(n)?
+		}
+	    };
+	    s.record(Event::Rand(i));
+	    s.stk.push(Vi32(i))
+	}
+	// Each superinstruction advances the pc past every instruction
+	// of its sequence before doing that instruction's part, so errors
+	// report the same locations as unfused execution. Where its
+	// pushes might fail, it runs the sequence one instruction at a
+	// time instead.
+	PushBinary(..) | IncLocal(_) | BranchTo(..)
+	    if s.cfg.max_memory_bytes.is_some()
+	    || s.stk.len() + op.fused_len().1 > s.cfg.stack_size => {
+	    let start = s.pc - 1;
+	    for pc in start..start + op.fused_len().0 {
+		s.pc = pc + 1;
+		dispatch(s, s.prog.ops[pc as usize])?;
+	    }
+	}
+	PushBinary(v2, b) => {
+	    s.pc += 1;
+	    let v1 = s.pop()?;
+	    binary(s, b, v1, v2)?
+	}
+	IncLocal(i) => {
+	    let ix = s.local(i)?;
+	    s.pc += 2;
+	    binary(s, Add, s.stk[ix], Vi32(1))?;
+	    s.pc += 1;
+	    s.stk[ix] = s.pop()?
+	}
+	BranchTo(target, when) => {
+	    s.pc += 1;
+	    let vb = s.pop()?;
+	    if expect(vb, Val::to_bool, "bool")? == when {
+		s.jump(target)?
+	    }
+	}
+    }
+    Ok(StepOutcome::Continue)
+}
+
+/// Destination of the execution trace passed to exec.
+trait TraceWriter {
+    fn write_trace(&mut self, s: &str) -> Result<(), String>;
+}
+
+#[cfg(feature = "std")]
+impl<W: Write + ?Sized> TraceWriter for W {
+    fn write_trace(&mut self, s: &str) -> Result<(), String> {
+	self.write_all(s.as_bytes()).map_err(|e| e.to_string())
+    }
+}
+
+/// Execute from initial state s until the machine halts or exits,
+/// writing the state before each instruction to trace if given, and
+/// to s.io if s.trace is set. Returns the exit code if the program
+/// stopped via Exit.
+fn exec(mut trace: Option<&mut (dyn TraceWriter + '_)>, s: &mut State)
+	-> Result<Option<i32>, VmError> {
+    check_verified(s)?;
+    loop {
+	if let Some(w) = trace.as_mut() {
+	    let line = match s.trace_format {
+		TraceFormat::Text => format!("{}\n\n", s),
+		TraceFormat::Json => format!("{}\n", s.to_json())
+	    };
+	    w.write_trace(&line).map_err(VmError::Trace)?
+	}
+	if s.trace {
+	    let dump = format!("{}\n\n", s);
+	    s.io.write_str(&dump).map_err(VmError::Trace)?
+	}
+	match step(s).map_err(|err| s.with_backtrace(err))? {
+	    StepOutcome::Continue => (),
+	    StepOutcome::Halted(_) => return Ok(None),
+	    StepOutcome::Exited(code) => return Ok(Some(code))
+	}
+	#[cfg(feature = "std")]
+	if let Some(deadline) = s.deadline.as_mut() {
+	    deadline.tick()?
+	}
+    }
+}
+
+/// The default I/O backend.
+fn std_io() -> Box<dyn VmIo> {
+    #[cfg(feature = "std")]
+    return Box::new(StdIo);
+    #[cfg(not(feature = "std"))]
+    return Box::new(NoIo);
+}
+
+/// A random seed derived from the system clock, or a fixed one
+/// without a clock.
+fn clock_seed() -> u64 {
+    #[cfg(feature = "std")]
+    return SystemTime::now().duration_since(UNIX_EPOCH)
+	.map(|t| t.as_nanos() as u64)
+	.unwrap_or(0);
+    #[cfg(not(feature = "std"))]
+    return 0;
+}
+
+/// Execute s to completion and report how it stopped, tracing to s.io
+/// if d is DEBUG.
+fn finish(d: Debug, s: &mut State) -> Result<Outcome, VmError> {
+    s.trace = matches!(d, Debug::DEBUG);
+    match exec(None, s)? {
+	Some(code) => Ok(Outcome::Exited(code)),
+	None => Ok(Outcome::Halted(s.pop()?))
+    }
+}
+
+/// Entry point from outside of this module. Run the given program in
+/// the VM, reading input from stdin.
+///
+/// Running never panics on any program Vec::<Instr>::from_bytes can
+/// produce: every failure, down to malformed operands, is an Err.
+pub fn run(d: Debug, prog: &[Instr]) -> Result<Val, VmError> {
+    run_full(d, prog)?.result()
+}
+
+/// Run the given program in the VM, reading input from stdin, and
+/// return the whole machine state it stopped in.
+pub fn run_full(d: Debug, prog: &[Instr]) -> Result<FinalState, VmError> {
+    let mut s = State::init(prog, std_io(), VmConfig::default())?;
+    s.trace = matches!(d, Debug::DEBUG);
+    let exit_code = exec(None, &mut s)?;
+    let heap_stats = s.heap_stats();
+    Ok(FinalState { stack: s.stk, heap: s.heap, heap_stats, pc: s.pc, fp: s.fp, exit_code })
+}
+
+/// Run the given program in the VM with the given stack and heap
+/// limits, reading input from stdin.
+pub fn run_with_config(d: Debug, cfg: VmConfig, prog: &[Instr]) -> Result<Val, VmError> {
+    let mut s = State::init(prog, std_io(), cfg)?;
+    finish(d, &mut s).map(Outcome::value)
+}
+
+/// Run the given program in the VM, executing at most fuel
+/// instructions.
+pub fn run_with_fuel(d: Debug, prog: &[Instr], fuel: u64) -> Result<Val, VmError> {
+    let mut s = State::init(prog, std_io(), VmConfig::default())?;
+    s.fuel = Some(fuel);
+    finish(d, &mut s).map(Outcome::value)
+}
+
+/// Run the given program in the VM, reading input from stdin, and
+/// fail with VmError::Timeout if it is still running after about
+/// limit. The clock is only read every few thousand instructions, so
+/// the run can overshoot limit by the time those take.
+#[cfg(feature = "std")]
+pub fn run_with_timeout(d: Debug, prog: &[Instr], limit: Duration) -> Result<Val, VmError> {
+    let mut s = State::init(prog, std_io(), VmConfig::default())?;
+    s.deadline = Some(Deadline::new(limit));
+    finish(d, &mut s).map(Outcome::value)
+}
+
+/// Run the given program in the VM with a gas budget, charging each
+/// instruction its cost before executing it. Returns the result and
+/// the gas consumed.
+pub fn run_metered(prog: &[Instr], budget: u64) -> Result<(Val, u64), VmError> {
+    run_metered_with_config(VmConfig::default(), prog, budget)
+}
+
+/// Like run_metered, with the given VM configuration.
+pub fn run_metered_with_config(cfg: VmConfig, prog: &[Instr], budget: u64)
+			       -> Result<(Val, u64), VmError> {
+    let mut s = State::init(prog, std_io(), cfg)?;
+    s.gas = Some(budget);
+    let v = finish(Debug::NODEBUG, &mut s)?.value();
+    Ok((v, s.gas_used))
+}
+
+/// Run the given program in the VM, collecting execution statistics.
+pub fn run_with_stats(d: Debug, prog: &[Instr]) -> Result<(Val, ExecStats), VmError> {
+    let mut s = State::init(prog, std_io(), VmConfig::default())?;
+    s.stats = Some(ExecStats::new());
+    let v = finish(d, &mut s)?.value();
+    Ok((v, s.stats.take().unwrap()))
+}
+
+/// Run the given program in the VM, recording into profile.
+fn run_with_profile(cfg: VmConfig, prog: &[Instr], profile: Profile)
+		    -> (Result<Val, VmError>, Profile) {
+    let mut s = match State::init(prog, std_io(), cfg) {
+	Ok(s) => s,
+	Err(err) => return (Err(err), profile)
+    };
+    s.profile = Some(profile);
+    let result = finish(Debug::NODEBUG, &mut s).map(Outcome::value);
+    let mut profile = s.profile.take().unwrap();
+    profile.stop();
+    (result, profile)
+}
+
+/// Run the given program in the VM, profiling each instruction, and
+/// timing each if timed is set.
+#[cfg(feature = "std")]
+pub fn run_profiled_with_timing(prog: &[Instr], timed: bool) -> (Result<Val, VmError>, Profile) {
+    run_profiled_with_config(VmConfig::default(), prog, timed)
+}
+
+/// Like run_profiled_with_timing, with the given VM configuration.
+#[cfg(feature = "std")]
+pub fn run_profiled_with_config(cfg: VmConfig, prog: &[Instr], timed: bool)
+				-> (Result<Val, VmError>, Profile) {
+    run_with_profile(cfg, prog, Profile::new(prog.len(), timed))
+}
+
+/// Run the given program in the VM, counting the executions of each
+/// instruction. The profile covers the run even if it fails.
+pub fn run_profiled(prog: &[Instr]) -> (Result<Val, VmError>, Profile) {
+    run_with_profile(VmConfig::default(), prog, Profile::new(prog.len(), false))
+}
+
+/// Run the given program in the VM, reading input from stdin, and
+/// report whether it halted or exited.
+pub fn run_outcome(d: Debug, prog: &[Instr]) -> Result<Outcome, VmError> {
+    run_outcome_with_config(d, VmConfig::default(), prog)
+}
+
+/// Like run_outcome, with the given VM configuration.
+pub fn run_outcome_with_config(d: Debug, cfg: VmConfig, prog: &[Instr])
+			       -> Result<Outcome, VmError> {
+    let mut s = State::init(prog, std_io(), cfg)?;
+    finish(d, &mut s)
+}
+
+/// Run the given program in the VM, writing the machine state before
+/// each instruction to trace.
+#[cfg(feature = "std")]
+pub fn run_with_trace(prog: &[Instr], trace: &mut dyn Write) -> Result<Val, VmError> {
+    run_with_trace_format(prog, trace, TraceFormat::Text)
+}
+
+/// Like run_with_trace, writing the trace in the given format.
+#[cfg(feature = "std")]
+pub fn run_with_trace_format(prog: &[Instr], trace: &mut dyn Write, format: TraceFormat)
+			     -> Result<Val, VmError> {
+    let mut s = State::init(prog, std_io(), VmConfig::default())?;
+    s.trace_format = format;
+    match exec(Some(&mut &mut *trace), &mut s)? {
+	Some(code) => Ok(Vi32(code)),
+	None => s.pop()
+    }
+}
+
+/// Run the given program in the VM with the given I/O backend, which
+/// also receives the trace if d is DEBUG.
+pub fn run_with_io(d: Debug, prog: &[Instr], io: Box<dyn VmIo>)
+		   -> Result<Val, VmError> {
+    let mut s = State::init(prog, io, VmConfig::default())?;
+    finish(d, &mut s).map(Outcome::value)
+}
+
+/// Run the given program in the VM, seeding the random number
+/// generator with seed so that executions are reproducible.
+pub fn run_with_seed(d: Debug, prog: &[Instr], seed: u64) -> Result<Val, VmError> {
+    run_with_config(d, VmConfig { seed: Some(seed), ..VmConfig::default() }, prog)
+}
+
+/// Run each of programs in the VM like run, spread across the given
+/// number of threads. Each program's output is written to stdout in
+/// one piece when it stops, so the outputs of different programs
+/// never interleave. Returns the results in the order of programs.
+#[cfg(feature = "std")]
+pub fn run_many(programs: &[Vec<Instr>], threads: usize) -> Vec<Result<Val, VmError>> {
+    let run_held = |prog: &[Instr]| {
+	let out = Arc::new(Mutex::new(String::new()));
+	let result = run_with_io(Debug::NODEBUG, prog, Box::new(HeldStdIo(Arc::clone(&out))));
+	let out = out.lock().unwrap();
+	io::stdout().write_all(out.as_bytes()).map_err(|e| VmError::Write(e.to_string()))?;
+	result
+    };
+    // Each thread takes the next program not yet started.
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<Option<Result<Val, VmError>>> = programs.iter().map(|_| None).collect();
+    thread::scope(|scope| {
+	let workers: Vec<_> = (0..threads.max(1)).map(|_| scope.spawn(|| {
+	    let mut done = Vec::new();
+	    loop {
+		let i = next.fetch_add(1, Ordering::Relaxed);
+		match programs.get(i) {
+		    Some(prog) => done.push((i, run_held(prog))),
+		    None => return done
+		}
+	    }
+	})).collect();
+	for worker in workers {
+	    for (i, result) in worker.join().unwrap() {
+		results[i] = Some(result)
+	    }
+	}
+    });
+    results.into_iter().map(Option::unwrap).collect()
+}
+
+/// What a hook sees before each instruction executes.
+pub struct HookEvent<'a> {
+    /// Location of the instruction.
+    pub pc: u32,
+    /// The instruction about to execute.
+    pub instr: &'a Instr,
+    /// Number of values on the stack.
+    pub stack_depth: usize,
+    /// The frame pointer.
+    pub fp: u32,
+    /// Number of calls the running thread is inside.
+    pub call_depth: usize
+}
+
+/// A callback run before each instruction. Returning an error aborts
+/// execution with that error.
+type Hook = Box<dyn FnMut(&HookEvent) -> Result<(), VmError> + Send>;
+
+/// Snapshots kept for stepping back.
+struct History {
+    /// Instructions executed between snapshots.
+    every: u64,
+    /// Maximum number of snapshots kept.
+    capacity: usize,
+    /// Number of instructions executed since history was turned on.
+    steps: u64,
+    /// The snapshots, oldest first, each with the number of
+    /// instructions executed before it was taken and the index of the
+    /// next event in the tape's history at the time.
+    snapshots: VecDeque<(u64, Vec<u8>, usize)>
+}
+
+impl History {
+    /// Take a snapshot of s if one is due before the next instruction,
+    /// dropping the oldest snapshot, and the events from before the
+    /// next oldest, if there are too many.
+    fn save(&mut self, s: &mut State) {
+	if !self.steps.is_multiple_of(self.every)
+	    || self.snapshots.back().is_some_and(|&(n, ..)| n == self.steps) {
+	    return
+	}
+	let next = match &s.tape {
+	    Tape::History(_, next) => *next,
+	    _ => 0
+	};
+	self.snapshots.push_back((self.steps, s.to_bytes(), next));
+	if self.snapshots.len() > self.capacity {
+	    self.snapshots.pop_front();
+	    let first = self.snapshots[0].2;
+	    if let Tape::History(events, next) = &mut s.tape {
+		events.drain(..first);
+		*next -= first
+	    }
+	    for snapshot in &mut self.snapshots {
+		snapshot.2 -= first
+	    }
+	}
+    }
+}
+
+/// A GrumpyVM instance that can be driven one instruction at a time.
+pub struct Vm {
+    s: State,
+    hook: Option<Hook>,
+    /// Instructions executed between checks of the interrupt flag.
+    interrupt_interval: u32,
+    /// Snapshots for step_back, if kept.
+    history: Option<History>
+}
+
+static INTERRUPT_INTERVAL: u32 = 1024;
+
+impl Vm {
+    /// Create a VM ready to run the given program, using stdin and
+    /// stdout for I/O. Fails if the program pushes an address or a
+    /// size.
+    pub fn new(prog: &[Instr]) -> Result<Vm, VmError> {
+	Vm::with_config(prog, VmConfig::default())
+    }
+    /// Create a VM ready to run the given program with the given
+    /// configuration, using stdin and stdout for I/O. Fails if the
+    /// program pushes an address or a size, or if cfg.verify is set
+    /// and the program does not verify.
+    pub fn with_config(prog: &[Instr], cfg: VmConfig) -> Result<Vm, VmError> {
+	let s = State::init(prog, std_io(), cfg)?;
+	check_verified(&s)?;
+	Ok(Vm { s, hook: None, interrupt_interval: INTERRUPT_INTERVAL, history: None })
+    }
+    /// Create a VM ready to run prog, sharing it and its decoding
+    /// rather than copying them, using stdin and stdout for I/O.
+    pub fn with_shared_program(prog: &SharedProgram) -> Vm {
+	Vm {
+	    s: State::with_program(prog.clone(), std_io(), VmConfig::default()),
+	    hook: None,
+	    interrupt_interval: INTERRUPT_INTERVAL,
+	    history: None
+	}
+    }
+    /// Save the machine state, including the program, so that
+    /// execution can later be resumed with restore.
+    pub fn snapshot(&self) -> Vec<u8> {
+	self.s.to_bytes()
+    }
+    /// Resume from a snapshot. The restored machine uses stdin and
+    /// stdout for I/O and has no hook or host functions. Fails if the
+    /// bytes are not a snapshot or describe a machine that could not
+    /// have been running, such as one whose free list leaves the heap.
+    pub fn restore(bytes: &[u8]) -> Result<Vm, ParseError> {
+	let s = State::from_bytes(&mut bytes.iter().copied())?;
+	Ok(Vm { s, hook: None, interrupt_interval: INTERRUPT_INTERVAL, history: None })
+    }
+    /// Use io for Read and Print instructions.
+    pub fn set_io<I: VmIo + 'static>(&mut self, io: I) {
+	self.s.io = Box::new(io)
+    }
+    /// Call f before executing each instruction.
+    pub fn set_hook<F>(&mut self, f: F)
+    where F: FnMut(&HookEvent) -> Result<(), VmError> + Send + 'static {
+	self.hook = Some(Box::new(f))
+    }
+    /// Execute the next instruction.
+    pub fn step(&mut self) -> Result<StepOutcome, VmError> {
+	if let Some(hook) = self.hook.as_mut() {
+	    if let Some(instr) = self.s.prog.get(self.s.pc as usize) {
+		hook(&HookEvent {
+		    pc: self.s.pc,
+		    instr,
+		    stack_depth: self.s.stk.len(),
+		    fp: self.s.fp,
+		    call_depth: self.s.call_depth
+		})?
+	    }
+	}
+	if let Some(history) = self.history.as_mut() {
+	    history.save(&mut self.s)
+	}
+	let outcome = step(&mut self.s).map_err(|err| self.s.with_backtrace(err))?;
+	if let Some(history) = self.history.as_mut() {
+	    history.steps += 1
+	}
+	Ok(outcome)
+    }
+    /// Keep a snapshot after each run of every instructions executed
+    /// by step, and at most snapshots of them, so that step_back can
+    /// go back at least (snapshots - 1) * every instructions while
+    /// memory use stays bounded. Starts the history over from the
+    /// current state. The values taken from outside the machine since
+    /// the oldest snapshot are kept too, so that re-executed
+    /// instructions see them again.
+    pub fn keep_history(&mut self, every: u32, snapshots: usize) {
+	self.s.tape = Tape::History(Vec::new(), 0);
+	self.history = Some(History {
+	    every: every.max(1) as u64,
+	    capacity: snapshots.max(1),
+	    steps: 0,
+	    snapshots: VecDeque::new()
+	})
+    }
+    /// Undo the last instruction executed by step, by restoring the
+    /// last snapshot before it and re-executing up to it. Output is
+    /// not taken back. Stepping forward again replays the values the
+    /// undone instructions took from outside the machine.
+    pub fn step_back(&mut self) -> Result<(), VmError> {
+	let history = self.history.as_mut().ok_or(VmError::NoHistory)?;
+	if history.steps == 0 {
+	    return Err(VmError::AtStart)
+	}
+	let target = history.steps - 1;
+	while history.snapshots.back().is_some_and(|&(n, ..)| n > target) {
+	    history.snapshots.pop_back();
+	}
+	let (n, bytes, next) = history.snapshots.back().ok_or(VmError::NoHistory)?;
+	self.s.redo(bytes, *next, target - n)?;
+	history.steps = target;
+	Ok(())
+    }
+    /// Execute until the machine halts or exits, returning the result
+    /// as run does.
+    pub fn run(&mut self) -> Result<Val, VmError> {
+	loop {
+	    match self.step()? {
+		StepOutcome::Continue => (),
+		StepOutcome::Halted(v) => return Ok(v),
+		StepOutcome::Exited(code) => return Ok(Vi32(code))
+	    }
+	}
+    }
+    /// Like run, but also return a recording of every value the run
+    /// took from outside the machine: input lines, host function
+    /// results, and random numbers. Stops keeping history.
+    pub fn run_recorded(&mut self) -> (Result<Val, VmError>, Recording) {
+	self.history = None;
+	self.s.tape = Tape::Record(Vec::new());
+	let result = self.run();
+	let events = match mem::replace(&mut self.s.tape, Tape::Off) {
+	    Tape::Record(events) => events,
+	    _ => Vec::new()
+	};
+	(result, Recording { events })
+    }
+    /// Like run, but take every value from outside the machine from
+    /// recording instead of the input source, host functions, or
+    /// random number generator. Fails with VmError::ReplayDiverged if
+    /// the program asks for a value other than the next one recorded,
+    /// or stops before using them all. Stops keeping history.
+    pub fn run_replay(&mut self, recording: &Recording) -> Result<Val, VmError> {
+	self.history = None;
+	self.s.tape = Tape::Replay(recording.events.clone(), 0);
+	let result = self.run();
+	let left = match mem::replace(&mut self.s.tape, Tape::Off) {
+	    Tape::Replay(events, next) => events.len() - next,
+	    _ => 0
+	};
+	match result {
+	    Ok(_) if left > 0 => Err(VmError::ReplayDiverged {
+		pc: self.s.pc, msg: format!("stopped with {} recorded values unused", left)
+	    }),
+	    result => result
+	}
+    }
+    /// Make f callable as host function index by Syscall, replacing
+    /// any function already registered there. Errors returned by f
+    /// stop execution with VmError::HostFn.
+    pub fn register_host_fn<F>(&mut self, index: u32, f: F)
+    where F: FnMut(&mut [Val]) -> Result<Val, String> + Send + 'static {
+	self.s.host_fns.insert(index, Box::new(f));
+    }
+    /// Watch heap location addr (a slot index, as reported by heap):
+    /// writes to it by Set, SetF, or ArrCopy stop run_until_break.
+    /// Watches are by location, so arrays moved by a copying collector
+    /// are not followed.
+    pub fn watch(&mut self, addr: Address) {
+	if !self.s.watches.contains(&addr) {
+	    self.s.watches.push(addr)
+	}
+    }
+    /// Stop watching heap location addr.
+    pub fn unwatch(&mut self, addr: Address) {
+	self.s.watches.retain(|&a| a != addr)
+    }
+    /// Execute until the machine halts or exits, or a watched heap
+    /// location is written. If one instruction writes several watched
+    /// locations, each is reported by a separate call.
+    pub fn run_until_break(&mut self) -> Result<BreakOutcome, VmError> {
+	loop {
+	    if !self.s.watch_hits.is_empty() {
+		return Ok(BreakOutcome::WatchHit(self.s.watch_hits.remove(0)))
+	    }
+	    match self.step()? {
+		StepOutcome::Continue => (),
+		StepOutcome::Halted(v) => return Ok(BreakOutcome::Halted(v)),
+		StepOutcome::Exited(code) => return Ok(BreakOutcome::Exited(code))
+	    }
+	}
+    }
+    /// Like run, but stop with VmError::Interrupted once flag is set.
+    /// The flag is checked every interrupt_interval instructions and
+    /// cleared when the interrupt is taken, so the machine can be
+    /// resumed by calling run or run_interruptible again.
+    pub fn run_interruptible(&mut self, flag: Arc<AtomicBool>) -> Result<Val, VmError> {
+	loop {
+	    for _ in 0..self.interrupt_interval {
+		match self.step()? {
+		    StepOutcome::Continue => (),
+		    StepOutcome::Halted(v) => return Ok(v),
+		    StepOutcome::Exited(code) => return Ok(Vi32(code))
+		}
+	    }
+	    if flag.swap(false, Ordering::Relaxed) {
+		return Err(VmError::Interrupted)
+	    }
+	}
+    }
+    /// Set how many instructions run_interruptible executes between
+    /// checks of its flag (at least 1).
+    pub fn set_interrupt_interval(&mut self, n: u32) {
+	self.interrupt_interval = n.max(1)
+    }
+    /// The program counter.
+    pub fn pc(&self) -> u32 {
+	self.s.pc
+    }
+    /// The frame pointer.
+    pub fn fp(&self) -> u32 {
+	self.s.fp
+    }
+    /// Return addresses of the running thread's active frames,
+    /// innermost first.
+    pub fn backtrace(&self) -> Vec<u32> {
+	self.s.backtrace()
+    }
+    /// The instruction at pc, if any.
+    pub fn instr(&self, pc: u32) -> Option<&Instr> {
+	self.s.prog.get(pc as usize)
+    }
+    /// The running thread's stack.
+    pub fn stack(&self) -> &[Val] {
+	&self.s.stk
+    }
+    /// The heap.
+    pub fn heap(&self) -> &[Val] {
+	&self.s.heap
+    }
+    /// Occupancy of the heap.
+    pub fn heap_stats(&self) -> HeapStats {
+	self.s.heap_stats()
+    }
+    /// A copy of the elements of the array at addr, for pulling
+    /// results out of the heap.
+    pub fn read_array(&self, addr: Address) -> Result<Vec<Val>, VmError> {
+	self.s.array(addr).map(<[Val]>::to_vec)
+    }
+    /// Number of bytes taken up by the heap and stack.
+    pub fn memory_usage(&self) -> usize {
+	self.s.memory_usage()
+    }
+    /// Append instrs to the program. Execution continues from the
+    /// current pc. Fails, leaving the program as it was, if instrs
+    /// push an address or a size.
+    pub fn extend_program(&mut self, instrs: &[Instr]) -> Result<(), VmError> {
+	Arc::make_mut(&mut self.s.prog).extend(instrs)
+    }
+    /// Number of instructions in the program.
+    pub fn program_len(&self) -> u32 {
+	self.s.prog.len() as u32
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    /// Initial state for prog with no input and a fixed seed.
+    fn test_state(prog: Vec<Instr>) -> State {
+	State::init(prog, Box::new(MemIo::default()), VmConfig::default()).unwrap()
+    }
+
+    /// Run prog on a stack that starts out holding vals. Programs
+    /// can't push addresses, but a restored snapshot may hold any.
+    fn run_on_stack(vals: &[Val], prog: Vec<Instr>) -> Result<Val, VmError> {
+	let mut s = test_state(prog);
+	s.stk.extend_from_slice(vals);
+	finish(Debug::NODEBUG, &mut s).map(Outcome::value)
+    }
+
+    #[test]
+    fn test_dup() {
+	let prog = vec![Push(Vi32(21)), Dup, Binary(Add), Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi32(42)));
+	assert_eq!(run(Debug::NODEBUG, &[Dup, Halt]),
+		   Err(VmError::StackUnderflow));
+    }
+
+    #[test]
+    fn test_nop() {
+	let prog = vec![Push(Vi32(7)), Nop, Nop, Nop, Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi32(7)));
+    }
+
+    #[test]
+    fn test_negi() {
+	let prog = vec![Push(Vi32(5)), Unary(NegI), Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi32(-5)));
+	assert_eq!(unop(NegI, Vi32(-5)), Ok(Vi32(5)));
+	assert_eq!(unop(NegI, Vi32(0)), Ok(Vi32(0)));
+	assert_eq!(unop(NegI, Vi32(i32::MAX)), Ok(Vi32(-i32::MAX)));
+	assert_eq!(unop(NegI, Vi32(i32::MIN)), Err(VmError::Overflow));
+	assert_eq!(unop(NegI, Vbool(true)),
+		   Err(VmError::TypeError { expected: "i32", found: Vbool(true) }));
+    }
+
+    #[test]
+    fn test_jmp() {
+	let prog = vec![Push(Vi32(1)), Jmpi(3), Push(Vi32(2)), Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi32(1)));
+	let prog = vec![Push(Vi32(1)), Push(Vloc(4)), Jmp, Push(Vi32(2)), Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi32(1)));
+	assert_eq!(run(Debug::NODEBUG, &[Jmpi(2), Halt]),
+		   Err(VmError::BadJumpTarget { target: 2, pc: 0 }));
+	// Computed targets are checked at the jump, too.
+	let prog = [Push(Vbool(true)), Push(Vloc(9)), Branch, Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog), Err(VmError::BadJumpTarget { target: 9, pc: 2 }));
+	let prog = [Push(Vloc(u32::MAX)), Call, Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog),
+		   Err(VmError::BadJumpTarget { target: u32::MAX, pc: 1 }));
+	assert_eq!(VmError::BadJumpTarget { target: 9, pc: 2 }.to_string(),
+		   "jump target out of bounds: 9 at pc 2");
+	// Count down from 3 with a backward branch.
+	let prog = [Push(Vi32(3)), Dec(0), Var(0), Push(Vi32(0)), Binary(Gt),
+		    Push(Vloc(1)), Branch, Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi32(0)));
+	assert_eq!(run(Debug::NODEBUG, &[Push(Vi32(0)), Jmp, Halt]),
+		   Err(VmError::TypeError { expected: "location", found: Vi32(0) }));
+    }
+
+    #[test]
+    fn test_calli() {
+	let prog = vec![
+	    Push(Vi32(20)),
+	    SetFrame(1),
+	    Calli(4),
+	    Halt,
+	    // Function doubling its argument.
+	    Var(0),
+	    Var(0),
+	    Binary(Add),
+	    Ret
+	];
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi32(40)));
+	assert_eq!(run(Debug::NODEBUG, &[Calli(5)]),
+		   Err(VmError::BadJumpTarget { target: 5, pc: 0 }));
+    }
+
+    /// Run prog with the given text as input.
+    fn run_input(prog: &[Instr], input: &'static str) -> Result<Val, VmError> {
+	run_with_io(Debug::NODEBUG, prog, Box::new(MemIo::new(input)))
+    }
+
+    #[test]
+    fn test_peek() {
+	let prog = |i| [Push(Vi32(1)), Push(Vi32(2)), Push(Vi32(3)), Peek(i), Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog(0)), Ok(Vi32(3)));
+	assert_eq!(run(Debug::NODEBUG, &prog(2)), Ok(Vi32(1)));
+	assert_eq!(run(Debug::NODEBUG, &prog(3)), Err(VmError::PeekOutOfBounds(3)));
+	assert_eq!(run(Debug::NODEBUG, &prog(u32::MAX)),
+		   Err(VmError::PeekOutOfBounds(u32::MAX)));
+	assert_eq!(run(Debug::NODEBUG, &[Peek(0), Halt]), Err(VmError::PeekOutOfBounds(0)));
+    }
+
+    #[test]
+    fn test_read() {
+	let prog = vec![Read, Read, Binary(Sub), Halt];
+	assert_eq!(run_input(&prog, "10\n 3 \n"), Ok(Vi32(7)));
+	assert_eq!(run_input(&prog, "3\n"), Err(VmError::Read("end of input".into())));
+	assert_eq!(run_input(&prog, "3\nten\n"),
+		   Err(VmError::Read("malformed input: ten".into())));
+    }
+
+    #[test]
+    fn test_print() {
+	let prog = vec![Read, Dup, Print, Push(Vi32(2)), Binary(Mul), Print,
+			Push(Vbool(true)), Print, Push(Vunit), Halt];
+	let io = MemIo::new("21\n");
+	assert_eq!(run_with_io(Debug::NODEBUG, &prog, Box::new(io.clone())), Ok(Vunit));
+	assert_eq!(io.output(), "21\n42\ntrue\n");
+
+	let mut vm = Vm::new(&prog).unwrap();
+	let io = MemIo::new("5\n");
+	vm.set_io(io.clone());
+	assert_eq!(vm.run(), Ok(Vunit));
+	assert_eq!(io.output(), "5\n10\ntrue\n");
+
+	// The debug trace goes to the same backend, interleaved with
+	// printed values.
+	let prog = vec![Push(Vi32(7)), Print, Push(Vunit), Halt];
+	let io = MemIo::default();
+	assert_eq!(run_with_io(Debug::DEBUG, &prog, Box::new(io.clone())), Ok(Vunit));
+	let out = io.output();
+	assert_eq!(out.matches("pc: ").count(), 4);
+	assert!(out.contains("instr: print\nfp: 0\ncall depth: 0\nstk: [7]"));
+	assert!(out.contains("heap size: 0\n\n7\npc: 2\n"));
+    }
+
+    struct FailingIo;
+
+    impl VmIo for FailingIo {
+	fn write_str(&mut self, _: &str) -> Result<(), String> {
+	    Err("disk full".into())
+	}
+	fn read_line(&mut self) -> Result<String, String> {
+	    Err("no input".into())
+	}
+    }
+
+    #[test]
+    fn test_io_errors() {
+	assert_eq!(run_with_io(Debug::NODEBUG, &[Push(Vunit), Print, Halt], Box::new(FailingIo)),
+		   Err(VmError::Write("disk full".into())));
+	assert_eq!(run_with_io(Debug::NODEBUG, &[Read, Halt], Box::new(FailingIo)),
+		   Err(VmError::Read("no input".into())));
+	assert_eq!(run_with_io(Debug::DEBUG, &[Push(Vunit), Halt], Box::new(FailingIo)),
+		   Err(VmError::Trace("disk full".into())));
+    }
+
+    #[test]
+    fn test_abs() {
+	assert_eq!(run(Debug::NODEBUG, &[Push(Vi32(-5)), Unary(Abs), Halt]),
+		   Ok(Vi32(5)));
+	assert_eq!(unop(Abs, Vi32(5)), Ok(Vi32(5)));
+	assert_eq!(unop(Abs, Vi32(0)), Ok(Vi32(0)));
+	assert_eq!(unop(Abs, Vi32(-i32::MAX)), Ok(Vi32(i32::MAX)));
+	assert_eq!(unop(Abs, Vi32(i32::MIN)), Err(VmError::Overflow));
+	assert_eq!(unop(Abs, Vunit),
+		   Err(VmError::TypeError { expected: "i32", found: Vunit }));
+    }
+
+    #[test]
+    fn test_typetest() {
+	let tags = [TypeTag::I32, TypeTag::Bool, TypeTag::Unit, TypeTag::Addr,
+		    TypeTag::Loc, TypeTag::Undef, TypeTag::F32, TypeTag::I64,
+		    TypeTag::Char];
+	let vals = [Vi32(0), Vbool(false), Vunit, Vaddr(0), Vloc(0), Vundef,
+		    Vf32(0.0), Vi64(0), Vchar('a')];
+	// The ith tag matches exactly the ith value.
+	for (i, &t) in tags.iter().enumerate() {
+	    for (j, &v) in vals.iter().enumerate() {
+		assert_eq!(run_on_stack(&[v], vec![TypeTest(t), Halt]), Ok(Vbool(i == j)));
+	    }
+	    assert!(!t.matches(&Vsize(0)));
+	}
+	let prog = vec![Push(Vi32(1)), Push(Vi32(1)), Alloc, TypeTest(TypeTag::Addr), Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vbool(true)));
+	assert_eq!(run(Debug::NODEBUG, &[TypeTest(TypeTag::I32)]),
+		   Err(VmError::StackUnderflow));
+    }
+
+    #[test]
+    fn test_bool_conversions() {
+	assert_eq!(unop(BoolToI32, Vbool(true)), Ok(Vi32(1)));
+	assert_eq!(unop(BoolToI32, Vbool(false)), Ok(Vi32(0)));
+	assert_eq!(unop(BoolToI32, Vi32(1)),
+		   Err(VmError::TypeError { expected: "bool", found: Vi32(1) }));
+	assert_eq!(unop(I32ToBool, Vi32(0)), Ok(Vbool(false)));
+	assert_eq!(unop(I32ToBool, Vi32(-7)), Ok(Vbool(true)));
+	assert_eq!(unop(I32ToBool, Vbool(true)),
+		   Err(VmError::TypeError { expected: "i32", found: Vbool(true) }));
+
+	// Count the elements greater than 2 without branching.
+	let elems = [5, 1, 7, 2];
+	let mut prog = vec![Push(Vi32(elems.len() as i32)), Push(Vi32(0)), Alloc];
+	for (i, &x) in elems.iter().enumerate() {
+	    prog.extend(vec![Dup, Push(Vi32(i as i32)), Push(Vi32(x)), Set]);
+	}
+	prog.push(Push(Vi32(0)));
+	for i in 0..elems.len() {
+	    prog.extend(vec![Var(0), Push(Vi32(i as i32)), Get, Push(Vi32(2)),
+			     Binary(Gt), Unary(BoolToI32), Binary(Add)]);
+	}
+	prog.push(Halt);
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi32(2)));
+    }
+
+    #[test]
+    fn test_popn() {
+	let prog = vec![Push(Vi32(1)), Push(Vi32(2)), Push(Vi32(3)), PopN(2), Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi32(1)));
+	let prog = vec![Push(Vi32(1)), PopN(0), Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi32(1)));
+	let prog = vec![Push(Vi32(1)), Push(Vi32(2)), PopN(3), Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog),
+		   Err(VmError::StackUnderflow));
+    }
+
+    #[test]
+    fn test_rot() {
+	let prog = vec![Push(Vi32(0)), Push(Vi32(1)), Push(Vi32(2)), Push(Vi32(3)),
+			Rot, Halt];
+	let mut s = test_state(prog);
+	assert_eq!(exec(None, &mut s), Ok(None));
+	// 1 2 3 -> 2 3 1, leaving the values below untouched.
+	assert_eq!(s.stk, vec![Vi32(0), Vi32(2), Vi32(3), Vi32(1)]);
+	// Three rotations are the identity.
+	let prog = vec![Push(Vi32(1)), Push(Vi32(2)), Push(Vi32(3)),
+			Rot, Rot, Rot, Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi32(3)));
+	let prog = vec![Push(Vi32(1)), Push(Vi32(2)), Rot, Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog),
+		   Err(VmError::StackUnderflow));
+    }
+
+    #[test]
+    fn test_over() {
+	let prog = vec![Push(Vi32(1)), Push(Vi32(2)), Over, Halt];
+	let mut s = test_state(prog);
+	assert_eq!(exec(None, &mut s), Ok(None));
+	assert_eq!(s.stk, vec![Vi32(1), Vi32(2), Vi32(1)]);
+	assert_eq!(run(Debug::NODEBUG, &[Push(Vi32(1)), Over, Halt]),
+		   Err(VmError::StackUnderflow));
+
+	// Sum 5 + 4 + ... + 1, keeping the accumulator under the counter.
+	let prog = vec![
+	    Push(Vi32(0)),
+	    Push(Vi32(5)),
+	    Over,
+	    Over,
+	    Binary(Add),
+	    Rot,
+	    Pop,
+	    Swap,
+	    Push(Vi32(-1)),
+	    Binary(Add),
+	    Dup,
+	    Push(Vi32(0)),
+	    Binary(Neq),
+	    Push(Vloc(2)),
+	    Branch,
+	    Pop,
+	    Halt
+	];
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi32(15)));
+    }
+
+    #[test]
+    fn test_select() {
+	for &cond in &[true, false] {
+	    let select = vec![Push(Vi32(1)), Push(Vi32(2)), Push(Vbool(cond)),
+			      Select, Halt];
+	    let branch = vec![
+		Push(Vbool(cond)),
+		Push(Vloc(5)),
+		Branch,
+		Push(Vi32(2)),
+		Jmpi(6),
+		Push(Vi32(1)),
+		Halt
+	    ];
+	    let expected = Ok(Vi32(if cond { 1 } else { 2 }));
+	    assert_eq!(run(Debug::NODEBUG, &select), expected);
+	    assert_eq!(run(Debug::NODEBUG, &branch), expected);
+	}
+	let prog = vec![Push(Vi32(1)), Push(Vi32(2)), Push(Vi32(0)), Select, Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog),
+		   Err(VmError::TypeError { expected: "bool", found: Vi32(0) }));
+    }
+
+    #[test]
+    fn test_alloci() {
+	let alloc = vec![Push(Vi32(3)), Push(Vbool(true)), Alloc,
+			 Push(Vi32(0)), Push(Vunit), Alloc, Halt];
+	let alloci = vec![Push(Vbool(true)), AllocI(3),
+			  Push(Vunit), AllocI(0), Halt];
+	let mut s1 = test_state(alloc);
+	let mut s2 = test_state(alloci);
+	assert_eq!(exec(None, &mut s1), Ok(None));
+	assert_eq!(exec(None, &mut s2), Ok(None));
+	assert_eq!(s2.heap, vec![Vsize(3), Vbool(true), Vbool(true), Vbool(true),
+				 Vsize(0)]);
+	assert_eq!(s1.heap, s2.heap);
+	assert_eq!(s1.stk, s2.stk);
+	assert_eq!(run(Debug::NODEBUG, &[Push(Vunit), AllocI(MAX_HEAP as u32), Halt]),
+		   Err(VmError::OutOfHeap));
+    }
+
+    #[test]
+    fn test_alloc_size() {
+	let alloc = |size: i32| vec![Push(Vi32(size)), Push(Vunit), Alloc, Halt];
+	assert_eq!(run(Debug::NODEBUG, &alloc(-1)), Err(VmError::BadAllocSize(-1)));
+	assert_eq!(run(Debug::NODEBUG, &alloc(i32::MIN)), Err(VmError::BadAllocSize(i32::MIN)));
+	let mut s = test_state(alloc(0));
+	assert_eq!(exec(None, &mut s), Ok(None));
+	assert_eq!((s.heap, s.stk), (vec![Vsize(0)], vec![Vaddr(0)]));
+	// The array and its header fill an empty heap exactly.
+	let cfg = VmConfig { max_heap: 64, ..VmConfig::default() };
+	assert!(run_with_config(Debug::NODEBUG, cfg, &alloc(63)).is_ok());
+	assert_eq!(run_with_config(Debug::NODEBUG, cfg, &alloc(64)), Err(VmError::BadAllocSize(64)));
+	// Sizes that could fit in an empty heap may not fit in what is
+	// left.
+	let mut prog = vec![Push(Vi32(10)), Push(Vunit), Alloc];
+	prog.extend(alloc(53));
+	assert_eq!(run_with_config(Debug::NODEBUG, cfg, &prog), Err(VmError::OutOfHeap));
+	// Sizes whose header would overflow fail without wrapping.
+	let mut s = test_state(vec![Halt]);
+	assert_eq!(s.alloc(usize::MAX), Err(VmError::OutOfHeap));
+	s.cfg.max_memory_bytes = Some(1 << 20);
+	assert!(matches!(s.alloc(usize::MAX - 1), Err(VmError::MemoryLimit { .. })));
+    }
+
+    #[test]
+    fn test_getf_setf() {
+	// Build the record {7, 8} and read back field 1.
+	let prog = vec![Push(Vi32(0)), AllocI(2),
+			Dup, Push(Vi32(7)), SetF(0),
+			Dup, Push(Vi32(8)), SetF(1),
+			Dup, GetF(1), Swap, GetF(0), Binary(Sub), Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi32(1)));
+	let prog = vec![Push(Vi32(0)), AllocI(2), GetF(2), Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog),
+		   Err(VmError::IndexOutOfBounds { index: 2, len: 2 }));
+	let prog = vec![Push(Vi32(0)), AllocI(2), Push(Vi32(1)), SetF(2), Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog),
+		   Err(VmError::IndexOutOfBounds { index: 2, len: 2 }));
+	let prog = vec![Push(Vi32(0)), GetF(0), Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog),
+		   Err(VmError::TypeError { expected: "address", found: Vi32(0) }));
+	let prog = vec![Push(Vi32(0)), Push(Vi32(1)), SetF(0), Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog),
+		   Err(VmError::TypeError { expected: "address", found: Vi32(0) }));
+    }
+
+    #[test]
+    fn test_get_set_bounds() {
+	let get = |base: Val, ix: i32| {
+	    let mut prog = vec![Push(Vi32(0)), AllocI(3), Push(Vi32(0)), AllocI(2), Pop, Pop];
+	    prog.extend(vec![Push(Vi32(ix)), Get, Halt]);
+	    run_on_stack(&[base], prog)
+	};
+	assert_eq!(get(Vaddr(4), 1), Ok(Vi32(0)));
+	assert_eq!(get(Vaddr(1000), 0), Err(VmError::HeapOutOfBounds(1000)));
+	assert_eq!(get(Vaddr(usize::MAX), 0), Err(VmError::HeapOutOfBounds(usize::MAX)));
+	assert_eq!(get(Vaddr(0), i32::MAX),
+		   Err(VmError::IndexOutOfBounds { index: i32::MAX as usize, len: 3 }));
+	assert_eq!(get(Vaddr(0), -1), Err(VmError::IndexOutOfBounds { index: usize::MAX, len: 3 }));
+	// Address 2 is inside the first array, and 4 + 1 is inside the
+	// second.
+	assert_eq!(get(Vaddr(2), 0), Err(VmError::NotAnArray(2)));
+	assert_eq!(get(Vaddr(5), 0), Err(VmError::NotAnArray(5)));
+	// A freed array's address is stale.
+	let prog = vec![Push(Vi32(0)), AllocI(2), Dup, Free,
+			Push(Vi32(0)), Push(Vi32(1)), Set, Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog), Err(VmError::NotAnArray(0)));
+	let prog = vec![Push(Vi32(0)), AllocI(2), Push(Vi32(i32::MIN)), Push(Vi32(1)), Set, Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog),
+		   Err(VmError::IndexOutOfBounds { index: i32::MIN as usize, len: 2 }));
+    }
+
+    #[test]
+    fn test_len() {
+	let prog = vec![Push(Vi32(0)), AllocI(5), Len, Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi32(5)));
+	let prog = vec![Push(Vi32(0)), AllocI(0), Len, Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi32(0)));
+	assert_eq!(run_on_stack(&[Vaddr(100)], vec![Len, Halt]), Err(VmError::HeapOutOfBounds(100)));
+	// Address 1 is the first element of the array, not a header.
+	let prog = vec![Push(Vi32(0)), AllocI(5), Pop, Len, Halt];
+	assert_eq!(run_on_stack(&[Vaddr(1)], prog), Err(VmError::NotAnArray(1)));
+	let prog = vec![Push(Vi32(0)), Len, Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog),
+		   Err(VmError::TypeError { expected: "address", found: Vi32(0) }));
+    }
+
+    /// Run an ArrCopy over two arrays [0, 1, .., 4] and [10, 11, .., 14]
+    /// (at addresses 0 and 6), returning the final heap.
+    fn run_arrcopy(dst: Address, dst_off: i32, src: Address, src_off: i32, len: i32)
+		   -> Result<Vec<Val>, VmError> {
+	// The stack starts out holding dst and src.
+	let mut prog = vec![Push(Vi32(0)), AllocI(5), Push(Vi32(0)), AllocI(5)];
+	for i in 0..5 {
+	    prog.extend(vec![Var(2), Push(Vi32(i)), SetF(i as u32)]);
+	    prog.extend(vec![Var(3), Push(Vi32(10 + i)), SetF(i as u32)]);
+	}
+	prog.extend(vec![Pop, Pop, Push(Vi32(dst_off)), Swap, Push(Vi32(src_off)),
+			 Push(Vi32(len)), ArrCopy, Push(Vunit), Halt]);
+	let mut s = test_state(prog);
+	s.stk.extend([Vaddr(dst), Vaddr(src)]);
+	exec(None, &mut s)?;
+	Ok(s.heap)
+    }
+
+    #[test]
+    fn test_arrcopy() {
+	let ints = |is: &[i32]| is.iter().map(|i| Vi32(*i)).collect::<Vec<_>>();
+	let heap = run_arrcopy(0, 1, 6, 2, 3).unwrap();
+	assert_eq!(heap[1..6], ints(&[0, 12, 13, 14, 4])[..]);
+	assert_eq!(heap[7..12], ints(&[10, 11, 12, 13, 14])[..]);
+	// Overlapping windows in the same array behave like copy_within.
+	let heap = run_arrcopy(0, 1, 0, 0, 4).unwrap();
+	assert_eq!(heap[1..6], ints(&[0, 0, 1, 2, 3])[..]);
+	let heap = run_arrcopy(0, 0, 0, 1, 4).unwrap();
+	assert_eq!(heap[1..6], ints(&[1, 2, 3, 4, 4])[..]);
+	// Empty windows are allowed, even at the end of an array.
+	assert!(run_arrcopy(0, 5, 6, 5, 0).is_ok());
+	assert_eq!(run_arrcopy(0, 0, 6, 3, 3),
+		   Err(VmError::Context("arrcopy source", Box::new(
+		       VmError::WindowOutOfBounds { off: 3, len: 3, size: 5 }))));
+	assert_eq!(run_arrcopy(0, 4, 6, 0, 2),
+		   Err(VmError::Context("arrcopy destination", Box::new(
+		       VmError::WindowOutOfBounds { off: 4, len: 2, size: 5 }))));
+	assert_eq!(run_arrcopy(0, 0, 6, 0, -1),
+		   Err(VmError::TypeError { expected: "non-negative i32", found: Vi32(-1) }));
+	assert_eq!(run_arrcopy(0, 0, 1, 0, 1),
+		   Err(VmError::Context("arrcopy source", Box::new(VmError::NotAnArray(1)))));
+    }
+
+    #[test]
+    fn test_free() {
+	// Allocate and free a 100-element array 1000 times.
+	let prog = vec![
+	    Push(Vi32(1000)),
+	    Push(Vi32(0)),
+	    AllocI(100),
+	    Free,
+	    Push(Vi32(-1)),
+	    Binary(Add),
+	    Dup,
+	    Push(Vi32(0)),
+	    Binary(Neq),
+	    Push(Vloc(1)),
+	    Branch,
+	    Halt
+	];
+	let mut s = test_state(prog);
+	assert_eq!(exec(None, &mut s), Ok(None));
+	assert_eq!(s.heap.len(), 101);
+
+	// Freed blocks are split to satisfy smaller allocations.
+	let prog = vec![Push(Vi32(0)), AllocI(4), Free,
+			Push(Vi32(1)), AllocI(1), Push(Vi32(2)), AllocI(2), Halt];
+	let mut s = test_state(prog);
+	assert_eq!(exec(None, &mut s), Ok(None));
+	assert_eq!(s.stk, vec![Vaddr(0), Vaddr(2)]);
+	assert_eq!(s.heap, vec![Vsize(1), Vi32(1), Vsize(2), Vi32(2), Vi32(2)]);
+	assert!(s.free.is_empty());
+
+	let prog = vec![Push(Vi32(0)), AllocI(4), Dup, Free, Free, Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog), Err(VmError::DoubleFree(0)));
+	let prog = vec![Push(Vi32(0)), Free, Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog),
+		   Err(VmError::TypeError { expected: "address", found: Vi32(0) }));
+	let prog = vec![Push(Vi32(0)), AllocI(4), Pop, Free, Halt];
+	assert_eq!(run_on_stack(&[Vaddr(2)], prog), Err(VmError::NotAnArray(2)));
+    }
+
+    #[test]
+    fn test_heap_stats() {
+	// Three arrays of sizes 3, 5 and 2, and a freed one of size 4.
+	let prog = [Push(Vi32(7)), AllocI(3), Push(Vi32(1)), AllocI(5), Push(Vchar('x')), AllocI(2),
+		    Push(Vi32(0)), AllocI(4), Free, Halt];
+	let stats = HeapStats { used_slots: 13, object_count: 3, largest_object: 5, free_slots: 5 };
+	let mut vm = Vm::new(&prog).unwrap();
+	assert_eq!(vm.heap_stats(), HeapStats::default());
+	assert_eq!(vm.run(), Ok(Vaddr(10)));
+	assert_eq!(vm.heap_stats(), stats);
+	assert_eq!(vm.read_array(0), Ok(vec![Vi32(7); 3]));
+	assert_eq!(vm.read_array(4), Ok(vec![Vi32(1); 5]));
+	assert_eq!(vm.read_array(10), Ok(vec![Vchar('x'); 2]));
+	assert_eq!(vm.read_array(1), Err(VmError::NotAnArray(1)));
+	assert_eq!(vm.read_array(13), Err(VmError::NotAnArray(13)));
+	assert_eq!(vm.read_array(100), Err(VmError::HeapOutOfBounds(100)));
+	let fin = run_full(Debug::NODEBUG, &prog).unwrap();
+	assert_eq!(fin.heap_stats, stats);
+	assert_eq!(fin.read_array(4), Ok(vec![Vi32(1); 5]));
+	assert_eq!(fin.read_array(2), Err(VmError::NotAnArray(2)));
+    }
+
+    #[test]
+    fn test_gc() {
+	// Allocate 1000 garbage arrays in a heap with room for five.
+	let small_heap = VmConfig { max_heap: 64, ..VmConfig::default() };
+	let garbage = |size| vec![
+	    Push(Vi32(1000)),
+	    Push(Vi32(0)), AllocI(size), Pop, Dec(0),
+	    Var(0), Push(Vi32(0)), Binary(Neq), Push(Vloc(1)), Branch,
+	    Var(0), Halt
+	];
+	assert_eq!(run_with_config(Debug::NODEBUG, small_heap, &garbage(10)),
+		   Ok(Vi32(0)));
+	assert_eq!(run_with_config(Debug::NODEBUG, small_heap, &garbage(100)),
+		   Err(VmError::OutOfHeap));
+
+	// The inner array is reachable only through the outer one.
+	let prog = vec![
+	    Push(Vi32(42)), AllocI(1), AllocI(1),
+	    Push(Vi32(50)),
+	    Push(Vi32(0)), AllocI(20), Pop, Dec(1),
+	    Var(1), Push(Vi32(0)), Binary(Neq), Push(Vloc(4)), Branch,
+	    Pop, GetF(0), GetF(0), Halt
+	];
+	let mut s = State::init(prog, Box::new(MemIo::default()), small_heap).unwrap();
+	assert_eq!(exec(None, &mut s), Ok(None));
+	assert_eq!(s.stk, vec![Vi32(42)]);
+	assert!(s.heap.len() < 64);
+
+	// Dead arrays are swept; trailing free space is returned.
+	let prog = vec![Push(Vi32(1)), AllocI(2), Push(Vi32(2)), AllocI(3),
+			Push(Vi32(3)), AllocI(1), Store(1), Halt];
+	let mut s = test_state(prog);
+	assert_eq!(exec(None, &mut s), Ok(None));
+	s.collect();
+	assert_eq!(s.free, vec![(3, 4)]);
+	assert_eq!(s.heap.len(), 9);
+	s.stk.clear();
+	s.collect();
+	assert_eq!((s.free.len(), s.heap.len()), (0, 0));
+    }
+
+    #[test]
+    fn test_gc_stress() {
+	// The only reference to the caller's array is in its frame while
+	// the callee allocates; the array moves under copying collection.
+	let prog = vec![
+	    Push(Vi32(0)), AllocI(2), Pop,
+	    Push(Vi32(5)), AllocI(3),
+	    Push(Vi32(0)), SetFrame(1), Calli(14),
+	    Var(0), GetF(2), Swap, GetF(3), Binary(Add), Halt,
+	    Push(Vi32(1)), AllocI(4), Pop, Push(Vi32(2)), AllocI(4), Ret
+	];
+	for gc in [GcKind::MarkSweep, GcKind::Copying] {
+	    let cfg = VmConfig { gc, gc_stress: true, ..VmConfig::default() };
+	    assert_eq!(run_with_config(Debug::NODEBUG, cfg, &prog), Ok(Vi32(7)));
+	}
+	let cfg = VmConfig { gc: GcKind::Copying, gc_stress: true, ..VmConfig::default() };
+	let mut s = State::init(prog, Box::new(MemIo::default()), cfg).unwrap();
+	assert_eq!(exec(None, &mut s), Ok(None));
+	assert_eq!(s.stk[0], Vaddr(0));
+    }
+
+    /// Instructions that build a linked list of vals, leaving the head
+    /// on the stack. Each node is a [value, next] array; the last
+    /// node's next is unit.
+    fn build_list(vals: &[i32]) -> Vec<Instr> {
+	let mut prog = vec![Push(Vunit)];
+	for v in vals.iter().rev() {
+	    prog.extend(vec![AllocI(2), Dup, Push(Vi32(*v)), SetF(0)]);
+	}
+	prog
+    }
+
+    /// Instructions that push the sum of the first n values of the
+    /// list whose head is in variable x.
+    fn sum_list(x: u32, n: usize) -> Vec<Instr> {
+	let mut prog = vec![Push(Vi32(0))];
+	for i in 0..n {
+	    prog.push(Var(x));
+	    prog.extend(vec![GetF(1); i]);
+	    prog.extend(vec![GetF(0), Binary(Add)]);
+	}
+	prog
+    }
+
+    #[test]
+    fn test_copying_gc() {
+	// Keep one list alive across 200 iterations that each build,
+	// check and drop another list, plus some garbage.
+	let mut prog = build_list(&[1, 2, 3, 4, 5]);
+	prog.push(Push(Vi32(200)));
+	let start = prog.len() as u32;
+	prog.extend(build_list(&[10, 20, 30, 40]));
+	prog.extend(build_list(&[7, 7]));
+	prog.push(Pop);
+	prog.extend(sum_list(2, 4));
+	prog.extend(vec![Push(Vi32(100)), Binary(Eq), Assert, Pop]);
+	prog.extend(sum_list(0, 5));
+	prog.extend(vec![Push(Vi32(15)), Binary(Eq), Assert, Dec(1)]);
+	prog.extend(vec![Var(1), Push(Vi32(0)), Binary(Neq), Push(Vloc(start)), Branch]);
+	prog.extend(sum_list(0, 5));
+	prog.push(Halt);
+	for gc in [GcKind::MarkSweep, GcKind::Copying] {
+	    let cfg = VmConfig { max_heap: 64, gc, ..VmConfig::default() };
+	    let mut s = State::init(prog.clone(), Box::new(MemIo::default()), cfg).unwrap();
+	    assert_eq!(exec(None, &mut s), Ok(None));
+	    assert_eq!(s.stk.last(), Some(&Vi32(15)));
+	}
+
+	// Shared and cyclic structure is copied once.
+	let prog = vec![Push(Vi32(0)), AllocI(1), Push(Vi32(9)), AllocI(3), Dup,
+			Dup, SetF(0), Dup, Var(0), SetF(1), Halt];
+	let cfg = VmConfig { gc: GcKind::Copying, ..VmConfig::default() };
+	let mut s = State::init(prog, Box::new(MemIo::default()), cfg).unwrap();
+	assert_eq!(exec(None, &mut s), Ok(None));
+	s.stk.swap(0, 1);
+	s.collect();
+	assert_eq!(s.stk, vec![Vaddr(0), Vaddr(4)]);
+	assert_eq!(s.array(0), Ok(&[Vaddr(0), Vaddr(4), Vi32(9)][..]));
+	assert_eq!(s.array(4), Ok(&[Vi32(0)][..]));
+	assert_eq!(s.heap.len(), 6);
+    }
+
+    #[test]
+    fn test_grow() {
+	// Build the vector [0, 1, .., 15], doubling its capacity when full.
+	let mut prog = vec![Push(Vundef), AllocI(1)];
+	let mut cap = 1;
+	for i in 0..16 {
+	    if i == cap {
+		cap *= 2;
+		prog.extend(vec![Var(0), Push(Vi32(cap)), Grow, Store(0)]);
+	    }
+	    prog.extend(vec![Var(0), Push(Vi32(i)), SetF(i as u32)]);
+	}
+	prog.push(Halt);
+	let mut s = test_state(prog);
+	assert_eq!(exec(None, &mut s), Ok(None));
+	let addr = s.stk[0].to_address().unwrap();
+	let expected: Vec<Val> = (0..16).map(Vi32).collect();
+	assert_eq!(s.array(addr), Ok(&expected[..]));
+
+	let prog = vec![Push(Vi32(7)), AllocI(2), Push(Vi32(3)), Grow, Halt];
+	let mut s = test_state(prog);
+	assert_eq!(exec(None, &mut s), Ok(None));
+	assert_eq!(s.array(3), Ok(&[Vi32(7), Vi32(7), Vundef][..]));
+	assert_eq!(s.free, vec![(0, 3)]);
+
+	let prog = vec![Push(Vi32(7)), AllocI(2), Push(Vi32(1)), Grow, Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog),
+		   Err(VmError::CannotShrink { len: 2, size: 1 }));
+	let grow = |size: i32| vec![Push(Vi32(0)), AllocI(2), Push(Vi32(size)), Grow, Halt];
+	for size in [-1, i32::MIN, MAX_HEAP as i32, i32::MAX] {
+	    assert_eq!(run(Debug::NODEBUG, &grow(size)), Err(VmError::BadAllocSize(size)));
+	}
+	assert_eq!(run(Debug::NODEBUG, &grow(MAX_HEAP as i32 - 1)), Err(VmError::OutOfHeap));
+    }
+
+    /// Count down from n, accumulating the number of steps. The
+    /// recursive call is made by the given instructions.
+    fn countdown(n: i32, call: &[Instr]) -> Result<Val, VmError> {
+	let mut prog = vec![
+	    Push(Vi32(n)),          // 0
+	    Push(Vi32(0)),          // 1
+	    SetFrame(2),            // 2
+	    Calli(5),               // 3
+	    Halt,                   // 4
+	    Var(0),                 // 5: f(n, acc)
+	    Push(Vi32(0)),          // 6
+	    Binary(Eq),             // 7
+	    Push(Vloc(0)),          // 8: patched below
+	    Branch,                 // 9
+	    Var(0),                 // 10
+	    Push(Vi32(-1)),         // 11
+	    Binary(Add),            // 12
+	    Var(1),                 // 13
+	    Push(Vi32(1)),          // 14
+	    Binary(Add),            // 15
+	];
+	prog.extend_from_slice(call);
+	prog[8] = Push(Vloc(prog.len() as u32));
+	prog.extend(vec![Var(1), Ret]);
+	run(Debug::NODEBUG, &prog)
+    }
+
+    #[test]
+    fn test_tailcall() {
+	assert_eq!(countdown(10, &[Push(Vloc(5)), TailCall(2)]), Ok(Vi32(10)));
+	assert_eq!(countdown(10, &[SetFrame(2), Calli(5), Ret]), Ok(Vi32(10)));
+	assert_eq!(countdown(1_000_000, &[Push(Vloc(5)), TailCall(2)]),
+		   Ok(Vi32(1_000_000)));
+	assert!(matches!(countdown(1_000_000, &[SetFrame(2), Calli(5), Ret]),
+			 Err(VmError::Backtrace { error, .. }) if *error == VmError::StackOverflow));
+	assert_eq!(run(Debug::NODEBUG, &[Push(Vi32(1)), Push(Vloc(0)), TailCall(1)]),
+		   Err(VmError::ShortTailCall { args: 1, depth: 1 }));
+	assert_eq!(run(Debug::NODEBUG, &[Push(Vi32(1)), Push(Vi32(2)), Push(Vloc(0)), TailCall(0)]),
+		   Err(VmError::TypeError { expected: "location for return pc", found: Vi32(2) }));
+    }
+
+    #[test]
+    fn test_check_frames() {
+	let cfg = VmConfig { check_frames: true, ..VmConfig::default() };
+	let checked = |prog: &[Instr]| run_with_config(Debug::NODEBUG, cfg, prog);
+	// Well-behaved calls, returns, tail calls, and closure calls.
+	let prog = [Push(Vi32(20)), SetFrame(1), Push(Vloc(5)), Call, Halt,
+		    Var(0), Var(0), Binary(Add), Ret];
+	assert_eq!(checked(&prog), Ok(Vi32(40)));
+	let prog = [Push(Vi32(10)), Push(Vi32(0)), SetFrame(2), Calli(5), Halt,
+		    Var(0), Push(Vi32(0)), Binary(Eq), Push(Vloc(16)), Branch,
+		    Var(0), Push(Vi32(-1)), Binary(Add), Var(1), Push(Vloc(5)), TailCall(2),
+		    Var(1), Ret];
+	assert_eq!(checked(&prog), Ok(Vi32(0)));
+	let prog = [Push(Vi32(3)), SetFrame(1), Push(Vloc(6)), AllocClos(0), CallClos, Halt,
+		    Pop, Var(0), Ret];
+	assert_eq!(checked(&prog), Ok(Vi32(3)));
+	// The compiler's sequence, which swaps the target above the
+	// saved fp.
+	let prog = [Push(Vi32(20)), Push(Vloc(6)), SetFrame(2), Swap, Call, Halt,
+		    Var(0), Var(0), Binary(Add), Ret];
+	assert_eq!(checked(&prog), Ok(Vi32(40)));
+	// Checking doesn't change the frames the machine keeps, so
+	// errors carry the same backtrace either way.
+	let prog = [Push(Vi32(20)), Push(Vloc(6)), SetFrame(2), Swap, Call, Halt,
+		    Var(0), Push(Vbool(true)), Binary(Add), Ret];
+	assert!(checked(&prog).is_err());
+	assert_eq!(checked(&prog), run(Debug::NODEBUG, &prog));
+
+	// A function that leaves its temporaries on the stack. Unchecked,
+	// the return trips over them instead.
+	let prog = [Push(Vi32(1)), SetFrame(1), Calli(4), Halt,
+		    Var(0), Var(0), Var(0), Var(0), Var(0), Binary(Add), Ret];
+	assert_eq!(checked(&prog).unwrap_err().to_string(),
+		   "ret executed with 3 extra values on the frame at pc 10 (backtrace: 3)");
+	assert_eq!(run(Debug::NODEBUG, &prog).unwrap_err().to_string(),
+		   "expected location for return pc in return, found Vi32(1) (backtrace: 3)");
+	// A call whose arguments were pushed after its SetFrame.
+	let prog = [Push(Vi32(1)), SetFrame(0), Push(Vi32(2)), Calli(5), Halt, Var(0), Ret];
+	assert_eq!(checked(&prog),
+		   Err(VmError::FrameCheck {
+		       pc: 3, msg: "calli executed with 1 extra value on the frame".into() }));
+	// A call without a SetFrame, a function that pops part of its
+	// frame, and a return with no call.
+	assert_eq!(checked(&[Push(Vi32(1)), Calli(3), Halt, Var(0), Ret]),
+		   Err(VmError::FrameCheck { pc: 1, msg: "calli executed without a setframe".into() }));
+	let prog = [Push(Vi32(1)), SetFrame(1), Calli(4), Halt, Pop, Pop, Push(Vi32(0)), Ret];
+	assert_eq!(checked(&prog),
+		   Err(VmError::FrameCheck {
+		       pc: 7, msg: "ret executed with 2 values missing from the frame".into() }));
+	assert_eq!(checked(&[Push(Vi32(1)), Push(Vi32(1)), Push(Vi32(1)), Ret]),
+		   Err(VmError::FrameCheck { pc: 3, msg: "ret executed outside any call".into() }));
+
+	// Snapshots keep track of which frames have been called.
+	let prog = vec![Push(Vi32(20)), SetFrame(1), Calli(4), Halt, Var(0), Var(0), Binary(Add), Ret];
+	let mut s = State::init(prog, Box::new(MemIo::default()), cfg).unwrap();
+	for _ in 0..4 {
+	    step(&mut s).unwrap();
+	}
+	let mut resumed = State::from_bytes(&mut s.to_bytes().into_iter()).unwrap();
+	assert_eq!((resumed.cfg.check_frames, &resumed.shadow[..]), (true, &[(1, true)][..]));
+	assert_eq!(exec(None, &mut resumed).map(|_| resumed.stk), Ok(vec![Vi32(40)]));
+    }
+
+    #[test]
+    fn test_call_depth() {
+	let limited = |max, stack_size| VmConfig {
+	    max_call_depth: Some(max), stack_size, ..VmConfig::default()
+	};
+	let unwrap_backtrace = |result: Result<Val, VmError>| match result {
+	    Err(VmError::Backtrace { error, frames }) => (*error, frames.len()),
+	    result => panic!("expected an error inside a call, got {:?}", result)
+	};
+	// A function that calls itself forever.
+	let prog = [SetFrame(0), Calli(0)];
+	assert_eq!(unwrap_backtrace(run(Debug::NODEBUG, &prog)), (VmError::StackOverflow, 512));
+	assert_eq!(unwrap_backtrace(run_with_config(Debug::NODEBUG, limited(100, 1 << 20), &prog)),
+		   (VmError::CallDepthExceeded { depth: 101, pc: 1 }, 100));
+	// A mutually recursive pair, f(n) = g(n + 1) and g(n) = f(n + 1),
+	// which calls f first.
+	let prog = [Push(Vi32(0)), SetFrame(1), Calli(4), Halt,
+		    Var(0), Push(Vi32(1)), Binary(Add), SetFrame(1), Calli(12), Ret, Nop, Nop,
+		    Var(0), Push(Vi32(1)), Binary(Add), SetFrame(1), Calli(4), Ret];
+	let err = run_with_config(Debug::NODEBUG, limited(50, STK_SIZE), &prog).unwrap_err();
+	assert_eq!(err.to_string().split(" (").next(),
+		   Some("call depth limit exceeded: 51 calls deep at pc 16"));
+
+	// Returns and tail calls leave the depth where it was.
+	let prog = vec![Push(Vi32(20)), SetFrame(1), Calli(8), Push(Vi32(1)), SetFrame(1), Calli(8),
+			Binary(Add), Halt, Var(0), Var(0), Binary(Add), Ret];
+	let mut s = State::init(prog, Box::new(MemIo::default()), limited(1, STK_SIZE)).unwrap();
+	assert_eq!(exec(None, &mut s).map(|_| (s.stk.clone(), s.call_depth)),
+		   Ok((vec![Vi32(42)], 0)));
+	let prog = [Push(Vi32(10)), Push(Vi32(0)), SetFrame(2), Calli(5), Halt,
+		    Var(0), Push(Vi32(0)), Binary(Eq), Push(Vloc(16)), Branch,
+		    Var(0), Push(Vi32(-1)), Binary(Add), Var(1), Push(Vloc(5)), TailCall(2),
+		    Var(1), Ret];
+	assert_eq!(run_with_config(Debug::NODEBUG, limited(1, STK_SIZE), &prog), Ok(Vi32(0)));
+	// The depth is in the trace.
+	let mut out = Vec::new();
+	let prog = [Push(Vi32(1)), SetFrame(1), Calli(4), Halt, Var(0), Ret];
+	assert_eq!(run_with_trace(&prog, &mut out), Ok(Vi32(1)));
+	let out = String::from_utf8(out).unwrap();
+	assert!(out.contains("pc: 4\ninstr: var 0\nfp: 0\ncall depth: 1\n"));
+	assert!(out.contains("pc: 3\ninstr: halt\nfp: 0\ncall depth: 0\n"));
+    }
+
+    #[test]
+    fn test_ret() {
+	let ret = |prog: &[Instr]| {
+	    let mut s = test_state(prog.to_vec());
+	    let r = exec(None, &mut s);
+	    (r, s.stk)
+	};
+	assert_eq!(ret(&[Push(Vloc(0)), Push(Vi32(1)), Ret]),
+		   (Err(VmError::ShortReturn(2)), vec![Vloc(0), Vi32(1)]));
+	assert_eq!(VmError::ShortReturn(2).to_string(),
+		   "return requires a result, pc and fp on the stack, found 2 values");
+	let prog = [Push(Vloc(0)), Push(Vi32(5)), Push(Vi32(1)), Ret];
+	let (r, stk) = ret(&prog);
+	assert!(matches!(r, Err(VmError::BadReturn { expected: "location for return pc",
+						     found: Vi32(5) })));
+	assert_eq!(stk, vec![Vloc(0), Vi32(5), Vi32(1)]);
+	assert_eq!(r.unwrap_err().to_string(),
+		   "expected location for return pc in return, found Vi32(5)");
+	let prog = [Push(Vi32(5)), Push(Vloc(0)), Push(Vi32(1)), Ret];
+	assert!(matches!(ret(&prog).0, Err(VmError::BadReturn { expected: "location for saved fp",
+								 found: Vi32(5) })));
+	// The saved fp must not be above the current one.
+	let prog = [Push(Vloc(1)), Push(Vloc(0)), Push(Vi32(1)), Ret];
+	assert_eq!(ret(&prog).0, Err(VmError::BadSavedFp { saved: 1, fp: 0 }));
+	// The frame pointer must be below the saved fp.
+	let prog = [Push(Vloc(0)), SetFrame(0), Pop, Push(Vloc(0)), Push(Vi32(1)), Ret];
+	assert_eq!(ret(&prog).0, Err(VmError::BadFramePointer { fp: 1, saved_at: 0 }));
+	let prog = [Push(Vi32(1)), SetFrame(1), Calli(4), Halt, Var(0), Ret];
+	assert_eq!(ret(&prog), (Ok(None), vec![Vi32(1)]));
+    }
+
+    #[test]
+    fn test_implicit_halt() {
+	let implicit = VmConfig { implicit_halt: true, ..VmConfig::default() };
+	let prog = [Push(Vi32(1)), Push(Vi32(2)), Binary(Add)];
+	assert_eq!(run(Debug::NODEBUG, &prog), Err(VmError::MissingHalt(3)));
+	assert_eq!(VmError::MissingHalt(3).to_string(), "program ended without halt");
+	assert_eq!(run_with_config(Debug::NODEBUG, implicit, &prog), Ok(Vi32(3)));
+	assert_eq!(run_with_config(Debug::NODEBUG, implicit, &[Nop]), Err(VmError::StackUnderflow));
+	// Returning past the end is corruption either way.
+	let prog = [Push(Vloc(0)), Push(Vloc(50)), Push(Vi32(1)), Ret];
+	assert_eq!(run(Debug::NODEBUG, &prog), Err(VmError::PcOutOfBounds(50)));
+	assert_eq!(run_with_config(Debug::NODEBUG, implicit, &prog), Err(VmError::PcOutOfBounds(50)));
+	assert_eq!(VmError::PcOutOfBounds(50).to_string(), "pc out of bounds: 50");
+	// The verifier lets the program fall off the end, but checks the
+	// rest.
+	let verified = VmConfig { verify: true, ..implicit };
+	assert_eq!(run_with_config(Debug::NODEBUG, verified, &[Push(Vi32(1))]), Ok(Vi32(1)));
+	assert_eq!(run_with_config(Debug::NODEBUG, verified, &[Jmpi(1)]),
+		   Err(VmError::Verify(VerifyError::BadTarget { pc: 0, target: 1 })));
+	let verified = VmConfig { verify: true, ..VmConfig::default() };
+	assert_eq!(run_with_config(Debug::NODEBUG, verified, &[Push(Vi32(1))]),
+		   Err(VmError::Verify(VerifyError::FallsOffEnd(0))));
+    }
+
+    #[test]
+    fn test_setframe() {
+	let prog = [Push(Vi32(1)), Push(Vi32(2)), SetFrame(3), Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog), Err(VmError::BadFrame { depth: 2, requested: 3 }));
+	assert_eq!(run(Debug::NODEBUG, &[SetFrame(u32::MAX), Halt]),
+		   Err(VmError::BadFrame { depth: 0, requested: u32::MAX }));
+	// Two arguments, and a function that subtracts them.
+	let prog = [Push(Vi32(10)), Push(Vi32(3)), SetFrame(2), Calli(5), Halt,
+		    Var(0), Var(1), Binary(Sub), Ret];
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi32(7)));
+	assert_eq!(run(Debug::NODEBUG, &[SetFrame(0), Halt]), Ok(Vloc(0)));
+    }
+
+    #[test]
+    fn test_var_index() {
+	let bad = |fp, offset| VmError::BadVarIndex { fp, offset };
+	assert_eq!(run(Debug::NODEBUG, &[Push(Vi32(1)), Var(1), Halt]), Err(bad(0, 1)));
+	assert_eq!(run(Debug::NODEBUG, &[Push(Vi32(1)), Push(Vi32(2)), Store(1), Halt]),
+		   Err(bad(0, 1)));
+	// With fp 1, the largest offsets overflow a u32, and a 32-bit
+	// usize. The sum is checked, so debug and release builds fail the
+	// same way instead of panicking or wrapping to a small index.
+	for instr in [Var(u32::MAX), Store(u32::MAX), Inc(u32::MAX), Dec(u32::MAX)] {
+	    let prog = [Push(Vi32(0)), SetFrame(0), Push(Vi32(0)), instr, Halt];
+	    assert_eq!(run(Debug::NODEBUG, &prog), Err(bad(1, u32::MAX)));
+	}
+	// A corrupted frame pointer, such as a restored snapshot can hold,
+	// plain and in a fused increment.
+	let mut s = test_state(vec![Push(Vi32(0)), Var(1), Halt]);
+	s.fp = u32::MAX;
+	assert_eq!(exec(None, &mut s), Err(bad(u32::MAX, 1)));
+	let prog = vec![Push(Vi32(0)), Var(0), Push(Vi32(1)), Binary(Add), Store(0), Halt];
+	let fused = VmConfig { fuse: true, ..VmConfig::default() };
+	let mut s = State::init(prog, Box::new(MemIo::default()), fused).unwrap();
+	s.fp = u32::MAX;
+	assert_eq!(exec(None, &mut s), Err(bad(u32::MAX, 0)));
+	assert_eq!(bad(7, 3).to_string(), "variable 3 past end of stack (frame pointer 7)");
+    }
+
+    #[test]
+    fn test_globals() {
+	// A function that increments a global counter and returns it.
+	let prog = vec![
+	    Globals(1),             // 0
+	    Push(Vi32(0)),          // 1
+	    GSet(0),                // 2
+	    SetFrame(0),            // 3
+	    Calli(15),              // 4
+	    Pop,                    // 5
+	    SetFrame(0),            // 6
+	    Calli(15),              // 7
+	    Pop,                    // 8
+	    SetFrame(0),            // 9
+	    Calli(15),              // 10
+	    Pop,                    // 11
+	    GGet(0),                // 12
+	    Halt,                   // 13
+	    Halt,                   // 14
+	    GGet(0),                // 15: incr()
+	    Push(Vi32(1)),          // 16
+	    Binary(Add),            // 17
+	    Dup,                    // 18
+	    GSet(0),                // 19
+	    Ret                     // 20
+	];
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi32(3)));
+	assert_eq!(run(Debug::NODEBUG, &[Globals(2), GGet(1), Halt]), Ok(Vundef));
+	assert_eq!(run(Debug::NODEBUG, &[Globals(2), GGet(2), Halt]),
+		   Err(VmError::GlobalOutOfBounds { index: 2, len: 2 }));
+	assert_eq!(run(Debug::NODEBUG, &[Push(Vi32(1)), GSet(0), Halt]),
+		   Err(VmError::GlobalOutOfBounds { index: 0, len: 0 }));
+	assert_eq!(run(Debug::NODEBUG, &[Globals(u32::MAX), Halt]), Err(VmError::OutOfHeap));
+    }
+
+    #[test]
+    fn test_closures() {
+	// add = \x. \y. x + y, applied as (add 3) 4. The partial
+	// application is kept in a global across the second SetFrame.
+	let prog = vec![
+	    Globals(1),             // 0
+	    Push(Vi32(3)),          // 1
+	    SetFrame(1),            // 2
+	    Push(Vloc(13)),         // 3
+	    AllocClos(0),           // 4: add
+	    CallClos,               // 5: add 3
+	    GSet(0),                // 6
+	    Push(Vi32(4)),          // 7
+	    SetFrame(1),            // 8
+	    GGet(0),                // 9
+	    CallClos,               // 10: (add 3) 4
+	    Halt,                   // 11
+	    Halt,                   // 12
+	    Pop,                    // 13: add(x): no captures
+	    Push(Vloc(18)),         // 14
+	    Var(0),                 // 15: capture x
+	    AllocClos(1),           // 16
+	    Ret,                    // 17
+	    GetF(1),                // 18: add_x(y): captured x
+	    Var(0),                 // 19
+	    Binary(Add),            // 20
+	    Ret                     // 21
+	];
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi32(7)));
+
+	// Closure layout: code location, then captures in push order.
+	let prog = vec![Push(Vloc(3)), Push(Vi32(1)), Push(Vbool(true)), AllocClos(2), Halt];
+	let mut s = test_state(prog);
+	assert_eq!(exec(None, &mut s), Ok(None));
+	assert_eq!(s.heap, vec![Vsize(3), Vloc(3), Vi32(1), Vbool(true)]);
+
+	assert_eq!(run(Debug::NODEBUG, &[Push(Vi32(1)), AllocClos(0), Halt]),
+		   Err(VmError::TypeError { expected: "code location", found: Vi32(1) }));
+	assert_eq!(run(Debug::NODEBUG, &[Push(Vi32(1)), AllocClos(1), Halt]),
+		   Err(VmError::ShortClosure { captures: 1, depth: 1 }));
+	let prog = vec![Push(Vi32(0)), AllocI(1), CallClos, Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog),
+		   Err(VmError::TypeError { expected: "code location in closure", found: Vi32(0) }));
+    }
+
+    #[test]
+    fn test_switch() {
+	let prog = |i| vec![Push(Vi32(i)), Switch(vec![4, 6]), Push(Vi32(0)), Halt,
+			    Push(Vi32(10)), Halt, Push(Vi32(20)), Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog(0)), Ok(Vi32(10)));
+	assert_eq!(run(Debug::NODEBUG, &prog(1)), Ok(Vi32(20)));
+	assert_eq!(run(Debug::NODEBUG, &prog(2)), Ok(Vi32(0)));
+	assert_eq!(run(Debug::NODEBUG, &prog(-1)), Ok(Vi32(0)));
+	assert_eq!(run(Debug::NODEBUG, &prog(i32::MIN)), Ok(Vi32(0)));
+	assert_eq!(run(Debug::NODEBUG, &[Push(Vi32(0)), Switch(vec![9]), Halt]),
+		   Err(VmError::BadJumpTarget { target: 9, pc: 1 }));
+	assert_eq!(run(Debug::NODEBUG, &[Push(Vbool(true)), Switch(vec![]), Halt]),
+		   Err(VmError::TypeError { expected: "i32", found: Vbool(true) }));
+	// Tables of code appended to a running program are decoded too.
+	let mut vm = Vm::new(&[Push(Vi32(1)), Switch(vec![2, 4])]).unwrap();
+	vm.extend_program(&[Push(Vi32(10)), Halt, Push(Vi32(0)), Switch(vec![6]),
+			    Push(Vi32(20)), Halt]).unwrap();
+	assert_eq!(vm.run(), Ok(Vi32(20)));
+    }
+
+    #[test]
+    fn test_fusion() {
+	// Run prog with and without fusion, checking that the results,
+	// output and final stacks agree, and that the fused trace is the
+	// unfused one without the states inside fused sequences. Returns
+	// the number of states each trace left out.
+	let check = |prog: &[Instr], cfg: VmConfig, fuel: Option<u64>| {
+	    let run = |fuse| {
+		let io = MemIo::default();
+		let mut s = State::init(prog.to_vec(), Box::new(io.clone()), VmConfig { fuse, ..cfg }).unwrap();
+		s.fuel = fuel;
+		let mut trace = Vec::new();
+		let r = exec(Some(&mut trace), &mut s);
+		let trace = String::from_utf8(trace).unwrap();
+		(r, s.stk, io.output(), trace.split("\n\n").map(String::from).collect::<Vec<_>>())
+	    };
+	    let ((r1, stk1, out1, trace1), (r2, stk2, out2, trace2)) = (run(false), run(true));
+	    assert_eq!((r1, stk1, out1), (r2, stk2, out2));
+	    let mut rest = trace1.iter();
+	    assert!(trace2.iter().all(|state| rest.any(|s| s == state)));
+	    trace1.len() - trace2.len()
+	};
+	let cfg = VmConfig::default();
+	// Count to 5, printing each number.
+	let prog = [
+	    Push(Vi32(0)),
+	    Var(0), Push(Vi32(1)), Binary(Add), Store(0),
+	    Var(0), Print, Var(0), Push(Vi32(5)), Binary(Lt), Push(Vloc(1)), Branch,
+	    Var(0), Halt
+	];
+	assert_eq!(check(&prog, cfg, None), 25);
+	assert_eq!(check(&prog, cfg, Some(1000)), 0);
+	let mut until = prog.to_vec();
+	until[9] = Binary(Ge);
+	until[11] = BranchNot;
+	assert_eq!(check(&until, cfg, None), 25);
+	// A jump into the middle of a fused sequence.
+	check(&[Push(Vi32(1)), Push(Vi32(10)), Push(Vloc(5)), Jmp,
+		Push(Vi32(2)), Binary(Sub), Halt], cfg, None);
+	// Errors inside fused sequences.
+	check(&[Push(Vi32(1)), Push(Vi32(0)), Binary(Div), Halt], cfg, None);
+	check(&[Push(Vi32(1)), Binary(Add), Halt], cfg, None);
+	check(&[Push(Vbool(true)), Var(0), Push(Vi32(1)), Binary(Add), Store(0), Halt], cfg, None);
+	check(&[Var(3), Push(Vi32(1)), Binary(Add), Store(3), Halt], cfg, None);
+	let checked = VmConfig { arithmetic: Arithmetic::Checked, ..cfg };
+	check(&[Push(Vi32(i32::MAX)), Var(0), Push(Vi32(1)), Binary(Add), Store(0), Halt],
+	      checked, None);
+	check(&[Push(Vi32(1)), Push(Vloc(0)), Branch, Halt], cfg, None);
+	check(&[Push(Vbool(true)), Push(Vloc(9)), Branch, Halt], cfg, None);
+	check(&[Push(Vbool(true)), Push(Vloc(3)), Swap, Push(Vloc(5)), Branch, Halt], cfg, None);
+	// Sequences whose pushes might fail run unfused.
+	let small = VmConfig { stack_size: 2, ..cfg };
+	assert_eq!(check(&[Push(Vi32(1)), Push(Vi32(2)), Push(Vi32(3)), Binary(Add), Halt],
+			 small, None), 0);
+	let limited = VmConfig { max_memory_bytes: Some(32), ..cfg };
+	assert_eq!(check(&[Push(Vi32(1)), Push(Vi32(2)), Push(Vi32(3)), Binary(Add), Halt],
+			 limited, None), 0);
+    }
+
+    #[test]
+    fn test_divmod() {
+	// 17 divmod 3 leaves the remainder on top of the quotient.
+	let prog = vec![Push(Vi32(17)), Push(Vi32(3)), DivMod, Halt];
+	let mut s = test_state(prog);
+	assert_eq!(exec(None, &mut s), Ok(None));
+	assert_eq!(s.stk, vec![Vi32(5), Vi32(2)]);
+	let prog = vec![Push(Vi32(-7)), Push(Vi32(2)), DivMod, Halt];
+	let mut s = test_state(prog);
+	assert_eq!(exec(None, &mut s), Ok(None));
+	assert_eq!(s.stk, vec![Vi32(-3), Vi32(-1)]);
+	assert_eq!(run(Debug::NODEBUG, &[Push(Vi32(1)), Push(Vi32(0)), DivMod, Halt]),
+		   Err(VmError::DivByZero(2)));
+	let overflow = [Push(Vi32(i32::MIN)), Push(Vi32(-1)), DivMod, Halt];
+	assert_eq!(run(Debug::NODEBUG, &overflow), Err(VmError::Overflow));
+	let checked = VmConfig { arithmetic: Arithmetic::Checked, ..VmConfig::default() };
+	assert_eq!(run_with_config(Debug::NODEBUG, checked, &overflow), Err(VmError::Overflow));
+	assert_eq!(run(Debug::NODEBUG, &[Push(Vi32(1)), Push(Vbool(true)), DivMod, Halt]),
+		   Err(VmError::TypeError { expected: "i32", found: Vbool(true) }));
+
+	// Write the decimal digits of 1234 into a 4-element array.
+	let prog = vec![
+	    Push(Vi32(0)),          // 0
+	    AllocI(4),              // 1: arr
+	    Push(Vi32(1234)),       // 2: n
+	    Push(Vi32(3)),          // 3: i
+	    Var(1),                 // 4: loop
+	    Push(Vi32(10)),         // 5
+	    DivMod,                 // 6: q, r
+	    Var(0),                 // 7
+	    Var(2),                 // 8
+	    Var(4),                 // 9
+	    Set,                    // 10: arr[i] = r
+	    Pop,                    // 11
+	    Store(1),               // 12: n = q
+	    Var(2),                 // 13
+	    Push(Vi32(-1)),         // 14
+	    Binary(Add),            // 15
+	    Store(2),               // 16: i = i - 1
+	    Var(2),                 // 17
+	    Push(Vi32(-1)),         // 18
+	    Binary(Neq),            // 19
+	    Push(Vloc(4)),          // 20
+	    Branch,                 // 21
+	    Var(0),                 // 22
+	    Halt                    // 23
+	];
+	let mut s = test_state(prog);
+	assert_eq!(exec(None, &mut s), Ok(None));
+	assert_eq!(s.array(0), Ok(&[Vi32(1), Vi32(2), Vi32(3), Vi32(4)][..]));
+    }
+
+    #[test]
+    fn test_pushpc() {
+	// The pushed location is that of the next instruction.
+	assert_eq!(run(Debug::NODEBUG, &[Nop, PushPC, Halt]), Ok(Vloc(2)));
+
+	// Save the pc, jump away to a block that doubles x, and have the
+	// block branch back to the saved location until x reaches 160.
+	let prog = vec![
+	    Push(Vi32(5)),          // 0: x
+	    PushPC,                 // 1: saved
+	    Jmpi(4),                // 2
+	    Halt,                   // 3
+	    Var(0),                 // 4: block
+	    Var(0),                 // 5
+	    Binary(Add),            // 6
+	    Store(0),               // 7
+	    Var(0),                 // 8
+	    Push(Vi32(160)),        // 9
+	    Binary(Neq),            // 10
+	    Var(1),                 // 11
+	    Branch,                 // 12
+	    Var(0),                 // 13
+	    Halt                    // 14
+	];
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi32(160)));
+    }
+
+    /// Count from 0 to 1000, incrementing with the given instructions.
+    fn count_loop(incr: &[Instr]) -> Vec<Instr> {
+	let mut prog = vec![Push(Vi32(0))];
+	prog.extend_from_slice(incr);
+	prog.extend(vec![Var(0), Push(Vi32(1000)), Binary(Neq),
+			 Push(Vloc(1)), Branch, Var(0), Halt]);
+	prog
+    }
+
+    #[test]
+    fn test_inc_dec() {
+	let slow = count_loop(&[Var(0), Push(Vi32(1)), Binary(Add), Store(0)]);
+	let fast = count_loop(&[Inc(0)]);
+	assert_eq!(run(Debug::NODEBUG, &slow), Ok(Vi32(1000)));
+	assert_eq!(run(Debug::NODEBUG, &fast), Ok(Vi32(1000)));
+	// Each of the 1000 iterations executes three fewer instructions.
+	assert_eq!(slow.len() - fast.len(), 3);
+
+	let prog = vec![Push(Vi32(1)), Push(Vi32(5)), Dec(1), Dec(1), Inc(0), Halt];
+	let mut s = test_state(prog);
+	assert_eq!(exec(None, &mut s), Ok(None));
+	assert_eq!(s.stk, vec![Vi32(2), Vi32(3)]);
+
+	assert_eq!(run(Debug::NODEBUG, &[Push(Vi32(0)), Inc(1), Halt]),
+		   Err(VmError::BadVarIndex { fp: 0, offset: 1 }));
+	assert_eq!(run(Debug::NODEBUG, &[Push(Vbool(true)), Dec(0), Halt]),
+		   Err(VmError::TypeError { expected: "i32", found: Vbool(true) }));
+	// Overflow follows the configured arithmetic, as Binary(Add) does.
+	let checked = VmConfig { arithmetic: Arithmetic::Checked, ..VmConfig::default() };
+	for cfg in [VmConfig::default(), checked] {
+	    let want = |v| match cfg.arithmetic {
+		Arithmetic::Wrapping => Ok(v),
+		Arithmetic::Checked => Err(VmError::Overflow)
+	    };
+	    let inc = [Push(Vi32(i32::MAX)), Inc(0), Halt];
+	    assert_eq!(run_with_config(Debug::NODEBUG, cfg, &inc), want(Vi32(i32::MIN)));
+	    let dec = [Push(Vi32(i32::MIN)), Dec(0), Halt];
+	    assert_eq!(run_with_config(Debug::NODEBUG, cfg, &dec), want(Vi32(i32::MAX)));
+	    let add = [Push(Vi32(i32::MAX)), Var(0), Push(Vi32(1)), Binary(Add), Store(0), Halt];
+	    assert_eq!(run_with_config(Debug::NODEBUG, cfg, &add), want(Vi32(i32::MIN)));
+	}
+    }
+
+    /// A thread that increments element 0 of the array in global g n
+    /// times, yielding after each increment, then halts with the
+    /// element's final value.
+    fn incr_thread(start: u32, g: u32, n: i32) -> Vec<Instr> {
+	vec![
+	    Push(Vi32(n)),          // 0: counter
+	    GGet(g),                // 1: loop
+	    GGet(g),                // 2
+	    GetF(0),                // 3
+	    Push(Vi32(1)),          // 4
+	    Binary(Add),            // 5
+	    SetF(0),                // 6
+	    Yield,                  // 7
+	    Dec(0),                 // 8
+	    Var(0),                 // 9
+	    Push(Vi32(0)),          // 10
+	    Binary(Neq),            // 11
+	    Push(Vloc(start + 1)),  // 12
+	    Branch,                 // 13
+	    GGet(g),                // 14
+	    GetF(0),                // 15
+	    Halt                    // 16
+	]
+    }
+
+    #[test]
+    fn test_threads() {
+	let mut prog = vec![
+	    Globals(2),             // 0
+	    Push(Vi32(0)),          // 1
+	    AllocI(1),              // 2
+	    GSet(0),                // 3
+	    Push(Vi32(0)),          // 4
+	    AllocI(1),              // 5
+	    GSet(1),                // 6
+	    Push(Vloc(15)),         // 7
+	    Spawn,                  // 8: thread 1
+	    Push(Vloc(32)),         // 9
+	    Spawn,                  // 10: thread 2
+	    Join,                   // 11
+	    Swap,                   // 12
+	    Join,                   // 13
+	    Halt                    // 14
+	];
+	prog.extend(incr_thread(15, 0, 3));
+	prog.extend(incr_thread(32, 1, 5));
+	let mut s = test_state(prog);
+	assert_eq!(exec(None, &mut s), Ok(None));
+	assert_eq!(s.stk, vec![Vi32(5), Vi32(3)]);
+	assert_eq!(s.heap, vec![Vsize(1), Vi32(3), Vsize(1), Vi32(5)]);
+
+	// Yield with a single thread just continues.
+	assert_eq!(run(Debug::NODEBUG, &[Push(Vi32(1)), Yield, Halt]), Ok(Vi32(1)));
+	// Joining a thread that has already halted.
+	let prog = vec![Push(Vloc(6)), Spawn, Dup, Join, Swap, Join, Push(Vi32(7)), Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi32(7)));
+    }
+
+    #[test]
+    fn test_thread_errors() {
+	assert_eq!(run(Debug::NODEBUG, &[Push(Vi32(0)), Join, Halt]),
+		   Err(VmError::SelfJoin(0)));
+	assert_eq!(run(Debug::NODEBUG, &[Push(Vi32(3)), Join, Halt]),
+		   Err(VmError::UnknownThread(3)));
+	// Main joins thread 1, which joins main.
+	let prog = vec![Push(Vloc(4)), Spawn, Join, Halt, Push(Vi32(0)), Join, Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog), Err(VmError::Deadlock));
+	assert_eq!(run(Debug::NODEBUG, &[Push(Vloc(5)), Spawn, Halt]),
+		   Err(VmError::BadJumpTarget { target: 5, pc: 1 }));
+    }
+
+    #[test]
+    fn test_config() {
+	// Push 2000 values.
+	let deep = vec![Push(Vi32(0)), Push(Vunit), Inc(0), Var(0), Push(Vi32(2000)),
+			Binary(Neq), Push(Vloc(1)), Branch, Var(0), Halt];
+	let big_stack = VmConfig { stack_size: 4096, ..VmConfig::default() };
+	assert_eq!(run(Debug::NODEBUG, &deep), Err(VmError::StackOverflow));
+	assert_eq!(run_with_config(Debug::NODEBUG, big_stack, &deep), Ok(Vi32(2000)));
+	let small_stack = VmConfig { stack_size: 2, ..VmConfig::default() };
+	let prog = vec![Push(Vi32(1)), Push(Vi32(2)), Push(Vi32(3)), Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi32(3)));
+	assert_eq!(run_with_config(Debug::NODEBUG, small_stack, &prog),
+		   Err(VmError::StackOverflow));
+
+	let big = vec![Push(Vi32(0)), AllocI(2000), Push(Vi32(1999)), Get, Halt];
+	let big_heap = VmConfig { max_heap: 4096, ..VmConfig::default() };
+	assert_eq!(run(Debug::NODEBUG, &big), Err(VmError::OutOfHeap));
+	assert_eq!(run_with_config(Debug::NODEBUG, big_heap, &big), Ok(Vi32(0)));
+	let small_heap = VmConfig { max_heap: 8, ..VmConfig::default() };
+	let prog = vec![Push(Vi32(0)), AllocI(10), Len, Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi32(10)));
+	assert_eq!(run_with_config(Debug::NODEBUG, small_heap, &prog),
+		   Err(VmError::OutOfHeap));
+    }
+
+    #[test]
+    fn test_growable_heap() {
+	// Allocate 10k slots in 100 live arrays.
+	let mut prog = vec![];
+	for _ in 0..100 {
+	    prog.extend(vec![Push(Vi32(0)), AllocI(99)]);
+	}
+	prog.extend(vec![Push(Vi32(98)), Get, Halt]);
+	let big_heap = VmConfig { max_heap: 20_000, ..VmConfig::default() };
+	let mut s = State::init(prog.clone(), Box::new(MemIo::default()), big_heap).unwrap();
+	assert!(s.heap.capacity() < 100);
+	assert_eq!(exec(None, &mut s), Ok(None));
+	assert_eq!(s.heap.len(), 10_000);
+	assert!(s.heap.capacity() <= 20_000);
+	assert_eq!(s.stk.last(), Some(&Vi32(0)));
+	let small_heap = VmConfig { max_heap: 5_000, ..VmConfig::default() };
+	assert_eq!(run_with_config(Debug::NODEBUG, small_heap, &prog),
+		   Err(VmError::OutOfHeap));
+
+	let prog = vec![Push(Vi32(0)), AllocI(2), Push(Vi32(2)), Get, Halt];
+	assert_eq!(run_with_config(Debug::NODEBUG, big_heap, &prog),
+		   Err(VmError::IndexOutOfBounds { index: 2, len: 2 }));
+	let mut s = State::init(vec![Push(Vi32(0)), Get, Halt], Box::new(MemIo::default()),
+				big_heap).unwrap();
+	s.stk.push(Vaddr(500));
+	assert_eq!(exec(None, &mut s), Err(VmError::HeapOutOfBounds(500)));
+    }
+
+    #[test]
+    fn test_fuel() {
+	let prog = vec![Push(Vi32(0)), Jmpi(0)];
+	assert_eq!(run_with_fuel(Debug::NODEBUG, &prog, 1000),
+		   Err(VmError::OutOfFuel(0)));
+	let prog = vec![Nop, Jmpi(0)];
+	assert_eq!(run_with_fuel(Debug::NODEBUG, &prog, 1001),
+		   Err(VmError::OutOfFuel(1)));
+
+	// The count loop executes 1 + 1000 * 6 + 2 instructions.
+	let prog = count_loop(&[Inc(0)]);
+	for _ in 0..3 {
+	    assert_eq!(run_with_fuel(Debug::NODEBUG, &prog, 6003), Ok(Vi32(1000)));
+	    assert_eq!(run_with_fuel(Debug::NODEBUG, &prog, 6002),
+		       Err(VmError::OutOfFuel(8)));
+	}
+	assert_eq!(run_with_fuel(Debug::NODEBUG, &[Push(Vi32(1)), Halt], 0),
+		   Err(VmError::OutOfFuel(0)));
+    }
+
+    #[test]
+    fn test_timeout() {
+	let limit = Duration::from_millis(50);
+	let start = Instant::now();
+	let err = run_with_timeout(Debug::NODEBUG, &[Nop, Jmpi(0)], limit).unwrap_err();
+	let took = start.elapsed();
+	match err {
+	    VmError::Timeout { elapsed, instructions } => {
+		assert!(elapsed >= limit && elapsed <= took);
+		assert_eq!(instructions % DEADLINE_INTERVAL, 0)
+	    }
+	    err => panic!("expected a timeout, got {:?}", err)
+	}
+	assert!(took < 4 * limit, "took {:?}", took);
+
+	// The count loop finishes long before its limit.
+	let prog = count_loop(&[Inc(0)]);
+	assert_eq!(run_with_timeout(Debug::NODEBUG, &prog, Duration::from_secs(10)),
+		   Ok(Vi32(1000)));
+	assert_eq!(run_with_timeout(Debug::NODEBUG, &prog, Duration::ZERO), Ok(Vi32(1000)));
+    }
+
+    #[test]
+    fn test_memory_limit() {
+	let slot = mem::size_of::<Val>();
+	let cfg = VmConfig { max_memory_bytes: Some(50 * slot), ..VmConfig::default() };
+	// Heap growth.
+	assert_eq!(run_with_config(Debug::NODEBUG, cfg, &[Push(Vi32(0)), AllocI(100), Halt]),
+		   Err(VmError::MemoryLimit { used: slot, requested: 101 * slot,
+					      limit: 50 * slot }));
+	assert!(run_with_config(Debug::NODEBUG, cfg, &[Push(Vi32(0)), AllocI(40), Halt]).is_ok());
+	// Garbage is collected to stay under the limit.
+	let garbage = [
+	    Push(Vi32(100)),
+	    Push(Vi32(0)), AllocI(20), Pop, Dec(0),
+	    Var(0), Push(Vi32(0)), Binary(Neq), Push(Vloc(1)), Branch,
+	    Var(0), Halt
+	];
+	assert_eq!(run_with_config(Debug::NODEBUG, cfg, &garbage), Ok(Vi32(0)));
+	// Stack growth.
+	assert_eq!(run_with_config(Debug::NODEBUG, cfg, &[Push(Vi32(1)), Jmpi(0)]),
+		   Err(VmError::MemoryLimit { used: 50 * slot, requested: slot,
+					      limit: 50 * slot }));
+
+	let mut vm = Vm::new(&[Push(Vi32(1)), Push(Vi32(0)), AllocI(3), Halt]).unwrap();
+	assert_eq!(vm.memory_usage(), 0);
+	vm.run().unwrap();
+	assert_eq!(vm.memory_usage(), (4 + 2) * slot);
+    }
+
+    #[test]
+    fn test_gas() {
+	// 1 + 1 + (1 + 3) + 1 + 1 + 1.
+	let prog = [Push(Vi32(3)), Push(Vi32(0)), Alloc, Pop, Push(Vi32(1)), Halt];
+	assert_eq!(run_metered(&prog, 100), Ok((Vi32(1), 9)));
+	assert_eq!(run_metered(&prog, 9), Ok((Vi32(1), 9)));
+	assert_eq!(run_metered(&prog, 8), Err(VmError::OutOfGas { pc: 5, used: 8 }));
+	assert_eq!(run_metered(&prog, 5), Err(VmError::OutOfGas { pc: 2, used: 2 }));
+
+	// Copies are charged by length: two 4-element arrays, then an
+	// 8-element concatenation and a 3-element copy.
+	let prog = [
+	    Push(Vi32(0)), AllocI(4), Push(Vi32(0)), AllocI(4), SConcat,
+	    Dup, Push(Vi32(0)), Swap, Push(Vi32(4)), Push(Vi32(3)), ArrCopy,
+	    Push(Vunit), Halt
+	];
+	assert_eq!(run_metered(&prog, 1000), Ok((Vunit, 13 + 4 + 4 + 8 + 3)));
+
+	let mut cfg = VmConfig::default();
+	cfg.gas_costs.base[Pop.opcode() as usize] = 10;
+	cfg.gas_costs.per_slot = 0;
+	let prog = [Push(Vi32(3)), Push(Vi32(0)), Alloc, Pop, Push(Vi32(1)), Halt];
+	assert_eq!(run_metered_with_config(cfg, &prog, 100), Ok((Vi32(1), 15)));
+	assert_eq!(VmError::OutOfGas { pc: 5, used: 8 }.to_string(),
+		   "out of gas at pc 5 after using 8");
+    }
+
+    #[test]
+    fn test_vm_step() {
+	let prog = vec![Push(Vi32(2)), Push(Vi32(3)), Binary(Add), Dup, Binary(Mul),
+			Push(Vi32(0)), AllocI(2), Pop, Nop, Halt];
+	let mut vm = Vm::new(&prog).unwrap();
+	let mut outcomes = vec![];
+	loop {
+	    let pc = vm.pc();
+	    let outcome = vm.step().unwrap();
+	    let done = outcome != StepOutcome::Continue;
+	    outcomes.push(outcome);
+	    if done {
+		break
+	    }
+	    assert_ne!(vm.pc(), pc);
+	}
+	assert_eq!(outcomes.len(), 10);
+	assert!(outcomes[..9].iter().all(|o| *o == StepOutcome::Continue));
+	assert_eq!(outcomes[9], StepOutcome::Halted(Vi32(25)));
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi32(25)));
+	assert_eq!(vm.pc(), 10);
+	assert_eq!(vm.fp(), 0);
+	assert_eq!(vm.stack(), &[Vi32(25)]);
+	assert_eq!(vm.heap(), &[Vsize(2), Vi32(0), Vi32(0)]);
+
+	let mut vm = Vm::new(&[Push(Vi32(4)), Exit]).unwrap();
+	assert_eq!(vm.step(), Ok(StepOutcome::Continue));
+	assert_eq!(vm.step(), Ok(StepOutcome::Exited(4)));
+	let mut vm = Vm::new(&[Pop]).unwrap();
+	assert_eq!(vm.step(), Err(VmError::StackUnderflow));
+    }
+
+    #[test]
+    fn test_run_many() {
+	fn is_send<T: Send>() {}
+	is_send::<Vm>();
+	is_send::<MemIo>();
+
+	// A mix of loops, arithmetic, and failures.
+	let programs: Vec<Vec<Instr>> = (0..1000).map(|i| match i % 4 {
+	    0 => count_loop(&[Inc(0)]),
+	    1 => vec![Push(Vi32(i)), Push(Vi32(i % 7)), Binary(Div), Halt],
+	    2 => vec![Push(Vi32(0)), AllocI(i as u32 % 50), Len, Halt],
+	    _ => vec![Push(Vi32(i)), Push(Vloc(i as u32)), Jmp]
+	}).collect();
+	let sequential: Vec<_> = programs.iter().map(|p| run(Debug::NODEBUG, p)).collect();
+	assert!(sequential.iter().any(Result::is_err));
+	assert_eq!(run_many(&programs, 8), sequential);
+	assert_eq!(run_many(&programs[..3], 0), sequential[..3]);
+	assert_eq!(run_many(&[], 8), []);
+    }
+
+    #[test]
+    fn test_shared_program() {
+	let prog = SharedProgram::new(vec![Push(Vi32(2)), Push(Vi32(3)), Binary(Mul), Halt]).unwrap();
+	let mut vms: Vec<Vm> = (0..3).map(|_| Vm::with_shared_program(&prog)).collect();
+	// The machines share the decoded program too.
+	assert_eq!(Arc::strong_count(&prog.0), 4);
+	assert!(vms.iter().all(|vm| Arc::ptr_eq(&vm.s.prog, &prog.0)));
+	for vm in &mut vms {
+	    assert_eq!(vm.run(), Ok(Vi32(6)))
+	}
+	// Extending one machine's program leaves the others alone.
+	let mut vm = Vm::with_shared_program(&prog);
+	vm.extend_program(&[Nop]).unwrap();
+	assert_eq!((vm.program_len(), vms[0].program_len()), (5, 4));
+	assert_eq!(&prog[..], [Push(Vi32(2)), Push(Vi32(3)), Binary(Mul), Halt]);
+    }
+
+    #[test]
+    fn test_illegal_push() {
+	// Addresses and sizes are rejected as constants whether or not
+	// the program is verified.
+	let illegal = |pc, val| VmError::Verify(VerifyError::IllegalConstant { pc, val });
+	assert_eq!(run(Debug::NODEBUG, &[Push(Vaddr(0)), Halt]), Err(illegal(0, Vaddr(0))));
+	assert_eq!(Vm::new(&[Nop, Push(Vsize(3)), Halt]).err(), Some(illegal(1, Vsize(3))));
+	assert!(SharedProgram::new(vec![Push(Vaddr(1))]).is_err());
+	let mut vm = Vm::new(&[Push(Vi32(1))]).unwrap();
+	assert_eq!(vm.extend_program(&[Nop, Push(Vaddr(2))]), Err(illegal(2, Vaddr(2))));
+	assert_eq!(vm.program_len(), 1);
+    }
+
+    #[test]
+    fn test_vm_with_config() {
+	let implicit = VmConfig { implicit_halt: true, verify: true, ..VmConfig::default() };
+	let prog = [Push(Vi32(1)), Push(Vi32(2)), Binary(Add)];
+	assert_eq!(Vm::with_config(&prog, implicit).and_then(|mut vm| vm.run()), Ok(Vi32(3)));
+	assert_eq!(Vm::new(&prog).unwrap().run(), Err(VmError::MissingHalt(3)));
+	assert_eq!(run_profiled_with_config(implicit, &prog, false).0, Ok(Vi32(3)));
+	// Verification happens up front.
+	let verified = VmConfig { verify: true, ..VmConfig::default() };
+	assert!(matches!(Vm::with_config(&[Jmpi(7), Halt], verified), Err(VmError::Verify(_))));
+    }
+
+    /// A writer that always fails.
+    struct BrokenPipe;
+
+    impl Write for BrokenPipe {
+	fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+	    Err(io::ErrorKind::BrokenPipe.into())
+	}
+	fn flush(&mut self) -> io::Result<()> {
+	    Ok(())
+	}
+    }
+
+    #[test]
+    fn test_trace() {
+	let prog = vec![Push(Vi32(1)), Push(Vi32(2)), Binary(Add), Halt];
+	let mut out = Vec::new();
+	assert_eq!(run_with_trace(&prog, &mut out), Ok(Vi32(3)));
+	let out = String::from_utf8(out).unwrap();
+	assert!(out.lines().next().unwrap().contains("pc: 0"));
+	// One state per executed instruction.
+	assert_eq!(out.matches("pc: ").count(), 4);
+	assert!(out.contains("pc: 3\ninstr: halt"));
+	assert_eq!(run_with_trace(&prog, &mut BrokenPipe),
+		   Err(VmError::Trace("broken pipe".into())));
+    }
+
+    #[test]
+    fn test_json_trace() {
+	// Sum 3 + 2 + 1 in a countdown loop.
+	let prog = vec![Push(Vi32(0)), Push(Vi32(3)),
+			Var(0), Var(1), Binary(Add), Store(0), Dec(1),
+			Var(1), Push(Vi32(0)), Binary(Gt), Push(Vloc(2)), Branch,
+			Pop, Push(Vchar('"')), Pop, Halt];
+	let mut out = Vec::new();
+	assert_eq!(run_with_trace_format(&prog, &mut out, TraceFormat::Json), Ok(Vi32(6)));
+	let steps: Vec<serde_json::Value> = String::from_utf8(out).unwrap().lines()
+	    .map(|line| serde_json::from_str(line).unwrap())
+	    .collect();
+	let pcs: Vec<u64> = steps.iter().map(|step| step["pc"].as_u64().unwrap()).collect();
+	let mut expected = vec![0, 1];
+	for _ in 0..3 {
+	    expected.extend(2..12)
+	}
+	expected.extend(12..16);
+	assert_eq!(pcs, expected);
+	assert_eq!(steps[4], serde_json::json!({
+	    "pc": 4, "instr": "binary +", "fp": 0, "call_depth": 0, "stack_depth": 4,
+	    "stack_top": ["0", "3", "0", "3"]
+	}));
+	assert_eq!(steps[steps.len() - 2]["stack_top"], serde_json::json!(["6", "'\"'"]));
+    }
+
+    #[test]
+    fn test_trace_bounds() {
+	// Fill a 500-element array, with 20 values below it on the stack.
+	let mut prog = vec![Push(Vi32(0)); 20];
+	prog.extend(vec![Push(Vi32(0)), AllocI(500), Push(Vi32(500)),
+			 Dec(21), Var(20), Var(21), Var(21), Set,
+			 Var(21), Push(Vi32(0)), Binary(Gt), Push(Vloc(23)), Branch, Halt]);
+	let cfg = VmConfig { max_heap: 4096, ..VmConfig::default() };
+	let mut s = State::init(prog.clone(), Box::new(MemIo::default()), cfg).unwrap();
+	let mut out = Vec::new();
+	assert_eq!(exec(Some(&mut out), &mut s), Ok(None));
+	let out = String::from_utf8(out).unwrap();
+	let steps: Vec<&str> = out.split("\n\n").filter(|step| !step.is_empty()).collect();
+	assert!(steps.len() > 500 * 10);
+	assert!(steps.iter().all(|step| step.len() < 300));
+	// Having just written element 251, the heap is shown around it.
+	assert!(out.contains("instr: set\nfp: 0\ncall depth: 0\nstk: [... 17 more, 0, 0, 0, addr:0, 250, addr:0, 250, 250]\n\
+			      heap: [... 249 more, 249: 0, 250: 0, 251: 0, 252: 251, \
+			      253: 252, 254: 253, 255: 254, 256: 255, ... 244 more]\nheap size: 501"));
+	// The number of values shown is configurable.
+	let cfg = VmConfig { trace_depth: 2, ..cfg };
+	let mut s = State::init(prog, Box::new(MemIo::default()), cfg).unwrap();
+	let mut out = Vec::new();
+	assert_eq!(exec(Some(&mut out), &mut s), Ok(None));
+	let out = String::from_utf8(out).unwrap();
+	assert!(out.contains("stk: [... 23 more, 250, 250]\nheap: [... 252 more, 252: 251, 253: 252, \
+			      ... 247 more]"));
+    }
+
+    #[test]
+    fn test_hooks() {
+	let prog = vec![Push(Vi32(1)), Jmpi(3), Push(Vi32(2)), Dup, Binary(Add), Halt];
+	let pcs = Arc::new(Mutex::new(vec![]));
+	let mut vm = Vm::new(&prog).unwrap();
+	let seen = pcs.clone();
+	vm.set_hook(move |e| {
+	    seen.lock().unwrap().push((e.pc, e.instr.clone()));
+	    Ok(())
+	});
+	assert_eq!(vm.run(), Ok(Vi32(2)));
+	assert_eq!(*pcs.lock().unwrap(), vec![(0, Push(Vi32(1))), (1, Jmpi(3)), (3, Dup),
+				       (4, Binary(Add)), (5, Halt)]);
+
+	// Abort once the stack holds more than three values.
+	let prog = vec![Push(Vunit), Jmpi(0)];
+	let mut vm = Vm::new(&prog).unwrap();
+	vm.set_hook(|e| if e.stack_depth > 3 {
+	    Err(format!("stack depth {} at pc {}", e.stack_depth, e.pc).into())
+	} else {
+	    Ok(())
+	});
+	assert_eq!(vm.run(), Err(VmError::Other("stack depth 4 at pc 1".into())));
+	assert_eq!(vm.stack().len(), 4);
+    }
+
+    #[test]
+    fn test_stats() {
+	let (v, stats) = run_with_stats(Debug::NODEBUG, &count_loop(&[Inc(0)])).unwrap();
+	assert_eq!(v, Vi32(1000));
+	assert_eq!(stats.total, 6003);
+	assert_eq!(stats.count(&Inc(0)), 1000);
+	assert_eq!(stats.count(&Branch), 1000);
+	assert_eq!(stats.count(&Var(0)), 1001);
+	assert_eq!(stats.count(&Halt), 1);
+	assert_eq!(stats.count(&Call), 0);
+	assert_eq!(stats.max_stack, 3);
+	assert_eq!(stats.calls, 0);
+
+	let prog = vec![Push(Vi32(0)), AllocI(4), Pop, Push(Vi32(20)), SetFrame(1),
+			Calli(7), Halt, Var(0), Var(0), Binary(Add), Ret];
+	let (v, stats) = run_with_stats(Debug::NODEBUG, &prog).unwrap();
+	assert_eq!(v, Vi32(40));
+	assert_eq!(stats.calls, 1);
+	assert_eq!(stats.count(&Binary(Mul)), 1);
+	assert_eq!(stats.max_stack, 5);
+	assert_eq!(stats.max_heap, 5);
+	assert_eq!(stats.total, 11);
+    }
+
+    #[test]
+    fn test_profile() {
+	let (v, profile) = run_profiled(&count_loop(&[Inc(0)]));
+	assert_eq!(v, Ok(Vi32(1000)));
+	assert!(profile.times.is_none());
+	assert_eq!(profile.counts, [1, 1000, 1000, 1000, 1000, 1000, 1000, 1, 1]);
+	assert_eq!(profile.hottest(3), [1, 2, 3]);
+	let mut symbols = Symbols::new();
+	symbols.insert("Lloop".into(), 1);
+	assert_eq!(profile.report(&count_loop(&[Inc(0)]), &symbols, 3),
+		   ["    pc      count  instruction",
+		    "     1       1000  inc 0           Lloop",
+		    "     2       1000  var 0           Lloop+1",
+		    "     3       1000  push 1000       Lloop+2"].join("\n"));
+
+	// Timing covers every executed instruction, and the profile
+	// survives a failed run.
+	let (v, profile) = run_profiled_with_timing(&[Push(Vi32(1)), Nop, Pop, Pop, Halt], true);
+	assert_eq!(v, Err(VmError::StackUnderflow));
+	assert_eq!(profile.counts, [1, 1, 1, 1, 0]);
+	let times = profile.times.as_ref().unwrap();
+	assert!(times[4].is_zero() && times.iter().sum::<Duration>() > Duration::ZERO);
+	assert!(profile.report(&[], &Symbols::new(), 1).starts_with("    pc      count      time ns"));
+    }
+
+    #[test]
+    fn test_exit() {
+	let prog = vec![Push(Vi32(1)), Push(Vi32(3)), Exit, Push(Vi32(2)), Halt];
+	assert_eq!(run_outcome(Debug::NODEBUG, &prog), Ok(Outcome::Exited(3)));
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi32(3)));
+	let prog = vec![Push(Vi32(1)), Halt];
+	assert_eq!(run_outcome(Debug::NODEBUG, &prog), Ok(Outcome::Halted(Vi32(1))));
+	assert_eq!(run(Debug::NODEBUG, &[Push(Vbool(true)), Exit]),
+		   Err(VmError::TypeError { expected: "i32", found: Vbool(true) }));
+    }
+
+    #[test]
+    fn test_exit_nested() {
+	// main calls f, which calls g, which exits without returning.
+	let prog = vec![
+	    Push(Vi32(0)),          // 0: arg to f
+	    SetFrame(1),            // 1
+	    Calli(5),               // 2: f
+	    Push(Vi32(99)),         // 3: unreachable
+	    Halt,                   // 4
+	    Push(Vi32(0)),          // 5: f: arg to g
+	    SetFrame(1),            // 6
+	    Calli(10),              // 7: g
+	    Ret,                    // 8: unreachable
+	    Halt,                   // 9
+	    Push(Vi32(42)),         // 10: g
+	    Exit,                   // 11
+	    Ret                     // 12: unreachable
+	];
+	assert_eq!(run_outcome(Debug::NODEBUG, &prog), Ok(Outcome::Exited(42)));
+    }
+
+    #[test]
+    fn test_assert() {
+	let prog = vec![Push(Vi32(1)), Push(Vbool(true)), Assert, Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi32(1)));
+	let prog = vec![Push(Vi32(1)), Push(Vbool(false)), Assert, Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog), Err(VmError::AssertionFailed(2)));
+	let prog = vec![Push(Vi32(1)), Assert, Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog),
+		   Err(VmError::Context("assert", Box::new(
+		       VmError::TypeError { expected: "bool", found: Vi32(1) }))));
+    }
+
+    #[test]
+    fn test_rand() {
+	// Sum 100 draws from [0, 1000).
+	let mut prog = vec![Push(Vi32(0))];
+	for _ in 0..100 {
+	    prog.extend(vec![Push(Vi32(1000)), Rand, Binary(Add)]);
+	}
+	prog.push(Halt);
+	let sum = run_with_seed(Debug::NODEBUG, &prog, 42);
+	assert_eq!(sum, run_with_seed(Debug::NODEBUG, &prog, 42));
+	assert_ne!(sum, run_with_seed(Debug::NODEBUG, &prog, 43));
+
+	let mut s = State::init(vec![], Box::new(MemIo::default()),
+				VmConfig { seed: Some(42), ..VmConfig::default() }).unwrap();
+	let mut seen = [false; 10];
+	for _ in 0..10_000 {
+	    let i = s.rand(10).unwrap();
+	    assert!((0..10).contains(&i));
+	    seen[i as usize] = true
+	}
+	assert!(seen.iter().all(|b| *b));
+	assert_eq!(s.rand(1), Ok(0));
+	assert_eq!(s.rand(0), Err(VmError::BadRandBound(0)));
+	assert_eq!(run_with_seed(Debug::NODEBUG, &[Push(Vi32(-3)), Rand, Halt], 1),
+		   Err(VmError::BadRandBound(-3)));
+    }
+
+    /// A program that bubble sorts xs in a heap array and halts with
+    /// the array's address.
+    fn bubble_sort(xs: &[i32]) -> Vec<Instr> {
+	let n = xs.len() as i32;
+	// Locals: the array, passes left, index, and a temporary.
+	let mut prog = vec![Push(Vi32(n)), Push(Vi32(0)), Alloc];
+	for (i, x) in xs.iter().enumerate() {
+	    prog.extend([Var(0), Push(Vi32(i as i32)), Push(Vi32(*x)), Set]);
+	}
+	prog.extend([Push(Vi32(n)), Push(Vi32(0)), Push(Vi32(0))]);
+	let outer = prog.len() as u32;
+	prog.extend([Push(Vi32(0)), Store(2)]);
+	let inner = prog.len() as u32;
+	let no_swap = inner + 29;
+	prog.extend([
+	    Var(0), Var(2), Get,
+	    Var(0), Var(2), Push(Vi32(1)), Binary(Add), Get,
+	    // Swap if arr[j] > arr[j+1].
+	    Binary(Gt), Push(Vloc(no_swap)), BranchNot,
+	    Var(0), Var(2), Get, Store(3),
+	    Var(0), Var(2), Var(0), Var(2), Push(Vi32(1)), Binary(Add), Get, Set,
+	    Var(0), Var(2), Push(Vi32(1)), Binary(Add), Var(3), Set
+	]);
+	assert_eq!(prog.len() as u32, no_swap);
+	prog.extend([
+	    Inc(2),
+	    Var(2), Push(Vi32(n - 1)), Binary(Neq), Push(Vloc(inner)), Branch,
+	    Dec(1),
+	    Var(1), Push(Vi32(0)), Binary(Neq), Push(Vloc(outer)), Branch,
+	    Var(0), Halt
+	]);
+	prog
+    }
+
+    #[test]
+    fn test_run_full() {
+	let prog = bubble_sort(&[5, -2, 9, 0, 7, 3]);
+	let fin = run_full(Debug::NODEBUG, &prog).unwrap();
+	let base = match fin.result() {
+	    Ok(Vaddr(base)) => base,
+	    r => panic!("expected address, got {:?}", r)
+	};
+	assert_eq!(fin.heap[base], Vsize(6));
+	assert_eq!(fin.heap[base+1..base+7],
+		   [Vi32(-2), Vi32(0), Vi32(3), Vi32(5), Vi32(7), Vi32(9)]);
+	assert_eq!(fin.stack.len(), 5);
+	// Passes left and the final index.
+	assert_eq!(fin.stack[1..3], [Vi32(0), Vi32(5)]);
+	assert_eq!((fin.pc, fin.fp, fin.exit_code), (prog.len() as u32, 0, None));
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vaddr(base)));
+
+	let fin = run_full(Debug::NODEBUG, &[Push(Vi32(1)), Push(Vi32(3)), Exit]).unwrap();
+	assert_eq!(fin.result(), Ok(Vi32(3)));
+	assert_eq!((fin.stack, fin.exit_code), (vec![Vi32(1)], Some(3)));
+    }
+
+    #[test]
+    fn test_deterministic() {
+	// Allocate 50 arrays of random sizes, enough to force collections.
+	let prog = vec![
+	    Push(Vi32(50)),
+	    Push(Vi32(8)), Rand, Push(Vi32(1)), Binary(Add), Var(0), Alloc, Pop, Dec(0),
+	    Var(0), Push(Vi32(0)), Binary(Neq), Push(Vloc(1)), Branch,
+	    Var(0), Halt
+	];
+	for gc in [GcKind::MarkSweep, GcKind::Copying] {
+	    let cfg = VmConfig { max_heap: 64, gc, seed: Some(7), deterministic: true,
+				 ..VmConfig::default() };
+	    let trace = || {
+		let mut s = State::init(prog.clone(), Box::new(MemIo::default()), cfg).unwrap();
+		let mut out = Vec::new();
+		assert_eq!(exec(Some(&mut out), &mut s), Ok(None));
+		out
+	    };
+	    let first = trace();
+	    assert!(!first.is_empty());
+	    assert_eq!(first, trace());
+	}
+
+	let cfg = VmConfig { deterministic: true, ..VmConfig::default() };
+	assert_eq!(run_with_config(Debug::NODEBUG, cfg, &prog),
+		   Err(VmError::Nondeterministic("rand")));
+	let cfg = VmConfig { seed: Some(7), ..cfg };
+	assert_eq!(run_with_config(Debug::NODEBUG, cfg, &prog), Ok(Vi32(0)));
+    }
+
+    #[test]
+    fn test_verify_config() {
+	let cfg = VmConfig { verify: true, ..VmConfig::default() };
+	let prog = [Push(Vi32(1)), Push(Vloc(4)), Jmp];
+	assert_eq!(run_with_config(Debug::NODEBUG, cfg, &prog),
+		   Err(VmError::Verify(VerifyError::BadTarget { pc: 1, target: 4 })));
+	assert_eq!(run(Debug::NODEBUG, &prog), Err(VmError::BadJumpTarget { target: 4, pc: 2 }));
+	assert_eq!(run_with_config(Debug::NODEBUG, cfg, &[Push(Vi32(1)), Halt]), Ok(Vi32(1)));
+    }
+
+    /// Evaluate `a % b` by pushing a and then b.
+    fn run_mod(a: i32, b: i32) -> Result<Val, VmError> {
+	run(Debug::NODEBUG, &[Push(Vi32(a)), Push(Vi32(b)), Binary(Mod), Halt])
+    }
+
+    #[test]
+    fn test_mod() {
+	assert_eq!(run_mod(7, 3), Ok(Vi32(1)));
+	// Sign follows the dividend, as with Rust's %.
+	assert_eq!(run_mod(-7, 3), Ok(Vi32(-1)));
+	assert_eq!(run_mod(7, -3), Ok(Vi32(1)));
+	assert_eq!(run_mod(-7, -3), Ok(Vi32(-1)));
+	assert_eq!(run_mod(i32::MIN, -1), Ok(Vi32(0)));
+	assert_eq!(run_mod(7, 0), Err(VmError::DivByZero(2)));
+    }
+
+    #[test]
+    fn test_and_or() {
+	for &b1 in &[true, false] {
+	    for &b2 in &[true, false] {
+		assert_eq!(binop(And, Vbool(b1), Vbool(b2)), Ok(Vbool(b1 && b2)));
+		assert_eq!(binop(Or, Vbool(b1), Vbool(b2)), Ok(Vbool(b1 || b2)));
+	    }
+	}
+	let prog = vec![Push(Vbool(true)), Push(Vbool(false)), Binary(Or),
+			Push(Vbool(true)), Binary(And), Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vbool(true)));
+	assert_eq!(binop(And, Vi32(1), Vi32(1)),
+		   Err(VmError::OperandTypeError { op: And, expected: "bool" }));
+	assert_eq!(binop(Or, Vbool(true), Vi32(1)),
+		   Err(VmError::OperandTypeError { op: Or, expected: "bool" }));
+	assert_eq!(binop(Add, Vbool(true), Vbool(true)),
+		   Err(VmError::OperandTypeError { op: Add, expected: "numeric" }));
+    }
+
+    #[test]
+    fn test_bitwise() {
+	assert_eq!(binop(BitAnd, Vi32(-1), Vi32(0x0F0F)), Ok(Vi32(0x0F0F)));
+	assert_eq!(binop(BitOr, Vi32(i32::MIN), Vi32(1)), Ok(Vi32(i32::MIN | 1)));
+	assert_eq!(binop(BitXor, Vi32(-1), Vi32(0x55)), Ok(Vi32(!0x55)));
+	// ((0x1234 & 0xFF) | 0x100) ^ 0x0F
+	let prog = vec![Push(Vi32(0x0F)), Push(Vi32(0x100)), Push(Vi32(0xFF)),
+			Push(Vi32(0x1234)), Binary(BitAnd), Binary(BitOr),
+			Binary(BitXor), Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi32(0x13B)));
+	assert_eq!(binop(BitAnd, Vbool(true), Vbool(true)),
+		   Err(VmError::OperandTypeError { op: BitAnd, expected: "i32" }));
+    }
+
+    #[test]
+    fn test_f32() {
+	let prog = vec![Push(Vf32(3.0)), Push(Vf32(0.5)), Binary(Sub), Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vf32(2.5)));
+	assert_eq!(binop(Mul, Vf32(1.5), Vf32(-2.0)), Ok(Vf32(-3.0)));
+	assert_eq!(binop(Div, Vf32(1.0), Vf32(0.0)), Ok(Vf32(f32::INFINITY)));
+	assert_eq!(binop(Lt, Vf32(1.0), Vf32(2.0)), Ok(Vbool(true)));
+	assert_eq!(binop(Lt, Vf32(2.0), Vf32(2.0)), Ok(Vbool(false)));
+	// NaN compares unequal to everything, including itself.
+	let nan = Vf32(f32::NAN);
+	assert_eq!(binop(Eq, nan, nan), Ok(Vbool(false)));
+	assert_eq!(binop(Neq, nan, nan), Ok(Vbool(true)));
+	assert_eq!(binop(Lt, nan, Vf32(0.0)), Ok(Vbool(false)));
+	assert_eq!(binop(Eq, Vf32(0.0), Vf32(-0.0)), Ok(Vbool(true)));
+	// No implicit coercion between i32 and f32.
+	assert_eq!(binop(Add, Vi32(1), Vf32(1.0)),
+		   Err(VmError::OperandTypeError { op: Add, expected: "numeric" }));
+	assert_eq!(binop(Mod, Vf32(1.0), Vf32(1.0)),
+		   Err(VmError::OperandTypeError { op: Mod, expected: "i32" }));
+    }
+
+    #[test]
+    fn test_i64() {
+	// 13! overflows i32.
+	let prog = vec![
+	    Push(Vi64(1)),
+	    Push(Vi64(13)),
+	    Var(1),
+	    Var(0),
+	    Binary(Mul),
+	    Store(0),
+	    Var(1),
+	    Push(Vi64(1)),
+	    Binary(Sub),
+	    Store(1),
+	    Var(1),
+	    Push(Vi64(1)),
+	    Binary(Gt),
+	    Push(Vloc(2)),
+	    Branch,
+	    Var(0),
+	    Halt
+	];
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi64(6_227_020_800)));
+	assert_eq!(binop(Mod, Vi64(-7), Vi64(3)), Ok(Vi64(-1)));
+	assert_eq!(binop(Mod, Vi64(7), Vi64(0)), Err(VmError::DivByZero(0)));
+	assert_eq!(binop(Add, Vi32(1), Vi64(1)),
+		   Err(VmError::OperandTypeError { op: Add, expected: "numeric" }));
+	assert_eq!(binop(Lt, Vi64(1), Vi32(2)),
+		   Err(VmError::OperandTypeError { op: Lt, expected: "numeric" }));
+    }
+
+    #[test]
+    fn test_structural_eq() {
+	let vals = [Vunit, Vi32(1), Vbool(true), Vloc(1), Vundef, Vf32(1.0),
+		    Vi64(1), Vchar('a'), Vaddr(1)];
+	let others = [Vunit, Vi32(2), Vbool(false), Vloc(2), Vundef, Vf32(2.0),
+		      Vi64(2), Vchar('b'), Vaddr(2)];
+	for (i, &v1) in vals.iter().enumerate() {
+	    for (j, &v2) in vals.iter().enumerate() {
+		if i == j {
+		    assert_eq!(binop(Eq, v1, v2), Ok(Vbool(true)));
+		    assert_eq!(binop(Neq, v1, v2), Ok(Vbool(false)));
+		    let (eq, neq) = (binop(Eq, v1, others[i]), binop(Neq, v1, others[i]));
+		    // Unit and undef each have a single value.
+		    let same = v1 == others[i];
+		    assert_eq!(eq, Ok(Vbool(same)));
+		    assert_eq!(neq, Ok(Vbool(!same)));
+		} else {
+		    assert_eq!(binop(Eq, v1, v2),
+			       Err(VmError::OperandTypeError { op: Eq, expected: "same-typed" }));
+		    assert_eq!(binop(Neq, v1, v2),
+			       Err(VmError::OperandTypeError { op: Neq, expected: "same-typed" }));
+		}
+	    }
+	}
+	assert_eq!(binop(Eq, Vi32(-5), Vi32(-5)), Ok(Vbool(true)));
+	assert_eq!(binop(Eq, Vi32(-5), Vi32(5)), Ok(Vbool(false)));
+	// Two arrays with equal contents are different addresses.
+	let prog = vec![Push(Vi32(0)), AllocI(1), Push(Vi32(0)), AllocI(1),
+			Binary(Eq), Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vbool(false)));
+	let prog = vec![Push(Vi32(0)), AllocI(1), Dup, Binary(Eq), Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vbool(true)));
+    }
+
+    #[test]
+    fn test_char() {
+	assert_eq!(binop(Eq, Vchar('a'), Vchar('a')), Ok(Vbool(true)));
+	assert_eq!(binop(Lt, Vchar('a'), Vchar('b')), Ok(Vbool(true)));
+	assert_eq!(binop(Lt, Vchar('b'), Vchar('a')), Ok(Vbool(false)));
+	assert_eq!(binop(Add, Vchar('a'), Vchar('b')),
+		   Err(VmError::OperandTypeError { op: Add, expected: "numeric" }));
+	assert_eq!(binop(Eq, Vchar('a'), Vi32(97)),
+		   Err(VmError::OperandTypeError { op: Eq, expected: "same-typed" }));
+	// chr(ord('a') + 1)
+	let prog = vec![Push(Vi32(1)), Push(Vchar('a')), Unary(Ord),
+			Binary(Add), Unary(Chr), Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vchar('b')));
+	assert_eq!(unop(Ord, Vchar('λ')), Ok(Vi32(0x3BB)));
+	assert_eq!(unop(Ord, Vi32(1)),
+		   Err(VmError::TypeError { expected: "char", found: Vi32(1) }));
+	assert_eq!(unop(Chr, Vi32(0xD800)),
+		   Err(VmError::InvalidChar(55296)));
+	assert_eq!(unop(Chr, Vi32(-1)),
+		   Err(VmError::InvalidChar(-1)));
+    }
+
+    #[test]
+    fn test_strings() {
+	let prog = vec![
+	    Push(Vi32(2)), Push(Vchar('a')), Alloc,
+	    Push(Vi32(3)), Push(Vchar('b')), Alloc,
+	    SConcat,
+	    Dup, Push(Vi32(1)), Get, Swap,
+	    Dup, Push(Vi32(2)), Get, Swap,
+	    SLen,
+	    Halt
+	];
+	let mut s = test_state(prog);
+	assert_eq!(exec(None, &mut s), Ok(None));
+	assert_eq!(s.stk, vec![Vchar('a'), Vchar('b'), Vi32(5)]);
+	assert_eq!(s.array(7), Ok(&[Vchar('a'), Vchar('a'), Vchar('b'),
+				   Vchar('b'), Vchar('b')][..]));
+	assert_eq!(run(Debug::NODEBUG, &[Push(Vi32(1)), SLen, Halt]),
+		   Err(VmError::TypeError { expected: "address", found: Vi32(1) }));
+    }
+
+    #[test]
+    fn test_min_max() {
+	assert_eq!(binop(Min, Vi32(-3), Vi32(2)), Ok(Vi32(-3)));
+	assert_eq!(binop(Max, Vi32(-3), Vi32(2)), Ok(Vi32(2)));
+	assert_eq!(binop(Max, Vbool(true), Vbool(false)),
+		   Err(VmError::OperandTypeError { op: Max, expected: "i32" }));
+	// Clamp x into [0, 10].
+	let clamp = |x| run(Debug::NODEBUG, &[
+	    Push(Vi32(10)), Push(Vi32(0)), Push(Vi32(x)),
+	    Binary(Max), Binary(Min), Halt
+	]);
+	assert_eq!(clamp(-5), Ok(Vi32(0)));
+	assert_eq!(clamp(5), Ok(Vi32(5)));
+	assert_eq!(clamp(15), Ok(Vi32(10)));
+    }
+
+    #[test]
+    fn test_pow() {
+	// The exponent is on top of the base.
+	let prog = vec![Push(Vi32(2)), Push(Vi32(10)), Binary(Pow), Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi32(1024)));
+	assert_eq!(binop(Pow, Vi32(0), Vi32(0)), Ok(Vi32(1)));
+	assert_eq!(binop(Pow, Vi32(-3), Vi32(3)), Ok(Vi32(-27)));
+	assert_eq!(binop(Pow, Vi32(2), Vi32(30)), Ok(Vi32(1 << 30)));
+	assert_eq!(binop(Pow, Vi32(2), Vi32(31)), Err(VmError::Overflow));
+	assert_eq!(binop(Pow, Vi32(2), Vi32(-1)), Err(VmError::NegativeExponent(-1)));
+	assert_eq!(binop(Pow, Vi64(2), Vi64(3)),
+		   Err(VmError::OperandTypeError { op: Pow, expected: "i32" }));
+    }
+
+    #[test]
+    fn test_ltu() {
+	assert_eq!(binop(Ltu, Vi32(-1), Vi32(1)), Ok(Vbool(false)));
+	assert_eq!(binop(Lt, Vi32(-1), Vi32(1)), Ok(Vbool(true)));
+	assert_eq!(binop(Ltu, Vi32(1), Vi32(-1)), Ok(Vbool(true)));
+	assert_eq!(binop(Ltu, Vi32(i32::MAX), Vi32(i32::MIN)), Ok(Vbool(true)));
+	assert_eq!(binop(Ltu, Vi32(3), Vi32(5)), Ok(Vbool(true)));
+	assert_eq!(binop(Ltu, Vi32(5), Vi32(5)), Ok(Vbool(false)));
+	assert_eq!(binop(Ltu, Vi64(1), Vi64(2)),
+		   Err(VmError::OperandTypeError { op: Ltu, expected: "i32" }));
+    }
+
+    #[test]
+    fn test_carry() {
+	let eval = |b, a, c| {
+	    let mut s = test_state(vec![Push(Vi32(a)), Push(Vi32(c)), Binary(b), Halt]);
+	    exec(None, &mut s).map(|_| s.stk)
+	};
+	assert_eq!(eval(AddC, i32::MAX, 1), Ok(vec![Vi32(i32::MIN), Vbool(true)]));
+	assert_eq!(eval(SubC, i32::MIN, 1), Ok(vec![Vi32(i32::MAX), Vbool(true)]));
+	assert_eq!(eval(MulC, 1 << 16, 1 << 16), Ok(vec![Vi32(0), Vbool(true)]));
+	assert_eq!(eval(AddC, 2, 3), Ok(vec![Vi32(5), Vbool(false)]));
+	assert_eq!(eval(SubC, 2, 3), Ok(vec![Vi32(-1), Vbool(false)]));
+	assert_eq!(eval(MulC, -2, 3), Ok(vec![Vi32(-6), Vbool(false)]));
+	assert_eq!(binop(AddC, Vi64(1), Vi64(2)),
+		   Err(VmError::OperandTypeError { op: AddC, expected: "i32" }));
+    }
+
+    #[test]
+    fn test_shifts() {
+	// The shift amount is on top of the shifted value.
+	let prog = vec![Push(Vi32(3)), Push(Vi32(4)), Binary(Shl), Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi32(48)));
+	assert_eq!(binop(Shl, Vi32(1), Vi32(31)), Ok(Vi32(i32::MIN)));
+	assert_eq!(binop(Shr, Vi32(-16), Vi32(2)), Ok(Vi32(-4)));
+	assert_eq!(binop(Shr, Vi32(i32::MIN), Vi32(31)), Ok(Vi32(-1)));
+	// Shift amounts are masked to their low five bits.
+	assert_eq!(binop(Shl, Vi32(1), Vi32(32)), Ok(Vi32(1)));
+	assert_eq!(binop(Shl, Vi32(1), Vi32(33)), Ok(Vi32(2)));
+	assert_eq!(binop(Shr, Vi32(-8), Vi32(33)), Ok(Vi32(-4)));
+	assert_eq!(binop(Shl, Vi32(1), Vi32(-1)), Ok(Vi32(i32::MIN)));
+    }
+
+    /// Push a then b and evaluate `a op b`.
+    fn run_cmp(op: Binop, a: i32, b: i32) -> Result<Val, VmError> {
+	run(Debug::NODEBUG, &[Push(Vi32(a)), Push(Vi32(b)), Binary(op), Halt])
+    }
+
+    #[test]
+    fn test_operand_order() {
+	// The value below the top is the left operand, so
+	// `push 10; push 3; binary -` is `10 - 3`.
+	assert_eq!(run_cmp(Sub, 10, 3), Ok(Vi32(7)));
+	assert_eq!(run_cmp(Div, 10, 3), Ok(Vi32(3)));
+	assert_eq!(run_cmp(Lt, 10, 3), Ok(Vbool(false)));
+	assert_eq!(run_cmp(Lt, 3, 10), Ok(Vbool(true)));
+    }
+
+    #[test]
+    fn test_div() {
+	assert_eq!(run_cmp(Div, -7, 2), Ok(Vi32(-3)));
+	assert_eq!(run_cmp(Div, 7, 0), Err(VmError::DivByZero(2)));
+	assert_eq!(run_cmp(Div, i32::MIN, -1), Err(VmError::Overflow));
+	let prog = [Nop, Push(Vi64(7)), Push(Vi64(0)), Binary(Div), Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog), Err(VmError::DivByZero(3)));
+	let prog = [Push(Vi64(i64::MIN)), Push(Vi64(-1)), Binary(Div), Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog), Err(VmError::Overflow));
+	assert_eq!(VmError::DivByZero(3).to_string(), "divide by zero at pc 3");
+    }
+
+    #[test]
+    fn test_arithmetic() {
+	let checked = VmConfig { arithmetic: Arithmetic::Checked, ..VmConfig::default() };
+	let eval = |cfg: &VmConfig, v1, v2, b| {
+	    run_with_config(Debug::NODEBUG, *cfg, &[Push(v1), Push(v2), Binary(b), Halt])
+	};
+	let wrapping = VmConfig::default();
+	assert_eq!(eval(&wrapping, Vi32(i32::MAX), Vi32(1), Add), Ok(Vi32(i32::MIN)));
+	assert_eq!(eval(&wrapping, Vi32(i32::MIN), Vi32(-1), Mul), Ok(Vi32(i32::MIN)));
+	assert_eq!(eval(&wrapping, Vi32(i32::MIN), Vi32(1), Sub), Ok(Vi32(i32::MAX)));
+	assert_eq!(eval(&wrapping, Vi64(i64::MAX), Vi64(1), Add), Ok(Vi64(i64::MIN)));
+	assert_eq!(eval(&checked, Vi32(i32::MAX), Vi32(1), Add), Err(VmError::Overflow));
+	assert_eq!(eval(&checked, Vi32(i32::MIN), Vi32(-1), Mul), Err(VmError::Overflow));
+	assert_eq!(eval(&checked, Vi32(i32::MIN), Vi32(1), Sub), Err(VmError::Overflow));
+	assert_eq!(eval(&checked, Vi64(i64::MIN), Vi64(-1), Mul), Err(VmError::Overflow));
+	assert_eq!(eval(&checked, Vi32(i32::MAX - 1), Vi32(1), Add), Ok(Vi32(i32::MAX)));
+	// The carrying operators always wrap.
+	assert_eq!(eval(&checked, Vi32(i32::MAX), Vi32(1), AddC), Ok(Vbool(true)));
+	// The mode survives a snapshot.
+	let bytes = State::init(vec![Halt], Box::new(MemIo::default()), checked).unwrap().to_bytes();
+	let s = State::from_bytes(&mut bytes.into_iter()).unwrap();
+	assert_eq!(s.cfg.arithmetic, Arithmetic::Checked);
+    }
+
+    #[test]
+    fn test_comparisons() {
+	assert_eq!(run_cmp(Lt, 1, 2), Ok(Vbool(true)));
+	assert_eq!(run_cmp(Lt, 2, 1), Ok(Vbool(false)));
+	assert_eq!(run_cmp(Lt, 2, 2), Ok(Vbool(false)));
+	assert_eq!(run_cmp(Gt, 1, 2), Ok(Vbool(false)));
+	assert_eq!(run_cmp(Gt, 2, 1), Ok(Vbool(true)));
+	assert_eq!(run_cmp(Gt, 2, 2), Ok(Vbool(false)));
+	assert_eq!(run_cmp(Le, 1, 2), Ok(Vbool(true)));
+	assert_eq!(run_cmp(Le, 2, 1), Ok(Vbool(false)));
+	assert_eq!(run_cmp(Le, 2, 2), Ok(Vbool(true)));
+	assert_eq!(run_cmp(Ge, 1, 2), Ok(Vbool(false)));
+	assert_eq!(run_cmp(Ge, 2, 1), Ok(Vbool(true)));
+	assert_eq!(run_cmp(Ge, 2, 2), Ok(Vbool(true)));
+	for &(a, b) in &[(1, 2), (2, 1), (2, 2), (-5, 3)] {
+	    assert_eq!(run_cmp(Gt, a, b), run_cmp(Lt, b, a));
+	    assert_eq!(run_cmp(Le, a, b), run_cmp(Ge, b, a));
+	}
+    }
+
+    #[test]
+    fn test_neq() {
+	assert_eq!(run_cmp(Neq, 1, 2), Ok(Vbool(true)));
+	assert_eq!(run_cmp(Neq, 2, 2), Ok(Vbool(false)));
+	// Count up from 0, looping while the counter is not 5.
+	let prog = vec![
+	    Push(Vi32(0)),
+	    Var(0),
+	    Push(Vi32(1)),
+	    Binary(Add),
+	    Store(0),
+	    Push(Vi32(5)),
+	    Var(0),
+	    Binary(Neq),
+	    Push(Vloc(1)),
+	    Branch,
+	    Halt
+	];
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi32(5)));
+    }
+
+    #[test]
+    fn test_backtrace() {
+	let prog = vec![
+	    Push(Vi32(0)), SetFrame(1), Calli(4), Halt,
+	    Push(Vi32(0)), SetFrame(1), Calli(8), Ret,
+	    Push(Vi32(0)), SetFrame(1), Calli(12), Ret,
+	    Push(Vi32(1)), Push(Vbool(true)), Binary(Add), Ret
+	];
+	let err = run(Debug::NODEBUG, &prog).unwrap_err();
+	assert_eq!(err, VmError::Backtrace {
+	    error: Box::new(VmError::OperandTypeError { op: Add, expected: "numeric" }),
+	    frames: vec![11, 7, 3]
+	});
+	assert_eq!(err.to_string(), "Add: expected numeric operands (backtrace: 11, 7, 3)");
+
+	// Returned and tail-called frames are not reported.
+	let prog = vec![
+	    Push(Vi32(0)), SetFrame(1), Calli(5), Jmpi(99), Halt,
+	    Push(Vi32(7)), Push(Vloc(8)), TailCall(1),
+	    Var(0), Ret
+	];
+	assert_eq!(run(Debug::NODEBUG, &prog), Err(VmError::BadJumpTarget { target: 99, pc: 3 }));
+	let mut tail = prog.clone();
+	tail[8] = Jmpi(99);
+	assert_eq!(run(Debug::NODEBUG, &tail), Err(VmError::Backtrace {
+	    error: Box::new(VmError::BadJumpTarget { target: 99, pc: 8 }),
+	    frames: vec![3]
+	}));
+    }
+
+    #[test]
+    fn test_snapshot() {
+	let mut prog = vec![Globals(1)];
+	prog.extend(build_list(&[1, 2, 3, 4]));
+	prog.extend(vec![GSet(0), Push(Vi32(300))]);
+	let start = prog.len() as u32;
+	prog.extend(build_list(&[5, 6]));
+	prog.extend(vec![Pop, Dec(0), Var(0), Push(Vi32(0)), Binary(Neq),
+			 Push(Vloc(start)), Branch]);
+	let f = prog.len() as u32 + 4;
+	prog.extend(vec![Push(Vi32(0)), SetFrame(1), Calli(f), Halt,
+			 GGet(0), GetF(1), GetF(0), Ret]);
+	assert_eq!(Vm::new(&prog).unwrap().run(), Ok(Vi32(2)));
+
+	// Snapshot halfway through the loop, and inside the call.
+	for stop in [start + 3, f + 2] {
+	    let mut vm = Vm::new(&prog).unwrap();
+	    for _ in 0..1000 {
+		vm.step().unwrap();
+	    }
+	    while vm.pc() != stop {
+		vm.step().unwrap();
+	    }
+	    let bytes = vm.snapshot();
+	    let mut resumed = Vm::restore(&bytes).unwrap();
+	    assert_eq!((resumed.pc(), resumed.fp()), (vm.pc(), vm.fp()));
+	    assert_eq!(resumed.stack(), vm.stack());
+	    assert_eq!(resumed.heap(), vm.heap());
+	    assert_eq!(resumed.snapshot(), bytes);
+	    assert_eq!(resumed.run(), Ok(Vi32(2)));
+	    assert_eq!(vm.run(), Ok(Vi32(2)));
+	}
+
+	assert!(Vm::restore(b"nope").is_err());
+	let bytes = Vm::new(&prog).unwrap().snapshot();
+	assert!(Vm::restore(&bytes[..bytes.len() - 1]).is_err());
+	// Internal values are only legal inside snapshots.
+	assert!(Val::from_bytes(&mut vec![0x0A, 0, 0, 0, 1].into_iter()).is_err());
+    }
+
+    #[test]
+    fn test_restore_corrupt() {
+	let restore = |bytes: &[u8]| Vm::restore(bytes).map(drop).map_err(|err| err.0);
+	let mut bytes = Vm::new(&[Push(Vi32(1)), Halt]).unwrap().snapshot();
+	bytes[4..8].copy_from_slice(&[0xFF; 4]);
+	assert_eq!(restore(&bytes), Err("stack size too large: 4294967295".into()));
+
+	let corrupt = |f: fn(&mut State)| {
+	    let mut vm = Vm::new(&[Push(Vi32(1)), AllocI(2), Halt]).unwrap();
+	    vm.run().unwrap();
+	    f(&mut vm.s);
+	    restore(&vm.snapshot())
+	};
+	assert_eq!(corrupt(|_| ()), Ok(()));
+	assert_eq!(corrupt(|s| s.free.push((2, 2))), Err("free block out of range: 2".into()));
+	assert_eq!(corrupt(|s| s.free.push((0, 0))), Err("free block out of range: 0".into()));
+	assert_eq!(corrupt(|s| s.heap.resize(MAX_HEAP + 1, Vundef)),
+		   Err("heap larger than its maximum: 1025".into()));
+	assert_eq!(corrupt(|s| {
+	    let mut t = Thread::new(0);
+	    t.status = Status::Joining(2);
+	    s.threads.push(t)
+	}), Err("unknown thread: 2".into()));
+	assert_eq!(corrupt(|s| s.fp = STK_SIZE as u32 + 1),
+		   Err("frame pointer out of range: 1025".into()));
+	assert_eq!(corrupt(|s| s.frames.push(u32::MAX as usize)),
+		   Err("frame out of range: 4294967295".into()));
+	assert_eq!(corrupt(|s| {
+	    let mut t = Thread::new(0);
+	    t.shadow.push((STK_SIZE + 1, true));
+	    s.threads.push(t)
+	}), Err("frame out of range: 1025".into()));
+	assert_eq!(corrupt(|s| s.stk.resize(STK_SIZE + 1, Vunit)),
+		   Err("stack larger than its maximum: 1025".into()));
+    }
+
+    #[test]
+    fn test_interrupt() {
+	use std::sync::atomic::AtomicU64;
+	use std::time::Duration;
+
+	// Interrupt an infinite loop from another thread.
+	let mut vm = Vm::new(&[Nop, Jmpi(0)]).unwrap();
+	vm.set_interrupt_interval(100);
+	let steps = Arc::new(AtomicU64::new(0));
+	let counter = steps.clone();
+	vm.set_hook(move |_| {
+	    counter.fetch_add(1, Ordering::Relaxed);
+	    Ok(())
+	});
+	let flag = Arc::new(AtomicBool::new(false));
+	let setter = flag.clone();
+	let handle = thread::spawn(move || {
+	    thread::sleep(Duration::from_millis(20));
+	    setter.store(true, Ordering::Relaxed)
+	});
+	assert_eq!(vm.run_interruptible(flag.clone()), Err(VmError::Interrupted));
+	handle.join().unwrap();
+	assert!(steps.load(Ordering::Relaxed) > 0);
+	assert_eq!(steps.load(Ordering::Relaxed) % 100, 0);
+	assert!(!flag.load(Ordering::Relaxed));
+
+	// A flag that is already set stops the run within one interval.
+	steps.store(0, Ordering::Relaxed);
+	flag.store(true, Ordering::Relaxed);
+	assert_eq!(vm.run_interruptible(flag.clone()), Err(VmError::Interrupted));
+	assert_eq!(steps.load(Ordering::Relaxed), 100);
+
+	// An interrupted run can be resumed.
+	let prog = vec![Push(Vi32(5000)), Dec(0), Var(0), Push(Vi32(0)), Binary(Neq),
+			Push(Vloc(1)), Branch, Push(Vi32(7)), Halt];
+	let mut vm = Vm::new(&prog).unwrap();
+	flag.store(true, Ordering::Relaxed);
+	assert_eq!(vm.run_interruptible(flag.clone()), Err(VmError::Interrupted));
+	assert_eq!(vm.run_interruptible(flag), Ok(Vi32(7)));
+    }
+
+    #[test]
+    fn test_watch() {
+	// a[1] += 10, three times.
+	let prog = vec![
+	    Push(Vi32(5)), AllocI(2), Push(Vi32(3)),
+	    Var(0), Var(0), GetF(1), Push(Vi32(10)), Binary(Add), SetF(1),
+	    Dec(1), Var(1), Push(Vi32(0)), Binary(Neq), Push(Vloc(3)), Branch,
+	    Var(0), Push(Vi32(0)), Push(Vi32(7)), Set,
+	    Var(0), GetF(1), Halt
+	];
+	let mut vm = Vm::new(&prog).unwrap();
+	vm.watch(2);
+	for (old, new) in [(5, 15), (15, 25), (25, 35)] {
+	    assert_eq!(vm.run_until_break(), Ok(BreakOutcome::WatchHit(WatchHit {
+		addr: 2, old: Vi32(old), new: Vi32(new), pc: 8
+	    })));
+	}
+	assert_eq!(vm.run_until_break(), Ok(BreakOutcome::Halted(Vi32(35))));
+
+	// ArrCopy reports each watched slot it writes.
+	let prog = vec![
+	    Push(Vi32(1)), AllocI(3), Push(Vi32(2)), AllocI(3),
+	    Var(0), Push(Vi32(0)), Var(1), Push(Vi32(0)), Push(Vi32(3)), ArrCopy,
+	    Push(Vi32(0)), Halt
+	];
+	let mut vm = Vm::new(&prog).unwrap();
+	for addr in [1, 3, 5, 2] {
+	    vm.watch(addr);
+	}
+	vm.unwatch(3);
+	for addr in [1, 2] {
+	    assert_eq!(vm.run_until_break(), Ok(BreakOutcome::WatchHit(WatchHit {
+		addr, old: Vi32(1), new: Vi32(2), pc: 9
+	    })));
+	}
+	assert_eq!(vm.run_until_break(), Ok(BreakOutcome::Halted(Vi32(0))));
+	assert_eq!(vm.heap()[1..4], [Vi32(2), Vi32(2), Vi32(2)]);
+    }
+
+    #[test]
+    fn test_syscall() {
+	let prog = vec![Push(Vi32(1)), Push(Vi32(2)), Push(Vi32(3)), Syscall(0, 3),
+			Push(Vi32(10)), Syscall(1, 1), Halt];
+	let mut vm = Vm::new(&prog).unwrap();
+	vm.register_host_fn(0, |args| {
+	    args.iter().map(|v| v.to_i32().ok_or("expected i32".to_string()))
+		.sum::<Result<i32, String>>().map(Vi32)
+	});
+	vm.register_host_fn(1, |args| Ok(Vbool(args == [Vi32(10)])));
+	assert_eq!(vm.run(), Ok(Vbool(true)));
+	assert_eq!(vm.stack(), &[Vi32(6), Vbool(true)]);
+
+	let mut vm = Vm::new(&prog).unwrap();
+	vm.register_host_fn(0, |_| Err("database unavailable".into()));
+	let err = vm.run().unwrap_err();
+	assert_eq!(err, VmError::HostFn { index: 0, msg: "database unavailable".into() });
+	assert_eq!(err.to_string(), "host function 0: database unavailable");
+
+	assert_eq!(run(Debug::NODEBUG, &prog), Err(VmError::UnknownHostFn(0)));
+	let mut vm = Vm::new(&[Syscall(0, 1), Halt]).unwrap();
+	vm.register_host_fn(0, |_| Ok(Vunit));
+	assert_eq!(vm.run(), Err(VmError::StackUnderflow));
+	// The result counts against the stack limit.
+	let cfg = VmConfig { stack_size: 1, ..VmConfig::default() };
+	let mut vm = Vm::with_config(&[Push(Vi32(1)), Syscall(0, 0), Halt], cfg).unwrap();
+	vm.register_host_fn(0, |_| Ok(Vunit));
+	assert_eq!(vm.run(), Err(VmError::StackOverflow));
+    }
+
+    #[test]
+    fn test_record_replay() {
+	// Read a number n, then compute f(n) + f() + rand(100), where the
+	// host function f counts its calls from start.
+	let prog = [Read, Syscall(0, 1), Syscall(0, 0), Binary(Add), Push(Vi32(100)), Rand,
+		    Binary(Add), Halt];
+	let vm = |input: &str, start: i32| {
+	    let mut vm = Vm::new(&prog).unwrap();
+	    vm.set_io(MemIo::new(input));
+	    let mut calls = start;
+	    vm.register_host_fn(0, move |args| {
+		calls += 1;
+		Ok(Vi32(calls * 10 + args.iter().filter_map(Val::to_i32).sum::<i32>()))
+	    });
+	    vm
+	};
+	let (result, recording) = vm("5\n", 0).run_recorded();
+	let r = match recording.events[..] {
+	    [Event::Read(Ok(ref line)), Event::Syscall { index: 0, result: Ok(Vi32(15)) },
+	     Event::Syscall { index: 0, result: Ok(Vi32(20)) }, Event::Rand(r)]
+		if line == "5\n" => r,
+	    ref events => panic!("unexpected events {:?}", events)
+	};
+	assert_eq!(result, Ok(Vi32(35 + r)));
+	// Replaying needs no input and ignores what f would return now.
+	assert_eq!(vm("", 1000).run_replay(&recording), Ok(Vi32(35 + r)));
+	let decoded = Recording::from_bytes(&mut recording.to_bytes().into_iter()).unwrap();
+	assert_eq!(decoded, recording);
+	let mut replayed = Vm::new(&prog).unwrap();
+	assert_eq!(replayed.run_replay(&decoded), Ok(Vi32(35 + r)));
+	assert!(Recording::from_bytes(&mut b"GSNP".iter().copied()).is_err());
+
+	// Replays fail as soon as the program takes a different value,
+	// asks for more, or stops early.
+	assert_eq!(Vm::new(&[Push(Vi32(10)), Rand, Halt]).unwrap().run_replay(&recording),
+		   Err(VmError::ReplayDiverged {
+		       pc: 1, msg: "rand where the recording has read".into() }));
+	let err = Vm::new(&[Read, Syscall(1, 1), Halt]).unwrap().run_replay(&recording).unwrap_err();
+	assert_eq!(err.to_string(),
+		   "replay diverged at pc 1: syscall 1 where the recording has syscall 0");
+	assert_eq!(Vm::new(&[Read, Halt]).unwrap().run_replay(&recording),
+		   Err(VmError::ReplayDiverged {
+		       pc: 2, msg: "stopped with 3 recorded values unused".into() }));
+	let short = Recording { events: recording.events[..1].to_vec() };
+	assert_eq!(vm("", 0).run_replay(&short),
+		   Err(VmError::ReplayDiverged {
+		       pc: 1, msg: "syscall 0 where the recording has nothing more".into() }));
+
+	// Failures are recorded too, and replay the same way.
+	let mut failing = Vm::new(&[Syscall(0, 0), Halt]).unwrap();
+	failing.register_host_fn(0, |_| Err("database unavailable".into()));
+	let (result, recording) = failing.run_recorded();
+	let err = VmError::HostFn { index: 0, msg: "database unavailable".into() };
+	assert_eq!(result, Err(err.clone()));
+	assert_eq!(Vm::new(&[Syscall(0, 0), Halt]).unwrap().run_replay(&recording), Err(err));
+    }
+
+    #[test]
+    fn test_step_back() {
+	// Add up input lines and random numbers, printing the total.
+	let prog = vec![Push(Vi32(0)), Read, Binary(Add), Dup, Print,
+			Push(Vi32(10)), Rand, Binary(Add), Jmpi(1)];
+	let input = "1\n".repeat(20);
+	let vm = |io: &MemIo| Vm {
+	    s: State::init(prog.clone(), Box::new(io.clone()),
+			   VmConfig { seed: Some(7), ..VmConfig::default() }).unwrap(),
+	    hook: None,
+	    interrupt_interval: INTERRUPT_INTERVAL,
+	    history: None
+	};
+	let steps = |vm: &mut Vm, n| {
+	    for _ in 0..n {
+		assert_eq!(vm.step(), Ok(StepOutcome::Continue))
+	    }
+	};
+	let io = MemIo::new(&input);
+	let mut fresh = vm(&io);
+	steps(&mut fresh, 95);
+	let (at_95, out_95) = (fresh.snapshot(), io.output());
+	steps(&mut fresh, 5);
+	let (at_100, out_100) = (fresh.snapshot(), io.output());
+
+	let io = MemIo::new(&input);
+	let mut back = vm(&io);
+	back.keep_history(10, 4);
+	steps(&mut back, 100);
+	for _ in 0..5 {
+	    back.step_back().unwrap()
+	}
+	assert_eq!(back.snapshot(), at_95);
+	assert_eq!(io.output(), out_100);
+	// Stepping forward again takes the same input and random numbers.
+	steps(&mut back, 5);
+	assert_eq!(back.snapshot(), at_100);
+	assert_eq!(io.output(), out_100.clone() + &out_100[out_95.len()..]);
+
+	// Four snapshots every 10 instructions, the last taken before
+	// instruction 90, reach back to instruction 60.
+	for _ in 0..40 {
+	    back.step_back().unwrap()
+	}
+	assert_eq!(back.step_back(), Err(VmError::NoHistory));
+	let mut back = vm(&MemIo::new(&input));
+	back.keep_history(10, 4);
+	assert_eq!(back.step_back(), Err(VmError::AtStart));
+	steps(&mut back, 1);
+	back.step_back().unwrap();
+	assert_eq!(back.step_back(), Err(VmError::AtStart));
+	assert_eq!(vm(&MemIo::default()).step_back(), Err(VmError::NoHistory));
+    }
+
+    #[test]
+    fn test_vm_error() {
+	let overflow = vec![Push(Vi32(0)), Jmpi(0)];
+	assert!(matches!(run(Debug::NODEBUG, &overflow), Err(VmError::StackOverflow)));
+	let prog = vec![Push(Vbool(true)), Push(Vi32(1)), Binary(Add), Halt];
+	match run(Debug::NODEBUG, &prog) {
+	    Err(VmError::OperandTypeError { op: Add, expected }) => assert_eq!(expected, "numeric"),
+	    r => panic!("unexpected result {:?}", r)
+	}
+	let prog = vec![Push(Vi32(1)), Push(Vchar('x')), Jmp];
+	match run(Debug::NODEBUG, &prog) {
+	    Err(VmError::TypeError { expected: "location", found }) => assert_eq!(found, Vchar('x')),
+	    r => panic!("unexpected result {:?}", r)
+	}
+	let prog = vec![Push(Vi32(1)), Push(Vi32(0)), Binary(Mod), Halt];
+	assert!(matches!(run(Debug::NODEBUG, &prog), Err(VmError::DivByZero(2))));
+	assert!(matches!(run(Debug::NODEBUG, &[Jmpi(7)]), Err(VmError::BadJumpTarget { target: 7, pc: 0 })));
+	assert!(matches!(run(Debug::NODEBUG, &[Nop]), Err(VmError::MissingHalt(1))));
+
+	// Display keeps the familiar messages; context is exposed as the source.
+	let err = VmError::Context("arrcopy source", Box::new(VmError::NotAnArray(3)));
+	assert_eq!(err.to_string(), "arrcopy source: expected size at array location");
+	let source = std::error::Error::source(&err).map(|e| e.to_string());
+	assert_eq!(source, Some("expected size at array location".into()));
+	assert_eq!(VmError::OutOfFuel(4).to_string(), "out of fuel at pc 4");
+    }
+
+    #[test]
+    fn test_mini_fuzz() {
+	// Xorshift, so that failures are reproducible.
+	let mut rng = 0x2545_F491_4F6C_DD1Du64;
+	let mut next = move || {
+	    rng ^= rng << 13;
+	    rng ^= rng >> 7;
+	    rng ^= rng << 17;
+	    rng
+	};
+	// Bytes biased towards small values, so that counts and
+	// operands are often in range.
+	let byte = |r: u64| if r & 1 == 0 { (r >> 8) as u8 } else { (r >> 8) as u8 % 8 };
+	for i in 0..3000 {
+	    // Decoding arbitrary bytes fails cleanly.
+	    let bytes: Vec<u8> = (0..next() % 64).map(|_| byte(next())).collect();
+	    let _ = Vec::<Instr>::from_bytes(&mut bytes.into_iter());
+
+	    // So does running any decodable program. A few pushes first
+	    // let more of the programs get past their first instruction.
+	    let mut prog: Vec<Instr> = (0..next() % 4)
+		.map(|_| Push([Vi32(next() as i32 % 4), Vloc((next() % 32) as u32)][i % 2]))
+		.collect();
+	    while prog.len() < i % 32 {
+		let bytes: Vec<u8> = (0..16).map(|_| byte(next())).collect();
+		if let Ok(mut instr) = Instr::from_bytes(&mut bytes.into_iter()) {
+		    // Heap operations often get sizes at the extremes, and
+		    // Grow an array to work on.
+		    let size = [-1, i32::MIN, i32::MAX, MAX_HEAP as i32][next() as usize % 4];
+		    match instr {
+			Grow if next() & 1 == 0 =>
+			    prog.extend([Push(Vi32(0)), AllocI(2), Push(Vi32(size))]),
+			Alloc if next() & 1 == 0 => prog.extend([Push(Vi32(size)), Push(Vunit)]),
+			AllocI(_) if next() & 1 == 0 => instr = AllocI(size as u32),
+			_ => ()
+		    }
+		    prog.push(instr)
+		}
+	    }
+	    let cfg = VmConfig { seed: Some(i as u64), ..VmConfig::default() };
+	    let mut s = State::init(prog.clone(), Box::new(MemIo::new("12\n")), cfg).unwrap();
+	    s.fuel = Some(1000);
+	    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| exec(None, &mut s)));
+	    assert!(result.is_ok(), "program {} panicked: {:?}", i, prog);
+	}
+    }
+}
+
+/// Tests of the core without the std feature, run with
+/// `cargo test --no-default-features`.
+#[cfg(all(test, not(feature = "std")))]
+mod no_std_tests {
+    use super::*;
+    use core::sync::atomic::AtomicUsize;
+
+    /// Backend accepting exactly the output want, counting the bytes
+    /// matched so far in matched.
+    struct Expect {
+	want: &'static str,
+	matched: Arc<AtomicUsize>
+    }
+
+    impl VmIo for Expect {
+	fn write_str(&mut self, s: &str) -> Result<(), String> {
+	    let n = self.matched.load(Ordering::Relaxed);
+	    if !self.want[n..].starts_with(s) {
+		return Err(format!("unexpected output: {:?}", s))
+	    }
+	    self.matched.store(n + s.len(), Ordering::Relaxed);
+	    Ok(())
+	}
+	fn read_line(&mut self) -> Result<String, String> {
+	    Ok("7\n".into())
+	}
+    }
+
+    #[test]
+    fn test_run() {
+	let prog = [Push(Vi32(20)), SetFrame(1), Calli(4), Halt, Var(0), Var(0), Binary(Add), Ret];
+	assert_eq!(run(Debug::NODEBUG, &prog), Ok(Vi32(40)));
+	let prog = "push 3".parse::<Instr>().map(|i| vec![i, Dup, Binary(Mul), Halt]).unwrap();
+	assert_eq!(run_full(Debug::NODEBUG, &prog).map(|f| f.stack), Ok(vec![Vi32(9)]));
+	let (result, profile) = run_profiled(&prog);
+	assert_eq!((result, profile.counts, profile.times), (Ok(Vi32(9)), vec![1; 4], None));
+    }
+
+    #[test]
+    fn test_io() {
+	// Without a backend of its own, a program cannot print.
+	let prog = [Push(Vi32(1)), Print, Push(Vunit), Halt];
+	assert_eq!(run(Debug::NODEBUG, &prog), Err(VmError::Write("no output device".into())));
+	let matched = Arc::new(AtomicUsize::new(0));
+	let io = Expect { want: "8\n", matched: Arc::clone(&matched) };
+	let prog = [Read, Push(Vi32(1)), Binary(Add), Print, Push(Vunit), Halt];
+	assert_eq!(run_with_io(Debug::NODEBUG, &prog, Box::new(io)), Ok(Vunit));
+	assert_eq!(matched.load(Ordering::Relaxed), 2);
+	let io = Expect { want: "9\n", matched };
+	assert_eq!(run_with_io(Debug::NODEBUG, &prog, Box::new(io)),
+		   Err(VmError::Write("unexpected output: \"8\\n\"".into())));
     }
-}
-
-/// Entry point from outside of this module. Run the given program in the VM.
-pub fn run(d: Debug, prog: &[Instr]) -> Result<Val, String> {
-    let mut s = State::init(prog.into());
-    exec(d, &mut s)?;
-    s.pop()
 }